@@ -0,0 +1,117 @@
+//! ELF64 kernel image
+
+use core::{mem::size_of, ops::Range};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Elf64Header {
+    ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Elf64ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+pub struct ElfImage<'a> {
+    blob: &'a [u8],
+    header: Elf64Header,
+}
+
+impl<'a> ElfImage<'a> {
+    const MAGIC: [u8; 4] = *b"\x7FELF";
+    const CLASS_64: u8 = 2;
+    const DATA_LSB: u8 = 1;
+    const ET_EXEC: u16 = 2;
+    const EM_AARCH64: u16 = 183;
+    const PT_LOAD: u32 = 1;
+
+    pub fn parse(blob: &'a [u8]) -> Result<Self, &'static str> {
+        if blob.len() < size_of::<Elf64Header>() {
+            return Err("kernel image is too small");
+        }
+        let header = unsafe { (blob.as_ptr() as *const Elf64Header).read_unaligned() };
+        if header.ident[..4] != Self::MAGIC
+            || header.ident[4] != Self::CLASS_64
+            || header.ident[5] != Self::DATA_LSB
+        {
+            return Err("kernel image is not an ELF64 file");
+        }
+        if header.e_type != Self::ET_EXEC || header.e_machine != Self::EM_AARCH64 {
+            return Err("kernel image is not an AArch64 executable");
+        }
+        let ph_end =
+            header.e_phoff as usize + header.e_phnum as usize * size_of::<Elf64ProgramHeader>();
+        if header.e_phentsize as usize != size_of::<Elf64ProgramHeader>() || ph_end > blob.len() {
+            return Err("kernel image has bad program headers");
+        }
+        let image = Self { blob, header };
+        if image.segments().any(|ph| {
+            ph.p_offset.saturating_add(ph.p_filesz) > blob.len() as u64 || ph.p_filesz > ph.p_memsz
+        }) {
+            return Err("kernel image has bad segments");
+        }
+        Ok(image)
+    }
+
+    #[inline]
+    pub fn entry(&self) -> u64 {
+        self.header.e_entry
+    }
+
+    /// Returns the loadable segments.
+    fn segments(&self) -> impl Iterator<Item = Elf64ProgramHeader> + '_ {
+        let base = self.blob.as_ptr() as usize + self.header.e_phoff as usize;
+        (0..self.header.e_phnum as usize)
+            .map(move |index| unsafe {
+                (base as *const Elf64ProgramHeader)
+                    .add(index)
+                    .read_unaligned()
+            })
+            .filter(|ph| ph.p_type == Self::PT_LOAD && ph.p_memsz > 0)
+    }
+
+    /// Returns the range of physical addresses occupied by the image.
+    pub fn memory_range(&self) -> Range<u64> {
+        let (start, end) = self.segments().fold((u64::MAX, 0), |acc, ph| {
+            (acc.0.min(ph.p_paddr), acc.1.max(ph.p_paddr + ph.p_memsz))
+        });
+        start..end
+    }
+
+    /// Copies the segments to their physical addresses and clears the rest of them.
+    ///
+    /// # Safety
+    ///
+    /// The memory of [ElfImage::memory_range] must be allocated.
+    pub unsafe fn load(&self) {
+        for ph in self.segments() {
+            let dest = ph.p_paddr as usize as *mut u8;
+            let src = self.blob.as_ptr().add(ph.p_offset as usize);
+            dest.copy_from_nonoverlapping(src, ph.p_filesz as usize);
+            dest.add(ph.p_filesz as usize)
+                .write_bytes(0, (ph.p_memsz - ph.p_filesz) as usize);
+        }
+    }
+}
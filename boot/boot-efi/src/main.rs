@@ -0,0 +1,364 @@
+//! UEFI loader for rydia
+//!
+//! Loads the kernel ELF image from the boot volume, collects the memory map,
+//! the framebuffer and the configuration tables, exits the boot services and
+//! jumps to the kernel with a [BootInfo].
+#![no_std]
+#![no_main]
+
+mod elf;
+mod uefi;
+
+use bootprot::*;
+use core::{arch::asm, convert::Infallible, fmt::Write, mem::size_of, panic::PanicInfo, slice};
+use elf::ElfImage;
+use uefi::*;
+
+static mut CON_OUT: Option<&'static SimpleTextOutput> = None;
+
+const KERNEL_PATH: &str = "\\EFI\\RYDIA\\kernel.elf";
+const MAX_CMDLINE: usize = 0x1000;
+
+/// Entry point called by the firmware
+#[no_mangle]
+pub unsafe extern "efiapi" fn efi_main(image: Handle, st: &'static SystemTable) -> Status {
+    CON_OUT = Some(st.con_out());
+    let mut con_out = st.con_out();
+    match boot(image, st) {
+        Ok(never) => match never {},
+        Err(status) => {
+            let _ = writeln!(con_out, "boot-efi: failed to start the kernel ({})", status);
+            status
+        }
+    }
+}
+
+unsafe fn boot(image: Handle, st: &'static SystemTable) -> Result<Infallible, Status> {
+    let bs = st.boot_services();
+    let mut con_out = st.con_out();
+
+    let loaded_image = bs.handle_protocol::<LoadedImage>(image)?;
+
+    let kernel = {
+        let fs = bs.handle_protocol::<SimpleFileSystem>(loaded_image.device_handle)?;
+        let root = fs.open_volume()?;
+        let mut path = [0u16; 64];
+        let file = root.open(utf16(KERNEL_PATH, &mut path)).map_err(|err| {
+            let _ = writeln!(con_out, "boot-efi: {} not found", KERNEL_PATH);
+            err
+        })?;
+        let size = file.size()?;
+        let buf = bs.allocate_pages(None, MemoryType::BootServicesData, size)?;
+        let blob = slice::from_raw_parts_mut(buf as usize as *mut u8, size);
+        let result = file.read_exact(blob);
+        file.close();
+        root.close();
+        result?;
+        blob
+    };
+    let kernel = ElfImage::parse(kernel).map_err(|err| {
+        let _ = writeln!(con_out, "boot-efi: {}", err);
+        Status::LOAD_ERROR
+    })?;
+
+    // The kernel is not relocatable, and uses the memory below itself for the boot stack
+    // and the memory after itself for early allocation, mapped in 2MB blocks.
+    let range = kernel.memory_range();
+    let kernel_base = range.start & !0x1F_FFFF;
+    let kernel_end = range
+        .end
+        .checked_add(BootInfo::EARLY_RESERVE_SIZE + 0x1F_FFFF)
+        .map(|v| v & !0x1F_FFFF)
+        .filter(|&v| v > kernel_base)
+        .ok_or_else(|| {
+            let _ = writeln!(con_out, "boot-efi: kernel image does not fit in memory");
+            Status::LOAD_ERROR
+        })?;
+    let kernel_size = (kernel_end - kernel_base) as usize;
+    // Fails unless the whole range is free conventional memory in the memory map.
+    bs.allocate_pages(Some(kernel_base), MemoryType::LoaderData, kernel_size)
+        .map_err(|err| {
+            let _ = writeln!(
+                con_out,
+                "boot-efi: kernel memory {:012x}-{:012x} is not available",
+                kernel_base,
+                kernel_end - 1
+            );
+            err
+        })?;
+    kernel.load();
+
+    let info_pa = bs.allocate_pages(None, MemoryType::LoaderData, size_of::<BootInfo>())?;
+    let info = &mut *(info_pa as usize as *mut BootInfo);
+    *info = BootInfo::with_dtb(0);
+    info.source = BootSource::Uefi;
+    info.kernel_base = kernel_base;
+    info.kernel_end = kernel_end;
+
+    if let Some(rsdp) = st.find_config_table(&Guid::ACPI_20_TABLE) {
+        info.acpi_rsdp = rsdp as u64;
+    }
+
+    // The device tree may be in the boot services memory, so it is copied.
+    if let Some(dtb) = st.find_config_table(&Guid::DTB_TABLE) {
+        let size = u32::from_be((dtb as *const u32).add(1).read_unaligned()) as usize;
+        let copy = bs.allocate_pages(None, MemoryType::LoaderData, size)?;
+        (copy as usize as *mut u8).copy_from_nonoverlapping(dtb as *const u8, size);
+        info.dtb = copy;
+    }
+    if info.dtb == 0 {
+        let _ = writeln!(
+            con_out,
+            "boot-efi: no device tree is available, the kernel may not start"
+        );
+    }
+
+    if let Ok(gop) = bs.locate_protocol::<GraphicsOutput>() {
+        let mode = gop.mode();
+        let mode_info = mode.info();
+        if mode_info.pixel_format == PixelFormat::BlueGreenRedReserved8BitPerColor {
+            info.vram_base = mode.frame_buffer_base;
+            info.vram_stride = mode_info.pixels_per_scan_line;
+            info.screen_width = mode_info.horizontal_resolution;
+            info.screen_height = mode_info.vertical_resolution;
+        }
+    }
+
+    let cmdline = bs.allocate_pages(None, MemoryType::LoaderData, MAX_CMDLINE)?;
+    let cmdline_buf = slice::from_raw_parts_mut(cmdline as usize as *mut u8, MAX_CMDLINE);
+    let cmdline_len = load_options_to_cmdline(loaded_image.load_options(), cmdline_buf);
+    if cmdline_len > 0 {
+        info.cmdline = cmdline;
+        info.cmdline_len = cmdline_len as u32;
+    }
+
+    // Allocations change the memory map, so every buffer is allocated beforehand,
+    // with some room for the descriptors that they add.
+    let (map_size, desc_size) = bs.memory_map_size();
+    let map_capacity = map_size + desc_size * 16;
+    let raw_map = bs.allocate_pages(None, MemoryType::BootServicesData, map_capacity)?;
+    let raw_map = slice::from_raw_parts_mut(raw_map as usize as *mut u8, map_capacity);
+    let mmap_capacity = map_capacity / desc_size;
+    let mmap_size = mmap_capacity * size_of::<BootMemoryMapDescriptor>();
+    let mmap_base = bs.allocate_pages(None, MemoryType::LoaderData, mmap_size)?;
+    let mmap = slice::from_raw_parts_mut(
+        mmap_base as usize as *mut BootMemoryMapDescriptor,
+        mmap_capacity,
+    );
+
+    let _ = writeln!(con_out, "boot-efi: starting the kernel...");
+
+    let mut retry = 0;
+    let raw_map_size = loop {
+        let (size, key) = bs.get_memory_map(raw_map)?;
+        match bs.exit_boot_services(image, key) {
+            Ok(_) => break size,
+            Err(err) => {
+                // The memory map may change once while exiting the boot services.
+                retry += 1;
+                if retry > 1 {
+                    return Err(err);
+                }
+            }
+        }
+    };
+    CON_OUT = None;
+
+    let mmap_len = convert_memory_map(&raw_map[..raw_map_size], desc_size, mmap);
+    info.mmap_base = mmap_base;
+    info.mmap_len = mmap_len as u32;
+    info.total_memory_size = mmap[..mmap_len]
+        .iter()
+        .filter(|v| v.mem_type.is_ram())
+        .fold(0, |acc, v| acc + v.end() - v.base);
+
+    // The kernel starts with the caches off, so everything it reads or writes
+    // before enabling them must be in memory and not in the caches.
+    clean_dcache(kernel_base, kernel_end);
+    clean_dcache(info_pa, info_pa + size_of::<BootInfo>() as u64);
+    clean_dcache(mmap_base, mmap_base + mmap_size as u64);
+    clean_dcache(cmdline, cmdline + MAX_CMDLINE as u64);
+    if info.dtb != 0 {
+        let size = u32::from_be(((info.dtb + 4) as usize as *const u32).read_unaligned());
+        clean_dcache(info.dtb, info.dtb + size as u64);
+    }
+
+    start_kernel(kernel.entry(), info_pa)
+}
+
+/// Converts the UEFI memory map into `mmap` sorted by address and returns the number of entries.
+fn convert_memory_map(
+    raw_map: &[u8],
+    desc_size: usize,
+    mmap: &mut [BootMemoryMapDescriptor],
+) -> usize {
+    let mut len = 0;
+    for raw in raw_map.chunks_exact(desc_size) {
+        let desc = unsafe { (raw.as_ptr() as *const MemoryDescriptor).read_unaligned() };
+        let mem_type = match desc.mem_type {
+            x if x == MemoryType::Conventional as u32
+                || x == MemoryType::BootServicesCode as u32
+                || x == MemoryType::BootServicesData as u32
+                || x == MemoryType::LoaderCode as u32 =>
+            {
+                BootMemoryType::Available
+            }
+            x if x == MemoryType::LoaderData as u32 => BootMemoryType::OsLoader,
+            x if x == MemoryType::RuntimeServicesCode as u32
+                || x == MemoryType::RuntimeServicesData as u32 =>
+            {
+                BootMemoryType::UefiRuntime
+            }
+            x if x == MemoryType::AcpiReclaim as u32 => BootMemoryType::AcpiReclaim,
+            x if x == MemoryType::AcpiNvs as u32 => BootMemoryType::AcpiNvs,
+            x if x == MemoryType::MemoryMappedIo as u32
+                || x == MemoryType::MemoryMappedIoPortSpace as u32 =>
+            {
+                BootMemoryType::Mmio
+            }
+            x if x == MemoryType::Unusable as u32 => BootMemoryType::Unavailable,
+            _ => BootMemoryType::Reserved,
+        };
+        let mut base = desc.physical_start;
+        let mut pages = desc.number_of_pages;
+        while pages > 0 && len < mmap.len() {
+            let page_count = pages.min(u32::MAX as u64) as u32;
+            mmap[len] = BootMemoryMapDescriptor {
+                base,
+                page_count,
+                mem_type,
+            };
+            len += 1;
+            base += page_count as u64 * BootMemoryMapDescriptor::PAGE_SIZE;
+            pages -= page_count as u64;
+        }
+    }
+
+    let mmap = &mut mmap[..len];
+    mmap.sort_unstable_by_key(|v| v.base);
+
+    // Merges the adjacent entries of the same type.
+    let mut merged = 0;
+    for index in 0..len {
+        let desc = mmap[index];
+        if merged > 0 {
+            let last = &mut mmap[merged - 1];
+            if last.mem_type == desc.mem_type
+                && last.end() == desc.base
+                && (last.page_count as u64 + desc.page_count as u64) <= u32::MAX as u64
+            {
+                last.page_count += desc.page_count;
+                continue;
+            }
+        }
+        mmap[merged] = desc;
+        merged += 1;
+    }
+    merged
+}
+
+/// Converts the load options into an ASCII command line and returns its length.
+///
+/// The UEFI shell passes the name of the image as the first word, which is skipped.
+fn load_options_to_cmdline(options: &[u16], buf: &mut [u8]) -> usize {
+    let options = match options.iter().position(|v| *v == 0) {
+        Some(len) => &options[..len],
+        None => options,
+    };
+    let mut words = options.split(|v| *v == ' ' as u16).peekable();
+    if let Some(first) = words.peek() {
+        let is_image_name = first.len() >= 4
+            && first[first.len() - 4..]
+                .iter()
+                .map(|v| (*v as u8).to_ascii_lowercase())
+                .eq(".efi".bytes());
+        if is_image_name {
+            words.next();
+        }
+    }
+
+    let mut len = 0;
+    for word in words.filter(|v| !v.is_empty()) {
+        if len + word.len() + 1 > buf.len() {
+            break;
+        }
+        if len > 0 {
+            buf[len] = b' ';
+            len += 1;
+        }
+        for ch in word {
+            buf[len] = if *ch < 0x80 { *ch as u8 } else { b'?' };
+            len += 1;
+        }
+    }
+    len
+}
+
+/// Converts `s` into a UTF-16 string that ends with a NUL.
+fn utf16<'a>(s: &str, buf: &'a mut [u16]) -> &'a [u16] {
+    let mut len = 0;
+    for ch in s.encode_utf16().take(buf.len() - 1) {
+        buf[len] = ch;
+        len += 1;
+    }
+    buf[len] = 0;
+    &buf[..=len]
+}
+
+/// Cleans and invalidates the data cache of `start..end` to the point of coherency.
+unsafe fn clean_dcache(start: u64, end: u64) {
+    let ctr_el0: u64;
+    asm!("mrs {}, ctr_el0", out(reg) ctr_el0);
+    let line_size = 4 << ((ctr_el0 >> 16) & 0xF);
+    let mut addr = start & !(line_size - 1);
+    while addr < end {
+        asm!("dc civac, {}", in(reg) addr);
+        addr += line_size;
+    }
+    asm!("dsb sy");
+}
+
+/// Turns off the MMU and the caches of the current exception level and jumps to the kernel.
+unsafe fn start_kernel(entry: u64, info: u64) -> ! {
+    asm!(
+        "
+        mrs     x9, CurrentEL
+        cmp     x9, #8
+        b.ne    1f
+        mrs     x9, sctlr_el2
+        bic     x9, x9, #1
+        bic     x9, x9, #4
+        msr     sctlr_el2, x9
+        b       2f
+    1:
+        mrs     x9, sctlr_el1
+        bic     x9, x9, #1
+        bic     x9, x9, #4
+        msr     sctlr_el1, x9
+        isb
+        tlbi    vmalle1
+    2:
+        isb
+        ic      iallu
+        dsb     sy
+        isb
+        br      x2
+        ",
+        in("x0") info,
+        in("x1") BootInfo::MAGIC,
+        in("x2") entry,
+        options(noreturn)
+    );
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if let Some(mut con_out) = unsafe { CON_OUT } {
+        let _ = writeln!(con_out, "boot-efi: PANIC: {}", info);
+    }
+    loop {
+        unsafe {
+            asm!("wfe");
+        }
+    }
+}
@@ -0,0 +1,522 @@
+//! Minimal UEFI definitions used by the loader
+
+use core::{
+    ffi::c_void,
+    fmt,
+    ptr::{null, null_mut},
+};
+
+pub type Handle = *mut c_void;
+
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status(usize);
+
+impl Status {
+    const ERROR_BIT: usize = 1 << (usize::BITS - 1);
+
+    pub const SUCCESS: Self = Self(0);
+    pub const LOAD_ERROR: Self = Self(Self::ERROR_BIT | 1);
+    pub const BUFFER_TOO_SMALL: Self = Self(Self::ERROR_BIT | 5);
+
+    #[inline]
+    pub const fn is_error(&self) -> bool {
+        (self.0 & Self::ERROR_BIT) != 0
+    }
+
+    #[inline]
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_error() {
+            Err(self)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_error() {
+            write!(f, "EFI error {}", self.0 & !Self::ERROR_BIT)
+        } else {
+            write!(f, "EFI status {}", self.0)
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(u32, u16, u16, [u8; 8]);
+
+impl Guid {
+    pub const ACPI_20_TABLE: Self = Self(
+        0x8868E871,
+        0xE4F1,
+        0x11D3,
+        [0xBC, 0x22, 0x00, 0x80, 0xC7, 0x3C, 0x88, 0x81],
+    );
+    pub const DTB_TABLE: Self = Self(
+        0xB1B621D5,
+        0xF19C,
+        0x41A5,
+        [0x83, 0x0B, 0xD9, 0x15, 0x2C, 0x69, 0xAA, 0xE0],
+    );
+}
+
+/// A protocol interface that can be located by its GUID
+pub trait Protocol {
+    const GUID: Guid;
+}
+
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    hdr: TableHeader,
+    firmware_vendor: *const u16,
+    firmware_revision: u32,
+    console_in_handle: Handle,
+    con_in: *mut c_void,
+    console_out_handle: Handle,
+    con_out: *mut SimpleTextOutput,
+    standard_error_handle: Handle,
+    std_err: *mut SimpleTextOutput,
+    runtime_services: *mut c_void,
+    boot_services: *const BootServices,
+    number_of_table_entries: usize,
+    configuration_table: *const ConfigurationTable,
+}
+
+impl SystemTable {
+    #[inline]
+    pub fn boot_services(&self) -> &BootServices {
+        unsafe { &*self.boot_services }
+    }
+
+    #[inline]
+    pub fn con_out(&self) -> &SimpleTextOutput {
+        unsafe { &*self.con_out }
+    }
+
+    #[inline]
+    pub fn configuration_table(&self) -> &[ConfigurationTable] {
+        unsafe {
+            core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
+        }
+    }
+
+    #[inline]
+    pub fn find_config_table(&self, guid: &Guid) -> Option<*mut c_void> {
+        self.configuration_table()
+            .iter()
+            .find(|v| v.vendor_guid == *guid)
+            .map(|v| v.vendor_table)
+    }
+}
+
+#[repr(C)]
+pub struct ConfigurationTable {
+    vendor_guid: Guid,
+    vendor_table: *mut c_void,
+}
+
+#[repr(C)]
+pub struct SimpleTextOutput {
+    reset: usize,
+    output_string: extern "efiapi" fn(this: &Self, string: *const u16) -> Status,
+}
+
+impl SimpleTextOutput {
+    /// Prints a UTF-16 string that ends with a NUL.
+    #[inline]
+    pub fn output_string(&self, string: &[u16]) {
+        (self.output_string)(self, string.as_ptr());
+    }
+}
+
+impl fmt::Write for &SimpleTextOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut buf = [0u16; 128];
+        let mut len = 0;
+        for ch in s.chars() {
+            if len + 3 >= buf.len() {
+                buf[len] = 0;
+                self.output_string(&buf);
+                len = 0;
+            }
+            if ch == '\n' {
+                buf[len] = '\r' as u16;
+                len += 1;
+            }
+            len += ch.encode_utf16(&mut buf[len..]).len();
+        }
+        buf[len] = 0;
+        self.output_string(&buf);
+        Ok(())
+    }
+}
+
+#[repr(u32)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocateType {
+    AnyPages,
+    MaxAddress,
+    Address,
+}
+
+#[repr(u32)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    Reserved,
+    LoaderCode,
+    LoaderData,
+    BootServicesCode,
+    BootServicesData,
+    RuntimeServicesCode,
+    RuntimeServicesData,
+    Conventional,
+    Unusable,
+    AcpiReclaim,
+    AcpiNvs,
+    MemoryMappedIo,
+    MemoryMappedIoPortSpace,
+    PalCode,
+    Persistent,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDescriptor {
+    pub mem_type: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+#[repr(C)]
+pub struct BootServices {
+    hdr: TableHeader,
+    raise_tpl: usize,
+    restore_tpl: usize,
+    allocate_pages: extern "efiapi" fn(
+        alloc_type: AllocateType,
+        mem_type: MemoryType,
+        pages: usize,
+        memory: &mut u64,
+    ) -> Status,
+    free_pages: extern "efiapi" fn(memory: u64, pages: usize) -> Status,
+    get_memory_map: extern "efiapi" fn(
+        size: &mut usize,
+        map: *mut u8,
+        key: &mut usize,
+        desc_size: &mut usize,
+        desc_version: &mut u32,
+    ) -> Status,
+    allocate_pool: usize,
+    free_pool: usize,
+    create_event: usize,
+    set_timer: usize,
+    wait_for_event: usize,
+    signal_event: usize,
+    close_event: usize,
+    check_event: usize,
+    install_protocol_interface: usize,
+    reinstall_protocol_interface: usize,
+    uninstall_protocol_interface: usize,
+    handle_protocol:
+        extern "efiapi" fn(handle: Handle, guid: &Guid, interface: &mut *mut c_void) -> Status,
+    reserved: usize,
+    register_protocol_notify: usize,
+    locate_handle: usize,
+    locate_device_path: usize,
+    install_configuration_table: usize,
+    load_image: usize,
+    start_image: usize,
+    exit: usize,
+    unload_image: usize,
+    exit_boot_services: extern "efiapi" fn(image: Handle, key: usize) -> Status,
+    get_next_monotonic_count: usize,
+    stall: usize,
+    set_watchdog_timer: usize,
+    connect_controller: usize,
+    disconnect_controller: usize,
+    open_protocol: usize,
+    close_protocol: usize,
+    open_protocol_information: usize,
+    protocols_per_handle: usize,
+    locate_handle_buffer: usize,
+    locate_protocol: extern "efiapi" fn(
+        guid: &Guid,
+        registration: *const c_void,
+        interface: &mut *mut c_void,
+    ) -> Status,
+}
+
+impl BootServices {
+    pub const PAGE_SIZE: usize = 0x1000;
+
+    /// Allocates `size` bytes of pages at `address`, or anywhere if `address` is `None`.
+    pub fn allocate_pages(
+        &self,
+        address: Option<u64>,
+        mem_type: MemoryType,
+        size: usize,
+    ) -> Result<u64, Status> {
+        let pages = size.div_ceil(Self::PAGE_SIZE);
+        let (alloc_type, mut memory) = match address {
+            Some(address) => (AllocateType::Address, address),
+            None => (AllocateType::AnyPages, 0),
+        };
+        (self.allocate_pages)(alloc_type, mem_type, pages, &mut memory).into_result()?;
+        unsafe {
+            (memory as usize as *mut u8).write_bytes(0, pages * Self::PAGE_SIZE);
+        }
+        Ok(memory)
+    }
+
+    pub fn free_pages(&self, memory: u64, size: usize) {
+        let pages = (size + Self::PAGE_SIZE - 1) / Self::PAGE_SIZE;
+        let _ = (self.free_pages)(memory, pages);
+    }
+
+    /// Returns the buffer size needed for the memory map and the size of a descriptor.
+    pub fn memory_map_size(&self) -> (usize, usize) {
+        let mut size = 0;
+        let mut key = 0;
+        let mut desc_size = 0;
+        let mut desc_version = 0;
+        let _ = (self.get_memory_map)(
+            &mut size,
+            null_mut(),
+            &mut key,
+            &mut desc_size,
+            &mut desc_version,
+        );
+        (size, desc_size)
+    }
+
+    /// Reads the memory map into `buf` and returns its size and the map key.
+    pub fn get_memory_map(&self, buf: &mut [u8]) -> Result<(usize, usize), Status> {
+        let mut size = buf.len();
+        let mut key = 0;
+        let mut desc_size = 0;
+        let mut desc_version = 0;
+        (self.get_memory_map)(
+            &mut size,
+            buf.as_mut_ptr(),
+            &mut key,
+            &mut desc_size,
+            &mut desc_version,
+        )
+        .into_result()
+        .map(|_| (size, key))
+    }
+
+    pub fn handle_protocol<T: Protocol>(&self, handle: Handle) -> Result<&'static T, Status> {
+        let mut interface = null_mut();
+        (self.handle_protocol)(handle, &T::GUID, &mut interface).into_result()?;
+        Ok(unsafe { &*(interface as *const T) })
+    }
+
+    pub fn locate_protocol<T: Protocol>(&self) -> Result<&'static T, Status> {
+        let mut interface = null_mut();
+        (self.locate_protocol)(&T::GUID, null(), &mut interface).into_result()?;
+        Ok(unsafe { &*(interface as *const T) })
+    }
+
+    /// Terminates the boot services. Nothing in this table may be used after this succeeds.
+    #[inline]
+    pub unsafe fn exit_boot_services(&self, image: Handle, key: usize) -> Result<(), Status> {
+        (self.exit_boot_services)(image, key).into_result()
+    }
+}
+
+#[repr(C)]
+pub struct LoadedImage {
+    revision: u32,
+    parent_handle: Handle,
+    system_table: *const SystemTable,
+    pub device_handle: Handle,
+    file_path: *const c_void,
+    reserved: *const c_void,
+    load_options_size: u32,
+    load_options: *const u16,
+    image_base: *const c_void,
+    image_size: u64,
+    image_code_type: MemoryType,
+    image_data_type: MemoryType,
+    unload: usize,
+}
+
+impl Protocol for LoadedImage {
+    const GUID: Guid = Guid(
+        0x5B1B31A1,
+        0x9562,
+        0x11D2,
+        [0x8E, 0x3F, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+    );
+}
+
+impl LoadedImage {
+    /// Returns the load options, which are the command line in UCS-2.
+    #[inline]
+    pub fn load_options(&self) -> &[u16] {
+        if self.load_options.is_null() {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(self.load_options, self.load_options_size as usize / 2)
+        }
+    }
+}
+
+#[repr(C)]
+pub struct SimpleFileSystem {
+    revision: u64,
+    open_volume: extern "efiapi" fn(this: &Self, root: &mut *mut FileProtocol) -> Status,
+}
+
+impl Protocol for SimpleFileSystem {
+    const GUID: Guid = Guid(
+        0x964E5B22,
+        0x6459,
+        0x11D2,
+        [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B],
+    );
+}
+
+impl SimpleFileSystem {
+    pub fn open_volume(&self) -> Result<&FileProtocol, Status> {
+        let mut root = null_mut();
+        (self.open_volume)(self, &mut root).into_result()?;
+        Ok(unsafe { &*root })
+    }
+}
+
+#[repr(C)]
+pub struct FileProtocol {
+    revision: u64,
+    open: extern "efiapi" fn(
+        this: &Self,
+        new_handle: &mut *mut FileProtocol,
+        file_name: *const u16,
+        open_mode: u64,
+        attributes: u64,
+    ) -> Status,
+    close: extern "efiapi" fn(this: &Self) -> Status,
+    delete: usize,
+    read: extern "efiapi" fn(this: &Self, size: &mut usize, buffer: *mut u8) -> Status,
+    write: usize,
+    get_position: extern "efiapi" fn(this: &Self, position: &mut u64) -> Status,
+    set_position: extern "efiapi" fn(this: &Self, position: u64) -> Status,
+}
+
+impl FileProtocol {
+    const MODE_READ: u64 = 1;
+
+    /// Opens the file at `path`, which is a UTF-16 string that ends with a NUL.
+    pub fn open(&self, path: &[u16]) -> Result<&FileProtocol, Status> {
+        let mut file = null_mut();
+        (self.open)(self, &mut file, path.as_ptr(), Self::MODE_READ, 0).into_result()?;
+        Ok(unsafe { &*file })
+    }
+
+    #[inline]
+    pub fn close(&self) {
+        let _ = (self.close)(self);
+    }
+
+    /// Returns the size of the file.
+    pub fn size(&self) -> Result<usize, Status> {
+        let mut size = 0;
+        (self.set_position)(self, u64::MAX).into_result()?;
+        (self.get_position)(self, &mut size).into_result()?;
+        (self.set_position)(self, 0).into_result()?;
+        Ok(size as usize)
+    }
+
+    /// Reads the whole `buf` from the current position.
+    pub fn read_exact(&self, buf: &mut [u8]) -> Result<(), Status> {
+        let mut pos = 0;
+        while pos < buf.len() {
+            let mut size = buf.len() - pos;
+            (self.read)(self, &mut size, buf[pos..].as_mut_ptr()).into_result()?;
+            if size == 0 {
+                return Err(Status::LOAD_ERROR);
+            }
+            pos += size;
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+pub struct GraphicsOutput {
+    query_mode: usize,
+    set_mode: usize,
+    blt: usize,
+    mode: *const GraphicsOutputMode,
+}
+
+impl Protocol for GraphicsOutput {
+    const GUID: Guid = Guid(
+        0x9042A9DE,
+        0x23DC,
+        0x4A38,
+        [0x96, 0xFB, 0x7A, 0xDE, 0xD0, 0x80, 0x51, 0x6A],
+    );
+}
+
+impl GraphicsOutput {
+    #[inline]
+    pub fn mode(&self) -> &GraphicsOutputMode {
+        unsafe { &*self.mode }
+    }
+}
+
+#[repr(C)]
+pub struct GraphicsOutputMode {
+    max_mode: u32,
+    mode: u32,
+    info: *const GraphicsOutputModeInfo,
+    size_of_info: usize,
+    pub frame_buffer_base: u64,
+    pub frame_buffer_size: usize,
+}
+
+impl GraphicsOutputMode {
+    #[inline]
+    pub fn info(&self) -> &GraphicsOutputModeInfo {
+        unsafe { &*self.info }
+    }
+}
+
+#[repr(C)]
+pub struct GraphicsOutputModeInfo {
+    version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: PixelFormat,
+    pixel_information: [u32; 4],
+    pub pixels_per_scan_line: u32,
+}
+
+#[repr(u32)]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    RedGreenBlueReserved8BitPerColor,
+    BlueGreenRedReserved8BitPerColor,
+    BitMask,
+    BltOnly,
+}
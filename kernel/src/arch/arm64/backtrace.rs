@@ -0,0 +1,75 @@
+//! Stack unwinding with frame records
+//!
+//! The kernel is built with frame pointers, so `x29` points to a record of the previous
+//! `x29` and the return address in every function.
+
+use super::{exception, stack::KernelStack};
+use core::arch::asm;
+
+/// Program counters of the calls on the stack, starting from the innermost one
+pub struct Backtrace {
+    pc: Option<usize>,
+    fp: usize,
+    depth: usize,
+}
+
+impl Backtrace {
+    const MAX_DEPTH: usize = 64;
+
+    /// Starts at `pc` in the function whose frame record is at `fp`.
+    #[inline]
+    pub const fn new(pc: usize, fp: usize) -> Self {
+        Self {
+            pc: Some(pc),
+            fp,
+            depth: 0,
+        }
+    }
+
+    /// Starts at the caller of this function.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let fp: usize;
+        unsafe {
+            asm!("mov {}, x29", out(reg) fp, options(nomem, nostack));
+        }
+        Self {
+            pc: None,
+            fp,
+            depth: 0,
+        }
+    }
+
+    /// Frame records are read only where a stack can be.
+    #[inline]
+    fn is_valid_frame(fp: usize) -> bool {
+        (fp & 7) == 0
+            && (KernelStack::contains(fp) || exception::is_overflow_stack(fp))
+            && (KernelStack::contains(fp + 8) || exception::is_overflow_stack(fp + 8))
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pc) = self.pc.take() {
+            return Some(pc);
+        }
+        if self.depth >= Self::MAX_DEPTH || !Self::is_valid_frame(self.fp) {
+            return None;
+        }
+        let (fp, lr) = unsafe {
+            let record = self.fp as *const usize;
+            (record.read_volatile(), record.add(1).read_volatile())
+        };
+        if lr == 0 {
+            return None;
+        }
+        // Stacks grow down, so anything else is a broken or cyclic chain.
+        self.fp = if fp > self.fp { fp } else { 0 };
+        self.depth += 1;
+        // The call instruction before the return address
+        Some(lr - 4)
+    }
+}
@@ -0,0 +1,155 @@
+//! Entry points shared by all boards
+
+use super::smp::MAX_CPUS;
+use crate::mem::PhysicalAddress;
+use core::arch::asm;
+
+/// Kernel entry point
+///
+/// The first 64 bytes are an arm64 `Image` header, so that boot loaders such as
+/// QEMU's `-kernel` pass the device tree in `x0` and load the kernel at `text_offset`
+/// from the base of RAM.
+///
+/// `x0` and `x1` are passed to `main` as is: either the device tree and zero,
+/// or a `BootInfo` and its magic from the UEFI loader.
+///
+/// Only the boot core is expected here. Other cores that enter this way are parked,
+/// since they have no stack yet.
+///
+/// The boot core runs on the stack below `_start`, whose guard is set up by
+/// [KernelStack](super::stack::KernelStack).
+#[no_mangle]
+#[naked]
+#[link_section = ".text.boot"]
+pub(super) unsafe extern "C" fn _start() {
+    asm!(
+        "
+        b       1f
+        .long   0
+        .quad   0x80000
+        .quad   __image_size
+        .quad   0x2
+        .quad   0
+        .quad   0
+        .quad   0
+        .long   0x644d5241
+        .long   0
+    1:
+        mrs     x5, mpidr_el1
+        and     x5, x5, #0xFFFFFF
+        cbz     x5, 2f
+    100:
+        wfe
+        b       100b
+
+    2:
+        adr     x4, _start
+        mov     sp, x4
+        bl      _el1_setup
+
+        ldr     x5, =__bss_start
+        ldr     w6, =__bss_size
+    3:  cbz     w6, 4f
+        str     xzr, [x5], #8
+        sub     w6, w6, #1
+        cbnz    w6, 3b
+
+    4:  ldr     x5, =__percpu_start
+        ldr     x6, =__percpu_end
+        ldr     x7, =__percpu_boot
+        sub     x8, x7, x5
+        msr     tpidr_el1, x8
+    5:  cmp     x5, x6
+        b.hs    6f
+        ldr     x8, [x5], #8
+        str     x8, [x7], #8
+        b       5b
+
+    6:  bl      main
+    7:  wfe
+        b       7b
+    ",
+        options(noreturn)
+    );
+}
+
+/// Entry point of secondary cores, from either the spin table or PSCI `CPU_ON`
+///
+/// The core that wakes this core fills the boot slot of its logical id in `SMP_BOOT_SLOTS`
+/// beforehand, and this core finds the slot by its affinity. The core switches to its own
+/// stack once the MMU is on.
+#[naked]
+pub(super) unsafe extern "C" fn _start_secondary() {
+    asm!(
+        "
+        mrs     x5, mpidr_el1
+        and     x6, x5, #0xFF00000000
+        and     x5, x5, #0xFFFFFF
+        orr     x5, x5, x6
+        ldr     x6, =SMP_BOOT_SLOTS
+        mov     x0, #0
+    1:  ldp     x7, x4, [x6], #16
+        cmp     x7, x5
+        ccmp    x4, #0, #4, eq
+        b.ne    2f
+        add     x0, x0, #1
+        cmp     x0, #{max_cpus}
+        b.lo    1b
+    3:  wfe
+        b       3b
+
+    2:  mov     sp, x4
+        bl      _el1_setup
+        bl      _smp_main
+        b       3b
+    ",
+        max_cpus = const MAX_CPUS,
+        options(noreturn)
+    );
+}
+
+/// Enables the FPU and drops from EL2 to EL1 keeping the current stack if needed.
+///
+/// Preserves `x0` and `x1`.
+#[no_mangle]
+#[naked]
+unsafe extern "C" fn _el1_setup() {
+    asm!(
+        "
+        mov     x2, #3 << 20
+        msr     cpacr_el1, x2
+
+        mrs     x2, CurrentEL
+        cmp     x2, #8
+        b.ne    1f
+
+        mov     x4, sp
+        msr     sp_el1, x4
+
+        mrs     x2, midr_el1
+        mrs     x3, mpidr_el1
+        msr     vpidr_el2, x2
+        msr     vmpidr_el2, x3
+
+        mov     x2, #0x0002
+        movk    x2, #0x8000, lsl #16
+        msr     hcr_el2, x2
+        msr     elr_el2, x30
+        mov     x4, #0x03C5
+        msr     spsr_el2, x4
+        eret
+    1:
+        ret
+    ",
+        options(noreturn)
+    );
+}
+
+#[inline]
+pub(super) fn _end() -> PhysicalAddress {
+    let result: u64;
+    unsafe {
+        asm!("ldr {}, =_end", out(reg)result);
+    }
+    PhysicalAddress::new(result)
+}
@@ -1,11 +1,95 @@
+use super::timer::GenericTimer;
 use core::{
     arch::asm,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
 
+crate::percpu! {
+    static CURRENT: CpuLocal = CpuLocal::new();
+}
+
+/// State of a core kept in its per-core data
+pub struct CpuLocal {
+    id: AtomicUsize,
+    mpidr: AtomicU64,
+    need_resched: AtomicBool,
+    irq_count: AtomicU64,
+}
+
+impl CpuLocal {
+    const fn new() -> Self {
+        Self {
+            id: AtomicUsize::new(0),
+            mpidr: AtomicU64::new(0),
+            need_resched: AtomicBool::new(false),
+            irq_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the logical id of the core.
+    #[inline]
+    pub fn id(&self) -> usize {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    /// Returns `MPIDR_EL1` of the core without the `U` and `MT` flags.
+    #[inline]
+    pub fn mpidr(&self) -> u64 {
+        self.mpidr.load(Ordering::Relaxed)
+    }
+
+    /// Asks the core to look for other work at the next opportunity.
+    #[inline]
+    pub fn set_need_resched(&self) {
+        self.need_resched.store(true, Ordering::Release);
+    }
+
+    /// Returns and clears the request of [CpuLocal::set_need_resched].
+    #[inline]
+    pub fn take_need_resched(&self) -> bool {
+        self.need_resched.swap(false, Ordering::AcqRel)
+    }
+
+    /// Returns the number of interrupts the core has taken.
+    #[inline]
+    pub fn irq_count(&self) -> u64 {
+        self.irq_count.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(super) fn count_irq(&self) {
+        self.irq_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 pub struct Cpu {}
 
 impl Cpu {
+    /// Returns the state of the current core.
+    ///
+    /// Unless interrupts are disabled, the caller may be on another core by the time it
+    /// reads the state.
+    #[inline]
+    pub fn current() -> &'static CpuLocal {
+        CURRENT.get_current()
+    }
+
+    /// Returns the state of the core `cpuid`, if its per-core data has been set up.
+    #[inline]
+    pub fn local(cpuid: usize) -> Option<&'static CpuLocal> {
+        CURRENT.get_for(cpuid)
+    }
+
+    /// Sets up the state of the current core, once its per-core data is in place.
+    pub(super) fn init_current(cpuid: usize) {
+        let current = Self::current();
+        current.id.store(cpuid, Ordering::Relaxed);
+        current
+            .mpidr
+            .store(Self::current_mpidr(), Ordering::Relaxed);
+        GenericTimer::enable_event_stream();
+    }
+
     #[inline]
     pub fn no_op() {
         unsafe {
@@ -25,6 +109,16 @@ impl Cpu {
         }
     }
 
+    /// Returns `MPIDR_EL1` of the current core without the `U` and `MT` flags.
+    #[inline]
+    pub fn current_mpidr() -> u64 {
+        let result: u64;
+        unsafe {
+            asm!("mrs {}, mpidr_el1", out(reg) result, options(nomem, nostack));
+        }
+        result & 0xFF_00FF_FFFF
+    }
+
     #[inline]
     pub fn wait_for_interrupt() {
         unsafe {
@@ -32,6 +126,23 @@ impl Cpu {
         }
     }
 
+    /// Sleeps until an event is signaled by [Cpu::send_event], an interrupt or the event stream
+    /// of the timer.
+    #[inline]
+    pub fn wait_for_event() {
+        unsafe {
+            asm!("wfe");
+        }
+    }
+
+    /// Wakes up all cores waiting in [Cpu::wait_for_event].
+    #[inline]
+    pub fn send_event() {
+        unsafe {
+            asm!("dsb ishst", "sev");
+        }
+    }
+
     #[inline]
     pub unsafe fn enable_interrupt() {
         asm!("
@@ -0,0 +1,539 @@
+//! Exception vectors and the transitions between EL1 and EL0
+
+use super::{
+    cpu::Cpu,
+    gic::{Gic, GicVersion},
+    raspi::local::LocalIntc,
+    smp::{Smp, MAX_CPUS, SGI_IPI},
+    stack::KernelStack,
+    timer::GenericTimer,
+};
+use core::{
+    arch::asm,
+    arch::global_asm,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Registers saved on exception entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrapFrame {
+    pub x: [u64; 31],
+    pub sp: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
+}
+
+impl TrapFrame {
+    /// EL0t with all interrupts unmasked
+    const SPSR_EL0: u64 = 0;
+
+    /// Returns the initial state of a user thread.
+    #[inline]
+    pub fn new_user(entry: usize, sp: usize) -> Self {
+        Self {
+            sp: sp as u64,
+            elr: entry as u64,
+            spsr: Self::SPSR_EL0,
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub const fn exception_class(&self) -> ExceptionClass {
+        ExceptionClass((self.esr >> 26) as u32 & 0x3F)
+    }
+
+    /// Returns the system call number in `x8`.
+    #[inline]
+    pub const fn syscall_number(&self) -> usize {
+        self.x[8] as usize
+    }
+
+    /// Returns the system call argument `index` in `x0`-`x5`.
+    #[inline]
+    pub const fn syscall_arg(&self, index: usize) -> usize {
+        self.x[index] as usize
+    }
+
+    #[inline]
+    pub fn set_syscall_result(&mut self, value: isize) {
+        self.x[0] = value as u64;
+    }
+
+    /// Returns the registers of the caller, with the return address in `elr` and `x30`.
+    ///
+    /// Only `x19`-`x29` and `sp` are as they are at the call.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let mut frame = Self::default();
+        unsafe {
+            asm!(
+                "
+                stp     x0, x1, [x16]
+                stp     x2, x3, [x16, #16]
+                stp     x4, x5, [x16, #32]
+                stp     x6, x7, [x16, #48]
+                stp     x8, x9, [x16, #64]
+                stp     x10, x11, [x16, #80]
+                stp     x12, x13, [x16, #96]
+                stp     x14, x15, [x16, #112]
+                stp     x16, x17, [x16, #128]
+                stp     x18, x19, [x16, #144]
+                stp     x20, x21, [x16, #160]
+                stp     x22, x23, [x16, #176]
+                stp     x24, x25, [x16, #192]
+                stp     x26, x27, [x16, #208]
+                ldp     x17, x30, [x29]
+                stp     x28, x17, [x16, #224]
+                add     x17, x29, #16
+                stp     x30, x17, [x16, #240]
+                str     x30, [x16, #256]
+                mrs     x17, daif
+                str     x17, [x16, #264]
+                ",
+                in("x16") &mut frame as *mut Self,
+                out("x17") _,
+                out("x30") _,
+            );
+        }
+        frame
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, x) in self.x.iter().enumerate() {
+            let sep = if (index % 4) == 3 { "\n" } else { "  " };
+            let pad = if index < 10 { " " } else { "" };
+            write!(f, "{}x{} {:016x}{}", pad, index, x, sep)?;
+        }
+        writeln!(f, " sp {:016x}", self.sp)?;
+        writeln!(
+            f,
+            "elr {:016x} spsr {:08x} esr {:08x} far {:016x}",
+            self.elr, self.spsr, self.esr, self.far
+        )
+    }
+}
+
+/// Exception class in `ESR_EL1`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionClass(pub u32);
+
+impl ExceptionClass {
+    pub const UNKNOWN: Self = Self(0x00);
+    pub const SVC64: Self = Self(0x15);
+    pub const INSTRUCTION_ABORT_LOWER: Self = Self(0x20);
+    pub const INSTRUCTION_ABORT: Self = Self(0x21);
+    pub const PC_ALIGNMENT: Self = Self(0x22);
+    pub const DATA_ABORT_LOWER: Self = Self(0x24);
+    pub const DATA_ABORT: Self = Self(0x25);
+    pub const SP_ALIGNMENT: Self = Self(0x26);
+    pub const BRK64: Self = Self(0x3C);
+
+    #[inline]
+    pub const fn is_abort(&self) -> bool {
+        matches!(self.0, 0x20 | 0x21 | 0x24 | 0x25)
+    }
+}
+
+impl fmt::Debug for ExceptionClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Self::UNKNOWN => "Unknown",
+            Self::SVC64 => "SVC64",
+            Self::INSTRUCTION_ABORT_LOWER => "InstructionAbortLower",
+            Self::INSTRUCTION_ABORT => "InstructionAbort",
+            Self::PC_ALIGNMENT => "PcAlignment",
+            Self::DATA_ABORT_LOWER => "DataAbortLower",
+            Self::DATA_ABORT => "DataAbort",
+            Self::SP_ALIGNMENT => "SpAlignment",
+            Self::BRK64 => "BRK64",
+            _ => return write!(f, "ExceptionClass({:#04x})", self.0),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Reason why [run_user] returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserExit {
+    /// `svc` was executed
+    Syscall,
+    /// Any other synchronous exception or SError; the details are in `esr` and `far`
+    Fault,
+    /// The tick armed by [run_user] expired
+    Tick,
+}
+
+#[allow(dead_code)]
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExceptionKind {
+    Synchronous = 0,
+    Irq = 1,
+    Fiq = 2,
+    SError = 3,
+}
+
+/// Number of stacks used to report a stack overflow, one for each logical core
+const OVERFLOW_STACKS: usize = MAX_CPUS;
+const OVERFLOW_STACK_SIZE: usize = 0x2000;
+
+#[repr(C, align(16))]
+struct OverflowStacks([[u8; OVERFLOW_STACK_SIZE]; OVERFLOW_STACKS]);
+
+static mut KERNEL_OVERFLOW_STACKS: OverflowStacks =
+    OverflowStacks([[0; OVERFLOW_STACK_SIZE]; OVERFLOW_STACKS]);
+
+crate::percpu! {
+    /// Top of the overflow stack of the core, which `_kernel_overflow` switches to
+    #[no_mangle]
+    static KERNEL_OVERFLOW_SP: AtomicUsize = AtomicUsize::new(0);
+}
+
+crate::percpu! {
+    /// Frame of the fatal exception of the core, for the panic handler
+    static FAULT_FRAME: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Returns whether `va` is in one of the stacks used to report a stack overflow.
+pub(super) fn is_overflow_stack(va: usize) -> bool {
+    let base = unsafe { KERNEL_OVERFLOW_STACKS.0.as_ptr() as usize };
+    (base..base + OVERFLOW_STACK_SIZE * OVERFLOW_STACKS).contains(&va)
+}
+
+/// Returns the registers at the fatal exception that the current core is panicking for.
+pub fn fault_frame() -> Option<TrapFrame> {
+    let frame = FAULT_FRAME.with(|v| v.load(Ordering::Acquire)) as *const TrapFrame;
+    (!frame.is_null()).then(|| unsafe { frame.read() })
+}
+
+/// Installs the exception vectors on the current core.
+///
+/// The logical id of the core must be set up beforehand.
+pub unsafe fn init() {
+    let cpuid = Cpu::current().id();
+    assert!(cpuid < OVERFLOW_STACKS);
+    let stack = KERNEL_OVERFLOW_STACKS.0[cpuid].as_ptr() as usize;
+    KERNEL_OVERFLOW_SP.with(|v| v.store(stack + OVERFLOW_STACK_SIZE, Ordering::Relaxed));
+
+    asm!("
+        adr {0}, _exception_vectors
+        msr vbar_el1, {0}
+        isb
+        ", out(reg) _);
+}
+
+/// Runs the user context `frame` at EL0 until it needs the kernel or `tick` elapses.
+///
+/// Other interrupts taken from EL0 are handled without returning. The interrupt of the
+/// timer must be enabled with [GenericTimer::set_irq_enabled], and interrupts of the
+/// current core are masked on return.
+/// The current `TTBR0_EL1` must map the user address space.
+#[inline]
+pub unsafe fn run_user(frame: &mut TrapFrame, tick: Duration) -> UserExit {
+    // A tick taken at EL1 would disarm the timer before the context starts.
+    Cpu::disable_interrupt();
+    GenericTimer::set_timeout(tick);
+    let result = _run_user(frame);
+    GenericTimer::stop();
+    match result {
+        1 => UserExit::Syscall,
+        3 => UserExit::Tick,
+        _ => UserExit::Fault,
+    }
+}
+
+extern "C" {
+    fn _run_user(frame: *mut TrapFrame) -> u64;
+}
+
+/// Handles an interrupt, and returns whether it was the timer, which is disarmed.
+unsafe fn handle_irq() -> bool {
+    Cpu::current().count_irq();
+    if Gic::version() == GicVersion::None {
+        if !LocalIntc::is_available() {
+            return false;
+        }
+        if LocalIntc::acknowledge() {
+            Smp::handle_ipi();
+        }
+        let tick = LocalIntc::is_timer_pending();
+        if tick {
+            GenericTimer::stop();
+        }
+        return tick;
+    }
+    let irq = Gic::acknowledge();
+    let tick = irq == GenericTimer::VIRTUAL_TIMER_IRQ;
+    if tick {
+        // The timer is level triggered, and must be quiet before the end of the interrupt.
+        GenericTimer::stop();
+    }
+    if !irq.is_spurious() {
+        Gic::eoi(irq);
+    }
+    if irq == SGI_IPI {
+        Smp::handle_ipi();
+    }
+    tick
+}
+
+/// Called for exceptions taken from EL1.
+#[no_mangle]
+unsafe extern "C" fn _kernel_exception(frame: &mut TrapFrame, kind: ExceptionKind) {
+    match kind {
+        ExceptionKind::Irq => {
+            handle_irq();
+        }
+        ExceptionKind::Synchronous
+            if frame.exception_class() == ExceptionClass::DATA_ABORT
+                && KernelStack::guard_owner(frame.far as usize).is_some() =>
+        {
+            stack_overflow(frame.sp as usize, frame.elr as usize, frame.far as usize)
+        }
+        _ => {
+            FAULT_FRAME.with(|v| v.store(frame as *mut _ as usize, Ordering::Release));
+            panic!(
+                "{:?} exception {:?} at {:016x}, ESR {:08x} FAR {:016x}",
+                kind,
+                frame.exception_class(),
+                frame.elr,
+                frame.esr,
+                frame.far
+            )
+        }
+    }
+}
+
+/// Called on an overflow stack for exceptions taken from EL1 with `sp` out of its stack.
+#[no_mangle]
+unsafe extern "C" fn _kernel_stack_overflow(sp: usize, elr: usize, far: usize) -> ! {
+    stack_overflow(sp, elr, far)
+}
+
+fn stack_overflow(sp: usize, elr: usize, far: usize) -> ! {
+    let cpuid = Cpu::current().id();
+    match KernelStack::guard_owner(sp).or_else(|| KernelStack::guard_owner(far)) {
+        Some(thread) => panic!(
+            "stack overflow on core {} / thread {} at {:016x}, SP {:016x} FAR {:016x}",
+            cpuid, thread, elr, sp, far
+        ),
+        None => panic!(
+            "stack overflow on core {} at {:016x}, SP {:016x} FAR {:016x}",
+            cpuid, elr, sp, far
+        ),
+    }
+}
+
+/// Called for exceptions taken from EL0. Returns zero to resume the user context,
+/// or the value returned by [_run_user].
+#[no_mangle]
+unsafe extern "C" fn _user_exception(frame: &mut TrapFrame, kind: ExceptionKind) -> u64 {
+    match kind {
+        ExceptionKind::Irq => {
+            if handle_irq() {
+                3
+            } else {
+                0
+            }
+        }
+        ExceptionKind::Synchronous if frame.exception_class() == ExceptionClass::SVC64 => 1,
+        _ => 2,
+    }
+}
+
+// Layout of TrapFrame: x0-x30 at 0, sp at 248, elr at 256, spsr at 264, esr at 272, far at 280.
+//
+// While a user context runs, SP_EL1 points to the frame of _run_user, whose first slot
+// holds the pointer to the TrapFrame of the context.
+//
+// Exceptions from EL1 test bit 17 (KernelStack::SHIFT) of sp before saving anything, and
+// swap sp and x0 arithmetically to do so without a free register. A clear bit means sp
+// has run into the guard of its stack, and the exception goes to _kernel_overflow with
+// the faulting sp in x0.
+global_asm!(
+    "
+    .section .text
+    .balign 0x800
+    .global _exception_vectors
+_exception_vectors:
+    .irp kind, 0, 1, 2, 3
+    .balign 0x80
+    sub     sp, sp, #288
+    stp     x0, x1, [sp]
+    mov     x1, #\\kind
+    b       _kernel_entry
+    .endr
+
+    .irp kind, 0, 1, 2, 3
+    .balign 0x80
+    sub     sp, sp, #288
+    add     sp, sp, x0
+    sub     x0, sp, x0
+    tbz     x0, #17, _kernel_overflow
+    sub     x0, sp, x0
+    sub     sp, sp, x0
+    stp     x0, x1, [sp]
+    mov     x1, #\\kind
+    b       _kernel_entry
+    .endr
+
+    .irp kind, 0, 1, 2, 3
+    .balign 0x80
+    stp     x0, x1, [sp, #-16]!
+    mov     x1, #\\kind
+    b       _user_entry
+    .endr
+
+    .irp kind, 0, 1, 2, 3
+    .balign 0x80
+    stp     x0, x1, [sp, #-16]!
+    mov     x1, #\\kind
+    b       _user_entry
+    .endr
+
+_kernel_entry:
+    stp     x2, x3, [sp, #16]
+    stp     x4, x5, [sp, #32]
+    stp     x6, x7, [sp, #48]
+    stp     x8, x9, [sp, #64]
+    stp     x10, x11, [sp, #80]
+    stp     x12, x13, [sp, #96]
+    stp     x14, x15, [sp, #112]
+    stp     x16, x17, [sp, #128]
+    stp     x18, x19, [sp, #144]
+    stp     x20, x21, [sp, #160]
+    stp     x22, x23, [sp, #176]
+    stp     x24, x25, [sp, #192]
+    stp     x26, x27, [sp, #208]
+    stp     x28, x29, [sp, #224]
+    add     x2, sp, #288
+    stp     x30, x2, [sp, #240]
+    mrs     x2, elr_el1
+    mrs     x3, spsr_el1
+    stp     x2, x3, [sp, #256]
+    mrs     x2, esr_el1
+    mrs     x3, far_el1
+    stp     x2, x3, [sp, #272]
+
+    mov     x0, sp
+    bl      _kernel_exception
+
+    ldp     x2, x3, [sp, #256]
+    msr     elr_el1, x2
+    msr     spsr_el1, x3
+    ldp     x2, x3, [sp, #16]
+    ldp     x4, x5, [sp, #32]
+    ldp     x6, x7, [sp, #48]
+    ldp     x8, x9, [sp, #64]
+    ldp     x10, x11, [sp, #80]
+    ldp     x12, x13, [sp, #96]
+    ldp     x14, x15, [sp, #112]
+    ldp     x16, x17, [sp, #128]
+    ldp     x18, x19, [sp, #144]
+    ldp     x20, x21, [sp, #160]
+    ldp     x22, x23, [sp, #176]
+    ldp     x24, x25, [sp, #192]
+    ldp     x26, x27, [sp, #208]
+    ldp     x28, x29, [sp, #224]
+    ldr     x30, [sp, #240]
+    ldp     x0, x1, [sp]
+    add     sp, sp, #288
+    eret
+
+_kernel_overflow:
+    mrs     x1, tpidr_el1
+    ldr     x2, =KERNEL_OVERFLOW_SP
+    ldr     x2, [x2, x1]
+    mov     sp, x2
+    add     x0, x0, #288
+    mrs     x1, elr_el1
+    mrs     x2, far_el1
+    bl      _kernel_stack_overflow
+
+_user_entry:
+    ldr     x0, [sp, #16]
+    stp     x2, x3, [x0, #16]
+    stp     x4, x5, [x0, #32]
+    stp     x6, x7, [x0, #48]
+    stp     x8, x9, [x0, #64]
+    stp     x10, x11, [x0, #80]
+    stp     x12, x13, [x0, #96]
+    stp     x14, x15, [x0, #112]
+    stp     x16, x17, [x0, #128]
+    stp     x18, x19, [x0, #144]
+    stp     x20, x21, [x0, #160]
+    stp     x22, x23, [x0, #176]
+    stp     x24, x25, [x0, #192]
+    stp     x26, x27, [x0, #208]
+    stp     x28, x29, [x0, #224]
+    str     x30, [x0, #240]
+    ldp     x2, x3, [sp], #16
+    stp     x2, x3, [x0]
+    mrs     x2, sp_el0
+    str     x2, [x0, #248]
+    mrs     x2, elr_el1
+    mrs     x3, spsr_el1
+    stp     x2, x3, [x0, #256]
+    mrs     x2, esr_el1
+    mrs     x3, far_el1
+    stp     x2, x3, [x0, #272]
+
+    bl      _user_exception
+    cbnz    x0, _user_return
+    b       _user_restore
+
+    .global _run_user
+_run_user:
+    sub     sp, sp, #112
+    str     x0, [sp]
+    stp     x19, x20, [sp, #16]
+    stp     x21, x22, [sp, #32]
+    stp     x23, x24, [sp, #48]
+    stp     x25, x26, [sp, #64]
+    stp     x27, x28, [sp, #80]
+    stp     x29, x30, [sp, #96]
+
+_user_restore:
+    ldr     x0, [sp]
+    ldr     x2, [x0, #248]
+    msr     sp_el0, x2
+    ldp     x2, x3, [x0, #256]
+    msr     elr_el1, x2
+    msr     spsr_el1, x3
+    ldp     x2, x3, [x0, #16]
+    ldp     x4, x5, [x0, #32]
+    ldp     x6, x7, [x0, #48]
+    ldp     x8, x9, [x0, #64]
+    ldp     x10, x11, [x0, #80]
+    ldp     x12, x13, [x0, #96]
+    ldp     x14, x15, [x0, #112]
+    ldp     x16, x17, [x0, #128]
+    ldp     x18, x19, [x0, #144]
+    ldp     x20, x21, [x0, #160]
+    ldp     x22, x23, [x0, #176]
+    ldp     x24, x25, [x0, #192]
+    ldp     x26, x27, [x0, #208]
+    ldp     x28, x29, [x0, #224]
+    ldr     x30, [x0, #240]
+    ldp     x0, x1, [x0]
+    eret
+
+_user_return:
+    ldp     x19, x20, [sp, #16]
+    ldp     x21, x22, [sp, #32]
+    ldp     x23, x24, [sp, #48]
+    ldp     x25, x26, [sp, #64]
+    ldp     x27, x28, [sp, #80]
+    ldp     x29, x30, [sp, #96]
+    add     sp, sp, #112
+    ret
+    "
+);
@@ -0,0 +1,427 @@
+//! Generic Interrupt Controller (GICv2 / GICv3)
+
+use super::{
+    cpu::Cpu,
+    smp::{Smp, MAX_CPUS},
+};
+use crate::{
+    fw::dt::DeviceTree,
+    shell::{ShellCommand, ShellError},
+};
+use core::{
+    arch::asm,
+    fmt::Write,
+    sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering},
+};
+
+static GIC_VERSION: AtomicUsize = AtomicUsize::new(GicVersion::None as usize);
+static GICD_BASE: AtomicUsize = AtomicUsize::new(0);
+/// GICC base for GICv2, GICR base for GICv3
+static GICC_BASE: AtomicUsize = AtomicUsize::new(0);
+static MAX_IRQ: AtomicU32 = AtomicU32::new(0);
+/// Bit of the GICv2 CPU interface of each logical core, as in `GICD_ITARGETSR`
+static CPU_INTERFACES: [AtomicU8; MAX_CPUS] = [CPU_INTERFACE_NONE; MAX_CPUS];
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_INTERFACE_NONE: AtomicU8 = AtomicU8::new(0);
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicVersion {
+    None = 0,
+    V2,
+    V3,
+}
+
+/// Interrupt number
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Irq(pub u32);
+
+impl Irq {
+    /// First Shared Peripheral Interrupt
+    pub const SPI_BASE: u32 = 32;
+    /// Interrupt IDs from this value are special (spurious, etc.)
+    pub const SPECIAL: u32 = 1020;
+
+    /// Returns the IRQ of the Private Peripheral Interrupt `ppi`.
+    #[inline]
+    pub const fn ppi(ppi: u32) -> Self {
+        Self(16 + ppi)
+    }
+
+    /// Returns the IRQ of the Shared Peripheral Interrupt `spi`.
+    #[inline]
+    pub const fn spi(spi: u32) -> Self {
+        Self(Self::SPI_BASE + spi)
+    }
+
+    #[inline]
+    pub const fn is_spurious(&self) -> bool {
+        self.0 >= Self::SPECIAL
+    }
+}
+
+pub struct Gic;
+
+impl Gic {
+    const GICD_CTLR: usize = 0x0000;
+    const GICD_TYPER: usize = 0x0004;
+    const GICD_IGROUPR: usize = 0x0080;
+    const GICD_ISENABLER: usize = 0x0100;
+    const GICD_ICENABLER: usize = 0x0180;
+    const GICD_IPRIORITYR: usize = 0x0400;
+    const GICD_ITARGETSR: usize = 0x0800;
+    const GICD_SGIR: usize = 0x0F00;
+    const GICD_IROUTER: usize = 0x6000;
+
+    const GICC_CTLR: usize = 0x0000;
+    const GICC_PMR: usize = 0x0004;
+    const GICC_IAR: usize = 0x000C;
+    const GICC_EOIR: usize = 0x0010;
+
+    const GICR_FRAME_SIZE: usize = 0x2_0000;
+    const GICR_TYPER: usize = 0x0008;
+    const GICR_WAKER: usize = 0x0014;
+    const GICR_SGI_BASE: usize = 0x1_0000;
+
+    const GICD_CTLR_RWP: u32 = 1 << 31;
+    const GICR_TYPER_LAST: u64 = 1 << 4;
+
+    const DEFAULT_PRIORITY: u32 = 0xA0;
+
+    /// Finds the interrupt controller in the device tree and initializes the distributor.
+    pub unsafe fn init(dt: &DeviceTree) -> Result<GicVersion, ()> {
+        let (version, node) = if let Some(node) = dt.find_compatible("arm,gic-v3").next() {
+            (GicVersion::V3, node)
+        } else if let Some(node) = dt
+            .find_compatible("arm,cortex-a15-gic")
+            .chain(dt.find_compatible("arm,gic-400"))
+            .next()
+        {
+            (GicVersion::V2, node)
+        } else {
+            return Err(());
+        };
+
+        let mut reg = node.reg();
+        let gicd = reg.next().ok_or(())?.0;
+        let gicc = reg.next().ok_or(())?.0;
+        Self::init_at(version, gicd.as_usize(), gicc.as_usize());
+
+        Ok(version)
+    }
+
+    /// Initializes the distributor of the interrupt controller at a known address.
+    ///
+    /// `gicc` is the CPU interface for GICv2, or the first redistributor for GICv3.
+    pub unsafe fn init_at(version: GicVersion, gicd: usize, gicc: usize) {
+        if version == GicVersion::None {
+            return;
+        }
+        GICD_BASE.store(gicd, Ordering::Relaxed);
+        GICC_BASE.store(gicc, Ordering::Relaxed);
+        GIC_VERSION.store(version as usize, Ordering::Release);
+
+        let lines = ((Self::gicd_read(Self::GICD_TYPER) & 0x1F) + 1) * 32;
+        let max_irq = u32::min(lines, Irq::SPECIAL);
+        MAX_IRQ.store(max_irq, Ordering::Relaxed);
+
+        Self::gicd_write(Self::GICD_CTLR, 0);
+        Self::wait_rwp();
+
+        for irq in (Irq::SPI_BASE..max_irq).step_by(32) {
+            let index = (irq / 32) as usize * 4;
+            Self::gicd_write(Self::GICD_ICENABLER + index, u32::MAX);
+            Self::gicd_write(Self::GICD_IGROUPR + index, u32::MAX);
+        }
+        for irq in (Irq::SPI_BASE..max_irq).step_by(4) {
+            let pri = Self::DEFAULT_PRIORITY * 0x0101_0101;
+            Self::gicd_write(Self::GICD_IPRIORITYR + irq as usize, pri);
+        }
+
+        match version {
+            GicVersion::V2 => {
+                for irq in (Irq::SPI_BASE..max_irq).step_by(4) {
+                    Self::gicd_write(Self::GICD_ITARGETSR + irq as usize, 0x0101_0101);
+                }
+                Self::gicd_write(Self::GICD_CTLR, 1);
+            }
+            GicVersion::V3 => {
+                let aff = Cpu::current_mpidr();
+                for irq in Irq::SPI_BASE..max_irq {
+                    let p = (gicd + Self::GICD_IROUTER + irq as usize * 8) as *mut u64;
+                    p.write_volatile(aff);
+                }
+                // ARE_NS | EnableGrp1A | EnableGrp1
+                Self::gicd_write(Self::GICD_CTLR, (1 << 4) | (1 << 1) | (1 << 0));
+                Self::wait_rwp();
+            }
+            GicVersion::None => (),
+        }
+    }
+
+    /// Initializes the CPU interface of the current core.
+    pub unsafe fn init_cpu() {
+        match Self::version() {
+            GicVersion::None => (),
+            GicVersion::V2 => {
+                // The fields for SGIs read as the interface of the reading core.
+                let interface = Self::gicd_read(Self::GICD_ITARGETSR) as u8;
+                if let Some(v) = CPU_INTERFACES.get(Cpu::current().id()) {
+                    v.store(interface, Ordering::Relaxed);
+                }
+                Self::gicd_write(Self::GICD_ICENABLER, 0xFFFF_0000);
+                Self::gicd_write(Self::GICD_ISENABLER, 0x0000_FFFF);
+                for irq in (0..Irq::SPI_BASE).step_by(4) {
+                    let pri = Self::DEFAULT_PRIORITY * 0x0101_0101;
+                    Self::gicd_write(Self::GICD_IPRIORITYR + irq as usize, pri);
+                }
+                Self::gicc_write(Self::GICC_PMR, 0xF0);
+                Self::gicc_write(Self::GICC_CTLR, 1);
+            }
+            GicVersion::V3 => {
+                let Some(rd) = Self::current_redistributor() else {
+                    return;
+                };
+                let waker = (rd + Self::GICR_WAKER) as *mut u32;
+                waker.write_volatile(waker.read_volatile() & !(1 << 1));
+                while (waker.read_volatile() & (1 << 2)) != 0 {
+                    asm!("yield");
+                }
+
+                let sgi = rd + Self::GICR_SGI_BASE;
+                ((sgi + Self::GICD_IGROUPR) as *mut u32).write_volatile(u32::MAX);
+                ((sgi + Self::GICD_ICENABLER) as *mut u32).write_volatile(0xFFFF_0000);
+                ((sgi + Self::GICD_ISENABLER) as *mut u32).write_volatile(0x0000_FFFF);
+                for irq in (0..Irq::SPI_BASE).step_by(4) {
+                    let pri = Self::DEFAULT_PRIORITY * 0x0101_0101;
+                    ((sgi + Self::GICD_IPRIORITYR + irq as usize) as *mut u32).write_volatile(pri);
+                }
+
+                // ICC_SRE_EL1.SRE
+                asm!("
+                mrs {0}, S3_0_C12_C12_5
+                orr {0}, {0}, #1
+                msr S3_0_C12_C12_5, {0}
+                isb
+                ", out(reg) _);
+                // ICC_PMR_EL1
+                asm!("msr S3_0_C4_C6_0, {}", in(reg) 0xF0usize);
+                // ICC_IGRPEN1_EL1
+                asm!("msr S3_0_C12_C12_7, {}
+                isb", in(reg) 1usize);
+            }
+        }
+    }
+
+    #[inline]
+    pub fn version() -> GicVersion {
+        match GIC_VERSION.load(Ordering::Acquire) {
+            1 => GicVersion::V2,
+            2 => GicVersion::V3,
+            _ => GicVersion::None,
+        }
+    }
+
+    #[inline]
+    pub fn max_irq() -> u32 {
+        MAX_IRQ.load(Ordering::Relaxed)
+    }
+
+    pub unsafe fn enable_irq(irq: Irq) {
+        Self::write_enable(irq, Self::GICD_ISENABLER);
+    }
+
+    pub unsafe fn disable_irq(irq: Irq) {
+        Self::write_enable(irq, Self::GICD_ICENABLER);
+    }
+
+    /// Returns whether `irq` is enabled, on the current core for private interrupts.
+    pub fn is_enabled(irq: Irq) -> bool {
+        let index = (irq.0 / 32) as usize * 4;
+        let bit = 1 << (irq.0 % 32);
+        let val = unsafe {
+            match Self::version() {
+                GicVersion::None => 0,
+                GicVersion::V3 if irq.0 < Irq::SPI_BASE => match Self::current_redistributor() {
+                    Some(rd) => ((rd + Self::GICR_SGI_BASE + Self::GICD_ISENABLER) as *const u32)
+                        .read_volatile(),
+                    None => 0,
+                },
+                _ if irq.0 < Self::max_irq() => Self::gicd_read(Self::GICD_ISENABLER + index),
+                _ => 0,
+            }
+        };
+        (val & bit) != 0
+    }
+
+    unsafe fn write_enable(irq: Irq, offset: usize) {
+        let index = (irq.0 / 32) as usize * 4;
+        let bit = 1 << (irq.0 % 32);
+        if irq.0 < Irq::SPI_BASE && Self::version() == GicVersion::V3 {
+            if let Some(rd) = Self::current_redistributor() {
+                ((rd + Self::GICR_SGI_BASE + offset) as *mut u32).write_volatile(bit);
+            }
+        } else if irq.0 < Self::max_irq() {
+            Self::gicd_write(offset + index, bit);
+        }
+    }
+
+    /// Acknowledges the highest priority pending interrupt.
+    pub unsafe fn acknowledge() -> Irq {
+        match Self::version() {
+            GicVersion::None => Irq(Irq::SPECIAL + 3),
+            GicVersion::V2 => Irq(Self::gicc_read(Self::GICC_IAR) & 0x3FF),
+            GicVersion::V3 => {
+                let iar: usize;
+                // ICC_IAR1_EL1
+                asm!("mrs {}, S3_0_C12_C12_0", out(reg) iar);
+                Irq(iar as u32 & 0xFF_FFFF)
+            }
+        }
+    }
+
+    /// Signals the end of the interrupt `irq`.
+    pub unsafe fn eoi(irq: Irq) {
+        match Self::version() {
+            GicVersion::None => (),
+            GicVersion::V2 => Self::gicc_write(Self::GICC_EOIR, irq.0),
+            GicVersion::V3 => {
+                // ICC_EOIR1_EL1
+                asm!("msr S3_0_C12_C12_1, {}", in(reg) irq.0 as usize);
+            }
+        }
+    }
+
+    /// Sends the software generated interrupt `sgi` to the core `cpuid`.
+    ///
+    /// Returns `false` if the core cannot be addressed. GICv2 reaches the eight CPU
+    /// interfaces, and GICv3 reaches `Aff0` 0-15 of every cluster.
+    pub unsafe fn send_sgi(sgi: u32, cpuid: usize) -> bool {
+        let sgi = sgi & 0xF;
+        match Self::version() {
+            GicVersion::None => false,
+            GicVersion::V2 => {
+                let targets = CPU_INTERFACES
+                    .get(cpuid)
+                    .map_or(0, |v| v.load(Ordering::Relaxed));
+                if targets == 0 {
+                    return false;
+                }
+                Self::gicd_write(Self::GICD_SGIR, ((targets as u32) << 16) | sgi);
+                true
+            }
+            GicVersion::V3 => {
+                let Some(mpidr) = Smp::mpidr(cpuid) else {
+                    return false;
+                };
+                let aff0 = mpidr & 0xFF;
+                if aff0 >= 16 {
+                    return false;
+                }
+                let aff1 = (mpidr >> 8) & 0xFF;
+                let aff2 = (mpidr >> 16) & 0xFF;
+                let aff3 = (mpidr >> 32) & 0xFF;
+                let val =
+                    (aff3 << 48) | (aff2 << 32) | ((sgi as u64) << 24) | (aff1 << 16) | (1 << aff0);
+                // ICC_SGI1R_EL1
+                asm!("
+                dsb ishst
+                msr S3_0_C12_C11_5, {}
+                isb
+                ", in(reg) val);
+                true
+            }
+        }
+    }
+
+    /// Finds the redistributor frame of the current core.
+    unsafe fn current_redistributor() -> Option<usize> {
+        let aff = Cpu::current_mpidr();
+        let aff = ((aff >> 8) & 0xFF00_0000) | (aff & 0xFF_FFFF);
+        let mut rd = GICC_BASE.load(Ordering::Relaxed);
+        loop {
+            let typer = ((rd + Self::GICR_TYPER) as *const u64).read_volatile();
+            if (typer >> 32) == aff {
+                return Some(rd);
+            }
+            if (typer & Self::GICR_TYPER_LAST) != 0 {
+                return None;
+            }
+            rd += Self::GICR_FRAME_SIZE;
+        }
+    }
+
+    #[inline]
+    unsafe fn wait_rwp() {
+        while (Self::gicd_read(Self::GICD_CTLR) & Self::GICD_CTLR_RWP) != 0 {
+            asm!("yield");
+        }
+    }
+
+    #[inline]
+    unsafe fn gicd_read(offset: usize) -> u32 {
+        ((GICD_BASE.load(Ordering::Relaxed) + offset) as *const u32).read_volatile()
+    }
+
+    #[inline]
+    unsafe fn gicd_write(offset: usize, val: u32) {
+        ((GICD_BASE.load(Ordering::Relaxed) + offset) as *mut u32).write_volatile(val)
+    }
+
+    #[inline]
+    unsafe fn gicc_read(offset: usize) -> u32 {
+        ((GICC_BASE.load(Ordering::Relaxed) + offset) as *const u32).read_volatile()
+    }
+
+    #[inline]
+    unsafe fn gicc_write(offset: usize, val: u32) {
+        ((GICC_BASE.load(Ordering::Relaxed) + offset) as *mut u32).write_volatile(val)
+    }
+}
+
+struct IrqsCommand;
+
+impl ShellCommand for IrqsCommand {
+    fn name(&self) -> &'static str {
+        "irqs"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the interrupt controller and the interrupts taken by each core"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        for cpuid in 0..Smp::num_cpus() {
+            if let Some(cpu) = Cpu::local(cpuid) {
+                writeln!(out, "  cpu{:<3} {:10}", cpuid, cpu.irq_count())?;
+            }
+        }
+        match Gic::version() {
+            GicVersion::None => {
+                writeln!(out, "controller: BCM2836 local interrupt controller")?;
+            }
+            version => {
+                writeln!(
+                    out,
+                    "controller: GIC{:?}, {} interrupts",
+                    version,
+                    Gic::max_irq()
+                )?;
+                write!(out, "enabled:")?;
+                for irq in (0..Gic::max_irq()).map(Irq).filter(|&v| Gic::is_enabled(v)) {
+                    write!(out, " {}", irq.0)?;
+                }
+                writeln!(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static IRQS: IrqsCommand = IrqsCommand;
+}
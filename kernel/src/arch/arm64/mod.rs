@@ -1,51 +1,236 @@
-//! Architecture dependent module for RaspberryPi
+//! Architecture dependent module for AArch64 boards
 
 #[macro_use]
 pub mod cpu;
+pub mod backtrace;
+mod boot;
+pub mod exception;
+pub mod gic;
 pub mod page;
+pub mod percpu;
+pub mod psci;
 mod raspi;
+pub mod smp;
 pub mod spin;
+pub mod stack;
+pub mod timer;
+pub mod uspace;
+mod virt;
 
 use self::page::PhysicalAddress;
-use crate::io::uart::Uart;
+use self::{psci::Psci, smp::Smp};
+use crate::{
+    fw::dt::DeviceTree,
+    io::uart::Uart,
+    mem::MemoryManager,
+    param::UintParam,
+    shell::{ShellCommand, ShellError},
+};
+use bootprot::BootInfo;
+use core::{
+    arch::asm,
+    fmt::Write,
+    intrinsics::transmute,
+    ptr::null_mut,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
 use meggl::TrueColor;
 
+crate::kernel_param! {
+    static FB_WIDTH: UintParam = UintParam::new(
+        "fb.width",
+        1280,
+        320,
+        4096,
+        "Width of the standard screen",
+    );
+}
+
+crate::kernel_param! {
+    static FB_HEIGHT: UintParam = UintParam::new(
+        "fb.height",
+        720,
+        200,
+        2160,
+        "Height of the standard screen",
+    );
+}
+
+#[inline]
+pub unsafe fn init_early(info: &BootInfo) {
+    exception::init();
+
+    CURRENT_MACHINE_TYPE.store(
+        detect_machine_type(info.dtb as usize) as usize,
+        Ordering::Relaxed,
+    );
+
+    // The framebuffer of the UEFI loader is used as is instead of the board's own one.
+    if info.has_framebuffer() {
+        let stride = info.vram_stride as usize;
+        let height = info.screen_height as usize;
+        set_std_screen(
+            info.vram_base as usize as *mut TrueColor,
+            info.screen_width as usize,
+            height,
+            stride,
+        );
+        BOOT_VRAM_BASE.store(info.vram_base as usize, Ordering::Relaxed);
+        BOOT_VRAM_SIZE.store(stride * height * 4, Ordering::Relaxed);
+    }
+
+    match current_machine_type() {
+        MachineType::QemuVirt => virt::init_early(info),
+        _ => raspi::init_early(info),
+    }
+}
+
+/// Sets up the early allocation area of `size` bytes after the kernel image.
+///
+/// The area is limited to the memory reserved by the boot loader, if any.
+unsafe fn init_early_alloc(info: &BootInfo, size: usize) {
+    let start = boot::_end().rounding_up(0x1000);
+    let size = if info.kernel_end > start.as_u64() {
+        size.min((info.kernel_end - start.as_u64()) as usize)
+    } else {
+        size
+    };
+    MemoryManager::init_early(start, size);
+}
+
+/// Starts the secondary cores listed in the device tree.
+///
+/// This needs the page allocator for the stacks of the cores.
 #[inline]
-pub unsafe fn init_early(dtb: usize) {
-    raspi::init_early(dtb);
+pub unsafe fn init_smp(dt: &DeviceTree) {
+    smp::Smp::init(dt);
+}
+
+/// Starts the devices of the board that are not found in the device tree.
+#[inline]
+pub unsafe fn init_devices() {
+    match current_machine_type() {
+        MachineType::QemuVirt => (),
+        _ => raspi::init_devices(),
+    }
+}
+
+/// Detects the board from the root `compatible`, or from `MIDR_EL1` if there is no device tree.
+unsafe fn detect_machine_type(dtb: usize) -> MachineType {
+    if let Ok(dt) = DeviceTree::parse(dtb as *const u8) {
+        for compatible in dt.root_compatible() {
+            match compatible {
+                "linux,dummy-virt" => return MachineType::QemuVirt,
+                "brcm,bcm2837" => return MachineType::RPi3,
+                "brcm,bcm2711" => return MachineType::RPi4,
+                _ => (),
+            }
+        }
+    }
+
+    let midr_el1: usize;
+    asm!("mrs {}, midr_el1", out(reg) midr_el1);
+    match (midr_el1 >> 4) & 0xFFF {
+        // 0xB76 => // rpi1
+        // 0xC07 =>  // rpi2
+        0xD03 => MachineType::RPi3,
+        0xD08 => MachineType::RPi4,
+        _ => MachineType::Unknown,
+    }
+}
+
+/// Called on each secondary core after its MMU is enabled.
+#[inline]
+unsafe fn init_secondary(cpuid: usize) {
+    exception::init();
+
+    match current_machine_type() {
+        MachineType::QemuVirt => virt::init_secondary(cpuid),
+        _ => raspi::init_secondary(cpuid),
+    }
 }
 
 #[inline]
 pub fn std_uart<'a>() -> &'a mut dyn Uart {
-    raspi::uart::Uart0::shared() as &mut dyn Uart
+    match current_machine_type() {
+        MachineType::QemuVirt => virt::uart::Pl011::shared() as &mut dyn Uart,
+        _ => raspi::uart::Uart0::shared() as &mut dyn Uart,
+    }
 }
 
 #[inline]
 pub fn std_screen() -> Option<(*mut TrueColor, isize, isize, usize)> {
-    raspi::std_screen()
+    let ptr = STD_SCR_PTR.load(Ordering::Relaxed) as *mut TrueColor;
+    (ptr != null_mut()).then(|| {
+        (
+            ptr,
+            STD_SCR_W.load(Ordering::Relaxed) as isize,
+            STD_SCR_H.load(Ordering::Relaxed) as isize,
+            STD_SCR_S.load(Ordering::Relaxed) as usize,
+        )
+    })
+}
+
+/// Returns the requested size of the standard screen.
+#[inline]
+fn std_screen_size() -> (usize, usize) {
+    (FB_WIDTH.get(), FB_HEIGHT.get())
 }
 
+fn set_std_screen(ptr: *mut TrueColor, width: usize, height: usize, stride: usize) {
+    STD_SCR_PTR.store(ptr as usize, Ordering::Relaxed);
+    STD_SCR_W.store(width as u32, Ordering::Relaxed);
+    STD_SCR_H.store(height as u32, Ordering::Relaxed);
+    STD_SCR_S.store(stride as u32, Ordering::Relaxed);
+}
+
+static STD_SCR_PTR: AtomicUsize = AtomicUsize::new(0);
+static STD_SCR_W: AtomicU32 = AtomicU32::new(0);
+static STD_SCR_H: AtomicU32 = AtomicU32::new(0);
+static STD_SCR_S: AtomicU32 = AtomicU32::new(0);
+
 #[inline]
 pub fn max_pa() -> PhysicalAddress {
-    raspi::max_pa()
+    match current_machine_type() {
+        MachineType::QemuVirt => virt::max_pa(),
+        _ => raspi::max_pa(),
+    }
 }
 
 #[inline]
 pub fn device_memlist() -> impl Iterator<Item = (PhysicalAddress, usize)> {
-    raspi::device_memlist()
+    let is_virt = current_machine_type() == MachineType::QemuVirt;
+    let virt = is_virt.then(|| virt::device_memlist());
+    let raspi = (!is_virt).then(|| raspi::device_memlist());
+    virt.into_iter()
+        .flatten()
+        .chain(raspi.into_iter().flatten())
 }
 
 #[inline]
 pub fn vram_memlist() -> impl Iterator<Item = (PhysicalAddress, usize)> {
-    raspi::vram_memlist()
+    let is_virt = current_machine_type() == MachineType::QemuVirt;
+    let virt = is_virt.then(|| virt::vram_memlist());
+    let raspi = (!is_virt).then(|| raspi::vram_memlist());
+    let boot = [(
+        PhysicalAddress::from_usize(BOOT_VRAM_BASE.load(Ordering::Relaxed)),
+        BOOT_VRAM_SIZE.load(Ordering::Relaxed),
+    )];
+    virt.into_iter()
+        .flatten()
+        .chain(raspi.into_iter().flatten())
+        .chain(boot.into_iter())
 }
 
+static BOOT_VRAM_BASE: AtomicUsize = AtomicUsize::new(0);
+static BOOT_VRAM_SIZE: AtomicUsize = AtomicUsize::new(0);
+
 #[inline]
 pub fn fix_memlist(base: PhysicalAddress, size: usize) -> (PhysicalAddress, usize) {
     let page_size = 0x1000 as u64;
     let page_mask = page_size - 1;
     let frame_mask = !page_mask;
-    let end = (raspi::_end() + page_mask) & frame_mask;
+    let end = (boot::_end() + page_mask) & frame_mask;
     let area_end = base + size;
     if base >= end {
         (base, size)
@@ -58,3 +243,57 @@ pub fn fix_memlist(base: PhysicalAddress, size: usize) -> (PhysicalAddress, usiz
         }
     }
 }
+
+/// Resets the system, with PSCI if available, otherwise with the watchdog of the board.
+pub fn reset() -> ! {
+    Smp::halt_others();
+    if Psci::is_available() {
+        let _ = Psci::system_reset();
+    }
+    match current_machine_type() {
+        MachineType::RPi3 | MachineType::RPi4 => raspi::reset(),
+        _ => (),
+    }
+    Smp::halt()
+}
+
+#[inline]
+pub fn current_machine_type() -> MachineType {
+    unsafe { transmute(CURRENT_MACHINE_TYPE.load(Ordering::Relaxed)) }
+}
+
+static CURRENT_MACHINE_TYPE: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum MachineType {
+    #[default]
+    Unknown,
+    RPi3,
+    RPi4,
+    QemuVirt,
+}
+
+struct RebootCommand;
+
+impl ShellCommand for RebootCommand {
+    fn name(&self) -> &'static str {
+        "reboot"
+    }
+
+    fn help(&self) -> &'static str {
+        "Reset the system"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        writeln!(out, "rebooting...")?;
+        reset()
+    }
+}
+
+crate::shell_command! {
+    static REBOOT: RebootCommand = RebootCommand;
+}
@@ -1,11 +1,17 @@
-use crate::{fw::dt::DeviceTree, mem::MemoryManager};
+use super::stack::KernelStack;
+use crate::{
+    fw,
+    mem::{MProtect, MemoryManager},
+};
 use bitflags::*;
+use bootprot::BootInfo;
 use core::{
     alloc::Layout,
     arch::asm,
     ffi::c_void,
     fmt,
     iter::Step,
+    mem,
     num::NonZeroU64,
     ops::{Add, BitAnd, BitOr, Mul, Not, Sub},
     sync::atomic::{AtomicU64, Ordering},
@@ -236,7 +242,7 @@ static TTBR0: AtomicU64 = AtomicU64::new(0);
 static SCTLR: AtomicU64 = AtomicU64::new(0);
 
 impl PageManager {
-    const PAGE_SIZE_MIN: usize = 0x0000_1000;
+    pub const PAGE_SIZE_MIN: usize = 0x0000_1000;
     const PAGE_SIZE_2M: usize = 0x0020_0000;
     const PAGE_SIZE_1G: usize = 0x4000_0000;
     const PAGE_SIZE_M1: usize = 0xFFF;
@@ -250,13 +256,11 @@ impl PageManager {
     // const HEAP_BASE: usize = Self::PAGE_KERNEL_PREFIX | (Self::PAGE_KERNEL_HEAP << 39);
 
     #[inline]
-    pub unsafe fn init_early(dtb: usize) {
+    pub unsafe fn init_early(info: &BootInfo) {
         asm!("dsb sy");
 
-        if let Ok(dt) = DeviceTree::parse(dtb as *const u8) {
-            let max_pa = dt
-                .memory_ranges()
-                .unwrap()
+        if fw::memory_ranges(info).next().is_some() {
+            let max_pa = fw::memory_ranges(info)
                 .fold(0, |acc, val| acc.max(val.0.as_u64() + (val.1 as u64)));
             let max_pa = PhysicalAddress::new(u64::max(super::max_pa().as_u64(), max_pa))
                 .rounding_up(Self::PAGE_SIZE_1G);
@@ -274,7 +278,7 @@ impl PageManager {
             .identity_mapped::<TranslationTableDescriptor>();
             table_l2.write_bytes(0, alloc_l2_size);
 
-            for range in dt.memory_ranges().unwrap() {
+            for range in fw::memory_ranges(info) {
                 let start = range.0;
                 let end = (range.0 + range.1).rounding_up(Self::PAGE_SIZE_2M);
                 let base = table_l2.add(start.as_usize() / Self::PAGE_SIZE_2M);
@@ -346,6 +350,8 @@ impl PageManager {
                     .write_volatile(TranslationTableDescriptor::new(oa, attr));
             }
 
+            KernelStack::init_early(table_l1, table_l2);
+
             TTBR0.store(table_l1 as usize as u64, Ordering::SeqCst);
 
             SCTLR.store(0x00C0_181F, Ordering::SeqCst);
@@ -369,10 +375,47 @@ impl PageManager {
         isb", in(reg)SCTLR.load(Ordering::Relaxed));
     }
 
+    /// Cleans the registers loaded by [PageManager::init_mp] to the point of coherency,
+    /// as a core reads them with its caches off.
+    pub(super) unsafe fn clean_mp_config() {
+        super::smp::clean_dcache(&MAIR as *const _ as usize, mem::size_of_val(&MAIR));
+        super::smp::clean_dcache(&TCR as *const _ as usize, mem::size_of_val(&TCR));
+        super::smp::clean_dcache(&TTBR0 as *const _ as usize, mem::size_of_val(&TTBR0));
+        super::smp::clean_dcache(&SCTLR as *const _ as usize, mem::size_of_val(&SCTLR));
+    }
+
     #[inline]
     pub const fn direct_mapped(val: PhysicalAddress) -> *mut c_void {
         val.0 as usize as *mut c_void
     }
+
+    /// Returns the level 1 table of the kernel identity map.
+    #[inline]
+    pub fn kernel_table() -> PhysicalAddress {
+        PhysicalAddress::new(TTBR0.load(Ordering::Relaxed))
+    }
+
+    /// Returns whether the current translation lets EL1 read `va`, or write it if `write`.
+    pub fn is_accessible(va: usize, write: bool) -> bool {
+        let par: u64;
+        unsafe {
+            if write {
+                asm!("
+                at s1e1w, {0}
+                isb
+                mrs {0}, par_el1
+                ", inout(reg) va => par);
+            } else {
+                asm!("
+                at s1e1r, {0}
+                isb
+                mrs {0}, par_el1
+                ", inout(reg) va => par);
+            }
+        }
+        // PAR_EL1.F
+        (par & 1) == 0
+    }
 }
 
 bitflags! {
@@ -637,6 +680,11 @@ impl TranslationTableDescriptor {
         PhysicalAddress::new(self.0 & Self::OA48_MASK)
     }
 
+    #[inline]
+    pub const fn attributes(&self) -> PageAttributes {
+        PageAttributes(self.0 & !(Self::OA48_MASK | Self::VALID.0))
+    }
+
     #[inline]
     pub const fn bits(&self) -> u64 {
         self.0
@@ -659,15 +707,71 @@ impl PageAttributes {
     const TABLE: u64 = 1 << 1;
 
     const NS: u64 = 1 << 5;
+    /// Accessible from EL0
+    const AP_EL0: u64 = 1 << 6;
+    const AP_READ_ONLY: u64 = 1 << 7;
     const AF: u64 = 1 << 10;
+    /// Not global, tagged with the ASID
+    const NG: u64 = 1 << 11;
+    const PXN: u64 = 1 << 53;
+    const UXN: u64 = 1 << 54;
+    /// Software defined: writable, but read only while shared until the first write
+    const COPY_ON_WRITE: u64 = 1 << 55;
 
+    /// Attributes of a block of the kernel, which is never executable from EL0.
     #[inline]
     pub const fn block(sh: Option<Shareable>, attr_index: AttributeIndex) -> Self {
         let sh = match sh {
             Some(v) => v as u64,
             None => 0,
         };
-        Self(Self::AF | (sh << 8) | ((attr_index as u64) << 2))
+        Self(Self::AF | Self::UXN | (sh << 8) | ((attr_index as u64) << 2))
+    }
+
+    /// Attributes of a 4KB page of a user address space, which is never executable from EL1.
+    #[inline]
+    pub const fn user_page(prot: MProtect) -> Self {
+        let mut bits = Self::TABLE
+            | Self::AF
+            | Self::NG
+            | Self::PXN
+            | Self::AP_EL0
+            | ((Shareable::Inner as u64) << 8)
+            | ((AttributeIndex::Normal as u64) << 2);
+        if !prot.contains(MProtect::WRITE) {
+            bits |= Self::AP_READ_ONLY;
+        }
+        if !prot.contains(MProtect::EXEC) {
+            bits |= Self::UXN;
+        }
+        Self(bits)
+    }
+
+    /// Converts the attributes of a block into those of a 4KB page.
+    #[inline]
+    pub const fn as_page(self) -> Self {
+        Self(self.0 | Self::TABLE)
+    }
+
+    /// Makes a writable page read only until the first write.
+    #[inline]
+    pub const fn copy_on_write(self) -> Self {
+        if (self.0 & Self::AP_READ_ONLY) != 0 {
+            self
+        } else {
+            Self(self.0 | Self::AP_READ_ONLY | Self::COPY_ON_WRITE)
+        }
+    }
+
+    #[inline]
+    pub const fn is_copy_on_write(&self) -> bool {
+        (self.0 & Self::COPY_ON_WRITE) != 0
+    }
+
+    /// Restores the write permission of a copy-on-write page.
+    #[inline]
+    pub const fn writable(self) -> Self {
+        Self(self.0 & !(Self::AP_READ_ONLY | Self::COPY_ON_WRITE))
     }
 
     #[inline]
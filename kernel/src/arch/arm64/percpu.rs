@@ -0,0 +1,141 @@
+//! Per-core data
+//!
+//! Variables declared with [percpu!] are collected by the linker into the `.percpu`
+//! section, which is only a template: each core has its own copy of the section, and
+//! `TPIDR_EL1` holds the offset from the template to the copy of the core.
+//! `_start` makes the copy of the boot core in `.bss`, and [Smp::cpu_on] allocates
+//! the others.
+
+use super::{
+    cpu::{Cpu, InterruptGuard},
+    smp::Smp,
+};
+use crate::mem::MemoryManager;
+use core::{arch::asm, cell::UnsafeCell};
+
+/// Declares a per-core variable.
+///
+/// ```ignore
+/// crate::percpu! {
+///     static IRQ_COUNT: AtomicU64 = AtomicU64::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ( $(#[$attr:meta])* $vis:vis static $name:ident : $ty:ty = $init:expr ; ) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        $vis static $name: $crate::arch::percpu::PerCpu<$ty> =
+            $crate::arch::percpu::PerCpu::new($init);
+    };
+}
+
+/// A variable with a value for each core
+#[repr(transparent)]
+pub struct PerCpu<T>(UnsafeCell<T>);
+
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[inline]
+    pub const fn new(val: T) -> Self {
+        Self(UnsafeCell::new(val))
+    }
+
+    #[inline]
+    fn ptr(&self, offset: usize) -> *mut T {
+        (self.0.get() as usize).wrapping_add(offset) as *mut T
+    }
+
+    /// Calls `f` with the value of the current core, with interrupts disabled so that
+    /// the caller stays on the core and nothing else on it runs meanwhile.
+    ///
+    /// Values changed this way are kept in a `Cell` or a `RefCell`.
+    #[inline]
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let guard = unsafe { Cpu::interrupt_guard() };
+        f(self.get(&guard))
+    }
+
+    /// Returns the value of the current core, for as long as `_guard` keeps the caller
+    /// on the core.
+    #[inline]
+    pub fn get<'a>(&'a self, _guard: &'a InterruptGuard) -> &'a T {
+        unsafe { &*self.ptr(current_offset()) }
+    }
+
+    /// Returns the copy of the core that is running now.
+    ///
+    /// The caller may be on another core by the time it reads the value, so this is
+    /// only for data that the cores read and write through atomics.
+    #[inline]
+    pub fn get_current(&self) -> &T
+    where
+        T: Sync,
+    {
+        unsafe { &*self.ptr(current_offset()) }
+    }
+
+    /// Returns the value of the core `cpuid`, which the core may change meanwhile, so
+    /// this is only for data read and written through atomics.
+    #[inline]
+    pub fn get_for(&self, cpuid: usize) -> Option<&T>
+    where
+        T: Sync,
+    {
+        Smp::percpu_offset(cpuid).map(|offset| unsafe { &*self.ptr(offset) })
+    }
+}
+
+/// Returns the offset of the copy of the current core.
+#[inline]
+fn current_offset() -> usize {
+    let result: usize;
+    unsafe {
+        asm!("mrs {}, tpidr_el1", out(reg) result, options(nomem, nostack));
+    }
+    result
+}
+
+/// Points `TPIDR_EL1` of the current core to its copy at `offset`.
+#[inline]
+pub(super) unsafe fn set_current_offset(offset: usize) {
+    asm!("msr tpidr_el1, {}", in(reg) offset, options(nomem, nostack));
+}
+
+/// Returns the offset of the copy of the boot core, made by `_start`.
+#[inline]
+pub(super) fn boot_offset() -> usize {
+    let (template, _) = template();
+    extern "C" {
+        static __percpu_boot: u8;
+    }
+    unsafe { &__percpu_boot as *const u8 as usize - template }
+}
+
+/// Makes a copy of the template for a new core, and returns its offset.
+pub(super) unsafe fn alloc() -> Option<usize> {
+    let (template, size) = template();
+    let block = MemoryManager::alloc_pages(size.max(1))?
+        .get()
+        .direct_mapped::<u8>();
+    block.copy_from_nonoverlapping(template as *const u8, size);
+    Some((block as usize).wrapping_sub(template))
+}
+
+/// Returns the address and the size of the template.
+#[inline]
+fn template() -> (usize, usize) {
+    extern "C" {
+        static __percpu_start: u8;
+        static __percpu_end: u8;
+    }
+    unsafe {
+        let start = &__percpu_start as *const u8 as usize;
+        let end = &__percpu_end as *const u8 as usize;
+        (start, end - start)
+    }
+}
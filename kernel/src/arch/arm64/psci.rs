@@ -0,0 +1,153 @@
+//! Power State Coordination Interface
+
+use crate::fw::dt::{DeviceTree, PropName};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static PSCI_METHOD: AtomicUsize = AtomicUsize::new(PsciMethod::None as usize);
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciMethod {
+    None = 0,
+    Hvc,
+    Smc,
+}
+
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciError {
+    NotSupported = -1,
+    InvalidParameters = -2,
+    Denied = -3,
+    AlreadyOn = -4,
+    OnPending = -5,
+    InternalFailure = -6,
+    NotPresent = -7,
+    Disabled = -8,
+    InvalidAddress = -9,
+}
+
+impl PsciError {
+    #[inline]
+    fn from_isize(val: isize) -> Self {
+        match val {
+            -2 => Self::InvalidParameters,
+            -3 => Self::Denied,
+            -4 => Self::AlreadyOn,
+            -5 => Self::OnPending,
+            -6 => Self::InternalFailure,
+            -7 => Self::NotPresent,
+            -8 => Self::Disabled,
+            -9 => Self::InvalidAddress,
+            _ => Self::NotSupported,
+        }
+    }
+}
+
+pub struct Psci;
+
+impl Psci {
+    pub const PSCI_VERSION: u32 = 0x8400_0000;
+    pub const CPU_OFF: u32 = 0x8400_0002;
+    pub const CPU_ON: u32 = 0xC400_0003;
+    pub const AFFINITY_INFO: u32 = 0xC400_0004;
+    pub const SYSTEM_OFF: u32 = 0x8400_0008;
+    pub const SYSTEM_RESET: u32 = 0x8400_0009;
+
+    /// Finds the `/psci` node and its conduit.
+    pub fn init_dt(dt: &DeviceTree) -> bool {
+        let method = dt
+            .find_node("/psci")
+            .filter(|node| node.is_enabled())
+            .and_then(|node| node.prop_str(PropName::METHOD))
+            .map(|method| match method {
+                "hvc" => PsciMethod::Hvc,
+                "smc" => PsciMethod::Smc,
+                _ => PsciMethod::None,
+            })
+            .unwrap_or(PsciMethod::None);
+        PSCI_METHOD.store(method as usize, Ordering::Relaxed);
+        method != PsciMethod::None
+    }
+
+    #[inline]
+    pub fn method() -> PsciMethod {
+        match PSCI_METHOD.load(Ordering::Relaxed) {
+            1 => PsciMethod::Hvc,
+            2 => PsciMethod::Smc,
+            _ => PsciMethod::None,
+        }
+    }
+
+    #[inline]
+    pub fn is_available() -> bool {
+        Self::method() != PsciMethod::None
+    }
+
+    unsafe fn call(function_id: u32, arg1: usize, arg2: usize, arg3: usize) -> isize {
+        let result: isize;
+        match Self::method() {
+            PsciMethod::Hvc => {
+                asm!("hvc #0",
+                    inlateout("x0") function_id as usize => result,
+                    inlateout("x1") arg1 => _,
+                    inlateout("x2") arg2 => _,
+                    inlateout("x3") arg3 => _,
+                );
+            }
+            PsciMethod::Smc => {
+                asm!("smc #0",
+                    inlateout("x0") function_id as usize => result,
+                    inlateout("x1") arg1 => _,
+                    inlateout("x2") arg2 => _,
+                    inlateout("x3") arg3 => _,
+                );
+            }
+            PsciMethod::None => return PsciError::NotSupported as isize,
+        }
+        result
+    }
+
+    #[inline]
+    fn result(val: isize) -> Result<(), PsciError> {
+        (val >= 0).then_some(()).ok_or(PsciError::from_isize(val))
+    }
+
+    /// Returns the (major, minor) version of the PSCI implementation.
+    pub fn version() -> Option<(u16, u16)> {
+        let result = unsafe { Self::call(Self::PSCI_VERSION, 0, 0, 0) };
+        (result >= 0).then(|| ((result >> 16) as u16, result as u16))
+    }
+
+    /// Powers on the core with `mpidr`, which starts at the physical address `entry`
+    /// with `context_id` in `x0`.
+    pub unsafe fn cpu_on(mpidr: u64, entry: usize, context_id: usize) -> Result<(), PsciError> {
+        Self::result(Self::call(Self::CPU_ON, mpidr as usize, entry, context_id))
+    }
+
+    /// Powers off the calling core.
+    pub unsafe fn cpu_off() -> Result<(), PsciError> {
+        Self::result(Self::call(Self::CPU_OFF, 0, 0, 0))
+    }
+
+    /// Returns whether the core with `mpidr` is on.
+    pub fn affinity_info(mpidr: u64) -> Result<bool, PsciError> {
+        let result = unsafe { Self::call(Self::AFFINITY_INFO, mpidr as usize, 0, 0) };
+        match result {
+            0 => Ok(true),
+            1 | 2 => Ok(false),
+            _ => Err(PsciError::from_isize(result)),
+        }
+    }
+
+    pub fn system_off() -> Result<(), PsciError> {
+        Self::result(unsafe { Self::call(Self::SYSTEM_OFF, 0, 0, 0) })
+    }
+
+    pub fn system_reset() -> Result<(), PsciError> {
+        Self::result(unsafe { Self::call(Self::SYSTEM_RESET, 0, 0, 0) })
+    }
+}
@@ -1,6 +1,13 @@
-use crate::{arch::cpu::Cpu, mem::mmio::*};
+use super::super::{current_machine_type, MachineType};
+use crate::{
+    arch::cpu::Cpu,
+    mem::mmio::*,
+    shell::{parse_number, ShellCommand, ShellError},
+};
+use core::{fmt::Write, mem::transmute};
 
 #[allow(dead_code)]
+#[repr(u8)]
 #[derive(Debug, Clone, Copy)]
 pub enum Gpio {
     Pin00 = 0,
@@ -61,6 +68,9 @@ pub enum Gpio {
 
 #[allow(dead_code)]
 impl Gpio {
+    pub const NUM_PINS: usize = 54;
+
+    pub const FUNCTION_IN: u32 = 0;
     pub const FUNCTION_OUT: u32 = 1;
     pub const FUNCTION_ALT5: u32 = 2;
     pub const FUNCTION_ALT3: u32 = 7;
@@ -82,6 +92,23 @@ impl Gpio {
     pub const UART0_TXD: Self = Self::Pin14;
     pub const UART0_RXD: Self = Self::Pin15;
 
+    #[inline]
+    pub fn from_pin(pin: usize) -> Option<Self> {
+        (pin < Self::NUM_PINS).then(|| unsafe { transmute(pin as u8) })
+    }
+
+    /// Returns the current level of the pin.
+    #[inline]
+    pub fn level(&self) -> bool {
+        unsafe { Regs::GPLEV0._gpio_read(*self, 1) != 0 }
+    }
+
+    /// Returns the current function of the pin.
+    #[inline]
+    pub fn current_function(&self) -> u32 {
+        unsafe { Regs::GPFSEL0._gpio_read(*self, 3) }
+    }
+
     #[inline]
     pub fn set(&self, value: u32) {
         unsafe { Regs::GPSET0._gpio_call(*self, value, 1) }
@@ -208,4 +235,106 @@ impl Regs {
         curval |= value << shift;
         reg.write(curval);
     }
+
+    unsafe fn _gpio_read(&self, pin: Gpio, field_size: usize) -> u32 {
+        let pin_number = pin as usize;
+        let field_mask = (1 << field_size) - 1;
+        let num_fields = 32 / field_size;
+        let reg = Mmio32Reg(self.base_addr() + ((pin_number / num_fields) * 4));
+        let shift = (pin_number % num_fields) * field_size;
+
+        (reg.read() >> shift) & field_mask
+    }
+}
+
+struct GpioCommand;
+
+impl GpioCommand {
+    fn function_name(function: u32) -> &'static str {
+        match function {
+            Gpio::FUNCTION_IN => "IN",
+            Gpio::FUNCTION_OUT => "OUT",
+            Gpio::FUNCTION_ALT0 => "ALT0",
+            5 => "ALT1",
+            6 => "ALT2",
+            Gpio::FUNCTION_ALT3 => "ALT3",
+            3 => "ALT4",
+            _ => "ALT5",
+        }
+    }
+
+    fn parse_pin(s: &str) -> Result<Gpio, ShellError> {
+        Gpio::from_pin(parse_number(s)? as usize).ok_or(ShellError::InvalidArgument)
+    }
+}
+
+impl ShellCommand for GpioCommand {
+    fn name(&self) -> &'static str {
+        "gpio"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[pin [0|1]]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the GPIO pins, or drive an input or output pin"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !matches!(
+            current_machine_type(),
+            MachineType::RPi3 | MachineType::RPi4
+        ) {
+            return Err(ShellError::NotSupported);
+        }
+        match args {
+            [] => {
+                for pin in 0..Gpio::NUM_PINS {
+                    let gpio = Gpio::from_pin(pin).unwrap();
+                    write!(
+                        out,
+                        "  {:02} {:<4} {}",
+                        pin,
+                        Self::function_name(gpio.current_function()),
+                        gpio.level() as usize,
+                    )?;
+                    if pin % 4 == 3 || pin == Gpio::NUM_PINS - 1 {
+                        writeln!(out)?;
+                    }
+                }
+            }
+            [pin] => {
+                let gpio = Self::parse_pin(pin)?;
+                writeln!(
+                    out,
+                    "{:?}: {} {}",
+                    gpio,
+                    Self::function_name(gpio.current_function()),
+                    gpio.level() as usize,
+                )?;
+            }
+            [pin, value] => {
+                let gpio = Self::parse_pin(pin)?;
+                let value = match *value {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err(ShellError::InvalidArgument),
+                };
+                // Pins used by peripherals, such as the UART, are left alone.
+                match gpio.current_function() {
+                    Gpio::FUNCTION_IN => gpio.init_output_pin_with_pull_none(),
+                    Gpio::FUNCTION_OUT => (),
+                    _ => return Err(ShellError::InvalidArgument),
+                }
+                gpio.set_output(value);
+            }
+            _ => return Err(ShellError::Usage),
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static GPIO: GpioCommand = GpioCommand;
 }
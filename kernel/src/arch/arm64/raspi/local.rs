@@ -0,0 +1,69 @@
+//! ARM local peripherals of BCM2836 and BCM2837
+//!
+//! Each core has four mailboxes, and a mailbox raises an interrupt on its core while any
+//! of its bits is set. Mailbox 0 is used as the doorbell of inter-processor interrupts.
+
+use crate::{
+    arch::{cpu::Cpu, current_machine_type, MachineType},
+    mem::mmio::{Mmio32, Mmio32Reg},
+};
+
+pub struct LocalIntc;
+
+impl LocalIntc {
+    pub(super) const BASE: usize = 0x4000_0000;
+    pub(super) const SIZE: usize = 0x0004_0000;
+
+    const TIMER_INT_CONTROL: usize = 0x40;
+    const MAILBOX_INT_CONTROL: usize = 0x50;
+    const IRQ_SOURCE: usize = 0x60;
+    const MAILBOX_SET: usize = 0x80;
+    const MAILBOX_CLEAR: usize = 0xC0;
+
+    /// `nCNTVIRQ`, the virtual timer of the core
+    const CNTV_IRQ: u32 = 1 << 3;
+
+    /// Returns whether the board has this controller rather than a GIC.
+    #[inline]
+    pub fn is_available() -> bool {
+        current_machine_type() == MachineType::RPi3
+    }
+
+    #[inline]
+    fn current_core() -> usize {
+        (Cpu::current_mpidr() & 3) as usize
+    }
+
+    /// Enables the interrupt of mailbox 0 of the current core.
+    pub unsafe fn init_cpu() {
+        Mmio32Reg(Self::BASE + Self::MAILBOX_INT_CONTROL + Self::current_core() * 4).write(1);
+    }
+
+    /// Enables or disables the interrupt of the virtual timer of the current core.
+    pub unsafe fn set_timer_irq(enabled: bool) {
+        let value = if enabled { Self::CNTV_IRQ } else { 0 };
+        Mmio32Reg(Self::BASE + Self::TIMER_INT_CONTROL + Self::current_core() * 4).write(value);
+    }
+
+    /// Returns whether the virtual timer of the current core raises its interrupt.
+    #[inline]
+    pub unsafe fn is_timer_pending() -> bool {
+        let source = Mmio32Reg(Self::BASE + Self::IRQ_SOURCE + Self::current_core() * 4).read();
+        (source & Self::CNTV_IRQ) != 0
+    }
+
+    /// Rings the doorbell of `core`.
+    pub unsafe fn send(core: usize) {
+        Mmio32Reg(Self::BASE + Self::MAILBOX_SET + (core & 3) * 0x10).write(1);
+    }
+
+    /// Clears the doorbell of the current core, and returns whether it was rung.
+    pub unsafe fn acknowledge() -> bool {
+        let mailbox = Mmio32Reg(Self::BASE + Self::MAILBOX_CLEAR + Self::current_core() * 0x10);
+        let value = mailbox.read();
+        if value != 0 {
+            mailbox.write(value);
+        }
+        value != 0
+    }
+}
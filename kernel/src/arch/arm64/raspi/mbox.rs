@@ -1,6 +1,12 @@
-use crate::{arch::cpu::Cpu, mem::mmio::Mmio32};
+use super::super::{current_machine_type, MachineType};
+use crate::{
+    arch::cpu::Cpu,
+    mem::mmio::Mmio32,
+    shell::{ShellCommand, ShellError},
+};
 use core::{
     arch::asm,
+    fmt::Write,
     sync::atomic::{fence, Ordering},
 };
 
@@ -70,7 +76,12 @@ impl<const N: usize> MboxContext<N> {
     #[inline]
     unsafe fn flush_payload(&self) {
         // fence(Ordering::SeqCst);
-        asm!("dc civac, {}", in(reg) self.payload.0.as_ptr());
+        let range = self.payload.0.as_ptr_range();
+        let mut p = range.start as usize & !63;
+        while p < range.end as usize {
+            asm!("dc civac, {}", in(reg) p);
+            p += 64;
+        }
     }
 
     pub fn call(&mut self) -> Result<(), ()> {
@@ -144,13 +155,20 @@ pub struct Payload<const N: usize>([u32; N]);
 pub enum RawTag {
     LAST = 0,
 
+    GETFWREV = 0x1,
+    GETMODEL = 0x10001,
+    GETREV = 0x10002,
     GETSERIAL = 0x10004,
+    GETARMMEM = 0x10005,
+    GETVCMEM = 0x10006,
 
     SETPOWER = 0x28001,
     GetClockState = 0x00030001,
     SetClockState = 0x00038001,
     GetClockRate = 0x00030002,
     SETCLKRATE = 0x38002,
+    GETTEMP = 0x30006,
+    GETMAXTEMP = 0x3000a,
     SETPHYWH = 0x48003,
     SETVIRTWH = 0x48004,
     SETVIRTOFF = 0x48009,
@@ -195,8 +213,18 @@ pub enum Tag {
     SET_VIRTOFF(u32, u32),
     SET_DEPTH(u32),
     SET_PXLORDR(u32),
+    /// Device ID and state
+    SET_POWER(u32, u32),
     GET_FB(u32, u32),
     GET_PITCH,
+    GET_CLKRATE(ClockId),
+    GET_TEMP(u32),
+    GET_MAXTEMP(u32),
+    GET_FWREV,
+    GET_MODEL,
+    GET_REV,
+    GET_ARMMEM,
+    GET_VCMEM,
 }
 
 impl Tag {
@@ -209,8 +237,17 @@ impl Tag {
             Tag::SET_VIRTOFF(_, _) => (RawTag::SETVIRTOFF, 8, 8),
             Tag::SET_DEPTH(_) => (RawTag::SETDEPTH, 4, 4),
             Tag::SET_PXLORDR(_) => (RawTag::SETPXLORDR, 4, 4),
+            Tag::SET_POWER(_, _) => (RawTag::SETPOWER, 8, 8),
             Tag::GET_FB(_, _) => (RawTag::GETFB, 8, 8),
             Tag::GET_PITCH => (RawTag::GETPITCH, 4, 4),
+            Tag::GET_CLKRATE(_) => (RawTag::GetClockRate, 4, 8),
+            Tag::GET_TEMP(_) => (RawTag::GETTEMP, 4, 8),
+            Tag::GET_MAXTEMP(_) => (RawTag::GETMAXTEMP, 4, 8),
+            Tag::GET_FWREV => (RawTag::GETFWREV, 0, 4),
+            Tag::GET_MODEL => (RawTag::GETMODEL, 0, 4),
+            Tag::GET_REV => (RawTag::GETREV, 0, 4),
+            Tag::GET_ARMMEM => (RawTag::GETARMMEM, 0, 8),
+            Tag::GET_VCMEM => (RawTag::GETVCMEM, 0, 8),
         }
     }
 
@@ -256,8 +293,17 @@ impl Tag {
             Tag::SET_VIRTOFF(x, y) => Self::_push_slice(slice, index, &[x, y])?,
             Tag::SET_DEPTH(x) => Self::_push(slice, index, x)?,
             Tag::SET_PXLORDR(x) => Self::_push(slice, index, x)?,
+            Tag::SET_POWER(x, y) => Self::_push_slice(slice, index, &[x, y])?,
             Tag::GET_FB(x, y) => Self::_push_slice(slice, index, &[x, y])?,
             Tag::GET_PITCH => Self::_push(slice, index, 0)?,
+            Tag::GET_CLKRATE(x) => Self::_push_slice(slice, index, &[x as u32, 0])?,
+            Tag::GET_TEMP(x) => Self::_push_slice(slice, index, &[x, 0])?,
+            Tag::GET_MAXTEMP(x) => Self::_push_slice(slice, index, &[x, 0])?,
+            Tag::GET_FWREV => Self::_push(slice, index, 0)?,
+            Tag::GET_MODEL => Self::_push(slice, index, 0)?,
+            Tag::GET_REV => Self::_push(slice, index, 0)?,
+            Tag::GET_ARMMEM => Self::_push_slice(slice, index, &[0, 0])?,
+            Tag::GET_VCMEM => Self::_push_slice(slice, index, &[0, 0])?,
         };
 
         let index = Self::_push(slice, index, RawTag::LAST.as_u32())?;
@@ -269,3 +315,121 @@ impl Tag {
         Ok(result)
     }
 }
+
+struct MboxCommand;
+
+impl MboxCommand {
+    const CLOCKS: [ClockId; 13] = [
+        ClockId::UART,
+        ClockId::ARM,
+        ClockId::CORE,
+        ClockId::V3D,
+        ClockId::H264,
+        ClockId::ISP,
+        ClockId::SDRAM,
+        ClockId::PIXEL,
+        ClockId::PWM,
+        ClockId::HEVC,
+        ClockId::EMMC2,
+        ClockId::M2MC,
+        ClockId::PIXEL_BVB,
+    ];
+
+    fn temp(out: &mut dyn Write) -> Result<(), ShellError> {
+        let mut mbox = Mbox::PROP.mbox::<16>().ok_or(ShellError::DeviceError)?;
+        let temp = mbox
+            .append(Tag::GET_TEMP(0))
+            .map_err(|_| ShellError::DeviceError)?;
+        let max_temp = mbox
+            .append(Tag::GET_MAXTEMP(0))
+            .map_err(|_| ShellError::DeviceError)?;
+        mbox.call().map_err(|_| ShellError::DeviceError)?;
+        let temp = mbox.slice()[temp + 1];
+        let max_temp = mbox.slice()[max_temp + 1];
+        writeln!(
+            out,
+            "temperature: {}.{:03} C (max {}.{:03} C)",
+            temp / 1000,
+            temp % 1000,
+            max_temp / 1000,
+            max_temp % 1000
+        )?;
+        Ok(())
+    }
+
+    fn clocks(out: &mut dyn Write) -> Result<(), ShellError> {
+        for clock in Self::CLOCKS {
+            let mut mbox = Mbox::PROP.mbox::<16>().ok_or(ShellError::DeviceError)?;
+            let index = mbox
+                .append(Tag::GET_CLKRATE(clock))
+                .map_err(|_| ShellError::DeviceError)?;
+            mbox.call().map_err(|_| ShellError::DeviceError)?;
+            let rate = mbox.slice()[index + 1];
+            if rate != 0 {
+                writeln!(out, "  {:>10} Hz  {:?}", rate, clock)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn board(out: &mut dyn Write) -> Result<(), ShellError> {
+        let mut mbox = Mbox::PROP.mbox::<36>().ok_or(ShellError::DeviceError)?;
+        let mut append = |tag| mbox.append(tag).map_err(|_| ShellError::DeviceError);
+        let fw_rev = append(Tag::GET_FWREV)?;
+        let model = append(Tag::GET_MODEL)?;
+        let rev = append(Tag::GET_REV)?;
+        let arm_mem = append(Tag::GET_ARMMEM)?;
+        let vc_mem = append(Tag::GET_VCMEM)?;
+        mbox.call().map_err(|_| ShellError::DeviceError)?;
+        let slice = mbox.slice();
+        writeln!(out, "firmware:   {:08x}", slice[fw_rev])?;
+        writeln!(out, "model:      {:08x}", slice[model])?;
+        writeln!(out, "revision:   {:08x}", slice[rev])?;
+        writeln!(
+            out,
+            "ARM memory: {:08x} size {:08x}",
+            slice[arm_mem],
+            slice[arm_mem + 1]
+        )?;
+        writeln!(
+            out,
+            "VC memory:  {:08x} size {:08x}",
+            slice[vc_mem],
+            slice[vc_mem + 1]
+        )?;
+        Ok(())
+    }
+}
+
+impl ShellCommand for MboxCommand {
+    fn name(&self) -> &'static str {
+        "mbox"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<temp|clocks|board>"
+    }
+
+    fn help(&self) -> &'static str {
+        "Query the VideoCore firmware"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !matches!(
+            current_machine_type(),
+            MachineType::RPi3 | MachineType::RPi4
+        ) {
+            return Err(ShellError::NotSupported);
+        }
+        match args {
+            ["temp"] => Self::temp(out),
+            ["clocks"] => Self::clocks(out),
+            ["board"] => Self::board(out),
+            _ => Err(ShellError::Usage),
+        }
+    }
+}
+
+crate::shell_command! {
+    static MBOX: MboxCommand = MboxCommand;
+}
@@ -1,7 +1,17 @@
 use super::{gpio::*, mbox::*, *};
-use crate::{arch::cpu::Cpu, io::uart::Uart, mem::mmio::*};
+use crate::{arch::cpu::Cpu, io::uart::Uart, mem::mmio::*, param::UintParam};
 pub use core::fmt::Write;
 
+crate::kernel_param! {
+    static BAUD_RATE: UintParam = UintParam::new(
+        "uart.baud",
+        115200,
+        300,
+        Uart0::CLOCK as usize / 16,
+        "Baud rate of the serial console",
+    );
+}
+
 #[allow(dead_code)]
 pub struct MiniUart;
 
@@ -42,7 +52,7 @@ impl MiniUart {
 
             // TODO:
             match current_machine_type() {
-                MachineType::Unknown => {
+                MachineType::Unknown | MachineType::QemuVirt => {
                     //
                 }
                 MachineType::RPi3 => {
@@ -120,6 +130,8 @@ unsafe impl Mmio32 for Uart0 {
 
 #[allow(dead_code)]
 impl Uart0 {
+    pub const CLOCK: u32 = 3_000_000;
+
     #[inline]
     pub fn shared<'a>() -> &'a mut Self {
         unsafe { &mut UART0 }
@@ -138,13 +150,15 @@ impl Uart0 {
             Uart0::ICR.write(0x7FF);
 
             let mut mbox = Mbox::PROP.mbox::<10>().ok_or(())?;
-            mbox.append(Tag::SET_CLKRATE(ClockId::UART, 3000000, 0))?;
+            mbox.append(Tag::SET_CLKRATE(ClockId::UART, Self::CLOCK, 0))?;
             mbox.call()?;
 
-            // Divider = 3000000 / (16 * 115200) = 1.627 = ~1.
-            Uart0::IBRD.write(1);
-            // Fractional part register = (.627 * 64) + 0.5 = 40.6 = ~40.
-            Uart0::FBRD.write(40);
+            // Divider = CLOCK / (16 * baud) in 16.6 fixed point,
+            // e.g. 3000000 / (16 * 115200) = 1.627 = 1 + 40/64.
+            let baud = BAUD_RATE.get() as u32;
+            let divider = (Self::CLOCK * 4 + baud / 2) / baud;
+            Uart0::IBRD.write(divider >> 6);
+            Uart0::FBRD.write(divider & 0x3F);
 
             // Enable FIFO & 8 bit data transmission (1 stop bit, no parity).
             Uart0::LCRH.write(0x0070);
@@ -0,0 +1,874 @@
+//! Bring-up of secondary cores driven by the device tree, and inter-processor interrupts
+//!
+//! An IPI sets its bit in the pending mask of the target and rings the doorbell of the
+//! target: SGI 0 of the GIC, or mailbox 0 of the BCM2836 local interrupt controller.
+
+use super::{
+    boot::_start_secondary,
+    cpu::Cpu,
+    gic::{Gic, GicVersion, Irq},
+    page::PageManager,
+    percpu,
+    psci::{Psci, PsciError},
+    raspi::local::LocalIntc,
+    spin::Spinlock,
+    stack::KernelStack,
+    timer::GenericTimer,
+};
+use crate::{
+    fw::dt::{DeviceTree, PropName},
+    mem::MemoryManager,
+    shell::{ShellCommand, ShellError},
+    sync::spinlock::{McsMutex, RwSpinlock, SpinMutex, TicketMutex},
+};
+use core::{
+    arch::asm,
+    fmt::Write,
+    hint, mem,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Maximum number of cores
+pub const MAX_CPUS: usize = 256;
+
+/// Size of the stack a core runs on until it switches to its own
+const BOOT_STACK_SIZE: usize = 0x1000;
+
+/// Software generated interrupt used as the doorbell of IPIs
+pub(super) const SGI_IPI: Irq = Irq(0);
+
+/// Time to wait for a core to come up or go down
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Boot slot of each logical core, which `_start_secondary` finds by its affinity
+/// with the MMU off
+#[no_mangle]
+static SMP_BOOT_SLOTS: [BootSlot; MAX_CPUS] = [BootSlot::INIT; MAX_CPUS];
+static SMP_BOOT_LOCK: Spinlock = Spinlock::new();
+
+static SMP_BLOCK1: AtomicUsize = AtomicUsize::new(0);
+static SMP_LOCK: Spinlock = Spinlock::new();
+static SMP_TEST: AtomicUsize = AtomicUsize::new(0);
+
+/// Locks compared by the benchmark after the spin test
+const BENCH_LOCKS: [&str; 4] = ["tas", "ticket", "mcs", "rwlock"];
+/// Time each lock is hammered by all cores
+const BENCH_TIME: Duration = Duration::from_millis(20);
+static BENCH_TAS: SpinMutex<u64> = SpinMutex::new(0);
+static BENCH_TICKET: TicketMutex<u64> = TicketMutex::new(0);
+static BENCH_MCS: McsMutex<u64> = McsMutex::new(0);
+static BENCH_RW: RwSpinlock<u64> = RwSpinlock::new(0);
+/// The lock being measured plus one
+static BENCH_PHASE: AtomicUsize = AtomicUsize::new(0);
+static BENCH_START: AtomicU64 = AtomicU64::new(0);
+static BENCH_DONE: AtomicUsize = AtomicUsize::new(0);
+static BENCH_FINISHED: AtomicBool = AtomicBool::new(false);
+static BENCH_COUNTS: [AtomicU64; MAX_CPUS] = [BENCH_COUNT_ZERO; MAX_CPUS];
+#[allow(clippy::declare_interior_mutable_const)]
+const BENCH_COUNT_ZERO: AtomicU64 = AtomicU64::new(0);
+
+static HALTING: AtomicBool = AtomicBool::new(false);
+/// Number of cores that have not flushed their TLB for the current shootdown
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_LOCK: Spinlock = Spinlock::new();
+
+static NUM_CPUS: AtomicUsize = AtomicUsize::new(1);
+static CPUS: [SmpCpu; MAX_CPUS] = [SmpCpu::INIT; MAX_CPUS];
+
+/// How a core is started
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnableMethod {
+    None = 0,
+    SpinTable,
+    Psci,
+}
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    Offline = 0,
+    Starting,
+    Online,
+    Stopping,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    InvalidCpu,
+    AlreadyOnline,
+    NotOnline,
+    /// The enable method does not support the operation.
+    NotSupported,
+    OutOfMemory,
+    Timeout,
+    Psci(PsciError),
+}
+
+/// Inter-processor interrupt
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipi {
+    /// Runs the function posted by [Smp::call_on] or [Smp::broadcast].
+    Call = 0,
+    /// Only wakes the core up, so that it looks for work.
+    Reschedule,
+    /// Flushes the whole TLB of the core, see [Smp::shootdown_tlb].
+    TlbShootdown,
+    /// Stops the core.
+    Halt,
+}
+
+impl Ipi {
+    #[inline]
+    const fn bit(self) -> usize {
+        1 << self as usize
+    }
+}
+
+/// A function posted to other cores, which lives on the stack of the caller
+/// until all of them have run it
+struct CallRequest<'a> {
+    func: &'a (dyn Fn() + Sync),
+    remaining: AtomicUsize,
+}
+
+/// Affinity and top of the boot stack of a core, laid out for `_start_secondary`
+///
+/// A slot is used only by its own core, so a core that comes up late cannot run on
+/// the stack of another one.
+#[repr(C)]
+struct BootSlot {
+    mpidr: AtomicU64,
+    stack: AtomicUsize,
+}
+
+impl BootSlot {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        mpidr: AtomicU64::new(0),
+        stack: AtomicUsize::new(0),
+    };
+}
+
+struct SmpCpu {
+    mpidr: AtomicU64,
+    method: AtomicUsize,
+    release_addr: AtomicUsize,
+    stack: AtomicUsize,
+    /// Offset of the per-core data, see [percpu]
+    percpu: AtomicUsize,
+    state: AtomicUsize,
+    ipi_pending: AtomicUsize,
+    /// The posted [CallRequest]
+    call: AtomicUsize,
+}
+
+impl SmpCpu {
+    const INIT: Self = Self::new();
+
+    const fn new() -> Self {
+        Self {
+            mpidr: AtomicU64::new(0),
+            method: AtomicUsize::new(EnableMethod::None as usize),
+            release_addr: AtomicUsize::new(0),
+            stack: AtomicUsize::new(0),
+            percpu: AtomicUsize::new(0),
+            state: AtomicUsize::new(CpuState::Offline as usize),
+            ipi_pending: AtomicUsize::new(0),
+            call: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    fn method(&self) -> EnableMethod {
+        match self.method.load(Ordering::Relaxed) {
+            1 => EnableMethod::SpinTable,
+            2 => EnableMethod::Psci,
+            _ => EnableMethod::None,
+        }
+    }
+
+    #[inline]
+    fn state(&self) -> CpuState {
+        match self.state.load(Ordering::Acquire) {
+            1 => CpuState::Starting,
+            2 => CpuState::Online,
+            3 => CpuState::Stopping,
+            _ => CpuState::Offline,
+        }
+    }
+
+    #[inline]
+    fn set_state(&self, state: CpuState) {
+        self.state.store(state as usize, Ordering::Release);
+        unsafe {
+            asm!("sev");
+        }
+    }
+}
+
+#[no_mangle]
+unsafe fn _smp_main(cpuid: usize) -> ! {
+    // Nothing else is read or written before the MMU and caches are on.
+    PageManager::init_mp();
+    percpu::set_current_offset(CPUS[cpuid].percpu.load(Ordering::Relaxed));
+    Cpu::init_current(cpuid);
+
+    // The boot stack is only large enough to turn the MMU on.
+    asm!(
+        "
+        mov     sp, {0}
+        mov     x29, #0
+        b       _smp_idle
+        ",
+        in(reg) CPUS[cpuid].stack.load(Ordering::Relaxed),
+        in("x0") cpuid,
+        options(noreturn)
+    );
+}
+
+#[no_mangle]
+unsafe fn _smp_idle(cpuid: usize) -> ! {
+    super::init_secondary(cpuid);
+
+    crate::info!("started core #{}", cpuid);
+
+    let cpu = &CPUS[cpuid];
+    cpu.set_state(CpuState::Online);
+
+    while SMP_BLOCK1.load(Ordering::Acquire) == 0 {
+        asm!("nop");
+    }
+
+    SMP_LOCK.synchronized(|| {
+        crate::info!("SPIN TEST: #{} OK", cpuid);
+    });
+
+    SMP_TEST.fetch_add(1, Ordering::Release);
+    asm!("sev");
+
+    if !BENCH_FINISHED.load(Ordering::Acquire) {
+        for phase in 1..=BENCH_LOCKS.len() {
+            while BENCH_PHASE.load(Ordering::Acquire) < phase {
+                asm!("wfe");
+            }
+            lock_bench_run(cpuid, phase);
+        }
+    }
+
+    loop {
+        if HALTING.load(Ordering::Acquire) {
+            Smp::halt();
+        }
+        if cpu.state() == CpuState::Stopping {
+            cpu.set_state(CpuState::Offline);
+            let _ = Psci::cpu_off();
+        }
+        // IPIs are taken only while the core waits.
+        Cpu::enable_interrupt();
+        asm!("wfe");
+        Cpu::disable_interrupt();
+    }
+}
+
+pub struct Smp;
+
+impl Smp {
+    /// Finds the cores in `/cpus` and starts all of them.
+    pub(super) unsafe fn init(dt: &DeviceTree) {
+        Psci::init_dt(dt);
+
+        Cpu::init_current(0);
+        let current = Cpu::current_mpidr();
+        CPUS[0].mpidr.store(current, Ordering::Relaxed);
+        CPUS[0]
+            .percpu
+            .store(percpu::boot_offset(), Ordering::Relaxed);
+        CPUS[0]
+            .state
+            .store(CpuState::Online as usize, Ordering::Relaxed);
+
+        let mut num_cpus = 1;
+        if let Some(cpus) = dt.find_node("/cpus") {
+            for node in cpus.children() {
+                if node.prop_str(PropName::DEVICE_TYPE) != Some("cpu") || !node.is_enabled() {
+                    continue;
+                }
+                let Some((mpidr, _)) = node.reg().next() else {
+                    continue;
+                };
+                let mpidr = mpidr.as_u64();
+                if mpidr == current {
+                    continue;
+                }
+                if num_cpus >= MAX_CPUS {
+                    break;
+                }
+
+                let cpu = &CPUS[num_cpus];
+                cpu.mpidr.store(mpidr, Ordering::Relaxed);
+                let method = match node.prop_str(PropName::ENABLE_METHOD) {
+                    Some("spin-table") => {
+                        let release_addr = node
+                            .prop(PropName::CPU_RELEASE_ADDR)
+                            .and_then(|v| v.get(0..8))
+                            .map(|v| u64::from_be_bytes(v.try_into().unwrap()) as usize)
+                            .unwrap_or(0);
+                        cpu.release_addr.store(release_addr, Ordering::Relaxed);
+                        if release_addr != 0 {
+                            EnableMethod::SpinTable
+                        } else {
+                            EnableMethod::None
+                        }
+                    }
+                    Some("psci") if Psci::is_available() => EnableMethod::Psci,
+                    _ => EnableMethod::None,
+                };
+                cpu.method.store(method as usize, Ordering::Relaxed);
+                num_cpus += 1;
+            }
+        }
+        NUM_CPUS.store(num_cpus, Ordering::Release);
+
+        for cpuid in 1..num_cpus {
+            if let Err(err) = Self::cpu_on(cpuid) {
+                crate::error!("core #{} failed to start: {:?}", cpuid, err);
+            }
+        }
+        crate::info!("total {} cores", Self::online_cpus());
+
+        Self::spin_test();
+
+        // The boot core takes IPIs from now on.
+        Cpu::enable_interrupt();
+    }
+
+    /// Sends `ipi` to the core `cpuid`.
+    pub fn send_ipi(cpuid: usize, ipi: Ipi) -> Result<(), SmpError> {
+        if Self::state(cpuid) != Some(CpuState::Online) {
+            return Err(SmpError::NotOnline);
+        }
+        let cpu = &CPUS[cpuid];
+        cpu.ipi_pending.fetch_or(ipi.bit(), Ordering::AcqRel);
+
+        let mpidr = cpu.mpidr.load(Ordering::Relaxed);
+        unsafe {
+            asm!("dsb ishst");
+            match Gic::version() {
+                GicVersion::None if LocalIntc::is_available() => {
+                    LocalIntc::send(mpidr as usize & 3)
+                }
+                GicVersion::None => return Err(SmpError::NotSupported),
+                _ => {
+                    if !Gic::send_sgi(SGI_IPI.0, cpuid) {
+                        return Err(SmpError::NotSupported);
+                    }
+                }
+            }
+            // Wakes up cores that wait with interrupts masked.
+            asm!("sev");
+        }
+        Ok(())
+    }
+
+    /// Handles the IPIs pending on the current core.
+    ///
+    /// Called from the interrupt handler, and by cores waiting for other cores.
+    pub(super) fn handle_ipi() {
+        let cpu = &CPUS[Cpu::current().id()];
+        let pending = cpu.ipi_pending.swap(0, Ordering::AcqRel);
+        if (pending & Ipi::Halt.bit()) != 0 {
+            Self::halt();
+        }
+        if (pending & Ipi::Reschedule.bit()) != 0 {
+            Cpu::current().set_need_resched();
+        }
+        if (pending & Ipi::TlbShootdown.bit()) != 0 {
+            unsafe {
+                asm!(
+                    "
+                    dsb ishst
+                    tlbi vmalle1
+                    dsb nsh
+                    isb
+                    "
+                );
+            }
+            SHOOTDOWN_PENDING.fetch_sub(1, Ordering::Release);
+        }
+        if (pending & Ipi::Call.bit()) != 0 {
+            let request = cpu.call.swap(0, Ordering::AcqRel) as *const CallRequest;
+            if let Some(request) = unsafe { request.as_ref() } {
+                (request.func)();
+                // The request may be gone after this.
+                request.remaining.fetch_sub(1, Ordering::Release);
+            }
+        }
+    }
+
+    /// Runs `f` on the core `cpuid` and waits for it to return.
+    ///
+    /// `f` runs in the interrupt handler of the core, so it must not take locks that
+    /// the core can hold with interrupts enabled.
+    pub fn call_on<F>(cpuid: usize, f: F) -> Result<(), SmpError>
+    where
+        F: Fn() + Sync,
+    {
+        if cpuid == Cpu::current().id() {
+            f();
+            return Ok(());
+        }
+        let request = CallRequest {
+            func: &f,
+            remaining: AtomicUsize::new(1),
+        };
+        Self::post_call(cpuid, &request)?;
+        Self::wait_for(|| request.remaining.load(Ordering::Acquire) == 0);
+        Ok(())
+    }
+
+    /// Runs `f` on all online cores including the current one, and waits for all of them.
+    ///
+    /// The same restrictions as [Smp::call_on] apply.
+    pub fn broadcast<F>(f: F)
+    where
+        F: Fn() + Sync,
+    {
+        let current = Cpu::current().id();
+        let request = CallRequest {
+            func: &f,
+            remaining: AtomicUsize::new(0),
+        };
+        for cpuid in (0..Self::num_cpus()).filter(|&v| v != current) {
+            request.remaining.fetch_add(1, Ordering::AcqRel);
+            if Self::post_call(cpuid, &request).is_err() {
+                request.remaining.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+        f();
+        Self::wait_for(|| request.remaining.load(Ordering::Acquire) == 0);
+    }
+
+    /// Posts `request` to the core `cpuid` once its previous call is taken.
+    fn post_call(cpuid: usize, request: &CallRequest) -> Result<(), SmpError> {
+        if Self::state(cpuid) != Some(CpuState::Online) {
+            return Err(SmpError::NotOnline);
+        }
+        let cpu = &CPUS[cpuid];
+        let ptr = request as *const CallRequest as usize;
+        Self::wait_for(|| {
+            cpu.call
+                .compare_exchange(0, ptr, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        });
+        Self::send_ipi(cpuid, Ipi::Call).map_err(|err| {
+            cpu.call.store(0, Ordering::Release);
+            err
+        })
+    }
+
+    /// Flushes the TLBs of all online cores, and waits for all of them.
+    ///
+    /// The kernel unmaps pages with broadcast TLB maintenance, which needs no IPI. This is
+    /// for the cases where a core may hold entries that broadcasts do not reach.
+    pub fn shootdown_tlb() {
+        SHOOTDOWN_LOCK.synchronized(|| {
+            let current = Cpu::current().id();
+            for cpuid in (0..Self::num_cpus()).filter(|&v| v != current) {
+                SHOOTDOWN_PENDING.fetch_add(1, Ordering::AcqRel);
+                if Self::send_ipi(cpuid, Ipi::TlbShootdown).is_err() {
+                    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+                }
+            }
+            unsafe {
+                asm!(
+                    "
+                    dsb ishst
+                    tlbi vmalle1
+                    dsb nsh
+                    isb
+                    "
+                );
+            }
+            Self::wait_for(|| SHOOTDOWN_PENDING.load(Ordering::Acquire) == 0);
+        });
+    }
+
+    /// Spins until `cond` is met, handling the IPIs to the current core meanwhile,
+    /// so that two cores waiting for each other do not deadlock.
+    fn wait_for<F>(mut cond: F)
+    where
+        F: FnMut() -> bool,
+    {
+        while !cond() {
+            Self::handle_ipi();
+            hint::spin_loop();
+        }
+    }
+
+    /// Stops the other cores, leaving the system as it is for a fatal error report.
+    ///
+    /// Cores with interrupts masked stop when they next look for work.
+    pub fn halt_others() {
+        HALTING.store(true, Ordering::Release);
+        let current = Cpu::current().id();
+        for cpuid in (0..Self::num_cpus()).filter(|&v| v != current) {
+            let _ = Self::send_ipi(cpuid, Ipi::Halt);
+        }
+        unsafe {
+            asm!("sev");
+        }
+    }
+
+    /// Stops the current core.
+    pub fn halt() -> ! {
+        unsafe {
+            Cpu::disable_interrupt();
+        }
+        loop {
+            unsafe {
+                asm!("wfe");
+            }
+        }
+    }
+
+    /// Returns the number of cores in the system.
+    #[inline]
+    pub fn num_cpus() -> usize {
+        NUM_CPUS.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of cores that are running.
+    pub fn online_cpus() -> usize {
+        CPUS[..Self::num_cpus()]
+            .iter()
+            .filter(|cpu| cpu.state() == CpuState::Online)
+            .count()
+    }
+
+    #[inline]
+    pub fn state(cpuid: usize) -> Option<CpuState> {
+        (cpuid < Self::num_cpus()).then(|| CPUS[cpuid].state())
+    }
+
+    #[inline]
+    pub fn mpidr(cpuid: usize) -> Option<u64> {
+        (cpuid < Self::num_cpus()).then(|| CPUS[cpuid].mpidr.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn enable_method(cpuid: usize) -> Option<EnableMethod> {
+        (cpuid < Self::num_cpus()).then(|| CPUS[cpuid].method())
+    }
+
+    /// Returns the offset of the per-core data of the core `cpuid`, if it has been set up.
+    #[inline]
+    pub(super) fn percpu_offset(cpuid: usize) -> Option<usize> {
+        if cpuid >= Self::num_cpus() {
+            return None;
+        }
+        match CPUS[cpuid].percpu.load(Ordering::Acquire) {
+            0 if cpuid != 0 => None,
+            offset => Some(offset),
+        }
+    }
+
+    /// Starts the core `cpuid` and waits for it to come up.
+    pub unsafe fn cpu_on(cpuid: usize) -> Result<(), SmpError> {
+        if cpuid == 0 || cpuid >= Self::num_cpus() {
+            return Err(SmpError::InvalidCpu);
+        }
+        let cpu = &CPUS[cpuid];
+        let method = cpu.method();
+        if method == EnableMethod::None {
+            return Err(SmpError::NotSupported);
+        }
+
+        SMP_BOOT_LOCK.synchronized(|| {
+            if cpu.state() != CpuState::Offline {
+                return Err(SmpError::AlreadyOnline);
+            }
+            // A core released from the spin table never returns to it.
+            if method == EnableMethod::SpinTable && cpu.stack.load(Ordering::Relaxed) != 0 {
+                return Err(SmpError::NotSupported);
+            }
+
+            let slot = &SMP_BOOT_SLOTS[cpuid];
+            if slot.stack.load(Ordering::Relaxed) == 0 {
+                let boot_stack = MemoryManager::alloc_pages(BOOT_STACK_SIZE)
+                    .ok_or(SmpError::OutOfMemory)?
+                    .get()
+                    .direct_mapped::<u8>() as usize;
+                slot.mpidr
+                    .store(cpu.mpidr.load(Ordering::Relaxed), Ordering::Relaxed);
+                slot.stack
+                    .store(boot_stack + BOOT_STACK_SIZE, Ordering::Relaxed);
+            }
+            if cpu.stack.load(Ordering::Relaxed) == 0 {
+                // The stack of a core lives as long as the kernel.
+                let stack = KernelStack::new(cpuid).ok_or(SmpError::OutOfMemory)?;
+                cpu.stack.store(stack.top(), Ordering::Relaxed);
+                mem::forget(stack);
+            }
+            if cpu.percpu.load(Ordering::Relaxed) == 0 {
+                let offset = percpu::alloc().ok_or(SmpError::OutOfMemory)?;
+                cpu.percpu.store(offset, Ordering::Release);
+            }
+
+            // The new core searches the slots and runs on its boot stack with the MMU and
+            // caches off, so they are cleaned to the point of coherency before it is released.
+            clean_dcache(
+                SMP_BOOT_SLOTS.as_ptr() as usize,
+                mem::size_of_val(&SMP_BOOT_SLOTS),
+            );
+            clean_dcache(
+                slot.stack.load(Ordering::Relaxed) - BOOT_STACK_SIZE,
+                BOOT_STACK_SIZE,
+            );
+            PageManager::clean_mp_config();
+
+            cpu.set_state(CpuState::Starting);
+            match method {
+                EnableMethod::SpinTable => {
+                    let release_addr = cpu.release_addr.load(Ordering::Relaxed);
+                    (release_addr as *mut u64)
+                        .write_volatile(_start_secondary as *const () as usize as u64);
+                    clean_dcache(release_addr, mem::size_of::<u64>());
+                    asm!("sev");
+                }
+                EnableMethod::Psci => {
+                    let mpidr = cpu.mpidr.load(Ordering::Relaxed);
+                    if let Err(err) =
+                        Psci::cpu_on(mpidr, _start_secondary as *const () as usize, cpuid)
+                    {
+                        cpu.set_state(CpuState::Offline);
+                        return Err(SmpError::Psci(err));
+                    }
+                }
+                EnableMethod::None => unreachable!(),
+            }
+
+            // A core that does not come up in time stays starting, and cannot be started
+            // again while it may still be running on its boot stack.
+            wait_until(|| cpu.state() == CpuState::Online)
+                .then_some(())
+                .ok_or(SmpError::Timeout)
+        })
+    }
+
+    /// Asks the core `cpuid` to power itself off and waits for it to go down.
+    ///
+    /// Only cores started with PSCI can be powered off.
+    pub fn cpu_off(cpuid: usize) -> Result<(), SmpError> {
+        if cpuid == 0 || cpuid >= Self::num_cpus() {
+            return Err(SmpError::InvalidCpu);
+        }
+        let cpu = &CPUS[cpuid];
+        if cpu.method() != EnableMethod::Psci {
+            return Err(SmpError::NotSupported);
+        }
+
+        SMP_BOOT_LOCK.synchronized(|| {
+            if cpu.state() != CpuState::Online {
+                return Err(SmpError::NotOnline);
+            }
+            cpu.set_state(CpuState::Stopping);
+            if !wait_until(|| cpu.state() == CpuState::Offline) {
+                return Err(SmpError::Timeout);
+            }
+            let mpidr = cpu.mpidr.load(Ordering::Relaxed);
+            let mut result = Ok(());
+            wait_until(|| match Psci::affinity_info(mpidr) {
+                Ok(on) => !on,
+                Err(err) => {
+                    result = Err(SmpError::Psci(err));
+                    true
+                }
+            })
+            .then_some(())
+            .ok_or(SmpError::Timeout)
+            .and(result)
+        })
+    }
+
+    /// Runs the spin lock test on all started cores.
+    unsafe fn spin_test() {
+        let mut test = 0x12345678;
+        let (status, val) = _test_spin(&mut test);
+        crate::info!("SPIN TEST: {} {:x} {:x}", status, val, test);
+
+        SMP_BLOCK1.store(1, Ordering::Release);
+        asm!("sev");
+
+        let expected = Self::online_cpus() - 1;
+        while SMP_TEST.load(Ordering::Acquire) < expected {
+            asm!("wfe");
+        }
+
+        crate::info!("SPIN TEST: ALL OK");
+
+        Self::lock_bench();
+    }
+
+    /// Measures the throughput and the fairness of each kind of lock under contention
+    /// from all online cores.
+    unsafe fn lock_bench() {
+        let num_cpus = Self::num_cpus();
+        let online = Self::online_cpus();
+        for (index, name) in BENCH_LOCKS.iter().enumerate() {
+            let phase = index + 1;
+            for count in &BENCH_COUNTS[..num_cpus] {
+                count.store(0, Ordering::Relaxed);
+            }
+            BENCH_DONE.store(0, Ordering::Relaxed);
+            BENCH_START.store(
+                GenericTimer::counter() + GenericTimer::frequency() / 1000,
+                Ordering::Relaxed,
+            );
+            BENCH_PHASE.store(phase, Ordering::Release);
+            asm!("sev");
+
+            lock_bench_run(0, phase);
+            while BENCH_DONE.load(Ordering::Acquire) < online {
+                asm!("wfe");
+            }
+
+            let (total, min, max) = (0..num_cpus)
+                .filter(|&cpuid| CPUS[cpuid].state() == CpuState::Online)
+                .map(|cpuid| BENCH_COUNTS[cpuid].load(Ordering::Relaxed))
+                .fold((0, u64::MAX, 0), |(total, min, max), count| {
+                    (total + count, min.min(count), max.max(count))
+                });
+            crate::info!(
+                "LOCK BENCH: {} {} ops, {} ns/op, per core min {} max {}",
+                name,
+                total,
+                BENCH_TIME.as_nanos() as u64 / total.max(1),
+                min,
+                max
+            );
+        }
+        BENCH_FINISHED.store(true, Ordering::Release);
+    }
+}
+
+/// Takes the lock of `phase` repeatedly for [BENCH_TIME], and counts the acquisitions.
+fn lock_bench_run(cpuid: usize, phase: usize) {
+    let start = BENCH_START.load(Ordering::Relaxed);
+    let end = start + BENCH_TIME.as_micros() as u64 * GenericTimer::frequency() / 1_000_000;
+    while GenericTimer::counter() < start {
+        hint::spin_loop();
+    }
+    let mut count = 0;
+    while GenericTimer::counter() < end {
+        match phase {
+            1 => *BENCH_TAS.lock() += 1,
+            2 => *BENCH_TICKET.lock() += 1,
+            3 => *BENCH_MCS.lock() += 1,
+            // One writer for every three readers
+            _ if count % 4 == 0 => *BENCH_RW.write() += 1,
+            _ => {
+                hint::black_box(*BENCH_RW.read());
+            }
+        }
+        count += 1;
+    }
+    BENCH_COUNTS[cpuid].store(count, Ordering::Relaxed);
+    BENCH_DONE.fetch_add(1, Ordering::Release);
+    unsafe {
+        asm!("sev");
+    }
+}
+
+/// Waits until `cond` is met or the timeout expires.
+fn wait_until<F>(mut cond: F) -> bool
+where
+    F: FnMut() -> bool,
+{
+    let freq = GenericTimer::frequency();
+    let timeout = TIMEOUT.as_millis() as u64 * freq / 1000;
+    let start = GenericTimer::counter();
+    loop {
+        if cond() {
+            return true;
+        }
+        if GenericTimer::counter().wrapping_sub(start) >= timeout {
+            return false;
+        }
+        Cpu::no_op();
+    }
+}
+
+/// Cleans and invalidates the data cache of `start..start+len` to the point of coherency.
+///
+/// Memory that a core reads or writes with its caches off must be cleaned this way, so
+/// that neither side sees stale lines.
+pub(super) unsafe fn clean_dcache(start: usize, len: usize) {
+    let ctr_el0: usize;
+    asm!("mrs {}, ctr_el0", out(reg) ctr_el0);
+    let line_size = 4 << ((ctr_el0 >> 16) & 0xF);
+    let mut addr = start & !(line_size - 1);
+    while addr < start + len {
+        asm!("dc civac, {}", in(reg) addr);
+        addr += line_size;
+    }
+    asm!("dsb sy");
+}
+
+fn _test_spin(val: &mut u64) -> (u32, u64) {
+    let status: u32;
+    let result: u64;
+    unsafe {
+        asm!("
+        ldaxr {1}, [{2}]
+        add {1}, {1}, #1
+        stlxr {0:w}, {1}, [{2}]
+        ldar {3}, [{2}]
+        ", out(reg) status, out(reg)_, in(reg)val, out(reg)result);
+    };
+    (status, result)
+}
+
+struct CpusCommand;
+
+impl ShellCommand for CpusCommand {
+    fn name(&self) -> &'static str {
+        "cpus"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the cores and their states"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        writeln!(
+            out,
+            "{} of {} cores online",
+            Smp::online_cpus(),
+            Smp::num_cpus()
+        )?;
+        writeln!(out, "  id mpidr          irqs state")?;
+        for cpuid in 0..Smp::num_cpus() {
+            let irq_count = Cpu::local(cpuid).map(|v| v.irq_count()).unwrap_or(0);
+            writeln!(
+                out,
+                "{:4} {:08x} {:10} {:?} ({:?})",
+                cpuid,
+                Smp::mpidr(cpuid).unwrap_or(0),
+                irq_count,
+                Smp::state(cpuid).unwrap_or(CpuState::Offline),
+                Smp::enable_method(cpuid).unwrap_or(EnableMethod::None),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static CPUS_COMMAND: CpusCommand = CpusCommand;
+}
@@ -1,40 +1,127 @@
+//! Spinlocks
+//!
+//! [Spinlock] adds the lock validator to one of the raw locks: [TasLock] is the smallest
+//! and fastest without contention, while [TicketLock] and [McsLock] hand the lock over
+//! to the waiters in order, so that no core starves. [McsLock] waits on a node of each
+//! waiter instead of the lock itself, which keeps the cache line of the lock quiet.
+//!
+//! While the validator is enabled, waiters queue up in the same way, and check the
+//! timeout of the validator while they spin.
+
 use super::cpu::Cpu;
+use crate::sync::lockdep::{LockDep, LockDepMap};
 use core::{
     arch::asm,
-    sync::atomic::{AtomicUsize, Ordering},
+    panic::Location,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
 };
 
-#[derive(Default)]
-pub struct Spinlock {
-    value: AtomicUsize,
+/// A lock without the validator
+pub trait RawSpinlock: Sync {
+    /// The unlocked state
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self;
+
+    fn lock(&self);
+
+    /// Acquires the lock as [RawSpinlock::lock] does, calling `spin` while it waits.
+    fn lock_with<F: FnMut()>(&self, spin: F);
+
+    fn try_lock(&self) -> bool;
+
+    /// # Safety
+    ///
+    /// The current core must hold the lock.
+    unsafe fn unlock(&self);
 }
 
-impl Spinlock {
-    pub const LOCKED_VALUE: usize = 1;
-    pub const UNLOCKED_VALUE: usize = 0;
+pub struct Spinlock<L: RawSpinlock = TasLock> {
+    raw: L,
+    dep: LockDepMap,
+}
 
+impl<L: RawSpinlock> Spinlock<L> {
+    /// Creates a lock whose class for [crate::sync::lockdep] is the caller.
     #[inline]
+    #[track_caller]
     pub const fn new() -> Self {
         Self {
-            value: AtomicUsize::new(Self::UNLOCKED_VALUE),
+            raw: L::INIT,
+            dep: LockDepMap::new(),
         }
     }
 
     #[must_use]
+    #[track_caller]
     pub fn try_lock(&self) -> bool {
-        let result: u32;
-        unsafe {
-            asm!("
-            ldaxr {0:w}, [{1}]
-            cbnz {0:w}, 1f
-            stxr {0:w}, {2:w}, [{1}]
-            1:
-            ", out(reg)result, in(reg)&self.value, in(reg)1);
+        let result = self.raw.try_lock();
+        if result && LockDep::is_enabled() {
+            LockDep::try_locked(&self.dep, Location::caller());
         }
-        result == 0
+        result
     }
 
+    #[track_caller]
     pub fn lock(&self) {
+        if LockDep::is_enabled() {
+            LockDep::lock(
+                &self.dep,
+                Location::caller(),
+                || self.raw.try_lock(),
+                |spin| self.raw.lock_with(spin),
+            );
+        } else {
+            self.raw.lock();
+        }
+    }
+
+    #[inline]
+    pub unsafe fn force_unlock(&self) {
+        if LockDep::is_enabled() {
+            LockDep::unlock(&self.dep);
+        }
+        self.raw.unlock();
+    }
+
+    #[inline]
+    #[track_caller]
+    pub fn synchronized<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.lock();
+        let result = f();
+        unsafe {
+            self.force_unlock();
+        }
+        result
+    }
+}
+
+impl<L: RawSpinlock> Default for Spinlock<L> {
+    #[inline]
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Test-and-set lock with the exclusive monitor
+pub struct TasLock {
+    value: AtomicUsize,
+}
+
+impl TasLock {
+    pub const LOCKED_VALUE: usize = 1;
+    pub const UNLOCKED_VALUE: usize = 0;
+}
+
+impl RawSpinlock for TasLock {
+    const INIT: Self = Self {
+        value: AtomicUsize::new(Self::UNLOCKED_VALUE),
+    };
+
+    fn lock(&self) {
         unsafe {
             asm!(
                 "
@@ -48,22 +135,358 @@ impl Spinlock {
         }
     }
 
+    fn lock_with<F: FnMut()>(&self, mut spin: F) {
+        while !self.try_lock() {
+            spin();
+            Cpu::spin_loop_hint();
+        }
+    }
+
     #[inline]
-    pub unsafe fn force_unlock(&self) {
+    fn try_lock(&self) -> bool {
+        let result: u32;
+        unsafe {
+            asm!("
+            ldaxr {0:w}, [{1}]
+            cbnz {0:w}, 1f
+            stxr {0:w}, {2:w}, [{1}]
+            1:
+            ", out(reg)result, in(reg)&self.value, in(reg)1);
+        }
+        result == 0
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
         self.value.store(Self::UNLOCKED_VALUE, Ordering::Release);
     }
+}
+
+/// Ticket lock, which is taken in the order of arrival
+pub struct TicketLock {
+    next: AtomicU32,
+    owner: AtomicU32,
+}
+
+impl RawSpinlock for TicketLock {
+    const INIT: Self = Self {
+        next: AtomicU32::new(0),
+        owner: AtomicU32::new(0),
+    };
+
+    fn lock(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        wait_for_u32(&self.owner, ticket);
+    }
+
+    fn lock_with<F: FnMut()>(&self, spin: F) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        spin_for_u32(&self.owner, ticket, spin);
+    }
 
     #[inline]
-    pub fn synchronized<F, R>(&self, f: F) -> R
-    where
-        F: FnOnce() -> R,
-    {
-        self.lock();
-        let result = f();
-        unsafe {
-            self.force_unlock();
+    fn try_lock(&self) -> bool {
+        let owner = self.owner.load(Ordering::Acquire);
+        self.next
+            .compare_exchange(
+                owner,
+                owner.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    #[inline]
+    unsafe fn unlock(&self) {
+        self.owner.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Number of MCS locks a core can hold or wait for at once
+const MCS_NODES: usize = 8;
+
+crate::percpu! {
+    static MCS_NODE_POOL: McsNodePool = McsNodePool::new();
+}
+
+/// A waiter of an [McsLock]
+#[repr(align(64))]
+struct McsNode {
+    next: AtomicUsize,
+    /// Zero once the previous waiter hands the lock over
+    waiting: AtomicU32,
+}
+
+struct McsNodePool {
+    used: AtomicUsize,
+    nodes: [McsNode; MCS_NODES],
+}
+
+impl McsNodePool {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NODE: McsNode = McsNode {
+        next: AtomicUsize::new(0),
+        waiting: AtomicU32::new(0),
+    };
+
+    const fn new() -> Self {
+        Self {
+            used: AtomicUsize::new(0),
+            nodes: [Self::NODE; MCS_NODES],
         }
-        result
+    }
+
+    /// Takes a free node of the current core.
+    fn alloc() -> &'static McsNode {
+        let pool = MCS_NODE_POOL.get_current();
+        for (index, node) in pool.nodes.iter().enumerate() {
+            let bit = 1 << index;
+            if (pool.used.fetch_or(bit, Ordering::Acquire) & bit) == 0 {
+                node.next.store(0, Ordering::Relaxed);
+                node.waiting.store(1, Ordering::Relaxed);
+                return node;
+            }
+        }
+        panic!("MCS lock nesting too deep");
+    }
+
+    /// Returns a node taken by [McsNodePool::alloc] on the current core.
+    fn free(node: &McsNode) {
+        let pool = MCS_NODE_POOL.get_current();
+        let index = (node as *const McsNode as usize - pool.nodes.as_ptr() as usize)
+            / core::mem::size_of::<McsNode>();
+        pool.used.fetch_and(!(1 << index), Ordering::Release);
+    }
+}
+
+/// MCS queue lock, in which each waiter spins on its own node
+///
+/// The nodes are taken from the current core, so the lock must be released on the core
+/// that acquired it.
+pub struct McsLock {
+    tail: AtomicUsize,
+    /// Node of the holder
+    holder: AtomicUsize,
+}
+
+impl RawSpinlock for McsLock {
+    const INIT: Self = Self {
+        tail: AtomicUsize::new(0),
+        holder: AtomicUsize::new(0),
+    };
+
+    fn lock(&self) {
+        let node = McsNodePool::alloc();
+        let ptr = node as *const McsNode as usize;
+        let prev = self.tail.swap(ptr, Ordering::AcqRel) as *const McsNode;
+        if let Some(prev) = unsafe { prev.as_ref() } {
+            prev.next.store(ptr, Ordering::Release);
+            wait_for_u32(&node.waiting, 0);
+        }
+        self.holder.store(ptr, Ordering::Relaxed);
+    }
+
+    fn lock_with<F: FnMut()>(&self, spin: F) {
+        let node = McsNodePool::alloc();
+        let ptr = node as *const McsNode as usize;
+        let prev = self.tail.swap(ptr, Ordering::AcqRel) as *const McsNode;
+        if let Some(prev) = unsafe { prev.as_ref() } {
+            prev.next.store(ptr, Ordering::Release);
+            spin_for_u32(&node.waiting, 0, spin);
+        }
+        self.holder.store(ptr, Ordering::Relaxed);
+    }
+
+    fn try_lock(&self) -> bool {
+        let node = McsNodePool::alloc();
+        let ptr = node as *const McsNode as usize;
+        if self
+            .tail
+            .compare_exchange(0, ptr, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            self.holder.store(ptr, Ordering::Relaxed);
+            true
+        } else {
+            McsNodePool::free(node);
+            false
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        let node = &*(self.holder.load(Ordering::Relaxed) as *const McsNode);
+        let mut next = node.next.load(Ordering::Acquire);
+        if next == 0 {
+            if self
+                .tail
+                .compare_exchange(
+                    node as *const McsNode as usize,
+                    0,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                McsNodePool::free(node);
+                return;
+            }
+            // A waiter has swapped the tail but not linked itself yet.
+            while next == 0 {
+                Cpu::spin_loop_hint();
+                next = node.next.load(Ordering::Acquire);
+            }
+        }
+        (*(next as *const McsNode))
+            .waiting
+            .store(0, Ordering::Release);
+        McsNodePool::free(node);
+    }
+}
+
+/// Reader-writer lock that stops new readers while a writer waits
+pub struct RawRwSpinlock {
+    /// [RawRwSpinlock::WRITER], the number of waiting writers in units of
+    /// [RawRwSpinlock::WRITER_WAITING], and the number of readers in units of
+    /// [RawRwSpinlock::READER]
+    state: AtomicUsize,
+}
+
+impl RawRwSpinlock {
+    const WRITER: usize = 1;
+    const WRITER_WAITING: usize = 2;
+    const WAITING_MASK: usize = 0xFFFE;
+    const READER: usize = 0x1_0000;
+
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn read_lock(&self) {
+        self.read_lock_with(|| ());
+    }
+
+    /// Acquires the lock for reading, calling `spin` while it waits.
+    pub fn read_lock_with<F: FnMut()>(&self, mut spin: F) {
+        while !self.try_read_lock() {
+            spin();
+            Cpu::spin_loop_hint();
+        }
+    }
+
+    #[inline]
+    pub fn try_read_lock(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        (state & (Self::WRITER | Self::WAITING_MASK)) == 0
+            && self
+                .state
+                .compare_exchange_weak(
+                    state,
+                    state + Self::READER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    /// # Safety
+    ///
+    /// The current core must hold the lock for reading.
+    #[inline]
+    pub unsafe fn read_unlock(&self) {
+        self.state.fetch_sub(Self::READER, Ordering::Release);
+    }
+
+    /// Acquires the lock for writing.
+    ///
+    /// The writer is counted as waiting until it gets the lock, which keeps new
+    /// readers out even while another writer holds the lock.
+    #[inline]
+    pub fn write_lock(&self) {
+        self.write_lock_with(|| ());
+    }
+
+    /// Acquires the lock for writing as [RawRwSpinlock::write_lock] does, calling `spin`
+    /// while it waits.
+    pub fn write_lock_with<F: FnMut()>(&self, mut spin: F) {
+        self.state
+            .fetch_add(Self::WRITER_WAITING, Ordering::Relaxed);
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if (state & !Self::WAITING_MASK) == 0
+                && self
+                    .state
+                    .compare_exchange_weak(
+                        state,
+                        (state - Self::WRITER_WAITING) | Self::WRITER,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            {
+                return;
+            }
+            spin();
+            Cpu::spin_loop_hint();
+        }
+    }
+
+    #[inline]
+    pub fn try_write_lock(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        (state & !Self::WAITING_MASK) == 0
+            && self
+                .state
+                .compare_exchange(
+                    state,
+                    state | Self::WRITER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    /// # Safety
+    ///
+    /// The current core must hold the lock for writing.
+    #[inline]
+    pub unsafe fn write_unlock(&self) {
+        self.state.fetch_and(!Self::WRITER, Ordering::Release);
+    }
+}
+
+impl Default for RawRwSpinlock {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits until `value` becomes `expected`, sleeping until the value is written.
+#[inline]
+fn wait_for_u32(value: &AtomicU32, expected: u32) {
+    unsafe {
+        asm!(
+            "
+            sevl
+            1: wfe
+            ldaxr {0:w}, [{1}]
+            cmp {0:w}, {2:w}
+            b.ne 1b
+            ", out(reg)_, in(reg)value, in(reg)expected);
+    }
+}
+
+/// Waits until `value` becomes `expected` as [wait_for_u32] does, but polls and calls
+/// `spin` between the polls.
+fn spin_for_u32<F: FnMut()>(value: &AtomicU32, expected: u32, mut spin: F) {
+    while value.load(Ordering::Acquire) != expected {
+        spin();
+        Cpu::spin_loop_hint();
     }
 }
 
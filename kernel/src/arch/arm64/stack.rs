@@ -0,0 +1,276 @@
+//! Kernel stacks with guard pages
+//!
+//! Stacks are mapped in slots of twice their size in a region of the lower half, which
+//! every address space shares. The lower half of each slot is never mapped and guards
+//! the stack in the upper half, so bit [KernelStack::SHIFT] of `sp` is set as long as
+//! `sp` points into a stack. The exception vectors check this bit before saving anything.
+//!
+//! The boot core keeps the stack below `_start`, which is placed the same way.
+
+use super::{
+    boot::_start,
+    page::{
+        AttributeIndex, PageAttributes, PhysicalAddress, Shareable, TranslationTableDescriptor,
+    },
+    spin::Spinlock,
+};
+use crate::mem::MemoryManager;
+use core::{
+    alloc::Layout,
+    arch::asm,
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Owner of each slot plus one, or zero if the slot is free
+static OWNERS: [AtomicUsize; KernelStack::SLOTS] = [KernelStack::FREE; KernelStack::SLOTS];
+/// Level 2 table of the stack region
+static AREA_L2: AtomicUsize = AtomicUsize::new(0);
+static MAP_LOCK: Spinlock = Spinlock::new();
+
+/// A kernel stack with an unmapped guard below it
+///
+/// Each stack belongs to a kernel thread, which is reported when the stack overflows.
+/// The idle thread of each core is numbered with the logical id of the core.
+pub struct KernelStack {
+    slot: usize,
+    pa: PhysicalAddress,
+}
+
+impl KernelStack {
+    /// Bit of `sp` that is set inside a stack and clear inside its guard
+    pub const SHIFT: usize = 17;
+    pub const SIZE: usize = 1 << Self::SHIFT;
+
+    /// The last entry of the level 1 table below the user address space
+    const AREA_BASE: usize = 0x3F_C000_0000;
+    const AREA_SIZE: usize = 0x4000_0000;
+    const SLOTS: usize = Self::AREA_SIZE / (Self::SIZE * 2);
+    const PAGE_SIZE: usize = 0x1000;
+    const ENTRIES: usize = 512;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+
+    /// Sets up the table of the stack region and unmaps the guard of the boot stack.
+    ///
+    /// Called with the MMU off while the kernel identity map is built from `table_l1`
+    /// and the contiguous level 2 tables `table_l2`.
+    pub(super) unsafe fn init_early(
+        table_l1: *mut TranslationTableDescriptor,
+        table_l2: *mut TranslationTableDescriptor,
+    ) {
+        let alloc_table = || {
+            let pa = MemoryManager::early_alloc(Layout::from_size_align_unchecked(
+                Self::PAGE_SIZE,
+                Self::PAGE_SIZE,
+            ))
+            .unwrap()
+            .get();
+            pa.identity_mapped::<u8>().write_bytes(0, Self::PAGE_SIZE);
+            pa
+        };
+
+        let area_l2 = alloc_table();
+        table_l1
+            .add(Self::AREA_BASE >> 30)
+            .write_volatile(TranslationTableDescriptor::new(
+                area_l2,
+                PageAttributes::table(),
+            ));
+        AREA_L2.store(area_l2.as_usize(), Ordering::Relaxed);
+
+        // Splits the block containing the guard into pages to leave the guard unmapped.
+        let guard = Self::boot_guard();
+        let block = table_l2.add(guard.start >> 21);
+        let desc = block.read_volatile();
+        if desc.is_valid() {
+            let table_l3 = alloc_table();
+            let base = guard.start & !(Self::PAGE_SIZE * Self::ENTRIES - 1);
+            let attr = desc.attributes().as_page();
+            for index in 0..Self::ENTRIES {
+                if guard.contains(&(base + index * Self::PAGE_SIZE)) {
+                    continue;
+                }
+                table_l3
+                    .identity_mapped::<TranslationTableDescriptor>()
+                    .add(index)
+                    .write_volatile(TranslationTableDescriptor::new(
+                        desc.oa48() + index * Self::PAGE_SIZE,
+                        attr,
+                    ));
+            }
+            block.write_volatile(TranslationTableDescriptor::new(
+                table_l3,
+                PageAttributes::table(),
+            ));
+        }
+    }
+
+    /// Allocates a stack for the kernel thread `thread`.
+    pub fn new(thread: usize) -> Option<Self> {
+        let slot = OWNERS.iter().position(|owner| {
+            owner
+                .compare_exchange(0, thread + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        let pa = match unsafe { MemoryManager::alloc_pages(Self::SIZE) } {
+            Some(v) => v.get(),
+            None => {
+                OWNERS[slot].store(0, Ordering::Release);
+                return None;
+            }
+        };
+        let stack = Self { slot, pa };
+
+        let attr = PageAttributes::block(Some(Shareable::Inner), AttributeIndex::Normal).as_page();
+        MAP_LOCK.synchronized(|| {
+            for offset in (0..Self::SIZE).step_by(Self::PAGE_SIZE) {
+                let entry = Self::descriptor(stack.bottom() + offset, true)?;
+                unsafe {
+                    entry.write_volatile(TranslationTableDescriptor::new(stack.pa + offset, attr));
+                }
+            }
+            unsafe {
+                asm!(
+                    "
+                dsb ishst
+                isb
+                "
+                );
+            }
+            Some(())
+        })?;
+
+        Some(stack)
+    }
+
+    /// Returns the initial value of `sp`.
+    #[inline]
+    pub const fn top(&self) -> usize {
+        Self::AREA_BASE + (self.slot + 1) * Self::SIZE * 2
+    }
+
+    #[inline]
+    pub const fn bottom(&self) -> usize {
+        self.top() - Self::SIZE
+    }
+
+    /// Returns whether `va` is in a stack in use.
+    pub fn contains(va: usize) -> bool {
+        if (Self::AREA_BASE..Self::AREA_BASE + Self::AREA_SIZE).contains(&va) {
+            let offset = va - Self::AREA_BASE;
+            (offset & Self::SIZE) != 0
+                && OWNERS[offset / (Self::SIZE * 2)].load(Ordering::Acquire) != 0
+        } else {
+            let top = _start as *const () as usize;
+            (top - Self::SIZE..top).contains(&va)
+        }
+    }
+
+    /// Returns the kernel threads that own a stack, with the range of the stack.
+    pub fn owners() -> impl Iterator<Item = (usize, Range<usize>)> {
+        let top = _start as *const () as usize;
+        let boot = (0, top - Self::SIZE..top);
+        let slots = OWNERS.iter().enumerate().filter_map(|(slot, owner)| {
+            let owner = owner.load(Ordering::Acquire).checked_sub(1)?;
+            let top = Self::AREA_BASE + (slot + 1) * Self::SIZE * 2;
+            Some((owner, top - Self::SIZE..top))
+        });
+        core::iter::once(boot).chain(slots)
+    }
+
+    /// Returns the thread whose guard contains `va`.
+    pub fn guard_owner(va: usize) -> Option<usize> {
+        if (Self::AREA_BASE..Self::AREA_BASE + Self::AREA_SIZE).contains(&va) {
+            let offset = va - Self::AREA_BASE;
+            if (offset & Self::SIZE) != 0 {
+                return None;
+            }
+            match OWNERS[offset / (Self::SIZE * 2)].load(Ordering::Acquire) {
+                0 => None,
+                owner => Some(owner - 1),
+            }
+        } else {
+            // The idle thread of the boot core
+            Self::boot_guard().contains(&va).then_some(0)
+        }
+    }
+
+    /// The boot core starts with `sp` at `_start`, whose address is a multiple of twice
+    /// the stack size.
+    #[inline]
+    fn boot_guard() -> Range<usize> {
+        let top = _start as *const () as usize;
+        top - Self::SIZE * 2..top - Self::SIZE
+    }
+
+    /// Returns the level 3 descriptor of `va`, allocating the table if `create`.
+    fn descriptor(va: usize, create: bool) -> Option<*mut TranslationTableDescriptor> {
+        let area_l2 = PhysicalAddress::from_usize(AREA_L2.load(Ordering::Relaxed));
+        if area_l2 == PhysicalAddress::NULL {
+            return None;
+        }
+        unsafe {
+            let entry = area_l2
+                .direct_mapped::<TranslationTableDescriptor>()
+                .add((va >> 21) & (Self::ENTRIES - 1));
+            if !(*entry).is_valid() {
+                if !create {
+                    return None;
+                }
+                let table = MemoryManager::alloc_pages(Self::PAGE_SIZE)?.get();
+                entry.write_volatile(TranslationTableDescriptor::new(
+                    table,
+                    PageAttributes::table(),
+                ));
+            }
+            Some(
+                (*entry)
+                    .oa48()
+                    .direct_mapped::<TranslationTableDescriptor>()
+                    .add((va >> 12) & (Self::ENTRIES - 1)),
+            )
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        MAP_LOCK.synchronized(|| {
+            for va in (self.bottom()..self.top()).step_by(Self::PAGE_SIZE) {
+                let Some(entry) = Self::descriptor(va, false) else {
+                    continue;
+                };
+                unsafe {
+                    if !(*entry).is_valid() {
+                        continue;
+                    }
+                    entry.write_volatile(TranslationTableDescriptor::empty());
+                    asm!(
+                        "
+                        dsb ishst
+                        tlbi vaae1is, {}
+                        ",
+                        in(reg) va >> 12
+                    );
+                }
+            }
+            unsafe {
+                asm!(
+                    "
+                    dsb ish
+                    isb
+                    "
+                );
+            }
+        });
+        unsafe {
+            MemoryManager::pg_dealloc(
+                self.pa,
+                Layout::from_size_align_unchecked(Self::SIZE, Self::PAGE_SIZE),
+            );
+        }
+        OWNERS[self.slot].store(0, Ordering::Release);
+    }
+}
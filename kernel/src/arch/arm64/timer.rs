@@ -0,0 +1,122 @@
+//! ARM Generic Timer
+
+use super::{
+    gic::{Gic, GicVersion, Irq},
+    raspi::local::LocalIntc,
+};
+use core::{arch::asm, time::Duration};
+
+/// Virtual timer of the ARM Generic Timer
+pub struct GenericTimer;
+
+impl GenericTimer {
+    /// PPI of the EL1 virtual timer
+    pub const VIRTUAL_TIMER_IRQ: Irq = Irq::ppi(11);
+
+    const CTL_ENABLE: usize = 1 << 0;
+    const CTL_IMASK: usize = 1 << 1;
+    const CTL_ISTATUS: usize = 1 << 2;
+
+    const KCTL_EVNTEN: usize = 1 << 2;
+    const KCTL_EVNT_MASK: usize = 0xFC;
+
+    /// Returns the frequency of the system counter in Hz.
+    #[inline]
+    pub fn frequency() -> u64 {
+        let result: u64;
+        unsafe {
+            asm!("mrs {}, cntfrq_el0", out(reg) result);
+        }
+        result
+    }
+
+    /// Returns the current value of the virtual counter.
+    #[inline]
+    pub fn counter() -> u64 {
+        let result: u64;
+        unsafe {
+            asm!("
+            isb
+            mrs {}, cntvct_el0
+            ", out(reg) result);
+        }
+        result
+    }
+
+    #[inline]
+    pub fn monotonic() -> Duration {
+        let freq = Self::frequency().max(1);
+        let count = Self::counter();
+        Duration::new(count / freq, ((count % freq) * 1_000_000_000 / freq) as u32)
+    }
+
+    /// Enables the event stream of the current core, which wakes
+    /// [Cpu::wait_for_event](super::cpu::Cpu::wait_for_event) about every millisecond.
+    pub fn enable_event_stream() {
+        // An event is generated on every rising edge of the selected counter bit.
+        let bit = ((Self::frequency() / 1000).max(2).ilog2() - 1).min(15) as usize;
+        unsafe {
+            let mut kctl: usize;
+            asm!("mrs {}, cntkctl_el1", out(reg) kctl);
+            kctl = (kctl & !Self::KCTL_EVNT_MASK) | Self::KCTL_EVNTEN | (bit << 4);
+            asm!("
+            msr cntkctl_el1, {}
+            isb
+            ", in(reg) kctl);
+        }
+    }
+
+    /// Arms the timer to fire after `duration`.
+    pub fn set_timeout(duration: Duration) {
+        let ticks = (duration.as_nanos() * Self::frequency() as u128 / 1_000_000_000)
+            .min(i32::MAX as u128) as usize;
+        unsafe {
+            asm!("
+            msr cntv_tval_el0, {}
+            msr cntv_ctl_el0, {}
+            isb
+            ", in(reg) ticks, in(reg) Self::CTL_ENABLE);
+        }
+    }
+
+    /// Enables or disables the interrupt of the timer on the current core.
+    ///
+    /// The interrupt handler disarms the timer when it fires.
+    pub unsafe fn set_irq_enabled(enabled: bool) {
+        match Gic::version() {
+            GicVersion::None if LocalIntc::is_available() => LocalIntc::set_timer_irq(enabled),
+            GicVersion::None => (),
+            _ if enabled => Gic::enable_irq(Self::VIRTUAL_TIMER_IRQ),
+            _ => Gic::disable_irq(Self::VIRTUAL_TIMER_IRQ),
+        }
+    }
+
+    /// Disarms the timer.
+    pub fn stop() {
+        unsafe {
+            asm!("
+            msr cntv_ctl_el0, {}
+            isb
+            ", in(reg) Self::CTL_IMASK);
+        }
+    }
+
+    /// Returns whether the timer condition has been met.
+    #[inline]
+    pub fn is_expired() -> bool {
+        let ctl: usize;
+        unsafe {
+            asm!("mrs {}, cntv_ctl_el0", out(reg) ctl);
+        }
+        (ctl & Self::CTL_ISTATUS) != 0
+    }
+
+    /// Busy-waits for `duration`.
+    pub fn wait(duration: Duration) {
+        let ticks = (duration.as_nanos() * Self::frequency() as u128 / 1_000_000_000) as u64;
+        let start = Self::counter();
+        while Self::counter().wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+}
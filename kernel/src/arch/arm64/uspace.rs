@@ -0,0 +1,551 @@
+//! User address spaces
+//!
+//! The kernel identity map occupies the lower half of `TTBR0_EL1` and is shared by
+//! every address space as global EL1-only mappings. The upper half is private to
+//! each address space, mapped with 4KB non-global pages tagged with its ASID.
+//!
+//! User pages are reference counted by [MemoryManager::page], so that a forked
+//! address space shares them copy-on-write until the first write.
+
+use super::page::{PageAttributes, PageManager, PhysicalAddress, TranslationTableDescriptor};
+use crate::mem::{MProtect, MemoryManager};
+use core::{
+    alloc::Layout,
+    arch::asm,
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    OutOfMemory,
+    /// All address space identifiers are in use
+    NoAsid,
+    InvalidAddress,
+    AlreadyMapped,
+    NotMapped,
+}
+
+pub struct AddressSpace {
+    asid: u16,
+    l1: PhysicalAddress,
+    mapped_pages: usize,
+    table_pages: usize,
+}
+
+impl AddressSpace {
+    pub const PAGE_SIZE: usize = 0x1000;
+    pub const USER_BASE: usize = 0x40_0000_0000;
+    pub const USER_END: usize = 0x80_0000_0000;
+
+    const ENTRIES: usize = 512;
+    const USER_L1_START: usize = Self::USER_BASE >> 30;
+
+    /// Creates an empty address space.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let asid = Asid::alloc().ok_or(AddressSpaceError::NoAsid)?;
+        let l1 = match Self::alloc_table() {
+            Some(v) => v,
+            None => {
+                Asid::free(asid);
+                return Err(AddressSpaceError::OutOfMemory);
+            }
+        };
+        unsafe {
+            let kernel_l1 = PageManager::kernel_table()
+                .direct_mapped::<TranslationTableDescriptor>()
+                as *const _;
+            l1.direct_mapped::<TranslationTableDescriptor>()
+                .copy_from_nonoverlapping(kernel_l1, Self::USER_L1_START);
+        }
+        Ok(Self {
+            asid,
+            l1,
+            mapped_pages: 0,
+            table_pages: 1,
+        })
+    }
+
+    #[inline]
+    pub const fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Returns the number of pages mapped to the user.
+    #[inline]
+    pub const fn mapped_pages(&self) -> usize {
+        self.mapped_pages
+    }
+
+    /// Returns the number of pages used by the translation tables.
+    #[inline]
+    pub const fn table_pages(&self) -> usize {
+        self.table_pages
+    }
+
+    #[inline]
+    pub const fn is_user_range(va: usize, len: usize) -> bool {
+        va >= Self::USER_BASE
+            && len <= Self::USER_END - Self::USER_BASE
+            && va <= Self::USER_END - len
+    }
+
+    #[inline]
+    fn alloc_table() -> Option<PhysicalAddress> {
+        unsafe { MemoryManager::alloc_pages(Self::PAGE_SIZE).map(|v| v.get()) }
+    }
+
+    #[inline]
+    fn free_page(pa: PhysicalAddress) {
+        unsafe {
+            MemoryManager::pg_dealloc(
+                pa,
+                Layout::from_size_align_unchecked(Self::PAGE_SIZE, Self::PAGE_SIZE),
+            );
+        }
+    }
+
+    #[inline]
+    const fn index(va: usize, level: usize) -> usize {
+        (va >> (39 - level * 9)) & (Self::ENTRIES - 1)
+    }
+
+    /// Returns the last level descriptor of `va`, allocating the tables on the way if `create`.
+    fn descriptor(&mut self, va: usize, create: bool) -> Option<*mut TranslationTableDescriptor> {
+        let mut table = self.l1;
+        for level in 1..3 {
+            let entry = unsafe {
+                table
+                    .direct_mapped::<TranslationTableDescriptor>()
+                    .add(Self::index(va, level))
+            };
+            let desc = unsafe { &*entry };
+            table = if desc.is_valid() {
+                desc.oa48()
+            } else if create {
+                let next = Self::alloc_table()?;
+                self.table_pages += 1;
+                unsafe {
+                    entry.write_volatile(TranslationTableDescriptor::new(
+                        next,
+                        PageAttributes::table(),
+                    ));
+                }
+                next
+            } else {
+                return None;
+            };
+        }
+        Some(unsafe {
+            table
+                .direct_mapped::<TranslationTableDescriptor>()
+                .add(Self::index(va, 3))
+        })
+    }
+
+    /// Maps the page `pa` to `va`. The address space holds a reference to the page afterwards.
+    pub fn map_page(
+        &mut self,
+        va: usize,
+        pa: PhysicalAddress,
+        prot: MProtect,
+    ) -> Result<(), AddressSpaceError> {
+        if (va & (Self::PAGE_SIZE - 1)) != 0 || !Self::is_user_range(va, Self::PAGE_SIZE) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let entry = self
+            .descriptor(va, true)
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+        unsafe {
+            if (*entry).is_valid() {
+                return Err(AddressSpaceError::AlreadyMapped);
+            }
+            entry.write_volatile(TranslationTableDescriptor::new(
+                pa,
+                PageAttributes::user_page(prot),
+            ));
+            asm!("dsb ishst");
+        }
+        if let Some(page) = MemoryManager::page(pa) {
+            page.retain();
+        }
+        self.mapped_pages += 1;
+        Ok(())
+    }
+
+    /// Unmaps the page at `va`, and frees it if this was the last reference.
+    pub fn unmap_page(&mut self, va: usize) -> Result<(), AddressSpaceError> {
+        if !Self::is_user_range(va, Self::PAGE_SIZE) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let entry = self
+            .descriptor(va, false)
+            .ok_or(AddressSpaceError::NotMapped)?;
+        let pa = unsafe {
+            if !(*entry).is_valid() {
+                return Err(AddressSpaceError::NotMapped);
+            }
+            let pa = (*entry).oa48();
+            entry.write_volatile(TranslationTableDescriptor::empty());
+            self.invalidate_page(va);
+            pa
+        };
+        Self::release_page(pa);
+        self.mapped_pages -= 1;
+        Ok(())
+    }
+
+    /// Changes the protection of the page at `va`.
+    pub fn protect_page(&mut self, va: usize, prot: MProtect) -> Result<(), AddressSpaceError> {
+        if !Self::is_user_range(va, Self::PAGE_SIZE) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let entry = self
+            .descriptor(va, false)
+            .ok_or(AddressSpaceError::NotMapped)?;
+        unsafe {
+            if !(*entry).is_valid() {
+                return Err(AddressSpaceError::NotMapped);
+            }
+            let pa = (*entry).oa48();
+            let mut attr = PageAttributes::user_page(prot);
+            if MemoryManager::page(pa).map_or(false, |page| page.ref_count() > 1) {
+                attr = attr.copy_on_write();
+            }
+            entry.write_volatile(TranslationTableDescriptor::new(pa, attr));
+            self.invalidate_page(va);
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of this address space, sharing the pages copy-on-write.
+    pub fn fork(&mut self) -> Result<Self, AddressSpaceError> {
+        let mut child = Self::new()?;
+        let mut result = Ok(());
+        Self::walk(
+            self.l1,
+            1,
+            Self::USER_L1_START..Self::ENTRIES,
+            0,
+            &mut |va, entry| {
+                if result.is_err() {
+                    return;
+                }
+                let desc = unsafe { &*entry };
+                let pa = desc.oa48();
+                let page = match MemoryManager::page(pa) {
+                    Some(v) => v,
+                    None => {
+                        result = Err(AddressSpaceError::InvalidAddress);
+                        return;
+                    }
+                };
+                let child_entry = match child.descriptor(va, true) {
+                    Some(v) => v,
+                    None => {
+                        result = Err(AddressSpaceError::OutOfMemory);
+                        return;
+                    }
+                };
+                let desc = TranslationTableDescriptor::new(pa, desc.attributes().copy_on_write());
+                let child_desc = TranslationTableDescriptor::new(pa, desc.attributes());
+                unsafe {
+                    entry.write_volatile(desc);
+                    child_entry.write_volatile(child_desc);
+                }
+                page.retain();
+                child.mapped_pages += 1;
+            },
+        );
+        self.invalidate_all();
+        result.map(|_| child)
+    }
+
+    /// Resolves a write to the copy-on-write page at `va`.
+    ///
+    /// Returns `false` if the page is not copy-on-write.
+    pub fn copy_on_write(&mut self, va: usize) -> Result<bool, AddressSpaceError> {
+        let va = va & !(Self::PAGE_SIZE - 1);
+        if !Self::is_user_range(va, Self::PAGE_SIZE) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let entry = self
+            .descriptor(va, false)
+            .ok_or(AddressSpaceError::NotMapped)?;
+        let desc = unsafe { &*entry };
+        if !desc.is_valid() {
+            return Err(AddressSpaceError::NotMapped);
+        }
+        let attr = desc.attributes();
+        if !attr.is_copy_on_write() {
+            return Ok(false);
+        }
+        let pa = desc.oa48();
+        let shared = MemoryManager::page(pa).map_or(false, |page| page.ref_count() > 1);
+        let new_pa = if shared {
+            let new_pa = Self::alloc_table().ok_or(AddressSpaceError::OutOfMemory)?;
+            unsafe {
+                let dest = new_pa.direct_mapped::<u8>();
+                dest.copy_from_nonoverlapping(pa.direct_mapped::<u8>(), Self::PAGE_SIZE);
+                clean_dcache_pou(dest as usize, Self::PAGE_SIZE);
+            }
+            if let Some(page) = MemoryManager::page(new_pa) {
+                page.retain();
+            }
+            new_pa
+        } else {
+            pa
+        };
+        unsafe {
+            entry.write_volatile(TranslationTableDescriptor::new(new_pa, attr.writable()));
+            self.invalidate_page(va);
+        }
+        if shared {
+            Self::release_page(pa);
+            self.sync_icache();
+        }
+        Ok(true)
+    }
+
+    /// Returns the physical address that `va` is mapped to.
+    pub fn translate(&self, va: usize) -> Option<PhysicalAddress> {
+        if !Self::is_user_range(va, 1) {
+            return None;
+        }
+        let mut table = self.l1;
+        for level in 1..4 {
+            let desc = unsafe {
+                &*table
+                    .direct_mapped::<TranslationTableDescriptor>()
+                    .add(Self::index(va, level))
+            };
+            if !desc.is_valid() {
+                return None;
+            }
+            table = desc.oa48();
+        }
+        Some(table + (va & (Self::PAGE_SIZE - 1)))
+    }
+
+    /// Copies `data` to `va` through the kernel mapping of the pages.
+    ///
+    /// The data cache is cleaned so that the data can be executed after [AddressSpace::sync_icache].
+    pub fn write(&self, va: usize, data: &[u8]) -> Result<(), AddressSpaceError> {
+        if !Self::is_user_range(va, data.len()) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let mut offset = 0;
+        while offset < data.len() {
+            let va = va + offset;
+            let len = (Self::PAGE_SIZE - (va & (Self::PAGE_SIZE - 1))).min(data.len() - offset);
+            let pa = self.translate(va).ok_or(AddressSpaceError::NotMapped)?;
+            unsafe {
+                let dest = pa.direct_mapped::<u8>();
+                dest.copy_from_nonoverlapping(data[offset..].as_ptr(), len);
+                clean_dcache_pou(dest as usize, len);
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Copies the data at `va` to `buf` through the kernel mapping of the pages.
+    pub fn read(&self, va: usize, buf: &mut [u8]) -> Result<(), AddressSpaceError> {
+        if !Self::is_user_range(va, buf.len()) {
+            return Err(AddressSpaceError::InvalidAddress);
+        }
+        let mut offset = 0;
+        while offset < buf.len() {
+            let va = va + offset;
+            let len = (Self::PAGE_SIZE - (va & (Self::PAGE_SIZE - 1))).min(buf.len() - offset);
+            let pa = self.translate(va).ok_or(AddressSpaceError::NotMapped)?;
+            unsafe {
+                buf[offset..]
+                    .as_mut_ptr()
+                    .copy_from_nonoverlapping(pa.direct_mapped::<u8>(), len);
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Makes the instruction caches coherent with the data written by [AddressSpace::write].
+    #[inline]
+    pub fn sync_icache(&self) {
+        unsafe {
+            asm!(
+                "
+                dsb ish
+                ic ialluis
+                dsb ish
+                isb
+                "
+            );
+        }
+    }
+
+    /// Switches `TTBR0_EL1` of the current core to this address space.
+    #[inline]
+    pub unsafe fn activate(&self) {
+        asm!(
+            "
+            msr ttbr0_el1, {}
+            isb
+            ",
+            in(reg) self.l1.as_u64() | ((self.asid as u64) << 48)
+        );
+    }
+
+    /// Switches `TTBR0_EL1` of the current core back to the kernel.
+    #[inline]
+    pub unsafe fn deactivate() {
+        asm!(
+            "
+            msr ttbr0_el1, {}
+            isb
+            ",
+            in(reg) PageManager::kernel_table().as_u64()
+        );
+    }
+
+    #[inline]
+    fn release_page(pa: PhysicalAddress) {
+        if MemoryManager::page(pa).map_or(true, |page| page.release()) {
+            Self::free_page(pa);
+        }
+    }
+
+    #[inline]
+    fn invalidate_all(&self) {
+        unsafe {
+            asm!(
+                "
+                dsb ishst
+                tlbi aside1is, {}
+                dsb ish
+                isb
+                ",
+                in(reg) (self.asid as u64) << 48
+            );
+        }
+    }
+
+    #[inline]
+    unsafe fn invalidate_page(&self, va: usize) {
+        let arg = ((self.asid as u64) << 48) | ((va as u64 >> 12) & 0x0FFF_FFFF_FFFF);
+        asm!(
+            "
+            dsb ishst
+            tlbi vae1is, {}
+            dsb ish
+            isb
+            ",
+            in(reg) arg
+        );
+    }
+
+    /// Calls `f` with every valid last level descriptor in `range` of `table` at `level`.
+    fn walk(
+        table: PhysicalAddress,
+        level: usize,
+        range: Range<usize>,
+        base: usize,
+        f: &mut dyn FnMut(usize, *mut TranslationTableDescriptor),
+    ) {
+        for index in range {
+            let entry = unsafe {
+                table
+                    .direct_mapped::<TranslationTableDescriptor>()
+                    .add(index)
+            };
+            let desc = unsafe { &*entry };
+            if !desc.is_valid() {
+                continue;
+            }
+            let va = base | (index << (39 - level * 9));
+            if level < 3 {
+                Self::walk(desc.oa48(), level + 1, 0..Self::ENTRIES, va, f);
+            } else {
+                f(va, entry);
+            }
+        }
+    }
+
+    /// Frees the tables of the user half of `table` at `level`.
+    fn free_table(table: PhysicalAddress, level: usize, range: Range<usize>) {
+        for index in range {
+            let desc = unsafe {
+                &*table
+                    .direct_mapped::<TranslationTableDescriptor>()
+                    .add(index)
+            };
+            if desc.is_valid() && level < 3 {
+                Self::free_table(desc.oa48(), level + 1, 0..Self::ENTRIES);
+                Self::free_page(desc.oa48());
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        Self::walk(
+            self.l1,
+            1,
+            Self::USER_L1_START..Self::ENTRIES,
+            0,
+            &mut |_, entry| Self::release_page(unsafe { &*entry }.oa48()),
+        );
+        Self::free_table(self.l1, 1, Self::USER_L1_START..Self::ENTRIES);
+        Self::free_page(self.l1);
+        self.invalidate_all();
+        Asid::free(self.asid);
+    }
+}
+
+/// 8bit address space identifiers; zero is used by the kernel.
+struct Asid;
+
+static ASID_BITMAP: [AtomicU64; 4] = [
+    AtomicU64::new(1),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+impl Asid {
+    fn alloc() -> Option<u16> {
+        for (index, word) in ASID_BITMAP.iter().enumerate() {
+            let mut current = word.load(Ordering::Relaxed);
+            while current != u64::MAX {
+                let bit = (!current).trailing_zeros();
+                match word.compare_exchange_weak(
+                    current,
+                    current | (1 << bit),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some((index * 64) as u16 + bit as u16),
+                    Err(v) => current = v,
+                }
+            }
+        }
+        None
+    }
+
+    fn free(asid: u16) {
+        ASID_BITMAP[asid as usize / 64].fetch_and(!(1 << (asid % 64)), Ordering::AcqRel);
+    }
+}
+
+/// Cleans the data cache of `start..start+len` to the point of unification.
+unsafe fn clean_dcache_pou(start: usize, len: usize) {
+    let ctr_el0: usize;
+    asm!("mrs {}, ctr_el0", out(reg) ctr_el0);
+    let line_size = 4 << ((ctr_el0 >> 16) & 0xF);
+    let mut addr = start & !(line_size - 1);
+    while addr < start + len {
+        asm!("dc cvau, {}", in(reg) addr);
+        addr += line_size;
+    }
+}
@@ -0,0 +1,166 @@
+//! QEMU Firmware Configuration (fw_cfg) and `ramfb`
+
+use crate::mem::PhysicalAddress;
+use core::{
+    mem::size_of,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+static FW_CFG_BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub struct FwCfg;
+
+impl FwCfg {
+    const REG_DATA: usize = 0x00;
+    const REG_SELECTOR: usize = 0x08;
+    const REG_DMA: usize = 0x10;
+
+    const SEL_SIGNATURE: u16 = 0x0000;
+    const SEL_ID: u16 = 0x0001;
+    const SEL_FILE_DIR: u16 = 0x0019;
+
+    const SIGNATURE: [u8; 4] = *b"QEMU";
+    const ID_DMA: u32 = 1 << 1;
+
+    const DMA_ERROR: u32 = 0x01;
+    const DMA_SELECT: u32 = 0x08;
+    const DMA_WRITE: u32 = 0x10;
+
+    /// Probes the fw_cfg device at `base`, which must support DMA.
+    pub unsafe fn init(base: usize) -> Result<(), ()> {
+        if base == 0 {
+            return Err(());
+        }
+        FW_CFG_BASE.store(base, Ordering::Relaxed);
+
+        let mut signature = [0u8; 4];
+        Self::select(Self::SEL_SIGNATURE);
+        Self::read_data(&mut signature);
+        let mut id = [0u8; 4];
+        Self::select(Self::SEL_ID);
+        Self::read_data(&mut id);
+
+        if signature == Self::SIGNATURE && (u32::from_le_bytes(id) & Self::ID_DMA) != 0 {
+            Ok(())
+        } else {
+            FW_CFG_BASE.store(0, Ordering::Relaxed);
+            Err(())
+        }
+    }
+
+    #[inline]
+    fn base() -> usize {
+        FW_CFG_BASE.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    unsafe fn select(selector: u16) {
+        ((Self::base() + Self::REG_SELECTOR) as *mut u16).write_volatile(selector.to_be());
+    }
+
+    #[inline]
+    unsafe fn read_data(buf: &mut [u8]) {
+        let p = (Self::base() + Self::REG_DATA) as *const u8;
+        for byte in buf.iter_mut() {
+            *byte = p.read_volatile();
+        }
+    }
+
+    /// Returns the selector and size of the file `name`.
+    pub fn find_file(name: &str) -> Option<(u16, usize)> {
+        if Self::base() == 0 {
+            return None;
+        }
+        unsafe {
+            Self::select(Self::SEL_FILE_DIR);
+            let mut count = [0u8; 4];
+            Self::read_data(&mut count);
+            for _ in 0..u32::from_be_bytes(count) {
+                let mut entry = [0u8; 64];
+                Self::read_data(&mut entry);
+                let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                let selector = u16::from_be_bytes([entry[4], entry[5]]);
+                let file_name = &entry[8..];
+                let len = file_name
+                    .iter()
+                    .position(|v| *v == 0)
+                    .unwrap_or(file_name.len());
+                if &file_name[..len] == name.as_bytes() {
+                    return Some((selector, size as usize));
+                }
+            }
+        }
+        None
+    }
+
+    unsafe fn dma(control: u32, buf: *mut u8, len: usize) -> Result<(), ()> {
+        let access = FwCfgDmaAccess {
+            control: control.to_be(),
+            length: (len as u32).to_be(),
+            address: (buf as u64).to_be(),
+        };
+        let p_access = &access as *const _ as u64;
+        fence(Ordering::SeqCst);
+        ((Self::base() + Self::REG_DMA) as *mut u64).write_volatile(p_access.to_be());
+        loop {
+            let control = u32::from_be((&access.control as *const u32).read_volatile());
+            if (control & Self::DMA_ERROR) != 0 {
+                return Err(());
+            }
+            if (control & !Self::DMA_ERROR) == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes `data` to the file with `selector`.
+    pub unsafe fn write_file(selector: u16, data: &[u8]) -> Result<(), ()> {
+        let control = ((selector as u32) << 16) | Self::DMA_SELECT | Self::DMA_WRITE;
+        Self::dma(control, data.as_ptr() as *mut u8, data.len())
+    }
+}
+
+#[repr(C)]
+struct FwCfgDmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+/// Simple framebuffer provided by the `ramfb` device
+pub struct RamFb;
+
+impl RamFb {
+    const FILE_NAME: &'static str = "etc/ramfb";
+    /// DRM_FORMAT_XRGB8888
+    const FOURCC_XR24: u32 = 0x3432_5258;
+
+    /// Points `ramfb` at the framebuffer `fb`.
+    pub unsafe fn init(fb: PhysicalAddress, width: u32, height: u32) -> Result<(), ()> {
+        let (selector, size) = FwCfg::find_file(Self::FILE_NAME).ok_or(())?;
+        if size != size_of::<RamFbCfg>() {
+            return Err(());
+        }
+        let cfg = RamFbCfg {
+            addr: fb.as_u64().to_be(),
+            fourcc: Self::FOURCC_XR24.to_be(),
+            flags: 0,
+            width: width.to_be(),
+            height: height.to_be(),
+            stride: (width * 4).to_be(),
+        };
+        let data =
+            core::slice::from_raw_parts(&cfg as *const _ as *const u8, size_of::<RamFbCfg>());
+        FwCfg::write_file(selector, data)
+    }
+}
+
+#[repr(C, packed)]
+struct RamFbCfg {
+    addr: u64,
+    fourcc: u32,
+    flags: u32,
+    width: u32,
+    height: u32,
+    stride: u32,
+}
@@ -0,0 +1,105 @@
+use super::{gic::Gic, page::PageManager};
+use crate::{
+    fw::dt::DeviceTree,
+    mem::{self, PhysicalAddress},
+};
+use bootprot::BootInfo;
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub mod fw_cfg;
+pub mod uart;
+
+/// Address range of the on-chip peripherals (GIC, PL011, fw_cfg, virtio-mmio)
+const DEVICE_BASE: usize = 0x0800_0000;
+const DEVICE_SIZE: usize = 0x0800_0000;
+
+const PAGE_SIZE_2M: usize = 0x0020_0000;
+
+pub(super) unsafe fn init_early(info: &BootInfo) {
+    let dt = DeviceTree::parse(info.dtb as usize as *const u8).ok();
+    let dt = dt.as_ref();
+
+    let uart_base = dt
+        .and_then(|dt| dt.find_compatible("arm,pl011").next())
+        .and_then(|node| node.reg().next())
+        .map(|(base, _)| base.as_usize())
+        .unwrap_or_default();
+    let _ = uart::Pl011::init(uart_base);
+
+    let (width, height) = super::std_screen_size();
+    let use_ramfb = super::std_screen().is_none();
+    // The framebuffer gets its own 2MB blocks, since it is mapped with different attributes.
+    let fb_layout = Layout::from_size_align_unchecked(
+        (width * height * 4 + PAGE_SIZE_2M - 1) & !(PAGE_SIZE_2M - 1),
+        PAGE_SIZE_2M,
+    );
+    let fb_reserve = if use_ramfb {
+        fb_layout.size() + fb_layout.align()
+    } else {
+        0
+    };
+    super::init_early_alloc(info, mem::EARLY_ALLOC_SIZE.get() + fb_reserve);
+
+    if let Some(fw_cfg) = use_ramfb
+        .then(|| dt)
+        .flatten()
+        .and_then(|dt| dt.find_compatible("qemu,fw-cfg-mmio").next())
+        .and_then(|node| node.reg().next())
+    {
+        if fw_cfg::FwCfg::init(fw_cfg.0.as_usize()).is_ok() {
+            if let Some(fb) = mem::MemoryManager::early_alloc(fb_layout) {
+                let fb = fb.get();
+                if fw_cfg::RamFb::init(fb, width as u32, height as u32).is_ok() {
+                    VRAM_BASE.store(fb.as_usize(), Ordering::Relaxed);
+                    VRAM_SIZE.store(fb_layout.size(), Ordering::Relaxed);
+                    super::set_std_screen(fb.as_usize() as *mut _, width, height, width);
+                }
+            }
+        }
+    }
+
+    PageManager::init_early(info);
+
+    crate::info!("Starting QEMU virt...");
+
+    match dt.ok_or(()).and_then(|dt| Gic::init(dt)) {
+        Ok(version) => {
+            Gic::init_cpu();
+            crate::info!("GIC: {:?}", version);
+        }
+        Err(_) => crate::error!("GIC: not found"),
+    }
+
+    PageManager::init_mp();
+}
+
+/// Called on each secondary core after its MMU is enabled.
+pub(super) unsafe fn init_secondary(_cpuid: usize) {
+    Gic::init_cpu();
+}
+
+#[inline]
+pub(super) fn max_pa() -> PhysicalAddress {
+    PhysicalAddress::new(0x4000_0000)
+}
+
+#[inline]
+pub fn device_memlist() -> impl Iterator<Item = (PhysicalAddress, usize)> {
+    let list = [(PhysicalAddress::from_usize(DEVICE_BASE), DEVICE_SIZE)];
+    list.into_iter()
+}
+
+#[inline]
+pub fn vram_memlist() -> impl Iterator<Item = (PhysicalAddress, usize)> {
+    let list = [(
+        PhysicalAddress::from_usize(VRAM_BASE.load(Ordering::Relaxed)),
+        VRAM_SIZE.load(Ordering::Relaxed),
+    )];
+    list.into_iter()
+}
+
+static VRAM_BASE: AtomicUsize = AtomicUsize::new(0);
+static VRAM_SIZE: AtomicUsize = AtomicUsize::new(0);
@@ -0,0 +1,102 @@
+use crate::{arch::cpu::Cpu, io::uart::Uart, mem::mmio::*};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+static PL011_BASE: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(dead_code)]
+static mut PL011: Pl011 = Pl011::CR;
+
+/// PL011 UART at the address given by the device tree
+#[allow(dead_code)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy)]
+pub enum Pl011 {
+    DR = 0x00,
+    RSRECR = 0x04,
+    FR = 0x18,
+    ILPR = 0x20,
+    IBRD = 0x24,
+    FBRD = 0x28,
+    LCRH = 0x2C,
+    CR = 0x30,
+    IFLS = 0x34,
+    IMSC = 0x38,
+    RIS = 0x3C,
+    MIS = 0x40,
+    ICR = 0x44,
+    DMACR = 0x48,
+}
+
+unsafe impl Mmio32 for Pl011 {
+    #[inline]
+    fn addr(&self) -> usize {
+        PL011_BASE.load(Ordering::Relaxed) + *self as usize
+    }
+}
+
+impl Pl011 {
+    #[inline]
+    pub fn shared<'a>() -> &'a mut Self {
+        unsafe { &mut PL011 }
+    }
+
+    pub fn init(base: usize) -> Result<(), ()> {
+        if base == 0 {
+            return Err(());
+        }
+        PL011_BASE.store(base, Ordering::Relaxed);
+        unsafe {
+            // Disable UART.
+            Pl011::CR.write(0);
+
+            // Clear pending interrupts.
+            Pl011::ICR.write(0x7FF);
+
+            // Enable FIFO & 8 bit data transmission (1 stop bit, no parity).
+            Pl011::LCRH.write(0x0070);
+
+            // Mask all interrupts.
+            Pl011::IMSC.write(0x7F2);
+
+            // Enable UART, receive & transfer part of UART.
+            Pl011::CR.write(0x301);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn is_available(&self) -> bool {
+        PL011_BASE.load(Ordering::Relaxed) != 0
+    }
+}
+
+impl Uart for Pl011 {
+    #[inline]
+    fn is_output_ready(&mut self) -> bool {
+        self.is_available() && unsafe { (Pl011::FR.read() & 0x20) == 0 }
+    }
+
+    #[inline]
+    fn is_input_ready(&mut self) -> bool {
+        self.is_available() && unsafe { (Pl011::FR.read() & 0x10) == 0 }
+    }
+
+    fn write_byte(&mut self, ch: u8) {
+        if !self.is_available() {
+            return;
+        }
+        while !self.is_output_ready() {
+            Cpu::no_op();
+        }
+        unsafe {
+            Pl011::DR.write(ch as u32);
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        while !self.is_input_ready() {
+            Cpu::no_op();
+        }
+        unsafe { Pl011::DR.read() as u8 }
+    }
+}
@@ -0,0 +1,93 @@
+//! Shell commands of the device tree
+
+use crate::{
+    shell::{ShellCommand, ShellError},
+    system::System,
+};
+use core::{fmt::Write, str};
+
+/// Maximum number of cells or bytes shown for a property
+const MAX_VALUES: usize = 16;
+
+/// Returns whether the value is a list of NUL-terminated strings.
+fn is_str_list(value: &[u8]) -> bool {
+    value.last() == Some(&0)
+        && value[0] != 0
+        && value.windows(2).all(|v| v[0] != 0 || v[1] != 0)
+        && value
+            .iter()
+            .all(|&v| v == 0 || v.is_ascii_graphic() || v == b' ')
+}
+
+fn write_value(out: &mut dyn Write, value: &[u8]) -> Result<(), ShellError> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    write!(out, " =")?;
+    if is_str_list(value) {
+        for (index, s) in value[..value.len() - 1].split(|&v| v == 0).enumerate() {
+            let s = str::from_utf8(s).unwrap_or_default();
+            write!(out, "{} \"{}\"", if index > 0 { "," } else { "" }, s)?;
+        }
+    } else if value.len().is_multiple_of(4) {
+        write!(out, " <")?;
+        for (index, cell) in value.chunks_exact(4).take(MAX_VALUES).enumerate() {
+            let cell = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+            write!(out, "{}0x{:08x}", if index > 0 { " " } else { "" }, cell)?;
+        }
+        if value.len() > MAX_VALUES * 4 {
+            write!(out, " ...")?;
+        }
+        write!(out, ">")?;
+    } else {
+        write!(out, " [")?;
+        for (index, byte) in value.iter().take(MAX_VALUES).enumerate() {
+            write!(out, "{}{:02x}", if index > 0 { " " } else { "" }, byte)?;
+        }
+        if value.len() > MAX_VALUES {
+            write!(out, " ...")?;
+        }
+        write!(out, "]")?;
+    }
+    Ok(())
+}
+
+struct DtCommand;
+
+impl ShellCommand for DtCommand {
+    fn name(&self) -> &'static str {
+        "dt"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[path]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the properties and the children of a device tree node"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        let path = match args {
+            [] => "/",
+            [path] => path,
+            _ => return Err(ShellError::Usage),
+        };
+        let dt = System::device_tree().ok_or(ShellError::NotSupported)?;
+        let node = dt.find_node(path).ok_or(ShellError::InvalidArgument)?;
+
+        for (name, value) in node.props() {
+            write!(out, "  {}", name.0)?;
+            write_value(out, value)?;
+            writeln!(out)?;
+        }
+        for child in node.children() {
+            writeln!(out, "  {}/", child.name().0)?;
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static DT: DtCommand = DtCommand;
+}
@@ -1,5 +1,7 @@
 //! Device Tree
 
+mod cmd;
+
 use core::{
     ffi::c_void,
     ptr::null,
@@ -92,6 +94,18 @@ impl DeviceTree {
             .flatten()
     }
 
+    /// Returns the `bootargs` property of the `/chosen` node.
+    pub fn chosen_bootargs<'a>(&self) -> Option<&'a str> {
+        self.find_first_level_prop(NodeName::CHOSEN, PropName::BOOTARGS)
+            .and_then(|(ptr, len)| unsafe {
+                (len > 1).then(|| {
+                    let slice = slice::from_raw_parts(ptr as *const _, len - 1);
+                    core::str::from_utf8(slice).ok()
+                })
+            })
+            .flatten()
+    }
+
     pub fn memory_ranges(&self) -> Option<impl Iterator<Item = (PhysicalAddress, usize)>> {
         unsafe {
             let address_cells =
@@ -120,14 +134,328 @@ impl DeviceTree {
         }
     }
 
-    // /// Returns the `compatible` property of the root element.
-    // pub fn root_compatible<'a>(&self) -> Option<&'a str> {
-    //     self.find_root_prop(PropName::COMPATIBLE)
-    //         .and_then(|(ptr, len)| unsafe {
-    //             let slice = slice::from_raw_parts(ptr as *const _, len);
-    //             core::str::from_utf8(slice).ok()
-    //         })
-    // }
+    /// Returns the root node.
+    #[inline]
+    pub fn root(&self) -> Node<'static> {
+        Node {
+            header: self.header,
+            name: NodeName::ROOT,
+            index: 0,
+            address_cells: Node::DEFAULT_ADDRESS_CELLS,
+            size_cells: Node::DEFAULT_SIZE_CELLS,
+        }
+        .begin()
+    }
+
+    /// Returns the `compatible` property of the root element.
+    #[inline]
+    pub fn root_compatible(&self) -> StrList<'static> {
+        self.root().compatible()
+    }
+
+    /// Finds the node with the specified path, such as `/cpus/cpu@1`.
+    ///
+    /// Unit addresses may be omitted if they are unambiguous.
+    pub fn find_node(&self, path: &str) -> Option<Node<'static>> {
+        let mut node = self.root();
+        for component in path.split('/').filter(|v| !v.is_empty()) {
+            let name = NodeName(component);
+            node = node.children().find(|child| {
+                child.name() == name
+                    || (!component.contains('@') && child.name().without_unit() == name)
+            })?;
+        }
+        Some(node)
+    }
+
+    /// Returns all nodes in the tree in depth-first order.
+    #[inline]
+    pub fn nodes(&self) -> impl Iterator<Item = Node<'static>> {
+        AllNodes {
+            header: self.header,
+            index: 0,
+            level: 0,
+            cells: [(Node::DEFAULT_ADDRESS_CELLS, Node::DEFAULT_SIZE_CELLS); AllNodes::MAX_DEPTH],
+        }
+    }
+
+    /// Translates an address on the `/soc` bus into the physical address by its `ranges`.
+    ///
+    /// The address is returned as is if there is no translation.
+    pub fn soc_to_physical(&self, addr: PhysicalAddress) -> Option<PhysicalAddress> {
+        let Some(soc) = self.find_node("/soc") else {
+            return Some(addr);
+        };
+        let ranges = match soc.prop(PropName::RANGES) {
+            Some(v) if !v.is_empty() => v,
+            _ => return Some(addr),
+        };
+        let child_cells = soc.child_address_cells();
+        let parent_cells = self.root().child_address_cells();
+        let size_cells = soc.child_size_cells();
+        let slice =
+            unsafe { slice::from_raw_parts(ranges.as_ptr() as *const u32, ranges.len() / 4) };
+        let mut iter = FdtMemoryRangeIter::new(slice, child_cells, size_cells);
+        let addr = addr.as_u64();
+        loop {
+            let child = iter.fdt_get_reg_val(child_cells).ok()?;
+            let parent = iter.fdt_get_reg_val(parent_cells).ok()?;
+            let size = iter.fdt_get_reg_val(size_cells).ok()?;
+            if addr >= child && addr - child < size {
+                return Some(PhysicalAddress::new(addr - child + parent));
+            }
+        }
+    }
+
+    /// Returns all enabled nodes compatible with the specified string.
+    #[inline]
+    pub fn find_compatible<'a>(
+        &self,
+        compatible: &'a str,
+    ) -> impl Iterator<Item = Node<'static>> + 'a {
+        self.nodes()
+            .filter(move |node| node.is_compatible(compatible) && node.is_enabled())
+    }
+}
+
+/// A node of the device tree
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    header: &'a Header,
+    name: NodeName<'a>,
+    /// Index of the first token after `FDT_BEGIN_NODE`
+    index: usize,
+    /// `#address-cells` of the parent node
+    address_cells: usize,
+    /// `#size-cells` of the parent node
+    size_cells: usize,
+}
+
+impl<'a> Node<'a> {
+    pub const DEFAULT_ADDRESS_CELLS: usize = 2;
+    pub const DEFAULT_SIZE_CELLS: usize = 1;
+
+    /// Skips the `FDT_BEGIN_NODE` token of the node at `index`.
+    fn begin(mut self) -> Self {
+        let mut iter = FdtTokenIter {
+            header: self.header,
+            index: self.index,
+        };
+        if let Some(Token::BeginNode(name)) = iter.next() {
+            self.name = name;
+            self.index = iter.index;
+        }
+        self
+    }
+
+    #[inline]
+    pub const fn name(&self) -> NodeName<'a> {
+        self.name
+    }
+
+    /// Returns the properties of this node.
+    pub fn props(&self) -> impl Iterator<Item = (PropName<'a>, &'a [u8])> {
+        FdtTokenIter {
+            header: self.header,
+            index: self.index,
+        }
+        .map_while(|token| match token {
+            Token::Prop(name, ptr, len) => Some((name, unsafe {
+                slice::from_raw_parts(ptr as *const u8, len)
+            })),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of the specified property.
+    #[inline]
+    pub fn prop(&self, name: PropName) -> Option<&'a [u8]> {
+        self.props().find(|v| v.0 == name).map(|v| v.1)
+    }
+
+    /// Returns the value of the specified property as a big-endian `u32`.
+    #[inline]
+    pub fn prop_u32(&self, name: PropName) -> Option<u32> {
+        self.prop(name)
+            .and_then(|v| v.get(0..4))
+            .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+    }
+
+    /// Returns the value of the specified property as a string.
+    #[inline]
+    pub fn prop_str(&self, name: PropName) -> Option<&'a str> {
+        self.prop(name).and_then(|v| StrList(v).next())
+    }
+
+    /// Returns the `compatible` property of this node.
+    #[inline]
+    pub fn compatible(&self) -> StrList<'a> {
+        StrList(self.prop(PropName::COMPATIBLE).unwrap_or_default())
+    }
+
+    #[inline]
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|v| v == compatible)
+    }
+
+    /// Returns whether the `status` property is absent or `okay`.
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        match self.prop_str(PropName::STATUS) {
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+
+    /// Returns the address ranges in the `reg` property.
+    pub fn reg(&self) -> impl Iterator<Item = (PhysicalAddress, usize)> + 'a {
+        let reg = self.prop(PropName::REG).unwrap_or_default();
+        let slice = unsafe { slice::from_raw_parts(reg.as_ptr() as *const u32, reg.len() / 4) };
+        FdtMemoryRangeIter::new(slice, self.address_cells, self.size_cells)
+    }
+
+    /// Returns the `#address-cells` that applies to the children of this node.
+    #[inline]
+    pub fn child_address_cells(&self) -> usize {
+        self.prop_u32(PropName::ADDRESS_CELLS)
+            .map(|v| v as usize)
+            .unwrap_or(Self::DEFAULT_ADDRESS_CELLS)
+    }
+
+    /// Returns the `#size-cells` that applies to the children of this node.
+    #[inline]
+    pub fn child_size_cells(&self) -> usize {
+        self.prop_u32(PropName::SIZE_CELLS)
+            .map(|v| v as usize)
+            .unwrap_or(Self::DEFAULT_SIZE_CELLS)
+    }
+
+    /// Returns the direct children of this node.
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> {
+        NodeChildren {
+            header: self.header,
+            index: self.index,
+            address_cells: self.child_address_cells(),
+            size_cells: self.child_size_cells(),
+        }
+    }
+}
+
+/// Iterator over a `<stringlist>` property
+#[derive(Clone, Copy)]
+pub struct StrList<'a>(&'a [u8]);
+
+impl<'a> Iterator for StrList<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let len = self.0.iter().position(|v| *v == 0).unwrap_or(self.0.len());
+        let result = str::from_utf8(&self.0[..len]).ok();
+        self.0 = self.0.get(len + 1..).unwrap_or_default();
+        result
+    }
+}
+
+struct NodeChildren<'a> {
+    header: &'a Header,
+    index: usize,
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Iterator for NodeChildren<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut iter = FdtTokenIter {
+            header: self.header,
+            index: self.index,
+        };
+        loop {
+            let index = iter.index;
+            match iter.next()? {
+                Token::Prop(_, _, _) => (),
+                Token::EndNode => {
+                    self.index = index;
+                    return None;
+                }
+                Token::BeginNode(name) => {
+                    let node = Node {
+                        header: self.header,
+                        name,
+                        index: iter.index,
+                        address_cells: self.address_cells,
+                        size_cells: self.size_cells,
+                    };
+                    // skip the subtree
+                    let mut level = 1;
+                    while level > 0 {
+                        match iter.next()? {
+                            Token::BeginNode(_) => level += 1,
+                            Token::EndNode => level -= 1,
+                            Token::Prop(_, _, _) => (),
+                        }
+                    }
+                    self.index = iter.index;
+                    return Some(node);
+                }
+            }
+        }
+    }
+}
+
+struct AllNodes<'a> {
+    header: &'a Header,
+    index: usize,
+    level: usize,
+    /// `#address-cells` and `#size-cells` for the children of each level
+    cells: [(usize, usize); AllNodes::MAX_DEPTH],
+}
+
+impl AllNodes<'_> {
+    const MAX_DEPTH: usize = 16;
+}
+
+impl<'a> Iterator for AllNodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut iter = FdtTokenIter {
+            header: self.header,
+            index: self.index,
+        };
+        loop {
+            match iter.next()? {
+                Token::BeginNode(name) => {
+                    if self.level >= Self::MAX_DEPTH {
+                        return None;
+                    }
+                    let (address_cells, size_cells) = match self.level {
+                        0 => (Node::DEFAULT_ADDRESS_CELLS, Node::DEFAULT_SIZE_CELLS),
+                        level => self.cells[level - 1],
+                    };
+                    let node = Node {
+                        header: self.header,
+                        name,
+                        index: iter.index,
+                        address_cells,
+                        size_cells,
+                    };
+                    self.cells[self.level] = (node.child_address_cells(), node.child_size_cells());
+                    self.level += 1;
+                    self.index = iter.index;
+                    return Some(node);
+                }
+                Token::EndNode => {
+                    self.level = self.level.checked_sub(1)?;
+                }
+                Token::Prop(_, _, _) => (),
+            }
+        }
+    }
 }
 
 #[repr(C)]
@@ -272,16 +600,26 @@ pub struct PropName<'a>(pub &'a str);
 impl PropName<'_> {
     /// #address-cells <u32>
     pub const ADDRESS_CELLS: Self = Self("#address-cells");
+    /// bootargs <string>
+    pub const BOOTARGS: Self = Self("bootargs");
     ///
     pub const CLOCK_CELLS: Self = Self("#clock-cells");
     /// compatible <string-list>
     pub const COMPATIBLE: Self = Self("compatible");
+    /// cpu-release-addr <u64>
+    pub const CPU_RELEASE_ADDR: Self = Self("cpu-release-addr");
     /// device_type (deprecated) <string>
     pub const DEVICE_TYPE: Self = Self("device_type");
     /// dma-coherent <empty>
     pub const DMA_COHERENT: Self = Self("dma-coherent");
     /// dma-ranges <prop-encoded-array>
     pub const DMA_RANGES: Self = Self("dma-ranges");
+    /// enable-method <stringlist>
+    pub const ENABLE_METHOD: Self = Self("enable-method");
+    /// interrupts <prop-encoded-array>
+    pub const INTERRUPTS: Self = Self("interrupts");
+    /// method <string>
+    pub const METHOD: Self = Self("method");
     /// model <string>
     pub const MODEL: Self = Self("model");
     /// name (deprecated) <string>
@@ -420,6 +758,7 @@ impl<'a> FdtMemoryRangeIter<'a> {
 
     fn fdt_get_reg_val(&mut self, cell_size: usize) -> Result<u64, ()> {
         match cell_size {
+            0 => Ok(0),
             1 => Ok(self.iter.next().ok_or(())?.to_be() as u64),
             2 => {
                 let hi = self.iter.next().ok_or(())?.to_be() as u64;
@@ -435,6 +774,9 @@ impl Iterator for FdtMemoryRangeIter<'_> {
     type Item = (PhysicalAddress, usize);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.iter.len() == 0 {
+            return None;
+        }
         let base = match self.fdt_get_reg_val(self.address_cells) {
             Ok(v) => PhysicalAddress::new(v),
             Err(_) => return None,
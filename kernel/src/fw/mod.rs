@@ -1 +1,21 @@
 pub mod dt;
+
+use crate::mem::PhysicalAddress;
+use bootprot::BootInfo;
+
+/// Returns the RAM ranges from the memory map of the boot loader if available,
+/// otherwise from the device tree.
+pub fn memory_ranges(info: &BootInfo) -> impl Iterator<Item = (PhysicalAddress, usize)> + '_ {
+    let mmap = info
+        .memory_map()
+        .iter()
+        .filter(|v| v.mem_type.is_ram())
+        .map(|v| (PhysicalAddress::new(v.base), (v.end() - v.base) as usize));
+    let dt = info
+        .memory_map()
+        .is_empty()
+        .then(|| unsafe { dt::DeviceTree::parse(info.dtb as usize as *const u8) }.ok())
+        .flatten()
+        .and_then(|dt| dt.memory_ranges());
+    mmap.chain(dt.into_iter().flatten())
+}
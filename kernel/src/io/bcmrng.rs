@@ -0,0 +1,179 @@
+//! Broadcom hardware random number generators
+//!
+//! The RNG of the BCM2835 (Raspberry Pi 3) and the RNG200 of iProc and the BCM2711
+//! (Raspberry Pi 4) are found in the device tree, and feed [Random].
+
+use crate::{
+    arch::{cpu::Cpu, timer::GenericTimer},
+    fw::dt::DeviceTree,
+    mem::mmio::{Mmio32, Mmio32Reg},
+    random::{EntropySource, Random},
+    sync::spinlock::SpinMutex,
+};
+use core::time::Duration;
+
+pub static BCM_RNG: BcmRng = BcmRng::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RngKind {
+    Bcm2835,
+    Rng200,
+}
+
+pub struct BcmRng {
+    device: SpinMutex<Option<(usize, RngKind)>>,
+}
+
+impl BcmRng {
+    const BCM2835_COMPATIBLE: [&'static str; 1] = ["brcm,bcm2835-rng"];
+    const RNG200_COMPATIBLE: [&'static str; 2] = ["brcm,iproc-rng200", "brcm,bcm2711-rng200"];
+
+    // BCM2835 RNG
+    const RNG_CTRL: usize = 0x00;
+    const RNG_STATUS: usize = 0x04;
+    const RNG_DATA: usize = 0x08;
+    const RNG_INT_MASK: usize = 0x10;
+    const RNG_RBGEN: u32 = 0x0000_0001;
+    const RNG_INT_OFF: u32 = 0x0000_0001;
+    /// The initial numbers are discarded.
+    const RNG_WARMUP_COUNT: u32 = 0x0004_0000;
+
+    // RNG200
+    const RNG200_CTRL: usize = 0x00;
+    const RNG200_SOFT_RESET: usize = 0x04;
+    const RNG200_RBG_SOFT_RESET: usize = 0x08;
+    const RNG200_TOTAL_BIT_COUNT_THRESHOLD: usize = 0x10;
+    const RNG200_INT_STATUS: usize = 0x18;
+    const RNG200_FIFO_DATA: usize = 0x20;
+    const RNG200_FIFO_COUNT: usize = 0x24;
+    const RNG200_RBGEN_MASK: u32 = 0x0000_1FFF;
+    const RNG200_INT_MASTER_FAIL_LOCKUP: u32 = 0x8000_0000;
+    const RNG200_INT_NIST_FAIL: u32 = 0x0000_0020;
+    const RNG200_FIFO_COUNT_MASK: u32 = 0x0000_00FF;
+
+    const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+    const fn new() -> Self {
+        Self {
+            device: SpinMutex::new(None),
+        }
+    }
+
+    /// Starts the first RNG found in the device tree, and registers it to [Random].
+    pub unsafe fn init(dt: &DeviceTree) {
+        let found = Self::BCM2835_COMPATIBLE
+            .iter()
+            .flat_map(|v| dt.find_compatible(v))
+            .map(|v| (v, RngKind::Bcm2835))
+            .chain(
+                Self::RNG200_COMPATIBLE
+                    .iter()
+                    .flat_map(|v| dt.find_compatible(v))
+                    .map(|v| (v, RngKind::Rng200)),
+            )
+            .find_map(|(node, kind)| {
+                let (base, _) = node.reg().next()?;
+                Some((dt.soc_to_physical(base)?.as_usize(), kind))
+            });
+        let Some((base, kind)) = found else {
+            return;
+        };
+
+        let rng = &BCM_RNG;
+        *rng.device.lock() = Some((base, kind));
+        match kind {
+            RngKind::Bcm2835 => {
+                let int_mask = Mmio32Reg(base + Self::RNG_INT_MASK);
+                int_mask.write(int_mask.read() | Self::RNG_INT_OFF);
+                if (Mmio32Reg(base + Self::RNG_CTRL).read() & Self::RNG_RBGEN) == 0 {
+                    Mmio32Reg(base + Self::RNG_STATUS).write(Self::RNG_WARMUP_COUNT);
+                    Mmio32Reg(base + Self::RNG_CTRL).write(Self::RNG_RBGEN);
+                }
+            }
+            RngKind::Rng200 => {
+                Mmio32Reg(base + Self::RNG200_TOTAL_BIT_COUNT_THRESHOLD)
+                    .write(Self::RNG_WARMUP_COUNT);
+                Self::restart_rng200(base);
+            }
+        }
+        let _ = Random::add_source(rng);
+    }
+
+    #[inline]
+    pub fn is_present(&self) -> bool {
+        self.device.lock().is_some()
+    }
+
+    /// Resets the RNG200 after a failure and starts it.
+    unsafe fn restart_rng200(base: usize) {
+        let ctrl = Mmio32Reg(base + Self::RNG200_CTRL);
+        ctrl.write(ctrl.read() & !Self::RNG200_RBGEN_MASK);
+        for reset in [Self::RNG200_SOFT_RESET, Self::RNG200_RBG_SOFT_RESET] {
+            let reg = Mmio32Reg(base + reset);
+            reg.write(reg.read() | 1);
+            reg.write(reg.read() & !1);
+        }
+        Mmio32Reg(base + Self::RNG200_INT_STATUS).write(!0);
+        ctrl.write((ctrl.read() & !Self::RNG200_RBGEN_MASK) | 1);
+    }
+
+    /// Returns the number of words available.
+    unsafe fn available(base: usize, kind: RngKind) -> usize {
+        match kind {
+            RngKind::Bcm2835 => (Mmio32Reg(base + Self::RNG_STATUS).read() >> 24) as usize,
+            RngKind::Rng200 => {
+                let status = Mmio32Reg(base + Self::RNG200_INT_STATUS).read();
+                if (status & (Self::RNG200_INT_MASTER_FAIL_LOCKUP | Self::RNG200_INT_NIST_FAIL))
+                    != 0
+                {
+                    Self::restart_rng200(base);
+                    return 0;
+                }
+                (Mmio32Reg(base + Self::RNG200_FIFO_COUNT).read() & Self::RNG200_FIFO_COUNT_MASK)
+                    as usize
+            }
+        }
+    }
+}
+
+impl EntropySource for BcmRng {
+    fn name(&self) -> &'static str {
+        match *self.device.lock() {
+            Some((_, RngKind::Rng200)) => "RNG200",
+            _ => "BCM2835 RNG",
+        }
+    }
+
+    fn read_entropy(&self, buf: &mut [u8]) -> usize {
+        let Some((base, kind)) = *self.device.lock() else {
+            return 0;
+        };
+        let data = match kind {
+            RngKind::Bcm2835 => Mmio32Reg(base + Self::RNG_DATA),
+            RngKind::Rng200 => Mmio32Reg(base + Self::RNG200_FIFO_DATA),
+        };
+        let deadline = GenericTimer::counter()
+            + Self::READ_TIMEOUT.as_millis() as u64 * GenericTimer::frequency() / 1000;
+        let mut filled = 0;
+        while filled < buf.len() {
+            let available = unsafe { Self::available(base, kind) };
+            if available == 0 {
+                if GenericTimer::counter() >= deadline {
+                    break;
+                }
+                Cpu::spin_loop_hint();
+                continue;
+            }
+            for _ in 0..available {
+                let word = unsafe { data.read() }.to_le_bytes();
+                let len = (buf.len() - filled).min(4);
+                buf[filled..filled + len].copy_from_slice(&word[..len]);
+                filled += len;
+                if filled == buf.len() {
+                    break;
+                }
+            }
+        }
+        filled
+    }
+}
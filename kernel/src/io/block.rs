@@ -0,0 +1,57 @@
+//! Block devices
+//!
+//! Disks are read and written in blocks through [BlockDevice], on which partitions and
+//! filesystems can be built regardless of the driver.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks are beyond the end of the device, or the buffer is not a multiple of
+    /// the block size.
+    OutOfRange,
+    ReadOnly,
+    /// The medium is not present or not ready.
+    NotReady,
+    /// The device reported an error.
+    IoError,
+    Unsupported,
+}
+
+/// A device read and written in fixed-size blocks
+pub trait BlockDevice {
+    /// Returns the size of a block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Returns the number of blocks of this device.
+    fn num_blocks(&self) -> u64;
+
+    fn is_read_only(&self) -> bool;
+
+    /// Reads blocks starting at `lba` into `buf`, whose size must be a multiple of the
+    /// block size.
+    fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf` to blocks starting at `lba`.
+    fn write(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Flushes the write cache of the device.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// Returns the size of this device in bytes, saturating at `u64::MAX`.
+    #[inline]
+    fn size(&self) -> u64 {
+        self.num_blocks().saturating_mul(self.block_size() as u64)
+    }
+
+    /// Checks that the buffer covers whole blocks within the device.
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), BlockError> {
+        let block_size = self.block_size();
+        let count = (len / block_size) as u64;
+        if len % block_size != 0 || lba.saturating_add(count) > self.num_blocks() {
+            Err(BlockError::OutOfRange)
+        } else {
+            Ok(())
+        }
+    }
+}
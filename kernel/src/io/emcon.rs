@@ -1,43 +1,194 @@
 //! Emergency debugging console
+//!
+//! A text terminal on the main screen that understands the VT100 and ECMA-48 sequences:
+//! cursor movement, erasing, insertion and deletion, scroll regions, tab stops and SGR
+//! colors, including the 256 colors and 24-bit colors of xterm.
 
-use super::font::*;
-use crate::{drawing::*, system::System};
+use super::{
+    font::*,
+    input::cursor::POINTER,
+    tty::{TtyError, TtyWrite, ANSI_COLORS},
+};
+use crate::{arch::timer::GenericTimer, drawing::*, param::ColorParam, system::System};
 use core::fmt;
 
+crate::kernel_param! {
+    static FG_COLOR: ColorParam = ColorParam::new(
+        "emcon.fg",
+        EmConsole::DEFAULT_FG_COLOR,
+        "Foreground color of the emergency console",
+    );
+}
+
+crate::kernel_param! {
+    static BG_COLOR: ColorParam = ColorParam::new(
+        "emcon.bg",
+        EmConsole::DEFAULT_BG_COLOR,
+        "Background color of the emergency console",
+    );
+}
+
+/// Number of columns that can have a tab stop
+const MAX_TAB_COLS: usize = 256;
+
+const MAX_PARAMS: usize = 16;
+
+/// Interval of the blinking cursor in milliseconds
+const BLINK_INTERVAL: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParserState {
+    Ground,
+    Escape,
+    Csi,
+    /// Final byte of a character set designation (`ESC (` or `ESC )`)
+    Charset,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Attributes {
+    fg_color: Color,
+    bg_color: Color,
+    bold: bool,
+    underline: bool,
+    reverse: bool,
+}
+
+impl Attributes {
+    #[inline]
+    const fn new(fg_color: Color, bg_color: Color) -> Self {
+        Self {
+            fg_color,
+            bg_color,
+            bold: false,
+            underline: false,
+            reverse: false,
+        }
+    }
+
+    /// Returns the colors to draw a character with.
+    fn colors(&self) -> (Color, Color) {
+        let mut fg_color = self.fg_color;
+        if self.bold {
+            if let Color::Indexed(color) = fg_color {
+                if let Some(index) = ANSI_COLORS[..8].iter().position(|v| *v == color) {
+                    fg_color = ANSI_COLORS[index + 8].into();
+                }
+            }
+        }
+        if self.reverse {
+            (self.bg_color, fg_color)
+        } else {
+            (fg_color, self.bg_color)
+        }
+    }
+}
+
+/// The state kept by ESC 7 and CSI s
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    x: usize,
+    y: usize,
+    attr: Attributes,
+}
+
 pub struct EmConsole {
     x: usize,
     y: usize,
-    fg_color: Color,
-    bg_color: Color,
-    font: &'static FixedFontDriver<'static>,
+    /// The last column is written and the next character goes to the next line.
+    pending_wrap: bool,
+    attr: Attributes,
+    default_fg: Color,
+    default_bg: Color,
+    /// The top and bottom rows of the scroll region
+    scroll_region: Option<(usize, usize)>,
+    tab_stops: [u64; MAX_TAB_COLS / 64],
+    saved: SavedCursor,
+    state: ParserState,
+    params: [u16; MAX_PARAMS],
+    n_params: usize,
+    private: bool,
+    cursor_enabled: bool,
+    cursor_drawn: bool,
+    next_blink: u64,
+    font: &'static FontChain,
 }
 
 impl EmConsole {
     const DEFAULT_FG_COLOR: Color = Color::LIGHT_GRAY;
     const DEFAULT_BG_COLOR: Color = Color::from_rgb(0x000000);
     const PADDING: isize = 8;
+    /// A tab stop every 8 columns
+    const DEFAULT_TAB_STOPS: u64 = 0x0101_0101_0101_0101;
 
     #[inline]
-    pub const fn new(font: &'static FixedFontDriver<'static>) -> Self {
+    pub const fn new(font: &'static FontChain) -> Self {
+        let attr = Attributes::new(Self::DEFAULT_FG_COLOR, Self::DEFAULT_BG_COLOR);
         Self {
             x: 0,
             y: 0,
-            fg_color: Self::DEFAULT_FG_COLOR,
-            bg_color: Self::DEFAULT_BG_COLOR,
+            pending_wrap: false,
+            attr,
+            default_fg: Self::DEFAULT_FG_COLOR,
+            default_bg: Self::DEFAULT_BG_COLOR,
+            scroll_region: None,
+            tab_stops: [Self::DEFAULT_TAB_STOPS; MAX_TAB_COLS / 64],
+            saved: SavedCursor { x: 0, y: 0, attr },
+            state: ParserState::Ground,
+            params: [0; MAX_PARAMS],
+            n_params: 0,
+            private: false,
+            cursor_enabled: true,
+            cursor_drawn: false,
+            next_blink: 0,
             font,
         }
     }
 
-    fn dims(&self) -> (isize, isize) {
-        let bitmap = match System::main_screen() {
-            Some(v) => v,
-            None => return (0, 0),
-        };
-        let font = self.font;
-        let font_size = Size::new(font.width(), font.line_height());
-        let cols = (bitmap.width() as isize - Self::PADDING * 2) / font_size.width();
-        let rows = (bitmap.height() as isize - Self::PADDING * 2) / font_size.height();
-        (cols, rows)
+    /// Applies the colors given on the command line.
+    pub fn load_params(&mut self) {
+        self.default_fg = FG_COLOR.get();
+        self.default_bg = BG_COLOR.get();
+        self.attr = Attributes::new(self.default_fg, self.default_bg);
+        self.saved.attr = self.attr;
+    }
+
+    #[inline]
+    fn cell_size(&self) -> Size {
+        self.font.cell_size()
+    }
+
+    fn screen_dims(&self, bitmap: &Bitmap) -> (usize, usize) {
+        let cell_size = self.cell_size();
+        let cols = (bitmap.width() as isize - Self::PADDING * 2) / cell_size.width();
+        let rows = (bitmap.height() as isize - Self::PADDING * 2) / cell_size.height();
+        (cols.max(0) as usize, rows.max(0) as usize)
+    }
+
+    #[inline]
+    fn cell_origin(&self, x: usize, y: usize) -> Point {
+        let cell_size = self.cell_size();
+        Point::new(
+            x as isize * cell_size.width() + Self::PADDING,
+            y as isize * cell_size.height() + Self::PADDING,
+        )
+    }
+
+    fn cells_rect(&self, x: usize, y: usize, cols: usize, rows: usize) -> Rect {
+        let cell_size = self.cell_size();
+        Rect {
+            origin: self.cell_origin(x, y),
+            size: Size::new(
+                cols as isize * cell_size.width(),
+                rows as isize * cell_size.height(),
+            ),
+        }
+    }
+
+    /// Returns the top and bottom rows of the scroll region.
+    #[inline]
+    fn region(&self, rows: usize) -> (usize, usize) {
+        self.scroll_region.unwrap_or((0, rows - 1))
     }
 
     pub fn write_char(&mut self, c: char) {
@@ -45,120 +196,589 @@ impl EmConsole {
             Some(v) => v,
             None => return,
         };
-        let font = self.font;
-        let font_size = Size::new(font.width(), font.line_height());
         let bitmap = &mut bitmap;
+        let (cols, rows) = self.screen_dims(bitmap);
+        if cols == 0 || rows == 0 {
+            return;
+        }
+        let _pointer = POINTER.hide();
+        self.hide_cursor(bitmap);
+        self.put_char(bitmap, c);
+        self.show_cursor(bitmap);
+    }
 
-        // check bounds
-        let (cols, rows) = self.dims();
-        let cols = cols as usize;
-        let rows = rows as usize;
-        if self.x >= cols {
-            self.x = 0;
-            self.y += 1;
+    /// Blinks the cursor, which the idle loop calls periodically.
+    pub fn blink(&mut self) {
+        if !self.cursor_enabled || GenericTimer::counter() < self.next_blink {
+            return;
         }
-        if self.y >= rows {
-            self.y = rows - 1;
-            let sh = font_size.height() * self.y as isize;
-            let mut rect = bitmap.bounds();
-            rect.origin.y += font_size.height() + Self::PADDING;
-            rect.size.height = sh;
-            bitmap.blt_itself(Point::new(0, Self::PADDING), rect);
-            bitmap.fill_rect(
-                Rect::new(0, sh + Self::PADDING, rect.width(), font_size.height()),
-                self.bg_color.into(),
-            );
+        let mut bitmap = match System::main_screen() {
+            Some(v) => v,
+            None => return,
+        };
+        let _pointer = POINTER.hide();
+        self.toggle_cursor(&mut bitmap);
+    }
+
+    fn toggle_cursor(&mut self, bitmap: &mut Bitmap) {
+        let (cols, rows) = self.screen_dims(bitmap);
+        if self.x < cols && self.y < rows {
+            let rect = self.cells_rect(self.x, self.y, 1, 1);
+            for y in rect.y()..rect.y() + rect.height() {
+                for x in rect.x()..rect.x() + rect.width() {
+                    let point = Point::new(x, y);
+                    if let Some(color) = bitmap.get_pixel(point) {
+                        bitmap.set_pixel(point, Self::invert(color));
+                    }
+                }
+            }
         }
+        self.cursor_drawn = !self.cursor_drawn;
+        self.next_blink =
+            GenericTimer::counter() + BLINK_INTERVAL * GenericTimer::frequency() / 1000;
+    }
 
-        match c {
-            '\x08' => {
-                if self.x > 0 {
-                    self.x -= 1;
+    #[inline]
+    fn invert(color: Color) -> Color {
+        match color {
+            Color::Transparent => color,
+            Color::Indexed(v) => Color::Indexed(IndexedColor(v.0 ^ 0x0F)),
+            Color::Argb32(v) => Color::from_argb(v.argb() ^ 0x00FF_FFFF),
+        }
+    }
+
+    #[inline]
+    fn hide_cursor(&mut self, bitmap: &mut Bitmap) {
+        if self.cursor_drawn {
+            self.toggle_cursor(bitmap);
+        }
+    }
+
+    #[inline]
+    fn show_cursor(&mut self, bitmap: &mut Bitmap) {
+        if self.cursor_enabled && !self.cursor_drawn {
+            self.toggle_cursor(bitmap);
+        }
+    }
+
+    fn put_char(&mut self, bitmap: &mut Bitmap, c: char) {
+        match self.state {
+            ParserState::Ground => match c {
+                '\x1b' => self.state = ParserState::Escape,
+                '\x08' => {
+                    self.pending_wrap = false;
+                    self.x = self.x.saturating_sub(1);
+                }
+                '\t' => self.tab_forward(bitmap, 1),
+                '\n' | '\x0b' | '\x0c' => {
+                    self.x = 0;
+                    self.line_feed(bitmap);
                 }
+                '\r' => {
+                    self.pending_wrap = false;
+                    self.x = 0;
+                }
+                '\0'..='\x1f' | '\x7f' => (),
+                _ => self.print(bitmap, c),
+            },
+            ParserState::Escape => self.escape_dispatch(bitmap, c),
+            // only the default character set is supported
+            ParserState::Charset => self.state = ParserState::Ground,
+            ParserState::Csi => match c {
+                '0'..='9' => {
+                    if self.n_params == 0 {
+                        self.n_params = 1;
+                    }
+                    let param = &mut self.params[self.n_params - 1];
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add(c as u16 - '0' as u16);
+                }
+                ';' | ':' => {
+                    if self.n_params == 0 {
+                        self.n_params = 1;
+                    }
+                    if self.n_params < MAX_PARAMS {
+                        self.n_params += 1;
+                    }
+                }
+                '?' => self.private = true,
+                // intermediate bytes
+                ' '..='/' | '<'..='>' => (),
+                '@'..='~' => {
+                    self.state = ParserState::Ground;
+                    self.csi_dispatch(bitmap, c);
+                }
+                _ => self.state = ParserState::Ground,
+            },
+        }
+    }
+
+    fn escape_dispatch(&mut self, bitmap: &mut Bitmap, c: char) {
+        self.state = ParserState::Ground;
+        match c {
+            '[' => {
+                self.state = ParserState::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.n_params = 0;
+                self.private = false;
             }
-            '\r' => {
+            '(' | ')' => self.state = ParserState::Charset,
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(bitmap),
+            'c' => self.reset_screen(bitmap),
+            'D' => self.line_feed(bitmap),
+            'E' => {
                 self.x = 0;
+                self.line_feed(bitmap);
+            }
+            'M' => self.reverse_index(bitmap),
+            'H' if self.x < MAX_TAB_COLS => {
+                self.tab_stops[self.x / 64] |= 1 << (self.x % 64);
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns a parameter of the CSI sequence, or `default` if it is omitted or zero.
+    #[inline]
+    fn param(&self, index: usize, default: usize) -> usize {
+        match self.params[index] as usize {
+            0 => default,
+            v => v,
+        }
+    }
+
+    fn csi_dispatch(&mut self, bitmap: &mut Bitmap, c: char) {
+        let (cols, rows) = self.screen_dims(bitmap);
+        let (top, bottom) = self.region(rows);
+        let n = self.param(0, 1);
+        if c != 'm' {
+            self.pending_wrap = false;
+        }
+
+        if self.private {
+            if matches!(c, 'h' | 'l') && self.params[..self.n_params].contains(&25) {
+                self.cursor_enabled = c == 'h';
             }
-            '\n' => {
+            return;
+        }
+
+        match c {
+            'A' => {
+                let limit = if self.y >= top { top } else { 0 };
+                self.y = self.y.saturating_sub(n).max(limit);
+            }
+            'B' | 'e' => {
+                let limit = if self.y <= bottom { bottom } else { rows - 1 };
+                self.y = (self.y + n).min(limit);
+            }
+            'C' | 'a' => self.x = (self.x + n).min(cols - 1),
+            'D' => self.x = self.x.saturating_sub(n).min(cols - 1),
+            'E' => {
+                let limit = if self.y <= bottom { bottom } else { rows - 1 };
+                self.y = (self.y + n).min(limit);
                 self.x = 0;
-                self.y += 1;
             }
-            _ => {
-                let origin = Point::new(
-                    self.x as isize * font_size.width + Self::PADDING,
-                    self.y as isize * font_size.height + Self::PADDING,
-                );
-                bitmap.fill_rect(
-                    Rect {
-                        origin,
-                        size: font_size,
-                    },
-                    self.bg_color.into(),
-                );
-                font.draw_char(c, bitmap, origin, font.base_height(), self.fg_color.into());
+            'F' => {
+                let limit = if self.y >= top { top } else { 0 };
+                self.y = self.y.saturating_sub(n).max(limit);
+                self.x = 0;
+            }
+            'G' | '`' => self.x = (n - 1).min(cols - 1),
+            'H' | 'f' => {
+                self.y = (self.param(0, 1) - 1).min(rows - 1);
+                self.x = (self.param(1, 1) - 1).min(cols - 1);
+            }
+            'd' => self.y = (n - 1).min(rows - 1),
+            'I' => self.tab_forward(bitmap, n),
+            'Z' => {
+                for _ in 0..n {
+                    self.x = (0..self.x)
+                        .rev()
+                        .find(|x| self.is_tab_stop(*x))
+                        .unwrap_or(0);
+                }
+            }
+            'J' => match self.param(0, 0) {
+                0 => {
+                    self.erase_cells(bitmap, self.x, self.y, cols - self.x.min(cols), 1);
+                    self.erase_cells(bitmap, 0, self.y + 1, cols, rows - self.y - 1);
+                }
+                1 => {
+                    self.erase_cells(bitmap, 0, 0, cols, self.y);
+                    self.erase_cells(bitmap, 0, self.y, self.x + 1, 1);
+                }
+                2 | 3 => self.erase_cells(bitmap, 0, 0, cols, rows),
+                _ => (),
+            },
+            'K' => match self.param(0, 0) {
+                0 => self.erase_cells(bitmap, self.x, self.y, cols - self.x.min(cols), 1),
+                1 => self.erase_cells(bitmap, 0, self.y, self.x + 1, 1),
+                2 => self.erase_cells(bitmap, 0, self.y, cols, 1),
+                _ => (),
+            },
+            'L' => {
+                if (top..=bottom).contains(&self.y) {
+                    self.scroll_down(bitmap, self.y, bottom, n);
+                    self.x = 0;
+                }
+            }
+            'M' => {
+                if (top..=bottom).contains(&self.y) {
+                    self.scroll_up(bitmap, self.y, bottom, n);
+                    self.x = 0;
+                }
+            }
+            '@' => {
+                let n = n.min(cols - self.x);
+                // Copy from the right, because blt copies forward.
+                for x in (self.x..cols - n).rev() {
+                    let src = self.cells_rect(x, self.y, 1, 1);
+                    bitmap.blt_itself(self.cell_origin(x + n, self.y), src);
+                }
+                self.erase_cells(bitmap, self.x, self.y, n, 1);
+            }
+            'P' => {
+                let n = n.min(cols - self.x);
+                let src = self.cells_rect(self.x + n, self.y, cols - self.x - n, 1);
+                bitmap.blt_itself(self.cell_origin(self.x, self.y), src);
+                self.erase_cells(bitmap, cols - n, self.y, n, 1);
+            }
+            'X' => self.erase_cells(bitmap, self.x, self.y, n.min(cols - self.x), 1),
+            'S' => self.scroll_up(bitmap, top, bottom, n),
+            'T' => self.scroll_down(bitmap, top, bottom, n),
+            'r' => {
+                let new_top = self.param(0, 1) - 1;
+                let new_bottom = self.param(1, rows).min(rows) - 1;
+                if new_top < new_bottom {
+                    self.scroll_region = if new_top == 0 && new_bottom == rows - 1 {
+                        None
+                    } else {
+                        Some((new_top, new_bottom))
+                    };
+                    self.x = 0;
+                    self.y = 0;
+                }
+            }
+            'm' => self.select_graphic_rendition(),
+            'g' => match self.param(0, 0) {
+                0 => {
+                    if self.x < MAX_TAB_COLS {
+                        self.tab_stops[self.x / 64] &= !(1 << (self.x % 64));
+                    }
+                }
+                3 => self.tab_stops = [0; MAX_TAB_COLS / 64],
+                _ => (),
+            },
+            's' => self.save_cursor(),
+            'u' => self.restore_cursor(bitmap),
+            _ => (),
+        }
+    }
+
+    fn select_graphic_rendition(&mut self) {
+        let mut index = 0;
+        loop {
+            let param = if index < self.n_params {
+                self.params[index]
+            } else {
+                0
+            };
+            match param {
+                0 => self.attr = Attributes::new(self.default_fg, self.default_bg),
+                1 => self.attr.bold = true,
+                4 => self.attr.underline = true,
+                7 => self.attr.reverse = true,
+                22 => self.attr.bold = false,
+                24 => self.attr.underline = false,
+                27 => self.attr.reverse = false,
+                30..=37 => self.attr.fg_color = ANSI_COLORS[param as usize - 30].into(),
+                38 => {
+                    let (color, len) = self.extended_color(index + 1);
+                    if let Some(color) = color {
+                        self.attr.fg_color = color;
+                    }
+                    index += len;
+                }
+                39 => self.attr.fg_color = self.default_fg,
+                40..=47 => self.attr.bg_color = ANSI_COLORS[param as usize - 40].into(),
+                48 => {
+                    let (color, len) = self.extended_color(index + 1);
+                    if let Some(color) = color {
+                        self.attr.bg_color = color;
+                    }
+                    index += len;
+                }
+                49 => self.attr.bg_color = self.default_bg,
+                90..=97 => self.attr.fg_color = ANSI_COLORS[param as usize - 90 + 8].into(),
+                100..=107 => self.attr.bg_color = ANSI_COLORS[param as usize - 100 + 8].into(),
+                _ => (),
+            }
+            index += 1;
+            if index >= self.n_params {
+                break;
+            }
+        }
+    }
+
+    /// Parses the color of SGR 38 and 48, and returns it with the number of parameters used.
+    fn extended_color(&self, index: usize) -> (Option<Color>, usize) {
+        let param = |i: usize| {
+            if index + i < self.n_params {
+                self.params[index + i]
+            } else {
+                0
+            }
+        };
+        match param(0) {
+            5 => (Some(Self::xterm_color(param(1).min(255) as u8)), 2),
+            2 => {
+                let r = param(1).min(255) as u32;
+                let g = param(2).min(255) as u32;
+                let b = param(3).min(255) as u32;
+                (Some(Color::from_rgb((r << 16) | (g << 8) | b)), 4)
+            }
+            _ => (None, self.n_params.saturating_sub(index)),
+        }
+    }
 
-                self.x += 1;
+    /// Returns a color of the xterm 256 color palette.
+    fn xterm_color(index: u8) -> Color {
+        match index {
+            0..=15 => ANSI_COLORS[index as usize].into(),
+            16..=231 => {
+                // xterm orders the cube by red first, and IndexedColor by blue first
+                let v = index - 16;
+                let (r, g, b) = (v / 36, (v / 6) % 6, v % 6);
+                IndexedColor(16 + r + g * 6 + b * 36).into()
+            }
+            _ => {
+                let level = 8 + 10 * (index - 232) as u32;
+                Color::from_rgb(level * 0x01_01_01)
             }
         }
     }
+
+    fn print(&mut self, bitmap: &mut Bitmap, c: char) {
+        let (cols, _rows) = self.screen_dims(bitmap);
+        let font = self.font;
+        let cells = font.cells_of(c).min(cols);
+        // A wide character does not fit in the last column.
+        if self.pending_wrap || self.x + cells > cols {
+            self.pending_wrap = false;
+            self.x = 0;
+            self.line_feed(bitmap);
+        }
+
+        let rect = self.cells_rect(self.x, self.y, cells, 1);
+        let (fg_color, bg_color) = self.attr.colors();
+        bitmap.fill_rect(rect, bg_color);
+        // A glyph of a fallback font may be larger than its cells.
+        bitmap.view(rect, |bitmap| {
+            font.draw_char(c, bitmap, Point::default(), font.base_height(), fg_color)
+        });
+        if self.attr.underline {
+            bitmap.draw_hline(
+                Point::new(rect.x(), rect.y() + rect.height() - 1),
+                rect.width(),
+                fg_color,
+            );
+        }
+
+        if self.x + cells < cols {
+            self.x += cells;
+        } else {
+            self.x = cols - 1;
+            self.pending_wrap = true;
+        }
+    }
+
+    fn line_feed(&mut self, bitmap: &mut Bitmap) {
+        let (_cols, rows) = self.screen_dims(bitmap);
+        let (top, bottom) = self.region(rows);
+        self.pending_wrap = false;
+        if self.y == bottom {
+            self.scroll_up(bitmap, top, bottom, 1);
+        } else if self.y + 1 < rows {
+            self.y += 1;
+        }
+    }
+
+    fn reverse_index(&mut self, bitmap: &mut Bitmap) {
+        let (_cols, rows) = self.screen_dims(bitmap);
+        let (top, bottom) = self.region(rows);
+        self.pending_wrap = false;
+        if self.y == top {
+            self.scroll_down(bitmap, top, bottom, 1);
+        } else if self.y > 0 {
+            self.y -= 1;
+        }
+    }
+
+    #[inline]
+    fn is_tab_stop(&self, x: usize) -> bool {
+        x < MAX_TAB_COLS && (self.tab_stops[x / 64] & (1 << (x % 64))) != 0
+    }
+
+    fn tab_forward(&mut self, bitmap: &mut Bitmap, n: usize) {
+        let (cols, _rows) = self.screen_dims(bitmap);
+        for _ in 0..n {
+            self.x = (self.x + 1..cols)
+                .find(|x| self.is_tab_stop(*x))
+                .unwrap_or(cols - 1);
+        }
+    }
+
+    /// Scrolls the rows from `top` to `bottom` up by `n` rows.
+    fn scroll_up(&mut self, bitmap: &mut Bitmap, top: usize, bottom: usize, n: usize) {
+        let (cols, _rows) = self.screen_dims(bitmap);
+        let height = bottom + 1 - top;
+        let n = n.min(height);
+        if n < height {
+            let src = self.cells_rect(0, top + n, cols, height - n);
+            bitmap.blt_itself(self.cell_origin(0, top), src);
+        }
+        self.erase_cells(bitmap, 0, bottom + 1 - n, cols, n);
+    }
+
+    /// Scrolls the rows from `top` to `bottom` down by `n` rows.
+    fn scroll_down(&mut self, bitmap: &mut Bitmap, top: usize, bottom: usize, n: usize) {
+        let (cols, _rows) = self.screen_dims(bitmap);
+        let height = bottom + 1 - top;
+        let n = n.min(height);
+        // Copy from the bottom, because blt copies forward.
+        for y in (top..bottom + 1 - n).rev() {
+            let src = self.cells_rect(0, y, cols, 1);
+            bitmap.blt_itself(self.cell_origin(0, y + n), src);
+        }
+        self.erase_cells(bitmap, 0, top, cols, n);
+    }
+
+    #[inline]
+    fn erase_cells(&self, bitmap: &mut Bitmap, x: usize, y: usize, cols: usize, rows: usize) {
+        if cols > 0 && rows > 0 {
+            bitmap.fill_rect(self.cells_rect(x, y, cols, rows), self.attr.bg_color);
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = SavedCursor {
+            x: self.x,
+            y: self.y,
+            attr: self.attr,
+        };
+    }
+
+    fn restore_cursor(&mut self, bitmap: &mut Bitmap) {
+        let (cols, rows) = self.screen_dims(bitmap);
+        self.x = self.saved.x.min(cols - 1);
+        self.y = self.saved.y.min(rows - 1);
+        self.attr = self.saved.attr;
+        self.pending_wrap = false;
+    }
+
+    /// Restores the initial state and clears the screen.
+    fn reset_screen(&mut self, bitmap: &mut Bitmap) {
+        self.attr = Attributes::new(self.default_fg, self.default_bg);
+        self.scroll_region = None;
+        self.tab_stops = [Self::DEFAULT_TAB_STOPS; MAX_TAB_COLS / 64];
+        self.cursor_enabled = true;
+        self.pending_wrap = false;
+        self.x = 0;
+        self.y = 0;
+        self.save_cursor();
+        bitmap.fill_rect(bitmap.bounds(), self.attr.bg_color);
+    }
 }
 
 impl fmt::Write for EmConsole {
-    #[inline]
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut bitmap = match System::main_screen() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let bitmap = &mut bitmap;
+        let (cols, rows) = self.screen_dims(bitmap);
+        if cols == 0 || rows == 0 {
+            return Ok(());
+        }
+        let _pointer = POINTER.hide();
+        self.hide_cursor(bitmap);
         for c in s.chars() {
-            self.write_char(c);
+            self.put_char(bitmap, c);
         }
+        self.show_cursor(bitmap);
         Ok(())
     }
 }
 
-// impl TtyWrite for EmConsole {
-//     fn reset(&mut self) -> Result<(), super::tty::TtyError> {
-//         let mut bitmap = System::main_screen();
-//         let bitmap = &mut bitmap;
-//         bitmap.fill_rect(bitmap.bounds(), self.bg_color.into());
-//         Ok(())
-//     }
-
-//     fn dims(&self) -> (isize, isize) {
-//         let font = self.font;
-//         let font_size = Size::new(font.width(), font.line_height());
-//         let bitmap = System::main_screen();
-//         let cols = (bitmap.width() as isize - Self::PADDING * 2) / font_size.width();
-//         let rows = (bitmap.height() as isize - Self::PADDING * 2) / font_size.height();
-//         (cols, rows)
-//     }
-
-//     fn cursor_position(&self) -> (isize, isize) {
-//         (self.x as isize, self.y as isize)
-//     }
-
-//     fn set_cursor_position(&mut self, x: isize, y: isize) {
-//         self.x = x as usize;
-//         self.y = y as usize;
-//     }
-
-//     fn is_cursor_enabled(&self) -> bool {
-//         false
-//     }
-
-//     fn set_cursor_enabled(&mut self, _enabled: bool) -> bool {
-//         false
-//     }
-
-//     fn set_attribute(&mut self, attribute: u8) {
-//         if attribute > 0 {
-//             self.fg_color = IndexedColor(attribute & 0x0F).into();
-//             let bg_color = attribute >> 4;
-//             if bg_color > 0 {
-//                 self.bg_color = IndexedColor(bg_color).into();
-//             } else {
-//                 self.bg_color = Color::from_rgb(0x000000);
-//             }
-//         } else {
-//             self.fg_color = Self::DEFAULT_FG_COLOR;
-//             self.bg_color = Self::DEFAULT_BG_COLOR;
-//         }
-//     }
-// }
+impl TtyWrite for EmConsole {
+    fn reset(&mut self) -> Result<(), TtyError> {
+        let mut bitmap = System::main_screen().ok_or(TtyError::NotReady)?;
+        self.state = ParserState::Ground;
+        self.cursor_drawn = false;
+        let _pointer = POINTER.hide();
+        self.reset_screen(&mut bitmap);
+        self.show_cursor(&mut bitmap);
+        Ok(())
+    }
+
+    fn dims(&self) -> (isize, isize) {
+        match System::main_screen() {
+            Some(bitmap) => {
+                let (cols, rows) = self.screen_dims(&bitmap);
+                (cols as isize, rows as isize)
+            }
+            None => (0, 0),
+        }
+    }
+
+    fn cursor_position(&self) -> Option<(isize, isize)> {
+        Some((self.x as isize, self.y as isize))
+    }
+
+    fn set_cursor_position(&mut self, x: isize, y: isize) {
+        let mut bitmap = match System::main_screen() {
+            Some(v) => v,
+            None => return,
+        };
+        let (cols, rows) = self.screen_dims(&bitmap);
+        if cols == 0 || rows == 0 {
+            return;
+        }
+        self.hide_cursor(&mut bitmap);
+        self.x = (x.max(0) as usize).min(cols - 1);
+        self.y = (y.max(0) as usize).min(rows - 1);
+        self.pending_wrap = false;
+        self.show_cursor(&mut bitmap);
+    }
+
+    fn is_cursor_enabled(&self) -> bool {
+        self.cursor_enabled
+    }
+
+    fn set_cursor_enabled(&mut self, enabled: bool) -> bool {
+        let result = self.cursor_enabled;
+        if let Some(mut bitmap) = System::main_screen() {
+            self.hide_cursor(&mut bitmap);
+            self.cursor_enabled = enabled;
+            self.show_cursor(&mut bitmap);
+        } else {
+            self.cursor_enabled = enabled;
+        }
+        result
+    }
+
+    fn set_attribute(&mut self, attribute: u8) {
+        if attribute > 0 {
+            self.attr.fg_color = IndexedColor(attribute & 0x0F).into();
+            self.attr.bg_color = match attribute >> 4 {
+                0 => self.default_bg,
+                bg_color => IndexedColor(bg_color).into(),
+            };
+        } else {
+            self.attr = Attributes::new(self.default_fg, self.default_bg);
+        }
+    }
+}
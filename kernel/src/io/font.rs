@@ -1,129 +0,0 @@
-use crate::drawing::*;
-
-#[allow(dead_code)]
-mod embedded {
-    include!("megh0816.rs");
-}
-const SYSTEM_FONT: FixedFontDriver = FixedFontDriver::new(8, 16, &embedded::FONT_MEGH0816_DATA);
-
-pub struct FontManager;
-
-impl FontManager {
-    #[inline]
-    pub const fn fixed_system_font() -> &'static FixedFontDriver<'static> {
-        &SYSTEM_FONT
-    }
-
-    #[inline]
-    pub const fn preferred_console_font() -> &'static FixedFontDriver<'static> {
-        &SYSTEM_FONT
-    }
-}
-
-pub trait FontDriver {
-    fn is_scalable(&self) -> bool;
-
-    fn base_height(&self) -> isize;
-
-    fn preferred_line_height(&self) -> isize;
-
-    fn width_of(&self, character: char) -> isize;
-
-    fn kern(&self, first: char, second: char) -> isize;
-
-    fn draw_char(
-        &self,
-        character: char,
-        bitmap: &mut Bitmap,
-        origin: Point,
-        height: isize,
-        color: Color,
-    );
-}
-
-pub struct FixedFontDriver<'a> {
-    size: Size,
-    data: &'a [u8],
-    fix_y: isize,
-    line_height: isize,
-    stride: usize,
-}
-
-impl FixedFontDriver<'_> {
-    pub const fn new(width: usize, height: usize, data: &'static [u8]) -> FixedFontDriver<'static> {
-        let width = width as isize;
-        let height = height as isize;
-        let line_height = height * 5 / 4;
-        let fix_y = (line_height - height) / 2;
-        let stride = ((width as usize + 7) >> 3) * height as usize;
-        FixedFontDriver {
-            size: Size::new(width, height),
-            fix_y,
-            line_height,
-            stride,
-            data,
-        }
-    }
-
-    #[inline]
-    pub const fn width(&self) -> isize {
-        self.size.width
-    }
-
-    #[inline]
-    pub const fn line_height(&self) -> isize {
-        self.line_height
-    }
-
-    /// Glyph Data for Rasterized Font
-    fn glyph_for(&self, character: char) -> Option<&[u8]> {
-        let c = character as usize;
-        if c > 0x20 && c < 0x80 {
-            let base = self.stride * (c - 0x20);
-            Some(&self.data[base..base + self.stride])
-        } else {
-            None
-        }
-    }
-}
-
-impl FontDriver for FixedFontDriver<'_> {
-    #[inline]
-    fn is_scalable(&self) -> bool {
-        false
-    }
-
-    #[inline]
-    fn base_height(&self) -> isize {
-        self.size.height
-    }
-
-    #[inline]
-    fn preferred_line_height(&self) -> isize {
-        self.line_height
-    }
-
-    #[inline]
-    fn width_of(&self, _character: char) -> isize {
-        self.size.width
-    }
-
-    fn kern(&self, _first: char, _second: char) -> isize {
-        0
-    }
-
-    fn draw_char(
-        &self,
-        character: char,
-        bitmap: &mut Bitmap,
-        origin: Point,
-        _height: isize,
-        color: Color,
-    ) {
-        if let Some(font) = self.glyph_for(character) {
-            let origin = Point::new(origin.x, origin.y + self.fix_y);
-            let size = Size::new(self.width_of(character), self.size.height());
-            bitmap.draw_font(font, size, origin, color);
-        }
-    }
-}
@@ -0,0 +1,362 @@
+//! Bitmap fonts in the PSF2 and BDF formats
+//!
+//! Glyphs are kept in a sparse table sorted by code point, so a font can cover any range
+//! of Unicode. A glyph of a BDF font is as wide as its advance, so a CJK ideograph can be
+//! drawn across two cells of the console.
+
+use super::{FontDriver, FontError};
+use crate::drawing::*;
+use alloc::vec::Vec;
+use core::str;
+
+#[derive(Debug, Clone, Copy)]
+struct GlyphEntry {
+    character: char,
+    /// Width in pixels
+    width: u16,
+    /// Offset in the glyph data
+    offset: u32,
+}
+
+/// A bitmap font loaded at runtime
+pub struct BitmapFont {
+    /// Size of a narrow cell
+    size: Size,
+    line_height: isize,
+    fix_y: isize,
+    glyphs: Vec<GlyphEntry>,
+    /// Rows of each glyph, MSB first and padded to bytes
+    data: Vec<u8>,
+}
+
+impl BitmapFont {
+    const PSF2_MAGIC: u32 = 0x864A_B572;
+    const PSF2_HAS_UNICODE_TABLE: u32 = 0x0000_0001;
+    /// Separates the entries of the Unicode table of PSF2
+    const PSF2_SEPARATOR: u8 = 0xFF;
+    /// Starts a sequence of combining characters in the Unicode table of PSF2
+    const PSF2_START_SEQ: u8 = 0xFE;
+
+    const MAX_SIZE: isize = 256;
+
+    /// Parses a PSF2 or BDF font.
+    pub fn parse(data: &[u8]) -> Result<Self, FontError> {
+        if data.starts_with(&Self::PSF2_MAGIC.to_le_bytes()) {
+            Self::from_psf2(data)
+        } else if data.starts_with(b"STARTFONT") {
+            Self::from_bdf(data)
+        } else {
+            Err(FontError::InvalidData)
+        }
+    }
+
+    pub fn from_psf2(data: &[u8]) -> Result<Self, FontError> {
+        let header = |index: usize| -> Result<u32, FontError> {
+            data.get(index * 4..index * 4 + 4)
+                .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+                .ok_or(FontError::InvalidData)
+        };
+        if header(0)? != Self::PSF2_MAGIC {
+            return Err(FontError::InvalidData);
+        }
+        if header(1)? != 0 {
+            return Err(FontError::Unsupported);
+        }
+        let header_size = header(2)? as usize;
+        let flags = header(3)?;
+        let num_glyphs = header(4)? as usize;
+        let glyph_size = header(5)? as usize;
+        let height = header(6)? as usize;
+        let width = header(7)? as usize;
+        if width == 0
+            || height == 0
+            || width as isize > Self::MAX_SIZE
+            || height as isize > Self::MAX_SIZE
+            || glyph_size != width.div_ceil(8) * height
+        {
+            return Err(FontError::InvalidData);
+        }
+        let glyph_data = num_glyphs
+            .checked_mul(glyph_size)
+            .and_then(|len| data.get(header_size..header_size.checked_add(len)?))
+            .ok_or(FontError::InvalidData)?;
+
+        let entry = |character: char, index: usize| GlyphEntry {
+            character,
+            width: width as u16,
+            offset: (index * glyph_size) as u32,
+        };
+        let mut glyphs = Vec::new();
+        if (flags & Self::PSF2_HAS_UNICODE_TABLE) != 0 {
+            let table = &data[header_size + glyph_data.len()..];
+            for (index, chars) in table
+                .split(|v| *v == Self::PSF2_SEPARATOR)
+                .take(num_glyphs)
+                .enumerate()
+            {
+                // Sequences of combining characters cannot be drawn in a cell.
+                let chars = match chars.iter().position(|v| *v == Self::PSF2_START_SEQ) {
+                    Some(pos) => &chars[..pos],
+                    None => chars,
+                };
+                let chars = str::from_utf8(chars).map_err(|_| FontError::InvalidData)?;
+                glyphs.extend(chars.chars().map(|c| entry(c, index)));
+            }
+        } else {
+            glyphs.extend(
+                (0..num_glyphs)
+                    .filter_map(|index| char::from_u32(index as u32).map(|c| entry(c, index))),
+            );
+        }
+
+        Ok(Self::new(
+            Size::new(width as isize, height as isize),
+            glyphs,
+            glyph_data.to_vec(),
+        ))
+    }
+
+    pub fn from_bdf(data: &[u8]) -> Result<Self, FontError> {
+        let text = str::from_utf8(data).map_err(|_| FontError::InvalidData)?;
+        let mut lines = text.lines().map(|line| line.trim());
+
+        let mut bounding_box = None;
+        let mut ascent = None;
+        let mut descent = None;
+        // The cell and the ascent, fixed at the first glyph
+        let mut cell = None;
+        let mut glyphs = Vec::new();
+        let mut glyph_data = Vec::new();
+
+        while let Some(line) = lines.next() {
+            let mut words = line.split_ascii_whitespace();
+            match words.next() {
+                Some("FONTBOUNDINGBOX") => {
+                    let [w, h, x, y] = Self::bdf_numbers(words)?;
+                    bounding_box = Some((w, h, x, y));
+                }
+                Some("FONT_ASCENT") => ascent = Some(Self::bdf_numbers::<1>(words)?[0]),
+                Some("FONT_DESCENT") => descent = Some(Self::bdf_numbers::<1>(words)?[0]),
+                Some("STARTCHAR") => {
+                    let (size, ascent) = match cell {
+                        Some(cell) => cell,
+                        None => {
+                            let bounding_box = bounding_box.ok_or(FontError::InvalidData)?;
+                            *cell.insert(Self::bdf_cell(bounding_box, ascent, descent)?)
+                        }
+                    };
+                    if let Some(glyph) = Self::bdf_glyph(&mut lines, size, ascent, &mut glyph_data)?
+                    {
+                        glyphs.push(glyph);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let (size, _) = match cell {
+            Some(cell) => cell,
+            None => {
+                let bounding_box = bounding_box.ok_or(FontError::InvalidData)?;
+                Self::bdf_cell(bounding_box, ascent, descent)?
+            }
+        };
+        Ok(Self::new(size, glyphs, glyph_data))
+    }
+
+    /// Returns the cell of the glyphs and the ascent, from the font properties if present.
+    fn bdf_cell(
+        bounding_box: (isize, isize, isize, isize),
+        ascent: Option<isize>,
+        descent: Option<isize>,
+    ) -> Result<(Size, isize), FontError> {
+        let (width, bbx_h, _, bbx_y) = bounding_box;
+        let ascent = ascent.unwrap_or(bbx_h.saturating_add(bbx_y));
+        let height = ascent.saturating_add(descent.unwrap_or(bbx_y.saturating_neg()));
+        if width <= 0 || height <= 0 || width > Self::MAX_SIZE || height > Self::MAX_SIZE {
+            return Err(FontError::InvalidData);
+        }
+        Ok((Size::new(width, height), ascent))
+    }
+
+    /// Parses a glyph from STARTCHAR to ENDCHAR, and renders it as wide as its advance.
+    ///
+    /// Returns `None` for a glyph without a Unicode code point.
+    fn bdf_glyph<'a>(
+        lines: &mut impl Iterator<Item = &'a str>,
+        cell: Size,
+        ascent: isize,
+        glyph_data: &mut Vec<u8>,
+    ) -> Result<Option<GlyphEntry>, FontError> {
+        let mut character = None;
+        let mut advance = cell.width();
+        let mut bbx = (cell.width(), cell.height(), 0, ascent - cell.height());
+        loop {
+            let line = lines.next().ok_or(FontError::InvalidData)?;
+            let mut words = line.split_ascii_whitespace();
+            match words.next() {
+                Some("ENCODING") => {
+                    let [code] = Self::bdf_numbers(words)?;
+                    character = u32::try_from(code).ok().and_then(char::from_u32);
+                }
+                Some("DWIDTH") => {
+                    let [dx] = Self::bdf_numbers(words)?;
+                    advance = dx;
+                }
+                Some("BBX") => {
+                    let [w, h, x, y] = Self::bdf_numbers(words)?;
+                    bbx = (w, h, x, y);
+                }
+                Some("BITMAP") => break,
+                Some("ENDCHAR") => return Ok(None),
+                _ => (),
+            }
+        }
+
+        // The advance decides how many cells the glyph takes in a terminal.
+        let width = advance.clamp(1, Self::MAX_SIZE);
+        let stride = (width as usize).div_ceil(8);
+        let offset = glyph_data.len();
+        glyph_data.resize(offset + stride * cell.height() as usize, 0);
+        let glyph = &mut glyph_data[offset..];
+
+        let (bbx_w, bbx_h, bbx_x, bbx_y) = bbx;
+        let top = ascent - bbx_y - bbx_h;
+        let mut row = 0;
+        loop {
+            let line = lines.next().ok_or(FontError::InvalidData)?;
+            if line == "ENDCHAR" {
+                break;
+            }
+            let y = top + row;
+            row += 1;
+            if y < 0 || y >= cell.height() {
+                continue;
+            }
+            for (index, digit) in line.bytes().enumerate() {
+                let nibble = (digit as char).to_digit(16).ok_or(FontError::InvalidData)?;
+                for bit in 0..4 {
+                    let px = index as isize * 4 + bit;
+                    let x = bbx_x + px;
+                    if (nibble & (8 >> bit)) == 0 || px >= bbx_w || x < 0 || x >= width {
+                        continue;
+                    }
+                    glyph[y as usize * stride + x as usize / 8] |= 0x80 >> (x % 8);
+                }
+            }
+        }
+
+        match character {
+            Some(character) => Ok(Some(GlyphEntry {
+                character,
+                width: width as u16,
+                offset: offset as u32,
+            })),
+            None => {
+                glyph_data.truncate(offset);
+                Ok(None)
+            }
+        }
+    }
+
+    fn bdf_numbers<'a, const N: usize>(
+        mut words: impl Iterator<Item = &'a str>,
+    ) -> Result<[isize; N], FontError> {
+        let mut result = [0; N];
+        for value in result.iter_mut() {
+            *value = words
+                .next()
+                .and_then(|v| v.parse().ok())
+                .ok_or(FontError::InvalidData)?;
+        }
+        Ok(result)
+    }
+
+    fn new(size: Size, mut glyphs: Vec<GlyphEntry>, data: Vec<u8>) -> Self {
+        // The first glyph of a character wins.
+        glyphs.sort_by_key(|v| v.character);
+        glyphs.dedup_by_key(|v| v.character);
+        glyphs.shrink_to_fit();
+        let line_height = size.height() * 5 / 4;
+        Self {
+            size,
+            line_height,
+            fix_y: (line_height - size.height()) / 2,
+            glyphs,
+            data,
+        }
+    }
+
+    /// Returns the size of a narrow cell.
+    #[inline]
+    pub const fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Returns the number of characters the font has.
+    #[inline]
+    pub fn num_glyphs(&self) -> usize {
+        self.glyphs.len()
+    }
+
+    #[inline]
+    fn glyph_for(&self, character: char) -> Option<&GlyphEntry> {
+        self.glyphs
+            .binary_search_by_key(&character, |v| v.character)
+            .ok()
+            .map(|index| &self.glyphs[index])
+    }
+}
+
+impl FontDriver for BitmapFont {
+    #[inline]
+    fn is_scalable(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    fn has_glyph(&self, character: char) -> bool {
+        self.glyph_for(character).is_some()
+    }
+
+    #[inline]
+    fn base_height(&self) -> isize {
+        self.size.height()
+    }
+
+    #[inline]
+    fn preferred_line_height(&self) -> isize {
+        self.line_height
+    }
+
+    #[inline]
+    fn width_of(&self, character: char) -> isize {
+        self.glyph_for(character)
+            .map(|v| v.width as isize)
+            .unwrap_or(self.size.width())
+    }
+
+    fn kern(&self, _first: char, _second: char) -> isize {
+        0
+    }
+
+    fn draw_char(
+        &self,
+        character: char,
+        bitmap: &mut Bitmap,
+        origin: Point,
+        _height: isize,
+        color: Color,
+    ) {
+        let Some(glyph) = self.glyph_for(character) else {
+            return;
+        };
+        let size = Size::new(glyph.width as isize, self.size.height());
+        let len = (glyph.width as usize).div_ceil(8) * self.size.height() as usize;
+        let offset = glyph.offset as usize;
+        if let Some(data) = self.data.get(offset..offset + len) {
+            let origin = Point::new(origin.x, origin.y + self.fix_y);
+            bitmap.draw_font(data, size, origin, color);
+        }
+    }
+}
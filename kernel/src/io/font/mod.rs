@@ -0,0 +1,300 @@
+use crate::{drawing::*, mem::fixedvec::FixedVec, sync::spinlock::SpinMutex};
+
+pub mod bitmapfont;
+pub mod truetype;
+
+#[allow(dead_code)]
+mod embedded {
+    include!("megh0816.rs");
+}
+const SYSTEM_FONT: FixedFontDriver = FixedFontDriver::new(8, 16, &embedded::FONT_MEGH0816_DATA);
+
+static CONSOLE_FONT: FontChain = FontChain::new(&SYSTEM_FONT);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    /// The data is broken or in an unknown format.
+    InvalidData,
+    /// The format is known, but the font uses a feature that is not supported.
+    Unsupported,
+}
+
+pub struct FontManager;
+
+impl FontManager {
+    #[inline]
+    pub const fn fixed_system_font() -> &'static FixedFontDriver<'static> {
+        &SYSTEM_FONT
+    }
+
+    #[inline]
+    pub const fn preferred_console_font() -> &'static FontChain {
+        &CONSOLE_FONT
+    }
+
+    /// Appends a font to the fallback chain of the console.
+    ///
+    /// A bitmap font taller than the console font is rejected.
+    #[inline]
+    pub fn add_fallback_font(font: &'static (dyn FontDriver + Sync)) -> Result<(), ()> {
+        CONSOLE_FONT.add_fallback(font)
+    }
+}
+
+/// Returns whether the character occupies two cells in a terminal, such as CJK ideographs.
+pub fn is_wide_char(character: char) -> bool {
+    matches!(
+        character as u32,
+        0x1100..=0x115F
+            | 0x2E80..=0x303E
+            | 0x3041..=0x33FF
+            | 0x3400..=0x4DBF
+            | 0x4E00..=0x9FFF
+            | 0xA000..=0xA4CF
+            | 0xAC00..=0xD7A3
+            | 0xF900..=0xFAFF
+            | 0xFE30..=0xFE4F
+            | 0xFF00..=0xFF60
+            | 0xFFE0..=0xFFE6
+            | 0x1F300..=0x1F64F
+            | 0x1F900..=0x1F9FF
+            | 0x20000..=0x2FFFD
+            | 0x30000..=0x3FFFD
+    )
+}
+
+pub trait FontDriver {
+    fn is_scalable(&self) -> bool;
+
+    /// Returns whether the font has a glyph of the character.
+    fn has_glyph(&self, character: char) -> bool;
+
+    fn base_height(&self) -> isize;
+
+    fn preferred_line_height(&self) -> isize;
+
+    fn width_of(&self, character: char) -> isize;
+
+    fn kern(&self, first: char, second: char) -> isize;
+
+    fn draw_char(
+        &self,
+        character: char,
+        bitmap: &mut Bitmap,
+        origin: Point,
+        height: isize,
+        color: Color,
+    );
+}
+
+pub struct FixedFontDriver<'a> {
+    size: Size,
+    data: &'a [u8],
+    fix_y: isize,
+    line_height: isize,
+    stride: usize,
+}
+
+impl FixedFontDriver<'_> {
+    pub const fn new(width: usize, height: usize, data: &'static [u8]) -> FixedFontDriver<'static> {
+        let width = width as isize;
+        let height = height as isize;
+        let line_height = height * 5 / 4;
+        let fix_y = (line_height - height) / 2;
+        let stride = ((width as usize + 7) >> 3) * height as usize;
+        FixedFontDriver {
+            size: Size::new(width, height),
+            fix_y,
+            line_height,
+            stride,
+            data,
+        }
+    }
+
+    #[inline]
+    pub const fn width(&self) -> isize {
+        self.size.width
+    }
+
+    #[inline]
+    pub const fn line_height(&self) -> isize {
+        self.line_height
+    }
+
+    /// Glyph Data for Rasterized Font
+    fn glyph_for(&self, character: char) -> Option<&[u8]> {
+        let c = character as usize;
+        if c > 0x20 && c < 0x80 {
+            let base = self.stride * (c - 0x20);
+            Some(&self.data[base..base + self.stride])
+        } else {
+            None
+        }
+    }
+}
+
+impl FontDriver for FixedFontDriver<'_> {
+    #[inline]
+    fn is_scalable(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    fn has_glyph(&self, character: char) -> bool {
+        (0x20..0x80).contains(&(character as u32))
+    }
+
+    #[inline]
+    fn base_height(&self) -> isize {
+        self.size.height
+    }
+
+    #[inline]
+    fn preferred_line_height(&self) -> isize {
+        self.line_height
+    }
+
+    #[inline]
+    fn width_of(&self, _character: char) -> isize {
+        self.size.width
+    }
+
+    fn kern(&self, _first: char, _second: char) -> isize {
+        0
+    }
+
+    fn draw_char(
+        &self,
+        character: char,
+        bitmap: &mut Bitmap,
+        origin: Point,
+        _height: isize,
+        color: Color,
+    ) {
+        if let Some(font) = self.glyph_for(character) {
+            let origin = Point::new(origin.x, origin.y + self.fix_y);
+            let size = Size::new(self.width_of(character), self.size.height());
+            bitmap.draw_font(font, size, origin, color);
+        }
+    }
+}
+
+/// Fonts tried in order for each character
+///
+/// The first font determines the cell size of the console. A character that no font has
+/// is drawn as a replacement box.
+pub struct FontChain {
+    primary: &'static FixedFontDriver<'static>,
+    fallbacks:
+        SpinMutex<FixedVec<Option<&'static (dyn FontDriver + Sync)>, { Self::MAX_FALLBACKS }>>,
+}
+
+impl FontChain {
+    const MAX_FALLBACKS: usize = 8;
+
+    #[inline]
+    pub const fn new(primary: &'static FixedFontDriver<'static>) -> Self {
+        Self {
+            primary,
+            fallbacks: SpinMutex::new(FixedVec::new(None)),
+        }
+    }
+
+    /// Appends a font, which must fit in the height of the primary font unless it is scalable.
+    pub fn add_fallback(&self, font: &'static (dyn FontDriver + Sync)) -> Result<(), ()> {
+        if !font.is_scalable() && font.base_height() > self.primary.base_height() {
+            return Err(());
+        }
+        self.fallbacks.lock().push(Some(font)).map_err(|_| ())
+    }
+
+    /// Returns the size of a narrow cell.
+    #[inline]
+    pub const fn cell_size(&self) -> Size {
+        Size::new(self.primary.width(), self.primary.line_height())
+    }
+
+    /// Returns the number of cells the character occupies, which is 1 or 2.
+    pub fn cells_of(&self, character: char) -> usize {
+        let cell_width = self.primary.width();
+        match self.font_for(character) {
+            Some(font) => {
+                let width = font.width_of(character);
+                ((width + cell_width - 1) / cell_width).clamp(1, 2) as usize
+            }
+            None => 1 + is_wide_char(character) as usize,
+        }
+    }
+
+    /// Returns the first font that has the character.
+    fn font_for(&self, character: char) -> Option<&'static dyn FontDriver> {
+        if self.primary.has_glyph(character) {
+            return Some(self.primary);
+        }
+        let fallbacks = self.fallbacks.lock();
+        fallbacks
+            .as_slice()
+            .iter()
+            .flatten()
+            .find(|font| font.has_glyph(character))
+            .map(|font| *font as &dyn FontDriver)
+    }
+
+    fn draw_replacement(&self, character: char, bitmap: &mut Bitmap, origin: Point, color: Color) {
+        let cell_size = self.cell_size();
+        let height = self.primary.base_height();
+        let width = cell_size.width() * self.cells_of(character) as isize;
+        let rect = Rect::new(
+            origin.x + 1,
+            origin.y + (cell_size.height() - height) / 2 + 1,
+            width - 2,
+            height - 2,
+        );
+        bitmap.draw_rect(rect, color);
+    }
+}
+
+impl FontDriver for FontChain {
+    #[inline]
+    fn is_scalable(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    fn has_glyph(&self, _character: char) -> bool {
+        true
+    }
+
+    #[inline]
+    fn base_height(&self) -> isize {
+        self.primary.base_height()
+    }
+
+    #[inline]
+    fn preferred_line_height(&self) -> isize {
+        self.primary.preferred_line_height()
+    }
+
+    #[inline]
+    fn width_of(&self, character: char) -> isize {
+        self.primary.width() * self.cells_of(character) as isize
+    }
+
+    fn kern(&self, _first: char, _second: char) -> isize {
+        0
+    }
+
+    fn draw_char(
+        &self,
+        character: char,
+        bitmap: &mut Bitmap,
+        origin: Point,
+        height: isize,
+        color: Color,
+    ) {
+        match self.font_for(character) {
+            Some(font) => font.draw_char(character, bitmap, origin, height, color),
+            None => self.draw_replacement(character, bitmap, origin, color),
+        }
+    }
+}
@@ -0,0 +1,1004 @@
+//! TrueType font driver
+//!
+//! Parses fonts with TrueType outlines (`glyf`) from a byte slice and rasterizes glyphs
+//! with anti-aliasing in fixed point. Kerning is taken from the `kern` feature of `GPOS`,
+//! or from the `kern` table. OpenType fonts with CFF outlines are not supported.
+
+use super::{FontDriver, FontError};
+use crate::{drawing::*, sync::spinlock::SpinMutex};
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+
+/// Big-endian readers that fail instead of panicking on truncated data
+trait ReadBe {
+    fn u8_at(&self, offset: usize) -> Option<u8>;
+    fn u16_at(&self, offset: usize) -> Option<u16>;
+    fn u32_at(&self, offset: usize) -> Option<u32>;
+
+    #[inline]
+    fn i16_at(&self, offset: usize) -> Option<i16> {
+        self.u16_at(offset).map(|v| v as i16)
+    }
+}
+
+impl ReadBe for [u8] {
+    #[inline]
+    fn u8_at(&self, offset: usize) -> Option<u8> {
+        self.get(offset).copied()
+    }
+
+    #[inline]
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        self.get(offset..offset + 2)
+            .map(|v| u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    #[inline]
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        self.get(offset..offset + 4)
+            .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+    }
+}
+
+/// Glyph index
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GlyphId(pub u16);
+
+impl GlyphId {
+    /// The glyph shown for missing characters
+    pub const NOTDEF: Self = Self(0);
+}
+
+/// A glyph rasterized at a size
+pub struct Glyph {
+    /// Offset from the pen position to the left edge of the coverage
+    left: isize,
+    /// Offset from the baseline to the top edge of the coverage
+    top: isize,
+    size: Size,
+    coverage: Vec<u8>,
+}
+
+impl Glyph {
+    #[inline]
+    pub const fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Returns the coverage of each pixel, row by row.
+    #[inline]
+    pub fn coverage(&self) -> &[u8] {
+        &self.coverage
+    }
+}
+
+/// A point of an outline in font units
+#[derive(Clone, Copy)]
+struct OutlinePoint {
+    x: i32,
+    y: i32,
+    on_curve: bool,
+}
+
+#[derive(Default)]
+struct Outline {
+    points: Vec<OutlinePoint>,
+    /// Index after the last point of each contour
+    ends: Vec<usize>,
+    /// Components of composite glyphs loaded so far
+    components: usize,
+}
+
+/// A TrueType font loaded from a byte slice
+pub struct TrueTypeFont<'a> {
+    data: &'a [u8],
+    units_per_em: i32,
+    long_loca: bool,
+    num_glyphs: u16,
+    ascender: i32,
+    descender: i32,
+    line_gap: i32,
+    num_h_metrics: u16,
+    cmap: usize,
+    hmtx: usize,
+    loca: usize,
+    glyf: usize,
+    kern: Option<usize>,
+    gpos: Option<usize>,
+    cache: SpinMutex<BTreeMap<(GlyphId, u16), Arc<Glyph>>>,
+}
+
+impl<'a> TrueTypeFont<'a> {
+    /// Maximum number of glyphs kept rasterized
+    const MAX_CACHED_GLYPHS: usize = 1024;
+    /// Maximum nesting of composite glyphs
+    const MAX_COMPONENT_DEPTH: usize = 8;
+    /// Maximum number of components in a glyph, counting the nested ones
+    const MAX_COMPONENTS: usize = 64;
+    /// Largest size in pixels that is rasterized
+    const MAX_HEIGHT: isize = 512;
+
+    pub fn new(data: &'a [u8]) -> Result<Self, FontError> {
+        match data.u32_at(0) {
+            Some(0x0001_0000 | 0x7472_7565) => (),
+            // 'OTTO', 'ttcf'
+            Some(0x4F54_544F | 0x7474_6366) => return Err(FontError::Unsupported),
+            _ => return Err(FontError::InvalidData),
+        }
+        let table = |tag: &[u8; 4]| Self::find_table(data, u32::from_be_bytes(*tag));
+
+        let head = table(b"head").ok_or(FontError::InvalidData)?;
+        let maxp = table(b"maxp").ok_or(FontError::InvalidData)?;
+        let hhea = table(b"hhea").ok_or(FontError::InvalidData)?;
+        let cmap = table(b"cmap").ok_or(FontError::InvalidData)?;
+        let hmtx = table(b"hmtx").ok_or(FontError::InvalidData)?;
+        let loca = table(b"loca").ok_or(FontError::InvalidData)?;
+        let glyf = table(b"glyf").ok_or(FontError::InvalidData)?;
+
+        let units_per_em = data.u16_at(head + 18).ok_or(FontError::InvalidData)? as i32;
+        if !(16..=16384).contains(&units_per_em) {
+            return Err(FontError::InvalidData);
+        }
+        let long_loca = data.i16_at(head + 50).ok_or(FontError::InvalidData)? != 0;
+        let num_glyphs = data.u16_at(maxp + 4).ok_or(FontError::InvalidData)?;
+        let ascender = data.i16_at(hhea + 4).ok_or(FontError::InvalidData)? as i32;
+        let descender = data.i16_at(hhea + 6).ok_or(FontError::InvalidData)? as i32;
+        let line_gap = data.i16_at(hhea + 8).ok_or(FontError::InvalidData)? as i32;
+        let num_h_metrics = data.u16_at(hhea + 34).ok_or(FontError::InvalidData)?;
+        if num_h_metrics == 0 {
+            return Err(FontError::InvalidData);
+        }
+        let cmap = Self::find_cmap(data, cmap).ok_or(FontError::InvalidData)?;
+
+        Ok(Self {
+            data,
+            units_per_em,
+            long_loca,
+            num_glyphs,
+            ascender,
+            descender,
+            line_gap,
+            num_h_metrics,
+            cmap,
+            hmtx,
+            loca,
+            glyf,
+            kern: table(b"kern"),
+            gpos: table(b"GPOS"),
+            cache: SpinMutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn find_table(data: &[u8], tag: u32) -> Option<usize> {
+        let num_tables = data.u16_at(4)? as usize;
+        (0..num_tables)
+            .map(|i| 12 + i * 16)
+            .find(|&record| data.u32_at(record) == Some(tag))
+            .and_then(|record| {
+                let offset = data.u32_at(record + 8)? as usize;
+                let len = data.u32_at(record + 12)? as usize;
+                (offset.checked_add(len)? <= data.len()).then_some(offset)
+            })
+    }
+
+    /// Chooses the Unicode subtable, preferring format 12 for characters beyond the BMP.
+    fn find_cmap(data: &[u8], cmap: usize) -> Option<usize> {
+        let num_tables = data.u16_at(cmap + 2)? as usize;
+        let mut result = None;
+        for record in (0..num_tables).map(|i| cmap + 4 + i * 8) {
+            let platform = data.u16_at(record)?;
+            let encoding = data.u16_at(record + 2)?;
+            let subtable = cmap + data.u32_at(record + 4)? as usize;
+            let format = data.u16_at(subtable)?;
+            match (platform, encoding, format) {
+                (0, _, 12) | (3, 10, 12) => return Some(subtable),
+                (0, _, 4) | (3, 1, 4) => result = Some(subtable),
+                _ => (),
+            }
+        }
+        result
+    }
+
+    #[inline]
+    pub const fn units_per_em(&self) -> i32 {
+        self.units_per_em
+    }
+
+    #[inline]
+    pub const fn num_glyphs(&self) -> usize {
+        self.num_glyphs as usize
+    }
+
+    /// Returns the glyph of the character, or `None` if the font does not have it.
+    pub fn glyph_id(&self, character: char) -> Option<GlyphId> {
+        let data = self.data;
+        let c = character as u32;
+        let cmap = self.cmap;
+        let gid = match data.u16_at(cmap)? {
+            4 => {
+                if c > 0xFFFF {
+                    return None;
+                }
+                let seg_count = data.u16_at(cmap + 6)? as usize / 2;
+                let end_codes = cmap + 14;
+                let start_codes = end_codes + seg_count * 2 + 2;
+                let id_deltas = start_codes + seg_count * 2;
+                let id_range_offsets = id_deltas + seg_count * 2;
+                // The end codes are sorted.
+                let (mut lo, mut hi) = (0, seg_count);
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    if (data.u16_at(end_codes + mid * 2)? as u32) < c {
+                        lo = mid + 1;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                let seg = lo;
+                let start = data.u16_at(start_codes + seg * 2)? as u32;
+                if seg >= seg_count || c < start {
+                    return None;
+                }
+                let delta = data.u16_at(id_deltas + seg * 2)?;
+                let range_offset = data.u16_at(id_range_offsets + seg * 2)? as usize;
+                if range_offset == 0 {
+                    (c as u16).wrapping_add(delta)
+                } else {
+                    let offset =
+                        id_range_offsets + seg * 2 + range_offset + (c - start) as usize * 2;
+                    match data.u16_at(offset)? {
+                        0 => 0,
+                        gid => gid.wrapping_add(delta),
+                    }
+                }
+            }
+            12 => {
+                let num_groups = data.u32_at(cmap + 12)? as usize;
+                let (mut lo, mut hi) = (0, num_groups);
+                loop {
+                    if lo >= hi {
+                        return None;
+                    }
+                    let mid = (lo + hi) / 2;
+                    let group = cmap + 16 + mid * 12;
+                    let start = data.u32_at(group)?;
+                    let end = data.u32_at(group + 4)?;
+                    if c < start {
+                        hi = mid;
+                    } else if c > end {
+                        lo = mid + 1;
+                    } else {
+                        let gid = data.u32_at(group + 8)?.checked_add(c - start)?;
+                        break u16::try_from(gid).ok()?;
+                    }
+                }
+            }
+            _ => return None,
+        };
+        (gid != 0 && gid < self.num_glyphs).then_some(GlyphId(gid))
+    }
+
+    /// Returns the advance width in font units.
+    pub fn advance(&self, glyph: GlyphId) -> i32 {
+        let index = glyph.0.min(self.num_h_metrics - 1) as usize;
+        self.data.u16_at(self.hmtx + index * 4).unwrap_or(0) as i32
+    }
+
+    /// Returns the kerning between the pair in font units.
+    pub fn kerning(&self, left: GlyphId, right: GlyphId) -> i32 {
+        self.gpos
+            .and_then(|gpos| self.gpos_kerning(gpos, left, right))
+            .or_else(|| {
+                self.kern
+                    .and_then(|kern| self.kern_kerning(kern, left, right))
+            })
+            .unwrap_or(0)
+    }
+
+    /// Looks up the pair in the format 0 subtables of the `kern` table.
+    fn kern_kerning(&self, kern: usize, left: GlyphId, right: GlyphId) -> Option<i32> {
+        let data = self.data;
+        if data.u16_at(kern)? != 0 {
+            return None;
+        }
+        let key = ((left.0 as u32) << 16) | right.0 as u32;
+        let mut subtable = kern + 4;
+        for _ in 0..data.u16_at(kern + 2)? {
+            let len = data.u16_at(subtable + 2)? as usize;
+            let coverage = data.u16_at(subtable + 4)?;
+            // Horizontal kerning values in format 0
+            if (coverage & 0xFF07) == 0x0001 {
+                let num_pairs = data.u16_at(subtable + 6)? as usize;
+                let pairs = subtable + 14;
+                let (mut lo, mut hi) = (0, num_pairs);
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    let pair = pairs + mid * 6;
+                    let value = data.u32_at(pair)?;
+                    match value.cmp(&key) {
+                        core::cmp::Ordering::Less => lo = mid + 1,
+                        core::cmp::Ordering::Greater => hi = mid,
+                        core::cmp::Ordering::Equal => {
+                            return data.i16_at(pair + 4).map(|v| v as i32)
+                        }
+                    }
+                }
+            }
+            subtable += len;
+        }
+        None
+    }
+
+    /// Looks up the pair in the pair adjustment lookups of the `kern` feature.
+    fn gpos_kerning(&self, gpos: usize, left: GlyphId, right: GlyphId) -> Option<i32> {
+        let data = self.data;
+        let feature_list = gpos + data.u16_at(gpos + 6)? as usize;
+        let lookup_list = gpos + data.u16_at(gpos + 8)? as usize;
+        let mut found = false;
+        for feature in (0..data.u16_at(feature_list)? as usize).map(|i| feature_list + 2 + i * 6) {
+            if data.u32_at(feature)? != u32::from_be_bytes(*b"kern") {
+                continue;
+            }
+            let table = feature_list + data.u16_at(feature + 4)? as usize;
+            for i in 0..data.u16_at(table + 2)? as usize {
+                let index = data.u16_at(table + 4 + i * 2)? as usize;
+                let lookup = lookup_list + data.u16_at(lookup_list + 2 + index * 2)? as usize;
+                for j in 0..data.u16_at(lookup + 4)? as usize {
+                    let mut lookup_type = data.u16_at(lookup)?;
+                    let mut subtable = lookup + data.u16_at(lookup + 6 + j * 2)? as usize;
+                    if lookup_type == 9 {
+                        // Extension
+                        lookup_type = data.u16_at(subtable + 2)?;
+                        subtable += data.u32_at(subtable + 4)? as usize;
+                    }
+                    if lookup_type != 2 {
+                        continue;
+                    }
+                    found = true;
+                    if let Some(value) = self.pair_adjustment(subtable, left, right) {
+                        return Some(value);
+                    }
+                }
+            }
+        }
+        // The `kern` table is only a fallback for fonts without kerning in GPOS.
+        found.then_some(0)
+    }
+
+    fn pair_adjustment(&self, subtable: usize, left: GlyphId, right: GlyphId) -> Option<i32> {
+        let data = self.data;
+        let coverage = subtable + data.u16_at(subtable + 2)? as usize;
+        let coverage_index = self.coverage_index(coverage, left)?;
+        let format1 = data.u16_at(subtable + 4)?;
+        let format2 = data.u16_at(subtable + 6)?;
+        let size1 = format1.count_ones() as usize * 2;
+        let size2 = format2.count_ones() as usize * 2;
+        // XAdvance follows XPlacement and YPlacement in a value record.
+        let x_advance = |record: usize| -> Option<i32> {
+            if (format1 & 0x0004) == 0 {
+                return None;
+            }
+            let offset = (format1 & 0x0003).count_ones() as usize * 2;
+            data.i16_at(record + offset).map(|v| v as i32)
+        };
+
+        match data.u16_at(subtable)? {
+            1 => {
+                let pair_set = subtable + data.u16_at(subtable + 10 + coverage_index * 2)? as usize;
+                let record_size = 2 + size1 + size2;
+                let (mut lo, mut hi) = (0, data.u16_at(pair_set)? as usize);
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    let record = pair_set + 2 + mid * record_size;
+                    let second = data.u16_at(record)?;
+                    match second.cmp(&right.0) {
+                        core::cmp::Ordering::Less => lo = mid + 1,
+                        core::cmp::Ordering::Greater => hi = mid,
+                        core::cmp::Ordering::Equal => return x_advance(record + 2),
+                    }
+                }
+                None
+            }
+            2 => {
+                let class_def1 = subtable + data.u16_at(subtable + 8)? as usize;
+                let class_def2 = subtable + data.u16_at(subtable + 10)? as usize;
+                let class1_count = data.u16_at(subtable + 12)? as usize;
+                let class2_count = data.u16_at(subtable + 14)? as usize;
+                let class1 = self.class_of(class_def1, left)?;
+                let class2 = self.class_of(class_def2, right)?;
+                if class1 >= class1_count || class2 >= class2_count {
+                    return None;
+                }
+                let record = subtable + 16 + (class1 * class2_count + class2) * (size1 + size2);
+                x_advance(record).filter(|v| *v != 0)
+            }
+            _ => None,
+        }
+    }
+
+    fn coverage_index(&self, coverage: usize, glyph: GlyphId) -> Option<usize> {
+        let data = self.data;
+        let count = data.u16_at(coverage + 2)? as usize;
+        match data.u16_at(coverage)? {
+            1 => {
+                let (mut lo, mut hi) = (0, count);
+                while lo < hi {
+                    let mid = (lo + hi) / 2;
+                    let value = data.u16_at(coverage + 4 + mid * 2)?;
+                    match value.cmp(&glyph.0) {
+                        core::cmp::Ordering::Less => lo = mid + 1,
+                        core::cmp::Ordering::Greater => hi = mid,
+                        core::cmp::Ordering::Equal => return Some(mid),
+                    }
+                }
+                None
+            }
+            2 => (0..count).find_map(|i| {
+                let range = coverage + 4 + i * 6;
+                let start = data.u16_at(range)?;
+                let end = data.u16_at(range + 2)?;
+                (start..=end).contains(&glyph.0).then(|| {
+                    data.u16_at(range + 4)
+                        .map(|index| index as usize + (glyph.0 - start) as usize)
+                })?
+            }),
+            _ => None,
+        }
+    }
+
+    fn class_of(&self, class_def: usize, glyph: GlyphId) -> Option<usize> {
+        let data = self.data;
+        match data.u16_at(class_def)? {
+            1 => {
+                let start = data.u16_at(class_def + 2)?;
+                let count = data.u16_at(class_def + 4)?;
+                if glyph.0 < start || glyph.0 - start >= count {
+                    return Some(0);
+                }
+                data.u16_at(class_def + 6 + (glyph.0 - start) as usize * 2)
+                    .map(|v| v as usize)
+            }
+            2 => {
+                let count = data.u16_at(class_def + 2)? as usize;
+                for range in (0..count).map(|i| class_def + 4 + i * 6) {
+                    let start = data.u16_at(range)?;
+                    let end = data.u16_at(range + 2)?;
+                    if (start..=end).contains(&glyph.0) {
+                        return data.u16_at(range + 4).map(|v| v as usize);
+                    }
+                }
+                Some(0)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the range of the glyph in the `glyf` table.
+    fn glyph_range(&self, glyph: GlyphId) -> Option<(usize, usize)> {
+        let index = glyph.0 as usize;
+        let (start, end) = if self.long_loca {
+            (
+                self.data.u32_at(self.loca + index * 4)? as usize,
+                self.data.u32_at(self.loca + index * 4 + 4)? as usize,
+            )
+        } else {
+            (
+                self.data.u16_at(self.loca + index * 2)? as usize * 2,
+                self.data.u16_at(self.loca + index * 2 + 2)? as usize * 2,
+            )
+        };
+        (start < end).then_some((self.glyf + start, self.glyf + end))
+    }
+
+    fn outline(&self, glyph: GlyphId, outline: &mut Outline, depth: usize) -> Option<()> {
+        let Some((start, end)) = self.glyph_range(glyph) else {
+            // An empty glyph such as a space
+            return Some(());
+        };
+        let data = self.data.get(..end)?;
+        let num_contours = data.i16_at(start)?;
+        if num_contours >= 0 {
+            self.simple_outline(data, start + 10, num_contours as usize, outline)
+        } else if depth < Self::MAX_COMPONENT_DEPTH {
+            self.composite_outline(data, start + 10, outline, depth)
+        } else {
+            None
+        }
+    }
+
+    fn simple_outline(
+        &self,
+        data: &[u8],
+        start: usize,
+        num_contours: usize,
+        outline: &mut Outline,
+    ) -> Option<()> {
+        const ON_CURVE: u8 = 0x01;
+        const X_SHORT: u8 = 0x02;
+        const Y_SHORT: u8 = 0x04;
+        const REPEAT: u8 = 0x08;
+        const X_SAME_OR_POSITIVE: u8 = 0x10;
+        const Y_SAME_OR_POSITIVE: u8 = 0x20;
+
+        let base = outline.points.len();
+        let mut num_points = 0;
+        for i in 0..num_contours {
+            let end = data.u16_at(start + i * 2)? as usize + 1;
+            if end <= num_points {
+                return None;
+            }
+            num_points = end;
+            outline.ends.push(base + end);
+        }
+        let instructions = start + num_contours * 2;
+        let mut cursor = instructions + 2 + data.u16_at(instructions)? as usize;
+
+        let mut flags = Vec::with_capacity(num_points);
+        while flags.len() < num_points {
+            let flag = data.u8_at(cursor)?;
+            cursor += 1;
+            let repeat = if (flag & REPEAT) != 0 {
+                cursor += 1;
+                data.u8_at(cursor - 1)? as usize
+            } else {
+                0
+            };
+            for _ in 0..=repeat.min(num_points - flags.len() - 1) {
+                flags.push(flag);
+            }
+        }
+
+        let mut x = 0i32;
+        for &flag in &flags {
+            if (flag & X_SHORT) != 0 {
+                let dx = data.u8_at(cursor)? as i32;
+                cursor += 1;
+                x += if (flag & X_SAME_OR_POSITIVE) != 0 {
+                    dx
+                } else {
+                    -dx
+                };
+            } else if (flag & X_SAME_OR_POSITIVE) == 0 {
+                x += data.i16_at(cursor)? as i32;
+                cursor += 2;
+            }
+            outline.points.push(OutlinePoint {
+                x,
+                y: 0,
+                on_curve: (flag & ON_CURVE) != 0,
+            });
+        }
+        let mut y = 0i32;
+        for (index, &flag) in flags.iter().enumerate() {
+            if (flag & Y_SHORT) != 0 {
+                let dy = data.u8_at(cursor)? as i32;
+                cursor += 1;
+                y += if (flag & Y_SAME_OR_POSITIVE) != 0 {
+                    dy
+                } else {
+                    -dy
+                };
+            } else if (flag & Y_SAME_OR_POSITIVE) == 0 {
+                y += data.i16_at(cursor)? as i32;
+                cursor += 2;
+            }
+            outline.points[base + index].y = y;
+        }
+        Some(())
+    }
+
+    fn composite_outline(
+        &self,
+        data: &[u8],
+        mut cursor: usize,
+        outline: &mut Outline,
+        depth: usize,
+    ) -> Option<()> {
+        const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+        const ARGS_ARE_XY_VALUES: u16 = 0x0002;
+        const WE_HAVE_A_SCALE: u16 = 0x0008;
+        const MORE_COMPONENTS: u16 = 0x0020;
+        const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+        const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+        /// 1.0 in F2Dot14
+        const ONE: i32 = 0x4000;
+
+        loop {
+            outline.components += 1;
+            if outline.components > Self::MAX_COMPONENTS {
+                return None;
+            }
+            let flags = data.u16_at(cursor)?;
+            let glyph = GlyphId(data.u16_at(cursor + 2)?);
+            cursor += 4;
+            let (arg1, arg2) = if (flags & ARG_1_AND_2_ARE_WORDS) != 0 {
+                cursor += 4;
+                (
+                    data.i16_at(cursor - 4)? as i32,
+                    data.i16_at(cursor - 2)? as i32,
+                )
+            } else {
+                cursor += 2;
+                (
+                    data.u8_at(cursor - 2)? as i8 as i32,
+                    data.u8_at(cursor - 1)? as i8 as i32,
+                )
+            };
+            let mut matrix = [ONE, 0, 0, ONE];
+            if (flags & WE_HAVE_A_SCALE) != 0 {
+                let scale = data.i16_at(cursor)? as i32;
+                matrix = [scale, 0, 0, scale];
+                cursor += 2;
+            } else if (flags & WE_HAVE_AN_X_AND_Y_SCALE) != 0 {
+                matrix = [
+                    data.i16_at(cursor)? as i32,
+                    0,
+                    0,
+                    data.i16_at(cursor + 2)? as i32,
+                ];
+                cursor += 4;
+            } else if (flags & WE_HAVE_A_TWO_BY_TWO) != 0 {
+                for (index, value) in matrix.iter_mut().enumerate() {
+                    *value = data.i16_at(cursor + index * 2)? as i32;
+                }
+                cursor += 8;
+            }
+            // Anchoring components by matching points is not supported.
+            let (dx, dy) = if (flags & ARGS_ARE_XY_VALUES) != 0 {
+                (arg1, arg2)
+            } else {
+                (0, 0)
+            };
+
+            let base = outline.points.len();
+            self.outline(glyph, outline, depth + 1)?;
+            // Keeps nested transforms in a range the rasterizer scales without overflow.
+            let transform = |a: i32, b: i32, x: i32, y: i32, d: i32| {
+                (((a as i64 * x as i64 + b as i64 * y as i64) >> 14) + d as i64)
+                    .clamp(i16::MIN as i64 * 4, i16::MAX as i64 * 4) as i32
+            };
+            for point in &mut outline.points[base..] {
+                let (x, y) = (point.x, point.y);
+                point.x = transform(matrix[0], matrix[2], x, y, dx);
+                point.y = transform(matrix[1], matrix[3], x, y, dy);
+            }
+
+            if (flags & MORE_COMPONENTS) == 0 {
+                return Some(());
+            }
+        }
+    }
+
+    /// Returns the glyph rasterized at the height in pixels, from the cache if possible.
+    pub fn glyph(&self, glyph: GlyphId, height: isize) -> Option<Arc<Glyph>> {
+        if height <= 0 || height > Self::MAX_HEIGHT {
+            return None;
+        }
+        let key = (glyph, height as u16);
+        if let Some(glyph) = self.cache.lock().get(&key) {
+            return Some(glyph.clone());
+        }
+
+        let mut outline = Outline::default();
+        self.outline(glyph, &mut outline, 0)?;
+        let glyph = Arc::new(Rasterizer::rasterize(&outline, height, self.units_per_em)?);
+
+        let mut cache = self.cache.lock();
+        if cache.len() >= Self::MAX_CACHED_GLYPHS {
+            cache.clear();
+        }
+        cache.insert(key, glyph.clone());
+        Some(glyph)
+    }
+
+    /// Scales a value in font units to pixels at the height.
+    #[inline]
+    fn scale(&self, value: i32, height: isize) -> isize {
+        let value = value as isize * height;
+        let em = self.units_per_em as isize;
+        (value + value.signum() * em / 2) / em
+    }
+}
+
+/// Scanline rasterizer with exact horizontal coverage and vertical supersampling
+struct Rasterizer {
+    /// Edges in pixels with [Rasterizer::FRAC] fractional bits
+    edges: Vec<(i32, i32, i32, i32)>,
+}
+
+impl Rasterizer {
+    const FRAC: i32 = 8;
+    const ONE: i32 = 1 << Self::FRAC;
+    /// Sub-scanlines in a pixel
+    const SUBSAMPLES: i32 = 5;
+    /// Largest width or height of a glyph in multiples of the pixel height
+    const MAX_EXTENT: usize = 4;
+
+    /// Rasterizes the outline, or returns `None` if it reaches too far out of the em box.
+    fn rasterize(outline: &Outline, height: isize, units_per_em: i32) -> Option<Glyph> {
+        let scale = |v: i32| -> i32 {
+            ((v as i64 * height as i64 * Self::ONE as i64) / units_per_em as i64) as i32
+        };
+        let points = outline
+            .points
+            .iter()
+            .map(|p| OutlinePoint {
+                x: scale(p.x),
+                // Downward in pixels
+                y: -scale(p.y),
+                on_curve: p.on_curve,
+            })
+            .collect::<Vec<_>>();
+
+        let empty = Glyph {
+            left: 0,
+            top: 0,
+            size: Size::new(0, 0),
+            coverage: Vec::new(),
+        };
+        if points.is_empty() {
+            return Some(empty);
+        }
+        let left = points.iter().map(|p| p.x).min().unwrap() >> Self::FRAC;
+        let top = points.iter().map(|p| p.y).min().unwrap() >> Self::FRAC;
+        let right = (points.iter().map(|p| p.x).max().unwrap() + Self::ONE - 1) >> Self::FRAC;
+        let bottom = (points.iter().map(|p| p.y).max().unwrap() + Self::ONE - 1) >> Self::FRAC;
+        let width = (right - left).max(1) as usize;
+        let rows = (bottom - top).max(1) as usize;
+        let max_extent = height as usize * Self::MAX_EXTENT;
+        if width > max_extent || rows > max_extent {
+            return None;
+        }
+
+        let mut rasterizer = Self { edges: Vec::new() };
+        let origin = (left << Self::FRAC, top << Self::FRAC);
+        let mut start = 0;
+        for &end in &outline.ends {
+            if end > start {
+                rasterizer.add_contour(&points[start..end], origin);
+            }
+            start = end;
+        }
+
+        let mut coverage = vec![0u8; width.checked_mul(rows)?];
+        let mut accum = Vec::new();
+        let mut crossings = Vec::new();
+        for (row, coverage) in coverage.chunks_exact_mut(width).enumerate() {
+            accum.clear();
+            accum.resize(width + 1, 0i32);
+            for sub in 0..Self::SUBSAMPLES {
+                let y = row as i32 * Self::ONE + (sub * 2 + 1) * Self::ONE / (Self::SUBSAMPLES * 2);
+                rasterizer.fill_scanline(y, width, &mut accum, &mut crossings);
+            }
+            for (pixel, value) in coverage.iter_mut().zip(accum.iter()) {
+                *pixel = (value * 255 / (Self::ONE * Self::SUBSAMPLES)).clamp(0, 255) as u8;
+            }
+        }
+
+        Some(Glyph {
+            left: left as isize,
+            top: top as isize,
+            size: Size::new(width as isize, rows as isize),
+            coverage,
+        })
+    }
+
+    /// Adds a contour, inserting the implied on-curve points between off-curve points.
+    fn add_contour(&mut self, points: &[OutlinePoint], origin: (i32, i32)) {
+        let point = |p: &OutlinePoint| (p.x - origin.0, p.y - origin.1);
+        let midpoint = |a: (i32, i32), b: (i32, i32)| ((a.0 + b.0) / 2, (a.1 + b.1) / 2);
+
+        let len = points.len();
+        let first_on = points.iter().position(|p| p.on_curve);
+        let start = match first_on {
+            Some(index) => point(&points[index]),
+            // All points are off-curve.
+            None => midpoint(point(&points[0]), point(&points[len - 1])),
+        };
+        let offset = first_on.unwrap_or(0);
+
+        let mut current = start;
+        let mut control = None;
+        for i in 1..=len {
+            let p = &points[(offset + i) % len];
+            let next = point(p);
+            match (p.on_curve, control) {
+                (true, None) => {
+                    self.add_line(current, next);
+                    current = next;
+                }
+                (true, Some(c)) => {
+                    self.add_quad(current, c, next);
+                    current = next;
+                    control = None;
+                }
+                (false, None) => control = Some(next),
+                (false, Some(c)) => {
+                    let mid = midpoint(c, next);
+                    self.add_quad(current, c, mid);
+                    current = mid;
+                    control = Some(next);
+                }
+            }
+        }
+        match control {
+            Some(c) => self.add_quad(current, c, start),
+            None => self.add_line(current, start),
+        }
+    }
+
+    #[inline]
+    fn add_line(&mut self, p0: (i32, i32), p1: (i32, i32)) {
+        if p0.1 != p1.1 {
+            self.edges.push((p0.0, p0.1, p1.0, p1.1));
+        }
+    }
+
+    /// Flattens a quadratic curve into lines.
+    fn add_quad(&mut self, p0: (i32, i32), p1: (i32, i32), p2: (i32, i32)) {
+        let dx = (p0.0 - 2 * p1.0 + p2.0).abs();
+        let dy = (p0.1 - 2 * p1.1 + p2.1).abs();
+        // The error of n segments is about the deviation / (4 * n^2).
+        let deviation = (dx.max(dy) >> Self::FRAC) as usize;
+        let mut n = 1;
+        while n < 16 && n * n < deviation * 4 {
+            n += 1;
+        }
+        let n = n as i64;
+        let mut prev = p0;
+        for i in 1..=n {
+            let (a, b, c) = ((n - i) * (n - i), 2 * i * (n - i), i * i);
+            let eval = |v0: i32, v1: i32, v2: i32| {
+                ((a * v0 as i64 + b * v1 as i64 + c * v2 as i64) / (n * n)) as i32
+            };
+            let next = (eval(p0.0, p1.0, p2.0), eval(p0.1, p1.1, p2.1));
+            self.add_line(prev, next);
+            prev = next;
+        }
+    }
+
+    /// Accumulates the coverage of a sub-scanline with the non-zero winding rule.
+    fn fill_scanline(
+        &self,
+        y: i32,
+        width: usize,
+        accum: &mut [i32],
+        crossings: &mut Vec<(i32, i32)>,
+    ) {
+        crossings.clear();
+        for &(x0, y0, x1, y1) in &self.edges {
+            let (winding, top, bottom) = if y0 < y1 { (1, y0, y1) } else { (-1, y1, y0) };
+            if y < top || y >= bottom {
+                continue;
+            }
+            let x = x0 as i64 + (y - y0) as i64 * (x1 - x0) as i64 / (y1 - y0) as i64;
+            crossings.push((x as i32, winding));
+        }
+        crossings.sort_unstable_by_key(|v| v.0);
+
+        let limit = width as i32 * Self::ONE;
+        let mut winding = 0;
+        let mut span_start = 0;
+        for &(x, dir) in crossings.iter() {
+            let prev = winding;
+            winding += dir;
+            if prev == 0 && winding != 0 {
+                span_start = x;
+            } else if prev != 0 && winding == 0 {
+                Self::add_span(accum, span_start.clamp(0, limit), x.clamp(0, limit));
+            }
+        }
+    }
+
+    fn add_span(accum: &mut [i32], start: i32, end: i32) {
+        if start >= end {
+            return;
+        }
+        let first = (start >> Self::FRAC) as usize;
+        let last = (end >> Self::FRAC) as usize;
+        let mask = Self::ONE - 1;
+        if first == last {
+            accum[first] += end - start;
+            return;
+        }
+        accum[first] += Self::ONE - (start & mask);
+        for value in &mut accum[first + 1..last] {
+            *value += Self::ONE;
+        }
+        accum[last] += end & mask;
+    }
+}
+
+/// Font driver drawing a [TrueTypeFont] at a base height
+pub struct TrueTypeFontDriver<'a> {
+    font: &'a TrueTypeFont<'a>,
+    height: isize,
+}
+
+impl<'a> TrueTypeFontDriver<'a> {
+    #[inline]
+    pub const fn new(font: &'a TrueTypeFont<'a>, height: isize) -> Self {
+        Self { font, height }
+    }
+
+    /// Returns a driver of the same font at another height sharing the glyph cache.
+    #[inline]
+    pub const fn with_height(&self, height: isize) -> Self {
+        Self {
+            font: self.font,
+            height,
+        }
+    }
+
+    #[inline]
+    pub const fn font(&self) -> &'a TrueTypeFont<'a> {
+        self.font
+    }
+
+    #[inline]
+    fn glyph_id(&self, character: char) -> GlyphId {
+        self.font.glyph_id(character).unwrap_or(GlyphId::NOTDEF)
+    }
+
+    /// Returns the distance from the top of the line to the baseline.
+    #[inline]
+    fn baseline(&self, height: isize) -> isize {
+        let font = self.font;
+        font.scale(font.line_gap.max(0) / 2 + font.ascender, height)
+    }
+}
+
+impl FontDriver for TrueTypeFontDriver<'_> {
+    #[inline]
+    fn is_scalable(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn has_glyph(&self, character: char) -> bool {
+        self.font.glyph_id(character).is_some()
+    }
+
+    #[inline]
+    fn base_height(&self) -> isize {
+        self.height
+    }
+
+    fn preferred_line_height(&self) -> isize {
+        let font = self.font;
+        font.scale(
+            font.ascender - font.descender + font.line_gap.max(0),
+            self.height,
+        )
+    }
+
+    fn width_of(&self, character: char) -> isize {
+        self.font
+            .scale(self.font.advance(self.glyph_id(character)), self.height)
+    }
+
+    fn kern(&self, first: char, second: char) -> isize {
+        let kerning = self
+            .font
+            .kerning(self.glyph_id(first), self.glyph_id(second));
+        self.font.scale(kerning, self.height)
+    }
+
+    fn draw_char(
+        &self,
+        character: char,
+        bitmap: &mut Bitmap,
+        origin: Point,
+        height: isize,
+        color: Color,
+    ) {
+        let Some(glyph) = self.font.glyph(self.glyph_id(character), height) else {
+            return;
+        };
+        let origin = Point::new(
+            origin.x + glyph.left,
+            origin.y + self.baseline(height) + glyph.top,
+        );
+        bitmap.blend_alpha_mask(&glyph.coverage, glyph.size, origin, color);
+    }
+}
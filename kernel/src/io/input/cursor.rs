@@ -0,0 +1,131 @@
+//! The mouse pointer on the main screen
+//!
+//! The pointer is drawn by inverting the pixels under it, so that drawing it twice
+//! erases it. It appears once a pointing device moves.
+
+use super::{InputSubscriber, PointerEvent};
+use crate::{
+    drawing::*,
+    sync::spinlock::{SpinMutex, SpinMutexGuard},
+    system::System,
+};
+
+/// The pointer of the main screen
+pub static POINTER: PointerCursor = PointerCursor::new();
+
+pub struct PointerCursor {
+    state: SpinMutex<CursorState>,
+}
+
+struct CursorState {
+    position: Point,
+    visible: bool,
+    drawn: bool,
+}
+
+impl PointerCursor {
+    /// The shape of the pointer, a row for each line with the leftmost pixel in the MSB
+    const SHAPE: [u16; 16] = [
+        0x8000, 0xC000, 0xE000, 0xF000, 0xF800, 0xFC00, 0xFE00, 0xFF00, 0xFF80, 0xFFC0, 0xFC00,
+        0xEE00, 0xCE00, 0x8700, 0x0700, 0x0300,
+    ];
+
+    const fn new() -> Self {
+        Self {
+            state: SpinMutex::new(CursorState {
+                position: Point::new(0, 0),
+                visible: false,
+                drawn: false,
+            }),
+        }
+    }
+
+    /// Returns the position of the pointer on the main screen.
+    #[inline]
+    pub fn position(&self) -> Point {
+        self.state.lock().position
+    }
+
+    /// Erases the pointer before the screen is drawn over, and draws it again when the
+    /// returned guard is dropped.
+    ///
+    /// The pointer does not move until then, as pointer events wait for the guard.
+    pub fn hide(&self) -> PointerHidden<'_> {
+        let mut state = self.state.lock();
+        if state.drawn {
+            if let Some(mut bitmap) = System::main_screen() {
+                Self::invert(&mut bitmap, state.position);
+            }
+            state.drawn = false;
+        }
+        PointerHidden { state }
+    }
+
+    fn invert(bitmap: &mut Bitmap, origin: Point) {
+        for (y, row) in Self::SHAPE.iter().enumerate() {
+            for x in 0..16 {
+                if (row & (0x8000 >> x)) == 0 {
+                    continue;
+                }
+                let point = Point::new(origin.x + x, origin.y + y as isize);
+                match bitmap.get_pixel(point) {
+                    Some(Color::Indexed(v)) => {
+                        bitmap.set_pixel(point, Color::Indexed(IndexedColor(v.0 ^ 0x0F)))
+                    }
+                    Some(Color::Argb32(v)) => {
+                        bitmap.set_pixel(point, Color::from_argb(v.argb() ^ 0x00FF_FFFF))
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+impl InputSubscriber for PointerCursor {
+    fn pointer_event(&self, event: &PointerEvent) {
+        let Some(mut bitmap) = System::main_screen() else {
+            return;
+        };
+        let size = bitmap.size();
+        let mut state = self.state.lock();
+        if state.drawn {
+            Self::invert(&mut bitmap, state.position);
+        }
+
+        let (x, y) = if event.absolute {
+            (
+                event.x as isize * (size.width() - 1) / PointerEvent::ABS_MAX as isize,
+                event.y as isize * (size.height() - 1) / PointerEvent::ABS_MAX as isize,
+            )
+        } else {
+            (
+                state.position.x + event.x as isize,
+                state.position.y + event.y as isize,
+            )
+        };
+        state.position = Point::new(x.clamp(0, size.width() - 1), y.clamp(0, size.height() - 1));
+
+        Self::invert(&mut bitmap, state.position);
+        state.visible = true;
+        state.drawn = true;
+    }
+}
+
+/// The pointer erased by [PointerCursor::hide]
+#[must_use]
+pub struct PointerHidden<'a> {
+    state: SpinMutexGuard<'a, CursorState>,
+}
+
+impl Drop for PointerHidden<'_> {
+    fn drop(&mut self) {
+        let state = &mut *self.state;
+        if state.visible && !state.drawn {
+            if let Some(mut bitmap) = System::main_screen() {
+                PointerCursor::invert(&mut bitmap, state.position);
+                state.drawn = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Keymaps, which give the characters of the keys
+//!
+//! The US and JIS layouts are built in, and others can be loaded from a text file with a
+//! line for each key: the usage ID in hexadecimal, the character, and optionally the
+//! character with Shift. A character is written as itself or as `U+XXXX`.
+//!
+//! ```text
+//! name de
+//! base us
+//! 0x1C z Z
+//! 0x1D y Y
+//! ```
+
+use super::{Modifiers, Usage};
+use crate::{
+    mem::fixedvec::FixedVec,
+    shell::{ShellCommand, ShellError},
+    sync::spinlock::SpinMutex,
+};
+use alloc::{boxed::Box, string::String};
+use core::fmt::Write;
+
+/// Keymaps loaded at runtime
+static LOADED: SpinMutex<FixedVec<Option<&'static Keymap>, 8>> =
+    SpinMutex::new(FixedVec::new(None));
+
+/// A table of characters indexed by key usage, without and with Shift
+type KeyTable = [[char; 2]; Keymap::NUM_USAGES];
+
+pub static US: Keymap = Keymap::new("us", US_TABLE);
+
+pub static JIS: Keymap = Keymap::new("jis", Keymap::table(&US_TABLE, &JIS_KEYS));
+
+const BLANK: KeyTable = [[Keymap::NO_CHAR; 2]; Keymap::NUM_USAGES];
+
+const US_TABLE: KeyTable = Keymap::table(&BLANK, &US_KEYS);
+
+const US_KEYS: [(u8, char, char); 69] = [
+    (0x04, 'a', 'A'),
+    (0x05, 'b', 'B'),
+    (0x06, 'c', 'C'),
+    (0x07, 'd', 'D'),
+    (0x08, 'e', 'E'),
+    (0x09, 'f', 'F'),
+    (0x0A, 'g', 'G'),
+    (0x0B, 'h', 'H'),
+    (0x0C, 'i', 'I'),
+    (0x0D, 'j', 'J'),
+    (0x0E, 'k', 'K'),
+    (0x0F, 'l', 'L'),
+    (0x10, 'm', 'M'),
+    (0x11, 'n', 'N'),
+    (0x12, 'o', 'O'),
+    (0x13, 'p', 'P'),
+    (0x14, 'q', 'Q'),
+    (0x15, 'r', 'R'),
+    (0x16, 's', 'S'),
+    (0x17, 't', 'T'),
+    (0x18, 'u', 'U'),
+    (0x19, 'v', 'V'),
+    (0x1A, 'w', 'W'),
+    (0x1B, 'x', 'X'),
+    (0x1C, 'y', 'Y'),
+    (0x1D, 'z', 'Z'),
+    (0x1E, '1', '!'),
+    (0x1F, '2', '@'),
+    (0x20, '3', '#'),
+    (0x21, '4', '$'),
+    (0x22, '5', '%'),
+    (0x23, '6', '^'),
+    (0x24, '7', '&'),
+    (0x25, '8', '*'),
+    (0x26, '9', '('),
+    (0x27, '0', ')'),
+    (0x28, '\r', '\r'),
+    (0x29, '\x1b', '\x1b'),
+    (0x2A, '\x7f', '\x7f'),
+    (0x2B, '\t', '\t'),
+    (0x2C, ' ', ' '),
+    (0x2D, '-', '_'),
+    (0x2E, '=', '+'),
+    (0x2F, '[', '{'),
+    (0x30, ']', '}'),
+    (0x31, '\\', '|'),
+    (0x32, '#', '~'),
+    (0x33, ';', ':'),
+    (0x34, '\'', '"'),
+    (0x35, '`', '~'),
+    (0x36, ',', '<'),
+    (0x37, '.', '>'),
+    (0x38, '/', '?'),
+    // Keypad
+    (0x54, '/', '/'),
+    (0x55, '*', '*'),
+    (0x56, '-', '-'),
+    (0x57, '+', '+'),
+    (0x58, '\r', '\r'),
+    (0x59, '1', '1'),
+    (0x5A, '2', '2'),
+    (0x5B, '3', '3'),
+    (0x5C, '4', '4'),
+    (0x5D, '5', '5'),
+    (0x5E, '6', '6'),
+    (0x5F, '7', '7'),
+    (0x60, '8', '8'),
+    (0x61, '9', '9'),
+    (0x62, '0', '0'),
+    (0x63, '.', '.'),
+];
+
+/// The keys of JIS that differ from US
+const JIS_KEYS: [(u8, char, char); 17] = [
+    (0x1F, '2', '"'),
+    (0x23, '6', '&'),
+    (0x24, '7', '\''),
+    (0x25, '8', '('),
+    (0x26, '9', ')'),
+    (0x27, '0', Keymap::NO_CHAR),
+    (0x2D, '-', '='),
+    (0x2E, '^', '~'),
+    (0x2F, '@', '`'),
+    (0x30, '[', '{'),
+    (0x31, ']', '}'),
+    (0x32, ']', '}'),
+    (0x33, ';', '+'),
+    (0x34, ':', '*'),
+    // Hankaku/Zenkaku
+    (0x35, Keymap::NO_CHAR, Keymap::NO_CHAR),
+    (0x87, '\\', '_'),
+    (0x89, '\\', '|'),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapError {
+    /// The line has an invalid usage or character.
+    InvalidLine(usize),
+    /// The name is missing or taken.
+    InvalidName,
+    UnknownBase,
+    /// No more keymaps can be loaded.
+    TooMany,
+}
+
+pub struct Keymap {
+    name: &'static str,
+    table: KeyTable,
+}
+
+impl Keymap {
+    /// Keys up to International9
+    pub const NUM_USAGES: usize = 0x90;
+
+    const NO_CHAR: char = '\0';
+
+    const fn new(name: &'static str, table: KeyTable) -> Self {
+        Self { name, table }
+    }
+
+    const fn table(base: &KeyTable, keys: &[(u8, char, char)]) -> KeyTable {
+        let mut table = *base;
+        let mut index = 0;
+        while index < keys.len() {
+            let (usage, normal, shifted) = keys[index];
+            table[usage as usize] = [normal, shifted];
+            index += 1;
+        }
+        table
+    }
+
+    #[inline]
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the character of the key with the modifiers.
+    ///
+    /// Caps Lock applies to the letters, and Control gives the control characters.
+    pub fn translate(&self, usage: Usage, modifiers: Modifiers) -> Option<char> {
+        let [normal, shifted] = *self.table.get(usage.0 as usize)?;
+        if normal == Self::NO_CHAR {
+            return None;
+        }
+        let shift = modifiers.has_shift()
+            ^ (modifiers.contains(Modifiers::CAPS_LOCK) && normal.is_ascii_lowercase());
+        let c = if shift && shifted != Self::NO_CHAR {
+            shifted
+        } else {
+            normal
+        };
+        if !modifiers.has_ctrl() {
+            return Some(c);
+        }
+        Some(match c {
+            '@'..='_' | 'a'..='z' => ((c as u8) & 0x1F) as char,
+            ' ' => '\0',
+            '?' => '\x7f',
+            _ => c,
+        })
+    }
+
+    /// Finds the key that types the character, and returns its usage and whether it needs
+    /// Shift.
+    pub fn find(&self, character: char) -> Option<(Usage, bool)> {
+        if character == Self::NO_CHAR {
+            return None;
+        }
+        self.table.iter().enumerate().find_map(|(usage, keys)| {
+            keys.iter()
+                .position(|v| *v == character)
+                .map(|shift| (Usage(usage as u8), shift != 0))
+        })
+    }
+
+    /// Returns the built-in and loaded keymaps.
+    pub fn all() -> impl Iterator<Item = &'static Keymap> {
+        let mut loaded = [None; 8];
+        for (slot, keymap) in loaded.iter_mut().zip(LOADED.lock().iter()) {
+            *slot = *keymap;
+        }
+        [&US, &JIS].into_iter().chain(loaded.into_iter().flatten())
+    }
+
+    #[inline]
+    pub fn find_by_name(name: &str) -> Option<&'static Keymap> {
+        Self::all().find(|v| v.name() == name)
+    }
+
+    /// Loads a keymap from its text form, and makes it available by its name.
+    pub fn load(text: &str) -> Result<&'static Keymap, KeymapError> {
+        let mut name = None;
+        let mut table = BLANK;
+        for (index, line) in text.lines().enumerate() {
+            let line_error = KeymapError::InvalidLine(index + 1);
+            let mut words = line.split_ascii_whitespace();
+            match words.next() {
+                None => (),
+                Some(word) if word.starts_with('#') => (),
+                Some("name") => name = Some(words.next().ok_or(KeymapError::InvalidName)?),
+                Some("base") => {
+                    let base = words.next().ok_or(line_error)?;
+                    table = Self::find_by_name(base)
+                        .ok_or(KeymapError::UnknownBase)?
+                        .table;
+                }
+                Some(usage) => {
+                    let usage = usage
+                        .strip_prefix("0x")
+                        .and_then(|v| u8::from_str_radix(v, 16).ok())
+                        .filter(|v| (*v as usize) < Self::NUM_USAGES)
+                        .ok_or(line_error)?;
+                    let normal = words.next().and_then(Self::parse_char).ok_or(line_error)?;
+                    let shifted = match words.next() {
+                        Some(word) => Self::parse_char(word).ok_or(line_error)?,
+                        None => normal,
+                    };
+                    table[usage as usize] = [normal, shifted];
+                }
+            }
+        }
+
+        let name = name.ok_or(KeymapError::InvalidName)?;
+        let mut loaded = LOADED.lock();
+        if [&US, &JIS]
+            .iter()
+            .chain(loaded.iter().flatten())
+            .any(|v| v.name() == name)
+        {
+            return Err(KeymapError::InvalidName);
+        }
+        let name = Box::leak(String::from(name).into_boxed_str());
+        let keymap = Box::leak(Box::new(Self::new(name, table)));
+        loaded
+            .push(Some(keymap))
+            .map_err(|_| KeymapError::TooMany)?;
+        Ok(keymap)
+    }
+
+    fn parse_char(word: &str) -> Option<char> {
+        if let Some(hex) = word.strip_prefix("U+") {
+            return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+        }
+        let mut chars = word.chars();
+        let c = chars.next()?;
+        chars.next().is_none().then_some(c)
+    }
+}
+
+struct KeymapCommand;
+
+impl ShellCommand for KeymapCommand {
+    fn name(&self) -> &'static str {
+        "keymap"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[name]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the keymaps or select one"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        match args {
+            [] => {
+                let current = super::InputManager::keymap();
+                for keymap in Keymap::all() {
+                    let mark = if core::ptr::eq(keymap, current) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    writeln!(out, "{} {}", mark, keymap.name())?;
+                }
+                Ok(())
+            }
+            [name] => {
+                let keymap = Keymap::find_by_name(name).ok_or(ShellError::InvalidArgument)?;
+                super::InputManager::set_keymap(keymap);
+                Ok(())
+            }
+            _ => Err(ShellError::Usage),
+        }
+    }
+}
+
+crate::shell_command! {
+    static KEYMAP: KeymapCommand = KeymapCommand;
+}
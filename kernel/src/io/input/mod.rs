@@ -0,0 +1,368 @@
+//! Input events
+//!
+//! Keyboards, pointing devices and the serial console post their input to
+//! [InputManager], which turns key presses into [KeyEvent]s with the modifiers and the
+//! character of the current [Keymap], repeats held keys, and delivers the events to the
+//! registered [InputSubscriber]s. The devices have no interrupts yet, so
+//! [InputManager::poll] polls them.
+
+use self::{keymap::Keymap, serial::SerialDecoder};
+use crate::{
+    arch::timer::GenericTimer,
+    io::{usb::Usb, virtio::Virtio},
+    mem::fixedvec::FixedVec,
+    param::UintParam,
+    sync::spinlock::SpinMutex,
+    system::System,
+};
+use bitflags::*;
+
+pub mod cursor;
+pub mod keymap;
+mod serial;
+
+crate::kernel_param! {
+    static REPEAT_DELAY: UintParam = UintParam::new(
+        "input.repeat_delay",
+        500,
+        100,
+        5000,
+        "Time in milliseconds until a held key repeats",
+    );
+}
+
+crate::kernel_param! {
+    static REPEAT_INTERVAL: UintParam = UintParam::new(
+        "input.repeat_interval",
+        33,
+        0,
+        1000,
+        "Interval of the key repeat in milliseconds, or 0 to disable it",
+    );
+}
+
+/// Maximum number of subscribers
+const MAX_SUBSCRIBERS: usize = 8;
+
+static SUBSCRIBERS: SpinMutex<FixedVec<Option<&'static dyn InputSubscriber>, MAX_SUBSCRIBERS>> =
+    SpinMutex::new(FixedVec::new(None));
+
+static STATE: SpinMutex<InputState> = SpinMutex::new(InputState::new());
+
+/// A key in the keyboard page (0x07) of the HID usage tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Usage(pub u8);
+
+impl Usage {
+    pub const NONE: Self = Self(0x00);
+    pub const KEY_A: Self = Self(0x04);
+    pub const KEY_Z: Self = Self(0x1D);
+    pub const KEY_1: Self = Self(0x1E);
+    pub const KEY_0: Self = Self(0x27);
+    pub const ENTER: Self = Self(0x28);
+    pub const ESCAPE: Self = Self(0x29);
+    pub const BACKSPACE: Self = Self(0x2A);
+    pub const TAB: Self = Self(0x2B);
+    pub const SPACE: Self = Self(0x2C);
+    pub const CAPS_LOCK: Self = Self(0x39);
+    pub const F1: Self = Self(0x3A);
+    pub const F2: Self = Self(0x3B);
+    pub const F3: Self = Self(0x3C);
+    pub const F4: Self = Self(0x3D);
+    pub const F5: Self = Self(0x3E);
+    pub const F6: Self = Self(0x3F);
+    pub const F7: Self = Self(0x40);
+    pub const F8: Self = Self(0x41);
+    pub const F9: Self = Self(0x42);
+    pub const F10: Self = Self(0x43);
+    pub const F11: Self = Self(0x44);
+    pub const F12: Self = Self(0x45);
+    pub const INSERT: Self = Self(0x49);
+    pub const HOME: Self = Self(0x4A);
+    pub const PAGE_UP: Self = Self(0x4B);
+    pub const DELETE: Self = Self(0x4C);
+    pub const END: Self = Self(0x4D);
+    pub const PAGE_DOWN: Self = Self(0x4E);
+    pub const RIGHT: Self = Self(0x4F);
+    pub const LEFT: Self = Self(0x50);
+    pub const DOWN: Self = Self(0x51);
+    pub const UP: Self = Self(0x52);
+    /// The Ro key of the JIS keyboard
+    pub const INTERNATIONAL1: Self = Self(0x87);
+    /// The Yen key of the JIS keyboard
+    pub const INTERNATIONAL3: Self = Self(0x89);
+    pub const LEFT_CTRL: Self = Self(0xE0);
+    pub const RIGHT_GUI: Self = Self(0xE7);
+
+    /// Returns true for the keys from Left Control to Right GUI.
+    #[inline]
+    pub const fn is_modifier(&self) -> bool {
+        self.0 >= Self::LEFT_CTRL.0 && self.0 <= Self::RIGHT_GUI.0
+    }
+
+    /// Returns the modifier of the key, if it is a modifier key.
+    #[inline]
+    pub fn modifier(&self) -> Option<Modifiers> {
+        self.is_modifier()
+            .then(|| Modifiers::from_bits_truncate(1 << (self.0 - Self::LEFT_CTRL.0)))
+    }
+}
+
+bitflags! {
+    /// Modifier keys held and lock keys in effect
+    ///
+    /// The low byte is in the same order as the modifier byte of the HID boot protocol.
+    pub struct Modifiers: u16 {
+        const LEFT_CTRL     = 0x0001;
+        const LEFT_SHIFT    = 0x0002;
+        const LEFT_ALT      = 0x0004;
+        const LEFT_GUI      = 0x0008;
+        const RIGHT_CTRL    = 0x0010;
+        const RIGHT_SHIFT   = 0x0020;
+        const RIGHT_ALT     = 0x0040;
+        const RIGHT_GUI     = 0x0080;
+        const CAPS_LOCK     = 0x0100;
+
+        const CTRL  = Self::LEFT_CTRL.bits | Self::RIGHT_CTRL.bits;
+        const SHIFT = Self::LEFT_SHIFT.bits | Self::RIGHT_SHIFT.bits;
+        const ALT   = Self::LEFT_ALT.bits | Self::RIGHT_ALT.bits;
+        const GUI   = Self::LEFT_GUI.bits | Self::RIGHT_GUI.bits;
+    }
+}
+
+impl Modifiers {
+    #[inline]
+    pub fn has_ctrl(&self) -> bool {
+        self.intersects(Self::CTRL)
+    }
+
+    #[inline]
+    pub fn has_shift(&self) -> bool {
+        self.intersects(Self::SHIFT)
+    }
+
+    #[inline]
+    pub fn has_alt(&self) -> bool {
+        self.intersects(Self::ALT)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Released,
+    Pressed,
+    /// The key is held and repeats.
+    Repeated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The code of the key on its device: a HID usage, an evdev key code, or a byte
+    /// received from the serial console
+    pub scancode: u32,
+    pub usage: Usage,
+    pub state: KeyState,
+    /// The modifiers after the change
+    pub modifiers: Modifiers,
+    /// The character the key types, including the control characters, which is only
+    /// given while the key is pressed
+    pub character: Option<char>,
+}
+
+impl KeyEvent {
+    #[inline]
+    pub fn is_pressed(&self) -> bool {
+        self.state != KeyState::Released
+    }
+}
+
+bitflags! {
+    /// Buttons of a pointing device in the order of the HID boot protocol
+    pub struct MouseButtons: u8 {
+        const LEFT      = 0x01;
+        const RIGHT     = 0x02;
+        const MIDDLE    = 0x04;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointerEvent {
+    /// The buttons held
+    pub buttons: MouseButtons,
+    /// The movement, or the position from 0 to [PointerEvent::ABS_MAX] if absolute
+    pub x: i32,
+    pub y: i32,
+    /// Positive values scroll up.
+    pub wheel: i32,
+    pub absolute: bool,
+}
+
+impl PointerEvent {
+    pub const ABS_MAX: i32 = 0x7FFF;
+}
+
+/// Receiver of the input events
+///
+/// The methods are called while polling the devices, so they should return quickly.
+pub trait InputSubscriber: Sync {
+    fn key_event(&self, _event: &KeyEvent) {}
+
+    fn pointer_event(&self, _event: &PointerEvent) {}
+}
+
+struct InputState {
+    modifiers: Modifiers,
+    keymap: &'static Keymap,
+    /// The key to repeat and the time of its next repeat
+    repeat: Option<(KeyEvent, u64)>,
+    serial: SerialDecoder,
+}
+
+impl InputState {
+    const fn new() -> Self {
+        Self {
+            modifiers: Modifiers::empty(),
+            keymap: &keymap::US,
+            repeat: None,
+            serial: SerialDecoder::new(),
+        }
+    }
+}
+
+pub struct InputManager;
+
+impl InputManager {
+    /// Registers a subscriber of the input events.
+    pub fn subscribe(subscriber: &'static dyn InputSubscriber) -> Result<(), ()> {
+        SUBSCRIBERS.lock().push(Some(subscriber)).map_err(|_| ())
+    }
+
+    /// Returns the keymap that translates the keys into characters.
+    #[inline]
+    pub fn keymap() -> &'static Keymap {
+        STATE.lock().keymap
+    }
+
+    #[inline]
+    pub fn set_keymap(keymap: &'static Keymap) {
+        STATE.lock().keymap = keymap;
+    }
+
+    /// Returns the modifiers of the keyboards.
+    #[inline]
+    pub fn modifiers() -> Modifiers {
+        STATE.lock().modifiers
+    }
+
+    /// Polls the input devices, and delivers their events.
+    pub fn poll() {
+        let uart = System::stdout();
+        while uart.is_input_ready() {
+            Self::post_serial(uart.read_byte());
+        }
+        let now = GenericTimer::counter();
+        let events = STATE.lock().serial.flush(now);
+        events.iter().flatten().for_each(Self::dispatch_key);
+
+        Usb::poll();
+        for input in Virtio::input_devices() {
+            input.poll_input();
+        }
+
+        Self::repeat_key(now);
+    }
+
+    /// Posts a key pressed or released on a keyboard.
+    pub fn post_key(scancode: u32, usage: Usage, pressed: bool) {
+        let event = {
+            let mut state = STATE.lock();
+            if let Some(modifier) = usage.modifier() {
+                state.modifiers.set(modifier, pressed);
+            } else if usage == Usage::CAPS_LOCK && pressed {
+                state.modifiers.toggle(Modifiers::CAPS_LOCK);
+            }
+            let modifiers = state.modifiers;
+            let event = KeyEvent {
+                scancode,
+                usage,
+                state: if pressed {
+                    KeyState::Pressed
+                } else {
+                    KeyState::Released
+                },
+                modifiers,
+                character: pressed
+                    .then(|| state.keymap.translate(usage, modifiers))
+                    .flatten(),
+            };
+
+            if pressed && !usage.is_modifier() {
+                let delay = REPEAT_DELAY.get() as u64 * GenericTimer::frequency() / 1000;
+                state.repeat = Some((event, GenericTimer::counter() + delay));
+            } else if !pressed
+                && state
+                    .repeat
+                    .is_some_and(|(repeat, _)| repeat.scancode == scancode)
+            {
+                state.repeat = None;
+            }
+            event
+        };
+        Self::dispatch_key(&event);
+    }
+
+    /// Posts an event of a pointing device.
+    pub fn post_pointer(event: PointerEvent) {
+        for subscriber in Self::subscribers().iter().flatten() {
+            subscriber.pointer_event(&event);
+        }
+    }
+
+    /// Posts a byte received from the serial console.
+    ///
+    /// The bytes are decoded into keys, including the escape sequences of the cursor and
+    /// function keys sent by terminals.
+    pub fn post_serial(byte: u8) {
+        let events = {
+            let mut state = STATE.lock();
+            state.serial.feed(byte, GenericTimer::counter())
+        };
+        events.iter().flatten().for_each(Self::dispatch_key);
+    }
+
+    fn repeat_key(now: u64) {
+        let interval = REPEAT_INTERVAL.get() as u64 * GenericTimer::frequency() / 1000;
+        let event = {
+            let mut state = STATE.lock();
+            let modifiers = state.modifiers;
+            let keymap = state.keymap;
+            match state.repeat.as_mut() {
+                Some((event, next)) if interval > 0 && now >= *next => {
+                    *next = (*next + interval).max(now);
+                    KeyEvent {
+                        state: KeyState::Repeated,
+                        modifiers,
+                        character: keymap.translate(event.usage, modifiers),
+                        ..*event
+                    }
+                }
+                _ => return,
+            }
+        };
+        Self::dispatch_key(&event);
+    }
+
+    fn dispatch_key(event: &KeyEvent) {
+        for subscriber in Self::subscribers().iter().flatten() {
+            subscriber.key_event(event);
+        }
+    }
+
+    /// Returns a copy of the subscribers, which are called without holding the list.
+    fn subscribers() -> [Option<&'static dyn InputSubscriber>; MAX_SUBSCRIBERS] {
+        let mut result = [None; MAX_SUBSCRIBERS];
+        let subscribers = SUBSCRIBERS.lock();
+        result[..subscribers.len()].copy_from_slice(&subscribers);
+        result
+    }
+}
@@ -0,0 +1,243 @@
+//! Translation of the serial console input into key events
+
+use super::{keymap, KeyEvent, KeyState, Modifiers, Usage};
+use crate::arch::timer::GenericTimer;
+use core::str;
+
+/// The keys decoded from a byte, each as a press and a release
+pub(super) type SerialKeys = [Option<KeyEvent>; 4];
+
+/// The scancode, usage, modifiers and character of a key
+type Key = (u32, Usage, Modifiers, Option<char>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    Ground,
+    /// ESC was received at the time.
+    Escape(u64),
+    /// A control sequence, or SS3 if true
+    Sequence(bool),
+    Utf8,
+}
+
+/// Decodes the bytes sent by a terminal into keys.
+pub(super) struct SerialDecoder {
+    state: DecoderState,
+    params: [u16; Self::MAX_PARAMS],
+    n_params: usize,
+    utf8: [u8; 4],
+    utf8_len: usize,
+    utf8_need: usize,
+}
+
+impl SerialDecoder {
+    const MAX_PARAMS: usize = 4;
+
+    /// Time in milliseconds after which ESC is taken as the Escape key
+    const ESC_TIMEOUT: u64 = 50;
+
+    const ESC: u8 = 0x1B;
+
+    pub(super) const fn new() -> Self {
+        Self {
+            state: DecoderState::Ground,
+            params: [0; Self::MAX_PARAMS],
+            n_params: 0,
+            utf8: [0; 4],
+            utf8_len: 0,
+            utf8_need: 0,
+        }
+    }
+
+    /// Processes a received byte.
+    pub(super) fn feed(&mut self, byte: u8, now: u64) -> SerialKeys {
+        let mut keys = [None; 4];
+        match self.state {
+            DecoderState::Ground => self.ground(byte, now, &mut keys),
+            DecoderState::Escape(_) => match byte {
+                b'[' | b'O' => {
+                    self.state = DecoderState::Sequence(byte == b'O');
+                    self.params = [0; Self::MAX_PARAMS];
+                    self.n_params = 0;
+                }
+                Self::ESC => {
+                    Self::push_key(&mut keys, Self::ascii_key(Self::ESC));
+                    self.state = DecoderState::Escape(now);
+                }
+                0x00..=0x7F => {
+                    // Meta sends ESC before the key.
+                    let (scancode, usage, modifiers, character) = Self::ascii_key(byte);
+                    Self::push_key(
+                        &mut keys,
+                        (scancode, usage, modifiers | Modifiers::LEFT_ALT, character),
+                    );
+                    self.state = DecoderState::Ground;
+                }
+                _ => {
+                    Self::push_key(&mut keys, Self::ascii_key(Self::ESC));
+                    self.state = DecoderState::Ground;
+                    self.ground(byte, now, &mut keys);
+                }
+            },
+            DecoderState::Sequence(ss3) => match byte {
+                b'0'..=b'9' => {
+                    let param = &mut self.params[self.n_params.min(Self::MAX_PARAMS - 1)];
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                }
+                b';' => self.n_params = (self.n_params + 1).min(Self::MAX_PARAMS - 1),
+                0x40..=0x7E => {
+                    self.state = DecoderState::Ground;
+                    if let Some(key) = self.sequence_key(byte, ss3) {
+                        Self::push_key(&mut keys, key);
+                    }
+                }
+                0x20..=0x3F => (),
+                _ => self.state = DecoderState::Ground,
+            },
+            DecoderState::Utf8 => {
+                if (byte & 0xC0) != 0x80 {
+                    // The sequence was cut off.
+                    self.state = DecoderState::Ground;
+                    self.ground(byte, now, &mut keys);
+                } else {
+                    self.utf8[self.utf8_len] = byte;
+                    self.utf8_len += 1;
+                    if self.utf8_len == self.utf8_need {
+                        self.state = DecoderState::Ground;
+                        if let Some(c) = str::from_utf8(&self.utf8[..self.utf8_len])
+                            .ok()
+                            .and_then(|s| s.chars().next())
+                        {
+                            Self::push_key(
+                                &mut keys,
+                                (c as u32, Usage::NONE, Modifiers::empty(), Some(c)),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        keys
+    }
+
+    /// Takes a lone ESC as the Escape key once no sequence follows it.
+    pub(super) fn flush(&mut self, now: u64) -> SerialKeys {
+        let mut keys = [None; 4];
+        if let DecoderState::Escape(since) = self.state {
+            if now - since > Self::ESC_TIMEOUT * GenericTimer::frequency() / 1000 {
+                self.state = DecoderState::Ground;
+                Self::push_key(&mut keys, Self::ascii_key(Self::ESC));
+            }
+        }
+        keys
+    }
+
+    fn ground(&mut self, byte: u8, now: u64, keys: &mut SerialKeys) {
+        match byte {
+            Self::ESC => self.state = DecoderState::Escape(now),
+            0x00..=0x7F => Self::push_key(keys, Self::ascii_key(byte)),
+            0xC0..=0xF7 => {
+                self.utf8[0] = byte;
+                self.utf8_len = 1;
+                self.utf8_need = match byte {
+                    0xC0..=0xDF => 2,
+                    0xE0..=0xEF => 3,
+                    _ => 4,
+                };
+                self.state = DecoderState::Utf8;
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the key that types an ASCII character on a US keyboard.
+    fn ascii_key(byte: u8) -> Key {
+        let (usage, modifiers) = match byte {
+            b'\r' | b'\n' => (Usage::ENTER, Modifiers::empty()),
+            b'\t' => (Usage::TAB, Modifiers::empty()),
+            0x08 | 0x7F => (Usage::BACKSPACE, Modifiers::empty()),
+            Self::ESC => (Usage::ESCAPE, Modifiers::empty()),
+            0x00 => (Usage::SPACE, Modifiers::LEFT_CTRL),
+            0x01..=0x1A => (Usage(Usage::KEY_A.0 + byte - 1), Modifiers::LEFT_CTRL),
+            0x1C..=0x1F => (Usage::NONE, Modifiers::LEFT_CTRL),
+            _ => match keymap::US.find(byte as char) {
+                Some((usage, true)) => (usage, Modifiers::LEFT_SHIFT),
+                Some((usage, false)) => (usage, Modifiers::empty()),
+                None => (Usage::NONE, Modifiers::empty()),
+            },
+        };
+        (byte as u32, usage, modifiers, Some(byte as char))
+    }
+
+    /// Returns the cursor or function key of a control sequence.
+    fn sequence_key(&self, final_byte: u8, ss3: bool) -> Option<Key> {
+        // The second parameter is 1 plus the bits of Shift, Alt and Ctrl.
+        let modifiers = match self.params[1].saturating_sub(1) {
+            _ if self.n_params < 1 => Modifiers::empty(),
+            bits => {
+                let mut modifiers = Modifiers::empty();
+                modifiers.set(Modifiers::LEFT_SHIFT, (bits & 1) != 0);
+                modifiers.set(Modifiers::LEFT_ALT, (bits & 2) != 0);
+                modifiers.set(Modifiers::LEFT_CTRL, (bits & 4) != 0);
+                modifiers
+            }
+        };
+        let usage = match final_byte {
+            b'A' => Usage::UP,
+            b'B' => Usage::DOWN,
+            b'C' => Usage::RIGHT,
+            b'D' => Usage::LEFT,
+            b'H' => Usage::HOME,
+            b'F' => Usage::END,
+            b'P' => Usage::F1,
+            b'Q' => Usage::F2,
+            b'R' => Usage::F3,
+            b'S' => Usage::F4,
+            b'Z' if !ss3 => {
+                return Some((
+                    final_byte as u32,
+                    Usage::TAB,
+                    Modifiers::LEFT_SHIFT,
+                    Some('\t'),
+                ))
+            }
+            b'~' if !ss3 => match self.params[0] {
+                1 | 7 => Usage::HOME,
+                2 => Usage::INSERT,
+                3 => Usage::DELETE,
+                4 | 8 => Usage::END,
+                5 => Usage::PAGE_UP,
+                6 => Usage::PAGE_DOWN,
+                v @ 11..=15 => Usage(Usage::F1.0 + (v - 11) as u8),
+                v @ 17..=21 => Usage(Usage::F6.0 + (v - 17) as u8),
+                23 => Usage::F11,
+                24 => Usage::F12,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some((final_byte as u32, usage, modifiers, None))
+    }
+
+    /// Adds the press and the release of a key.
+    fn push_key(keys: &mut SerialKeys, key: Key) {
+        let (scancode, usage, modifiers, character) = key;
+        let Some(index) = keys.iter().position(|v| v.is_none()) else {
+            return;
+        };
+        for (slot, state) in keys[index..]
+            .iter_mut()
+            .zip([KeyState::Pressed, KeyState::Released])
+        {
+            *slot = Some(KeyEvent {
+                scancode,
+                usage,
+                state,
+                modifiers,
+                character: (state == KeyState::Pressed).then_some(character).flatten(),
+            });
+        }
+    }
+}
@@ -1,3 +1,109 @@
+pub mod bcmrng;
+pub mod block;
 pub mod emcon;
 pub mod font;
+pub mod input;
+pub mod tty;
 pub mod uart;
+pub mod usb;
+pub mod virtio;
+
+use self::{
+    bcmrng::BCM_RNG,
+    block::BlockDevice,
+    usb::Usb,
+    virtio::{rng::VIRTIO_ENTROPY, Virtio},
+};
+use crate::random::EntropySource;
+use crate::{
+    arch::{self, MachineType},
+    shell::{ShellCommand, ShellError},
+    system::System,
+};
+use core::fmt::Write;
+
+struct DevicesCommand;
+
+impl ShellCommand for DevicesCommand {
+    fn name(&self) -> &'static str {
+        "devices"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the devices in use"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        let machine = arch::current_machine_type();
+        writeln!(
+            out,
+            "machine: {:?} ({})",
+            machine,
+            System::model_name().unwrap_or("unknown model")
+        )?;
+        match machine {
+            MachineType::QemuVirt => writeln!(out, "uart:    PL011")?,
+            _ => writeln!(out, "uart:    PL011 UART0")?,
+        }
+        if let Some((ptr, width, height, stride)) = arch::std_screen() {
+            writeln!(
+                out,
+                "screen:  {}x{} stride {} at {:012x}",
+                width, height, stride, ptr as usize
+            )?;
+        }
+        for (index, blk) in Virtio::block_devices().iter().enumerate() {
+            writeln!(
+                out,
+                "blk{}:    {} MB{}",
+                index,
+                blk.size() >> 20,
+                if blk.is_read_only() { " read-only" } else { "" },
+            )?;
+        }
+        for (index, storage) in Usb::storage_devices().iter().enumerate() {
+            writeln!(
+                out,
+                "sd{}:     {} MB{} {}",
+                index,
+                storage.size() >> 20,
+                if storage.is_read_only() {
+                    " read-only"
+                } else {
+                    ""
+                },
+                storage.name(),
+            )?;
+        }
+        for (index, _) in Virtio::consoles().iter().enumerate() {
+            writeln!(out, "hvc{}:    virtio console", index)?;
+        }
+        for (index, input) in Virtio::input_devices().iter().enumerate() {
+            writeln!(out, "input{}:  {}", index, input.name())?;
+        }
+        for device in Usb::devices() {
+            writeln!(
+                out,
+                "usb{}:    {:04x}:{:04x} {}",
+                device.address(),
+                device.descriptor().vendor_id,
+                device.descriptor().product_id,
+                device.product(),
+            )?;
+        }
+        if BCM_RNG.is_present() {
+            writeln!(out, "rng:     {}", BCM_RNG.name())?;
+        }
+        if VIRTIO_ENTROPY.is_present() {
+            writeln!(out, "rng:     virtio entropy")?;
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static DEVICES: DevicesCommand = DevicesCommand;
+}
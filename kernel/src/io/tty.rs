@@ -0,0 +1,548 @@
+//! Terminals
+//!
+//! [TtyWrite] is the output interface shared by the consoles, and [Tty] puts a line
+//! discipline over a console: canonical input with editing or raw input, echo, CR/LF
+//! translation, and Ctrl-C as [TtySignal::Interrupt].
+
+use super::{
+    input::{InputManager, InputSubscriber, KeyEvent, Usage},
+    uart::Uart,
+};
+use crate::{arch::cpu::Cpu, drawing::IndexedColor, sync::spinlock::SpinMutex, system::System};
+use bitflags::*;
+use core::{
+    fmt::Write,
+    str,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyError {
+    NotSupported,
+    /// The device cannot take output yet, or no input is ready.
+    NotReady,
+    /// Ctrl-C was received while reading.
+    Interrupted,
+}
+
+/// Colors of SGR 30-37 and 90-97 in the order of [IndexedColor]
+pub const ANSI_COLORS: [IndexedColor; 16] = [
+    IndexedColor::BLACK,
+    IndexedColor::RED,
+    IndexedColor::GREEN,
+    IndexedColor::BROWN,
+    IndexedColor::BLUE,
+    IndexedColor::MAGENTA,
+    IndexedColor::CYAN,
+    IndexedColor::LIGHT_GRAY,
+    IndexedColor::DARK_GRAY,
+    IndexedColor::LIGHT_RED,
+    IndexedColor::LIGHT_GREEN,
+    IndexedColor::YELLOW,
+    IndexedColor::LIGHT_BLUE,
+    IndexedColor::LIGHT_MAGENTA,
+    IndexedColor::LIGHT_CYAN,
+    IndexedColor::WHITE,
+];
+
+/// Returns the ANSI color number of the first 16 indexed colors.
+#[inline]
+pub fn ansi_color_index(color: IndexedColor) -> Option<usize> {
+    ANSI_COLORS.iter().position(|v| *v == color)
+}
+
+/// A text terminal that follows VT100 and ECMA-48
+///
+/// The provided methods send the escape sequences, which suits serial terminals.
+/// Coordinates start at zero.
+pub trait TtyWrite: Write {
+    /// Clears the screen and restores the default colors.
+    fn reset(&mut self) -> Result<(), TtyError> {
+        self.write_str("\x1b[0m\x1b[2J\x1b[H")
+            .map_err(|_| TtyError::NotReady)
+    }
+
+    /// Returns the number of columns and rows.
+    fn dims(&self) -> (isize, isize) {
+        (80, 24)
+    }
+
+    /// Returns the position of the cursor, if the terminal can tell it.
+    fn cursor_position(&self) -> Option<(isize, isize)> {
+        None
+    }
+
+    fn set_cursor_position(&mut self, x: isize, y: isize) {
+        let _ = write!(self, "\x1b[{};{}H", y.max(0) + 1, x.max(0) + 1);
+    }
+
+    fn is_cursor_enabled(&self) -> bool {
+        true
+    }
+
+    /// Shows or hides the cursor, and returns the previous state.
+    fn set_cursor_enabled(&mut self, enabled: bool) -> bool {
+        let _ = self.write_str(if enabled { "\x1b[?25h" } else { "\x1b[?25l" });
+        true
+    }
+
+    /// Sets the colors from a text attribute, which has the foreground in the low
+    /// nibble and the background in the high nibble. Zero restores the default colors.
+    fn set_attribute(&mut self, attribute: u8) {
+        if attribute == 0 {
+            let _ = self.write_str("\x1b[0m");
+            return;
+        }
+        let sgr = |color: u8, base: usize| {
+            let index = ansi_color_index(IndexedColor(color)).unwrap_or(7);
+            if index < 8 {
+                base + index
+            } else {
+                base + 60 + index - 8
+            }
+        };
+        let _ = write!(
+            self,
+            "\x1b[{};{}m",
+            sgr(attribute & 0x0F, 30),
+            sgr(attribute >> 4, 40)
+        );
+    }
+}
+
+impl TtyWrite for dyn Uart {}
+
+bitflags! {
+    /// Modes of the line discipline
+    pub struct TtyMode: u32 {
+        /// Input is delivered a line at a time and can be edited.
+        const CANONICAL = 0x01;
+        const ECHO      = 0x02;
+        /// Ctrl-C raises [TtySignal::Interrupt] instead of being delivered.
+        const SIGNALS   = 0x04;
+        /// CR is received as LF.
+        const ICRNL     = 0x08;
+        /// LF is sent as CR LF.
+        const ONLCR     = 0x10;
+
+        const DEFAULT = Self::CANONICAL.bits
+            | Self::ECHO.bits
+            | Self::SIGNALS.bits
+            | Self::ICRNL.bits
+            | Self::ONLCR.bits;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtySignal {
+    Interrupt,
+}
+
+/// The console a [Tty] is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtyDevice {
+    /// The standard UART
+    Uart,
+    /// The emergency console
+    Screen,
+}
+
+/// Maximum length of a line in the canonical mode
+const MAX_LINE: usize = 256;
+
+/// Size of the input ready to read
+const MAX_INPUT: usize = 1024;
+
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const BS: u8 = 0x08;
+const DEL: u8 = 0x7F;
+
+static TTYS: [Tty; 2] = [Tty::new(TtyDevice::Uart), Tty::new(TtyDevice::Screen)];
+
+/// A terminal with a line discipline
+pub struct Tty {
+    inner: SpinMutex<TtyInner>,
+    signal: AtomicBool,
+}
+
+impl Tty {
+    /// The TTY on the standard UART
+    pub const CONSOLE: usize = 0;
+    /// The TTY on the emergency console
+    pub const SCREEN: usize = 1;
+
+    const fn new(device: TtyDevice) -> Self {
+        Self {
+            inner: SpinMutex::new(TtyInner::new(device)),
+            signal: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    pub fn get(index: usize) -> Option<&'static Tty> {
+        TTYS.get(index)
+    }
+
+    #[inline]
+    pub fn console() -> &'static Tty {
+        &TTYS[Self::CONSOLE]
+    }
+
+    #[inline]
+    pub fn device(&self) -> TtyDevice {
+        self.inner.lock().device
+    }
+
+    #[inline]
+    pub fn mode(&self) -> TtyMode {
+        self.inner.lock().mode
+    }
+
+    /// Sets the mode, and returns the previous one.
+    ///
+    /// An unfinished line is delivered when leaving the canonical mode.
+    pub fn set_mode(&self, mode: TtyMode) -> TtyMode {
+        let mut inner = self.inner.lock();
+        let result = inner.mode;
+        if !mode.contains(TtyMode::CANONICAL) {
+            inner.commit_line();
+        }
+        inner.mode = mode;
+        result
+    }
+
+    /// Takes the signal raised by the input, if any.
+    #[inline]
+    pub fn take_signal(&self) -> Option<TtySignal> {
+        self.signal
+            .swap(false, Ordering::AcqRel)
+            .then_some(TtySignal::Interrupt)
+    }
+
+    /// Processes a byte received from the keyboard of the device.
+    pub fn input(&self, byte: u8) {
+        if self.inner.lock().receive(byte) {
+            self.signal.store(true, Ordering::Release);
+        }
+        // Wakes a reader waiting on another core.
+        Cpu::send_event();
+    }
+
+    /// Processes the input waiting in the devices.
+    ///
+    /// The input reaches the TTYs subscribed to [InputManager].
+    #[inline]
+    pub fn poll(&self) {
+        InputManager::poll();
+    }
+
+    /// Reads the input without waiting.
+    ///
+    /// Returns zero at the end of the input, which Ctrl-D marks in the canonical mode.
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        self.poll();
+        if self.signal.load(Ordering::Acquire) {
+            return Err(TtyError::Interrupted);
+        }
+        self.inner.lock().read(buf)
+    }
+
+    /// Reads the input, waiting until a line or, in the raw mode, a byte is ready.
+    ///
+    /// The core sleeps between polls of the devices, until an interrupt or the next event of
+    /// the timer's event stream.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        loop {
+            match self.try_read(buf) {
+                Err(TtyError::NotReady) => Cpu::wait_for_event(),
+                result => return result,
+            }
+        }
+    }
+
+    /// Writes `bytes` to the device with the output processing.
+    pub fn write(&self, bytes: &[u8]) {
+        self.inner.lock().write(bytes);
+    }
+}
+
+impl InputSubscriber for Tty {
+    /// Types the key, sending the cursor and function keys as the sequences of xterm.
+    fn key_event(&self, event: &KeyEvent) {
+        if !event.is_pressed() {
+            return;
+        }
+        if let Some(c) = event.character {
+            if event.modifiers.has_alt() {
+                self.input(0x1B);
+            }
+            let mut buf = [0u8; 4];
+            for &byte in c.encode_utf8(&mut buf).as_bytes() {
+                self.input(byte);
+            }
+            return;
+        }
+        let sequence: &[u8] = match event.usage {
+            Usage::UP => b"\x1b[A",
+            Usage::DOWN => b"\x1b[B",
+            Usage::RIGHT => b"\x1b[C",
+            Usage::LEFT => b"\x1b[D",
+            Usage::HOME => b"\x1b[H",
+            Usage::END => b"\x1b[F",
+            Usage::INSERT => b"\x1b[2~",
+            Usage::DELETE => b"\x1b[3~",
+            Usage::PAGE_UP => b"\x1b[5~",
+            Usage::PAGE_DOWN => b"\x1b[6~",
+            Usage::F1 => b"\x1bOP",
+            Usage::F2 => b"\x1bOQ",
+            Usage::F3 => b"\x1bOR",
+            Usage::F4 => b"\x1bOS",
+            Usage::F5 => b"\x1b[15~",
+            Usage::F6 => b"\x1b[17~",
+            Usage::F7 => b"\x1b[18~",
+            Usage::F8 => b"\x1b[19~",
+            Usage::F9 => b"\x1b[20~",
+            Usage::F10 => b"\x1b[21~",
+            Usage::F11 => b"\x1b[23~",
+            Usage::F12 => b"\x1b[24~",
+            _ => return,
+        };
+        for &byte in sequence {
+            self.input(byte);
+        }
+    }
+}
+
+impl Write for &Tty {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        Tty::write(self, s.as_bytes());
+        Ok(())
+    }
+}
+
+struct TtyInner {
+    device: TtyDevice,
+    mode: TtyMode,
+    /// The line being edited
+    line: [u8; MAX_LINE],
+    line_len: usize,
+    /// The input ready to read, as a ring
+    input: [u8; MAX_INPUT],
+    input_head: usize,
+    input_len: usize,
+    /// Ctrl-D on an empty line
+    eof: bool,
+    /// An incomplete UTF-8 sequence written to the screen
+    partial: [u8; 4],
+    partial_len: usize,
+}
+
+impl TtyInner {
+    const fn new(device: TtyDevice) -> Self {
+        Self {
+            device,
+            mode: TtyMode::DEFAULT,
+            line: [0; MAX_LINE],
+            line_len: 0,
+            input: [0; MAX_INPUT],
+            input_head: 0,
+            input_len: 0,
+            eof: false,
+            partial: [0; 4],
+            partial_len: 0,
+        }
+    }
+
+    /// Processes a received byte, and returns true if it raises the signal.
+    fn receive(&mut self, byte: u8) -> bool {
+        let mode = self.mode;
+        if mode.contains(TtyMode::SIGNALS) && byte == CTRL_C {
+            self.line_len = 0;
+            self.input_len = 0;
+            self.echo(b"^C\n");
+            return true;
+        }
+        let byte = match byte {
+            b'\r' if mode.contains(TtyMode::ICRNL) => b'\n',
+            _ => byte,
+        };
+        if !mode.contains(TtyMode::CANONICAL) {
+            self.push_input(&[byte]);
+            self.echo(&[byte]);
+            return false;
+        }
+
+        match byte {
+            BS | DEL => {
+                if self.erase_char() {
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            CTRL_U => {
+                while self.erase_char() {
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            CTRL_W => {
+                while self.line_len > 0 && self.line[self.line_len - 1] == b' ' {
+                    self.erase_char();
+                    self.echo(b"\x08 \x08");
+                }
+                while self.line_len > 0 && self.line[self.line_len - 1] != b' ' {
+                    self.erase_char();
+                    self.echo(b"\x08 \x08");
+                }
+            }
+            CTRL_D => {
+                if self.line_len == 0 {
+                    self.eof = true;
+                } else {
+                    self.commit_line();
+                }
+            }
+            b'\n' => {
+                if self.line_len < MAX_LINE {
+                    self.line[self.line_len] = b'\n';
+                    self.line_len += 1;
+                }
+                self.commit_line();
+                self.echo(b"\n");
+            }
+            _ => {
+                // Keep room for the newline.
+                if self.line_len + 1 < MAX_LINE {
+                    self.line[self.line_len] = byte;
+                    self.line_len += 1;
+                    self.echo(&[byte]);
+                }
+            }
+        }
+        false
+    }
+
+    /// Removes the last character of the line, and returns false if the line is empty.
+    fn erase_char(&mut self) -> bool {
+        if self.line_len == 0 {
+            return false;
+        }
+        self.line_len -= 1;
+        // Remove the whole UTF-8 sequence.
+        while self.line_len > 0 && (self.line[self.line_len] & 0xC0) == 0x80 {
+            self.line_len -= 1;
+        }
+        true
+    }
+
+    /// Moves the line into the input ready to read.
+    fn commit_line(&mut self) {
+        let line = self.line;
+        self.push_input(&line[..self.line_len]);
+        self.line_len = 0;
+    }
+
+    fn push_input(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.input_len >= MAX_INPUT {
+                break;
+            }
+            self.input[(self.input_head + self.input_len) % MAX_INPUT] = byte;
+            self.input_len += 1;
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, TtyError> {
+        if self.input_len == 0 {
+            if self.eof {
+                self.eof = false;
+                return Ok(0);
+            }
+            return Err(TtyError::NotReady);
+        }
+        let canonical = self.mode.contains(TtyMode::CANONICAL);
+        let mut count = 0;
+        while count < buf.len() && self.input_len > 0 {
+            let byte = self.input[self.input_head];
+            buf[count] = byte;
+            count += 1;
+            self.input_head = (self.input_head + 1) % MAX_INPUT;
+            self.input_len -= 1;
+            // A read returns at most one line.
+            if canonical && byte == b'\n' {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    #[inline]
+    fn echo(&mut self, bytes: &[u8]) {
+        if self.mode.contains(TtyMode::ECHO) {
+            self.write(bytes);
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self.device {
+            TtyDevice::Uart => {
+                let uart = System::stdout();
+                for &byte in bytes {
+                    if byte == b'\n' && self.mode.contains(TtyMode::ONLCR) {
+                        uart.write_byte(b'\r');
+                    }
+                    uart.write_byte(byte);
+                }
+            }
+            TtyDevice::Screen => {
+                // The console takes characters, so a sequence split between writes is
+                // kept until it is complete.
+                let emcon = System::em_console();
+                let mut bytes = bytes;
+                if self.partial_len > 0 {
+                    let old_len = self.partial_len;
+                    let len = (old_len + bytes.len()).min(self.partial.len());
+                    self.partial[old_len..len].copy_from_slice(&bytes[..len - old_len]);
+                    let tail = write_utf8(emcon, &self.partial[..len]);
+                    if len - tail < old_len {
+                        // Still incomplete, and all the bytes are taken.
+                        self.partial_len = len;
+                        return;
+                    }
+                    self.partial_len = 0;
+                    bytes = &bytes[len - tail - old_len..];
+                }
+                let tail = write_utf8(emcon, bytes);
+                self.partial[..tail].copy_from_slice(&bytes[bytes.len() - tail..]);
+                self.partial_len = tail;
+            }
+        }
+    }
+}
+
+/// Writes `bytes` to the console, replacing each invalid sequence with U+FFFD.
+///
+/// Returns the length of an incomplete sequence left at the end.
+fn write_utf8(emcon: &mut impl Write, mut bytes: &[u8]) -> usize {
+    loop {
+        match str::from_utf8(bytes) {
+            Ok(s) => {
+                let _ = emcon.write_str(s);
+                return 0;
+            }
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                let _ = emcon.write_str(unsafe { str::from_utf8_unchecked(valid) });
+                match err.error_len() {
+                    Some(len) => {
+                        let _ = emcon.write_char(char::REPLACEMENT_CHARACTER);
+                        bytes = &rest[len..];
+                    }
+                    None => return rest.len(),
+                }
+            }
+        }
+    }
+}
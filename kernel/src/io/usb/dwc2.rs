@@ -0,0 +1,571 @@
+//! Synopsys DesignWare USB 2.0 OTG controller in host mode
+//!
+//! Transfers are made on channel 0 in buffer DMA mode, and the driver waits for the
+//! channel to halt, so only one transfer is in flight at a time.
+
+use super::{EndpointType, SetupPacket, UsbError, UsbHostController, UsbPipe, UsbSpeed};
+use crate::{
+    arch::{cpu::Cpu, timer::GenericTimer},
+    mem::{MemoryManager, PhysicalAddress},
+};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+pub struct Dwc2 {
+    base: usize,
+    /// Added to a physical address to make the address seen by the DMA of the controller
+    dma_offset: u32,
+    /// SETUP packet and bounce buffer
+    dma_pa: PhysicalAddress,
+    dma: *mut u8,
+}
+
+/// Registers of the controller, where the host channel registers are of channel 0
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+enum Dwc2Reg {
+    GAhbCfg = 0x008,
+    GUsbCfg = 0x00C,
+    GRstCtl = 0x010,
+    GRxFSiz = 0x024,
+    GNpTxFSiz = 0x028,
+    GSnpsId = 0x040,
+    HPTxFSiz = 0x100,
+    HCfg = 0x400,
+    HFNum = 0x408,
+    HPrt = 0x440,
+    HcChar = 0x500,
+    HcSplt = 0x504,
+    HcInt = 0x508,
+    HcIntMsk = 0x50C,
+    HcTSiz = 0x510,
+    HcDma = 0x514,
+}
+
+impl Dwc2 {
+    const SNPSID_MASK: u32 = 0xFFFF_0000;
+    const SNPSID_OTG2: u32 = 0x4F54_0000;
+
+    const GAHBCFG_DMA_EN: u32 = 1 << 5;
+
+    const GUSBCFG_FORCE_HOST: u32 = 1 << 29;
+    const GUSBCFG_FORCE_DEVICE: u32 = 1 << 30;
+
+    const GRSTCTL_CSFTRST: u32 = 1 << 0;
+    const GRSTCTL_RXFFLSH: u32 = 1 << 4;
+    const GRSTCTL_TXFFLSH: u32 = 1 << 5;
+    /// Flushes all TX FIFOs
+    const GRSTCTL_TXFNUM_ALL: u32 = 0x10 << 6;
+    const GRSTCTL_AHBIDLE: u32 = 1 << 31;
+
+    const HPRT_CONN_STS: u32 = 1 << 0;
+    const HPRT_CONN_DET: u32 = 1 << 1;
+    const HPRT_ENA: u32 = 1 << 2;
+    const HPRT_ENA_CHNG: u32 = 1 << 3;
+    const HPRT_OVRCUR_CHNG: u32 = 1 << 5;
+    const HPRT_RST: u32 = 1 << 8;
+    const HPRT_PWR: u32 = 1 << 12;
+    const HPRT_SPD_SHIFT: u32 = 17;
+    /// Bits cleared by writing 1, which must be written as 0 to keep them
+    const HPRT_W1C: u32 =
+        Self::HPRT_CONN_DET | Self::HPRT_ENA | Self::HPRT_ENA_CHNG | Self::HPRT_OVRCUR_CHNG;
+
+    const HCCHAR_EP_NUM_SHIFT: u32 = 11;
+    const HCCHAR_EP_DIR_IN: u32 = 1 << 15;
+    const HCCHAR_LSPD: u32 = 1 << 17;
+    const HCCHAR_EP_TYPE_SHIFT: u32 = 18;
+    const HCCHAR_MC_1: u32 = 1 << 20;
+    const HCCHAR_DEV_ADDR_SHIFT: u32 = 22;
+    const HCCHAR_ODD_FRM: u32 = 1 << 29;
+    const HCCHAR_CH_DIS: u32 = 1 << 30;
+    const HCCHAR_CH_ENA: u32 = 1 << 31;
+
+    const HCSPLT_HUB_ADDR_SHIFT: u32 = 7;
+    const HCSPLT_XACT_POS_ALL: u32 = 3 << 14;
+    const HCSPLT_COMP_SPLT: u32 = 1 << 16;
+    const HCSPLT_SPLT_ENA: u32 = 1 << 31;
+
+    const HCINT_XFER_COMPL: u32 = 1 << 0;
+    const HCINT_CH_HLTD: u32 = 1 << 1;
+    const HCINT_AHB_ERR: u32 = 1 << 2;
+    const HCINT_STALL: u32 = 1 << 3;
+    const HCINT_NAK: u32 = 1 << 4;
+    const HCINT_ACK: u32 = 1 << 5;
+    const HCINT_NYET: u32 = 1 << 6;
+    const HCINT_XACT_ERR: u32 = 1 << 7;
+    const HCINT_BBL_ERR: u32 = 1 << 8;
+    const HCINT_FRM_OVRUN: u32 = 1 << 9;
+    const HCINT_DATA_TGL_ERR: u32 = 1 << 10;
+    const HCINT_ALL: u32 = 0x7FF;
+
+    const HCTSIZ_XFER_SIZE_MASK: u32 = 0x7_FFFF;
+    const HCTSIZ_PKT_CNT_SHIFT: u32 = 19;
+    const HCTSIZ_PKT_CNT_MASK: u32 = 0x3FF;
+    const HCTSIZ_PID_SHIFT: u32 = 29;
+
+    const PID_DATA0: u32 = 0;
+    const PID_DATA1: u32 = 2;
+    const PID_SETUP: u32 = 3;
+
+    /// Sizes of the FIFOs in words
+    const RX_FIFO_SIZE: u32 = 0x400;
+    const NPTX_FIFO_SIZE: u32 = 0x400;
+    const PTX_FIFO_SIZE: u32 = 0x400;
+
+    const SETUP_OFFSET: usize = 0;
+    const BUFFER_OFFSET: usize = 0x1000;
+    const BUFFER_SIZE: usize = 0x4000;
+
+    const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+    const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+    const CHANNEL_TIMEOUT: Duration = Duration::from_millis(100);
+    /// Time to retry a control or bulk transfer answered with NAK
+    const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_ERRORS: usize = 3;
+
+    /// Resets the core and starts it in host mode.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the mapped registers of the controller.
+    pub unsafe fn new(base: usize, dma_offset: u32) -> Result<Self, UsbError> {
+        let (dma_pa, dma) = MemoryManager::alloc_dma::<u8>(Self::BUFFER_OFFSET + Self::BUFFER_SIZE)
+            .ok_or(UsbError::OutOfResources)?;
+        let hc = Self {
+            base,
+            dma_offset,
+            dma_pa,
+            dma,
+        };
+
+        if (hc.read(Dwc2Reg::GSnpsId) & Self::SNPSID_MASK) != Self::SNPSID_OTG2 {
+            return Err(UsbError::NoDevice);
+        }
+
+        Self::wait_until(Self::RESET_TIMEOUT, || {
+            (hc.read(Dwc2Reg::GRstCtl) & Self::GRSTCTL_AHBIDLE) != 0
+        })?;
+        hc.write(Dwc2Reg::GRstCtl, Self::GRSTCTL_CSFTRST);
+        Self::wait_until(Self::RESET_TIMEOUT, || {
+            (hc.read(Dwc2Reg::GRstCtl) & Self::GRSTCTL_CSFTRST) == 0
+        })?;
+
+        let usbcfg = hc.read(Dwc2Reg::GUsbCfg) & !Self::GUSBCFG_FORCE_DEVICE;
+        hc.write(Dwc2Reg::GUsbCfg, usbcfg | Self::GUSBCFG_FORCE_HOST);
+        // The core takes 25 ms to change its mode.
+        GenericTimer::wait(Duration::from_millis(25));
+
+        hc.write(Dwc2Reg::GRxFSiz, Self::RX_FIFO_SIZE);
+        hc.write(
+            Dwc2Reg::GNpTxFSiz,
+            (Self::NPTX_FIFO_SIZE << 16) | Self::RX_FIFO_SIZE,
+        );
+        hc.write(
+            Dwc2Reg::HPTxFSiz,
+            (Self::PTX_FIFO_SIZE << 16) | (Self::RX_FIFO_SIZE + Self::NPTX_FIFO_SIZE),
+        );
+        hc.write(
+            Dwc2Reg::GRstCtl,
+            Self::GRSTCTL_TXFFLSH | Self::GRSTCTL_TXFNUM_ALL,
+        );
+        Self::wait_until(Self::RESET_TIMEOUT, || {
+            (hc.read(Dwc2Reg::GRstCtl) & Self::GRSTCTL_TXFFLSH) == 0
+        })?;
+        hc.write(Dwc2Reg::GRstCtl, Self::GRSTCTL_RXFFLSH);
+        Self::wait_until(Self::RESET_TIMEOUT, || {
+            (hc.read(Dwc2Reg::GRstCtl) & Self::GRSTCTL_RXFFLSH) == 0
+        })?;
+
+        hc.write(Dwc2Reg::GAhbCfg, Self::GAHBCFG_DMA_EN);
+
+        let hprt = hc.read(Dwc2Reg::HPrt) & !Self::HPRT_W1C;
+        hc.write(Dwc2Reg::HPrt, hprt | Self::HPRT_PWR);
+
+        Ok(hc)
+    }
+
+    #[inline]
+    fn reg(&self, reg: Dwc2Reg) -> &AtomicU32 {
+        unsafe { &*((self.base + reg as usize) as *const AtomicU32) }
+    }
+
+    #[inline]
+    fn read(&self, reg: Dwc2Reg) -> u32 {
+        self.reg(reg).load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn write(&self, reg: Dwc2Reg, val: u32) {
+        self.reg(reg).store(val, Ordering::SeqCst)
+    }
+
+    fn wait_until(timeout: Duration, mut cond: impl FnMut() -> bool) -> Result<(), UsbError> {
+        let deadline = GenericTimer::counter()
+            + timeout.as_micros() as u64 * GenericTimer::frequency() / 1_000_000;
+        while !cond() {
+            if GenericTimer::counter() > deadline {
+                return Err(UsbError::Timeout);
+            }
+            Cpu::no_op();
+        }
+        Ok(())
+    }
+
+    /// Returns the address of the DMA buffer seen by the controller.
+    #[inline]
+    fn dma_addr(&self, offset: usize) -> u32 {
+        (self.dma_pa.as_u64() as u32 + offset as u32) | self.dma_offset
+    }
+
+    /// Cleans and invalidates the data cache of the DMA buffer.
+    #[inline]
+    fn flush_dma(&self, offset: usize, len: usize) {
+        let start = self.dma as usize + offset;
+        let mut p = start & !63;
+        while p < start + len {
+            unsafe {
+                asm!("dc civac, {}", in(reg) p);
+            }
+            p += 64;
+        }
+        unsafe {
+            asm!("dsb sy");
+        }
+    }
+
+    /// Stops the channel after a timeout.
+    fn halt_channel(&self) {
+        let hcchar = self.read(Dwc2Reg::HcChar);
+        if (hcchar & Self::HCCHAR_CH_ENA) != 0 {
+            self.write(
+                Dwc2Reg::HcChar,
+                hcchar | Self::HCCHAR_CH_ENA | Self::HCCHAR_CH_DIS,
+            );
+            let _ = Self::wait_until(Self::CHANNEL_TIMEOUT, || {
+                (self.read(Dwc2Reg::HcInt) & Self::HCINT_CH_HLTD) != 0
+            });
+        }
+        self.write(Dwc2Reg::HcInt, Self::HCINT_ALL);
+    }
+
+    /// Starts a transaction on the channel and waits until it halts.
+    ///
+    /// Returns HCINT and HCTSIZ at the halt.
+    #[allow(clippy::too_many_arguments)]
+    fn run_channel(
+        &self,
+        pipe: &UsbPipe,
+        is_in: bool,
+        size: usize,
+        packets: usize,
+        pid: u32,
+        offset: usize,
+        split: u32,
+    ) -> Result<(u32, u32), UsbError> {
+        let kind = match pipe.kind {
+            EndpointType::Control => 0,
+            EndpointType::Isochronous => 1,
+            EndpointType::Bulk => 2,
+            EndpointType::Interrupt => 3,
+        };
+        let mut hcchar = (pipe.max_packet_size as u32 & 0x7FF)
+            | ((pipe.endpoint as u32 & 0xF) << Self::HCCHAR_EP_NUM_SHIFT)
+            | (kind << Self::HCCHAR_EP_TYPE_SHIFT)
+            | Self::HCCHAR_MC_1
+            | ((pipe.address as u32 & 0x7F) << Self::HCCHAR_DEV_ADDR_SHIFT);
+        if is_in {
+            hcchar |= Self::HCCHAR_EP_DIR_IN;
+        }
+        if pipe.speed == UsbSpeed::Low {
+            hcchar |= Self::HCCHAR_LSPD;
+        }
+        // A periodic transaction is made in the next frame.
+        if matches!(
+            pipe.kind,
+            EndpointType::Interrupt | EndpointType::Isochronous
+        ) && (self.read(Dwc2Reg::HFNum) & 1) == 0
+        {
+            hcchar |= Self::HCCHAR_ODD_FRM;
+        }
+
+        self.write(Dwc2Reg::HcInt, Self::HCINT_ALL);
+        self.write(Dwc2Reg::HcIntMsk, Self::HCINT_ALL);
+        self.write(Dwc2Reg::HcSplt, split);
+        self.write(
+            Dwc2Reg::HcTSiz,
+            (size as u32 & Self::HCTSIZ_XFER_SIZE_MASK)
+                | ((packets as u32 & Self::HCTSIZ_PKT_CNT_MASK) << Self::HCTSIZ_PKT_CNT_SHIFT)
+                | (pid << Self::HCTSIZ_PID_SHIFT),
+        );
+        self.write(Dwc2Reg::HcDma, self.dma_addr(offset));
+        self.write(Dwc2Reg::HcChar, hcchar | Self::HCCHAR_CH_ENA);
+
+        match Self::wait_until(Self::CHANNEL_TIMEOUT, || {
+            (self.read(Dwc2Reg::HcInt) & Self::HCINT_CH_HLTD) != 0
+        }) {
+            Ok(_) => Ok((self.read(Dwc2Reg::HcInt), self.read(Dwc2Reg::HcTSiz))),
+            Err(err) => {
+                self.halt_channel();
+                Err(err)
+            }
+        }
+    }
+
+    /// Transfers the data in the bounce buffer, and returns the length transferred.
+    ///
+    /// A low or full speed device behind a high speed hub is reached with split
+    /// transactions of a packet each.
+    fn transfer(
+        &mut self,
+        pipe: &mut UsbPipe,
+        is_in: bool,
+        setup: bool,
+        offset: usize,
+        len: usize,
+    ) -> Result<usize, UsbError> {
+        let deadline = GenericTimer::counter()
+            + Self::TRANSFER_TIMEOUT.as_millis() as u64 * GenericTimer::frequency() / 1000;
+        let max_packet_size = (pipe.max_packet_size as usize).max(1);
+        let split = pipe
+            .tt
+            .filter(|_| pipe.speed != UsbSpeed::High)
+            .map(|(hub, port)| {
+                Self::HCSPLT_SPLT_ENA
+                    | Self::HCSPLT_XACT_POS_ALL
+                    | ((hub as u32 & 0x7F) << Self::HCSPLT_HUB_ADDR_SHIFT)
+                    | (port as u32 & 0x7F)
+            });
+
+        self.flush_dma(offset, len);
+        let mut done = 0;
+        let mut errors = 0;
+        let result = loop {
+            let remaining = len - done;
+            let size = match split {
+                Some(_) => remaining.min(max_packet_size),
+                None => remaining,
+            };
+            let packets = size.div_ceil(max_packet_size).max(1);
+            let pid = if setup {
+                Self::PID_SETUP
+            } else if pipe.toggle {
+                Self::PID_DATA1
+            } else {
+                Self::PID_DATA0
+            };
+            let buffer = offset + done;
+
+            let (hcint, hctsiz) = match split {
+                None => self.run_channel(pipe, is_in, size, packets, pid, buffer, 0)?,
+                Some(split) => {
+                    let (hcint, hctsiz) =
+                        self.run_channel(pipe, is_in, size, packets, pid, buffer, split)?;
+                    if (hcint & Self::HCINT_ACK) == 0 {
+                        (hcint, hctsiz)
+                    } else {
+                        // The hub has started the transaction, so its result is collected.
+                        loop {
+                            let (hcint, hctsiz) = self.run_channel(
+                                pipe,
+                                is_in,
+                                size,
+                                packets,
+                                pid,
+                                buffer,
+                                split | Self::HCSPLT_COMP_SPLT,
+                            )?;
+                            if (hcint & Self::HCINT_NYET) == 0 {
+                                break (hcint, hctsiz);
+                            }
+                            if GenericTimer::counter() > deadline {
+                                return Err(UsbError::Timeout);
+                            }
+                        }
+                    }
+                }
+            };
+
+            let left_size = (hctsiz & Self::HCTSIZ_XFER_SIZE_MASK) as usize;
+            let left_packets =
+                ((hctsiz >> Self::HCTSIZ_PKT_CNT_SHIFT) & Self::HCTSIZ_PKT_CNT_MASK) as usize;
+            let transferred = if (hcint & Self::HCINT_XFER_COMPL) != 0 && !is_in {
+                size
+            } else if is_in {
+                size.saturating_sub(left_size)
+            } else {
+                (packets.saturating_sub(left_packets) * max_packet_size).min(size)
+            };
+            done += transferred;
+            if !setup {
+                pipe.toggle = (hctsiz >> Self::HCTSIZ_PID_SHIFT) & 3 == Self::PID_DATA1;
+            }
+
+            if (hcint & Self::HCINT_XFER_COMPL) != 0 {
+                // A short packet ends the transfer.
+                if done >= len || (is_in && transferred < size) {
+                    break Ok(done);
+                }
+                continue;
+            }
+            if (hcint & Self::HCINT_STALL) != 0 {
+                break Err(UsbError::Stall);
+            }
+            if (hcint & Self::HCINT_BBL_ERR) != 0 {
+                break Err(UsbError::Babble);
+            }
+            if (hcint & Self::HCINT_DATA_TGL_ERR) != 0 {
+                break Err(UsbError::DataToggle);
+            }
+            if (hcint & Self::HCINT_AHB_ERR) != 0 {
+                break Err(UsbError::TransactionError);
+            }
+            if (hcint & (Self::HCINT_NAK | Self::HCINT_NYET)) != 0 {
+                if pipe.kind == EndpointType::Interrupt {
+                    break if done > 0 {
+                        Ok(done)
+                    } else {
+                        Err(UsbError::Nak)
+                    };
+                }
+                if GenericTimer::counter() > deadline {
+                    break Err(UsbError::Timeout);
+                }
+                continue;
+            }
+            if (hcint & (Self::HCINT_XACT_ERR | Self::HCINT_FRM_OVRUN)) != 0 {
+                errors += 1;
+                if errors < Self::MAX_ERRORS {
+                    continue;
+                }
+            }
+            break Err(UsbError::TransactionError);
+        };
+        if is_in {
+            self.flush_dma(offset, len);
+        }
+        result
+    }
+}
+
+impl UsbHostController for Dwc2 {
+    fn name(&self) -> &'static str {
+        "DesignWare USB 2.0 OTG"
+    }
+
+    fn reset_root_port(&mut self) -> Result<UsbSpeed, UsbError> {
+        Self::wait_until(Self::CONNECT_TIMEOUT, || {
+            (self.read(Dwc2Reg::HPrt) & Self::HPRT_CONN_STS) != 0
+        })
+        .map_err(|_| UsbError::NoDevice)?;
+
+        let hprt = self.read(Dwc2Reg::HPrt) & !Self::HPRT_W1C;
+        self.write(Dwc2Reg::HPrt, hprt | Self::HPRT_RST);
+        GenericTimer::wait(Duration::from_millis(50));
+        self.write(Dwc2Reg::HPrt, hprt & !Self::HPRT_RST);
+        GenericTimer::wait(Duration::from_millis(20));
+
+        let hprt = self.read(Dwc2Reg::HPrt);
+        if (hprt & Self::HPRT_ENA) == 0 {
+            return Err(UsbError::NoDevice);
+        }
+        // Acknowledges the changes without disabling the port.
+        self.write(
+            Dwc2Reg::HPrt,
+            (hprt & !Self::HPRT_W1C)
+                | (hprt & (Self::HPRT_CONN_DET | Self::HPRT_ENA_CHNG | Self::HPRT_OVRCUR_CHNG)),
+        );
+
+        Ok(match (hprt >> Self::HPRT_SPD_SHIFT) & 3 {
+            0 => UsbSpeed::High,
+            2 => UsbSpeed::Low,
+            _ => UsbSpeed::Full,
+        })
+    }
+
+    fn control_transfer(
+        &mut self,
+        pipe: &mut UsbPipe,
+        setup: SetupPacket,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let len = (setup.length as usize).min(data.len());
+        if len > Self::BUFFER_SIZE {
+            return Err(UsbError::OutOfResources);
+        }
+        let is_in = setup.is_in();
+
+        unsafe {
+            let bytes = setup.to_bytes();
+            self.dma
+                .add(Self::SETUP_OFFSET)
+                .copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+        }
+        self.transfer(pipe, false, true, Self::SETUP_OFFSET, 8)?;
+
+        let mut actual = 0;
+        if len > 0 {
+            pipe.toggle = true;
+            if !is_in {
+                unsafe {
+                    self.dma
+                        .add(Self::BUFFER_OFFSET)
+                        .copy_from_nonoverlapping(data.as_ptr(), len);
+                }
+            }
+            actual = self.transfer(pipe, is_in, false, Self::BUFFER_OFFSET, len)?;
+            if is_in {
+                unsafe {
+                    data.as_mut_ptr()
+                        .copy_from_nonoverlapping(self.dma.add(Self::BUFFER_OFFSET), actual);
+                }
+            }
+        }
+
+        // The status stage is a DATA1 packet in the other direction.
+        pipe.toggle = true;
+        self.transfer(pipe, !(is_in && len > 0), false, Self::BUFFER_OFFSET, 0)?;
+
+        Ok(actual)
+    }
+
+    fn transfer_in(&mut self, pipe: &mut UsbPipe, data: &mut [u8]) -> Result<usize, UsbError> {
+        // The channel takes whole packets, or a full packet at the end would babble.
+        let max_packet_size = (pipe.max_packet_size as usize).clamp(1, Self::BUFFER_SIZE);
+        let chunk_size = Self::BUFFER_SIZE / max_packet_size * max_packet_size;
+        let mut done = 0;
+        for chunk in data.chunks_mut(chunk_size) {
+            let size = chunk.len().next_multiple_of(max_packet_size);
+            let len = match self.transfer(pipe, true, false, Self::BUFFER_OFFSET, size) {
+                Ok(v) => v.min(chunk.len()),
+                Err(UsbError::Nak) if done > 0 => return Ok(done),
+                Err(err) => return Err(err),
+            };
+            unsafe {
+                chunk
+                    .as_mut_ptr()
+                    .copy_from_nonoverlapping(self.dma.add(Self::BUFFER_OFFSET), len);
+            }
+            done += len;
+            if len < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn transfer_out(&mut self, pipe: &mut UsbPipe, data: &[u8]) -> Result<usize, UsbError> {
+        let mut done = 0;
+        for chunk in data.chunks(Self::BUFFER_SIZE) {
+            unsafe {
+                self.dma
+                    .add(Self::BUFFER_OFFSET)
+                    .copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+            }
+            done += self.transfer(pipe, false, false, Self::BUFFER_OFFSET, chunk.len())?;
+        }
+        Ok(done)
+    }
+}
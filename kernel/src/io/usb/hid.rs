@@ -0,0 +1,452 @@
+//! USB human interface device class driver
+//!
+//! Keyboards and mice are used with the boot protocol. Other pointing devices, such as
+//! tablets, are read with the layout found in their report descriptor.
+
+use super::{
+    ConfigItem, EndpointType, InterfaceDescriptor, SetupPacket, UsbDevice, UsbError,
+    UsbHostController, UsbPipe,
+};
+use crate::io::input::{InputManager, MouseButtons, PointerEvent, Usage};
+use alloc::vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HidKind {
+    Keyboard,
+    Mouse,
+    Tablet,
+}
+
+/// A field of an input report
+#[derive(Debug, Clone, Copy)]
+struct ReportField {
+    /// Offset in bits after the report ID
+    offset: usize,
+    size: usize,
+    /// The logical minimum is negative.
+    signed: bool,
+    logical_max: i32,
+}
+
+impl ReportField {
+    fn read(&self, report: &[u8]) -> i32 {
+        let mut value = 0u32;
+        for bit in 0..self.size.min(32) {
+            let pos = self.offset + bit;
+            if (report.get(pos / 8).copied().unwrap_or(0) & (1 << (pos % 8))) != 0 {
+                value |= 1 << bit;
+            }
+        }
+        if self.signed && self.size > 0 && self.size < 32 && (value & (1 << (self.size - 1))) != 0 {
+            value |= !0 << self.size;
+        }
+        value as i32
+    }
+}
+
+/// The fields of a pointing device found in its report descriptor
+#[derive(Debug, Clone, Copy, Default)]
+struct PointerLayout {
+    report_id: Option<u8>,
+    /// The first button and the number of buttons
+    buttons: Option<(usize, usize)>,
+    x: Option<ReportField>,
+    y: Option<ReportField>,
+    wheel: Option<ReportField>,
+    absolute: bool,
+}
+
+impl PointerLayout {
+    const PAGE_GENERIC_DESKTOP: u32 = 0x01;
+    const PAGE_BUTTON: u32 = 0x09;
+    const USAGE_X: u32 = 0x30;
+    const USAGE_Y: u32 = 0x31;
+    const USAGE_WHEEL: u32 = 0x38;
+
+    const INPUT_CONSTANT: u32 = 0x01;
+    const INPUT_RELATIVE: u32 = 0x04;
+
+    const MAX_USAGES: usize = 16;
+    /// Fields end within the longest report read
+    const MAX_REPORT_BITS: usize = HidDevice::MAX_REPORT_LEN * 8;
+    /// Fields of the pointer fit in an `i32`.
+    const MAX_REPORT_SIZE: usize = 32;
+
+    /// Parses the input items of a report descriptor.
+    fn parse(desc: &[u8]) -> Option<Self> {
+        let mut layout = Self::default();
+        let mut page = 0u32;
+        let mut logical_min = 0i32;
+        let mut logical_max = 0i32;
+        let mut report_size = 0usize;
+        let mut report_count = 0usize;
+        let mut report_id = None;
+        let mut offset = 0usize;
+        let mut usages = [0u32; Self::MAX_USAGES];
+        let mut num_usages = 0;
+        let mut usage_range = None;
+
+        let mut cursor = 0;
+        while let Some(&prefix) = desc.get(cursor) {
+            if prefix == 0xFE {
+                // Long item
+                cursor += 3 + *desc.get(cursor + 1)? as usize;
+                continue;
+            }
+            let len = [0, 1, 2, 4][(prefix & 3) as usize];
+            let data = desc.get(cursor + 1..cursor + 1 + len)?;
+            cursor += 1 + len;
+            let value = data
+                .iter()
+                .rev()
+                .fold(0u32, |acc, v| (acc << 8) | *v as u32);
+            let signed = match len {
+                1 => value as u8 as i8 as i32,
+                2 => value as u16 as i16 as i32,
+                _ => value as i32,
+            };
+
+            match prefix & 0xFC {
+                // Input
+                0x80 => {
+                    let bits = report_size * report_count;
+                    if (value & Self::INPUT_CONSTANT) == 0
+                        && (layout.x.is_none() || layout.report_id == report_id)
+                    {
+                        layout.report_id = report_id;
+                        layout.add_fields(
+                            page,
+                            &usages[..num_usages],
+                            usage_range,
+                            offset,
+                            report_size,
+                            report_count,
+                            (logical_min < 0, logical_max),
+                            (value & Self::INPUT_RELATIVE) == 0,
+                        );
+                    }
+                    offset += bits;
+                }
+                // Usage Page
+                0x04 => page = value,
+                // Logical Minimum
+                0x14 => logical_min = signed,
+                // Logical Maximum
+                0x24 => {
+                    logical_max = if logical_min >= 0 && len < 4 {
+                        value as i32
+                    } else {
+                        signed
+                    }
+                }
+                // Report Size
+                0x74 => report_size = (value as usize).min(Self::MAX_REPORT_SIZE),
+                // Report ID
+                0x84 => {
+                    report_id = Some(value as u8);
+                    offset = 0;
+                    // The report with the coordinates is the one to read.
+                    if layout.x.is_none() {
+                        layout = Self::default();
+                    }
+                }
+                // Report Count
+                0x94 => report_count = (value as usize).min(Self::MAX_REPORT_BITS),
+                // Usage
+                0x08 => {
+                    if num_usages < Self::MAX_USAGES {
+                        usages[num_usages] = value;
+                        num_usages += 1;
+                    }
+                }
+                // Usage Minimum
+                0x18 => usage_range = Some((value, usage_range.map_or(value, |v| v.1))),
+                // Usage Maximum
+                0x28 => usage_range = Some((usage_range.map_or(0, |v| v.0), value)),
+                _ => (),
+            }
+            // Local items are cleared by main items.
+            if (prefix & 0x0C) == 0 {
+                num_usages = 0;
+                usage_range = None;
+            }
+        }
+
+        (layout.x.is_some() && layout.y.is_some()).then_some(layout)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add_fields(
+        &mut self,
+        page: u32,
+        usages: &[u32],
+        usage_range: Option<(u32, u32)>,
+        offset: usize,
+        size: usize,
+        count: usize,
+        (signed, logical_max): (bool, i32),
+        absolute: bool,
+    ) {
+        if page == Self::PAGE_BUTTON {
+            if self.buttons.is_none() && size == 1 {
+                self.buttons = Some((offset, count.min(8)));
+            }
+            return;
+        }
+        for index in 0..count {
+            let usage = match (usages.get(index).or(usages.last()), usage_range) {
+                (Some(usage), _) => *usage,
+                (None, Some((min, _))) => min.wrapping_add(index as u32),
+                (None, None) => continue,
+            };
+            // Extended usages carry the page in the upper half.
+            let (usage_page, usage) = if usage > 0xFFFF {
+                (usage >> 16, usage & 0xFFFF)
+            } else {
+                (page, usage)
+            };
+            if usage_page != Self::PAGE_GENERIC_DESKTOP {
+                continue;
+            }
+            let field = Some(ReportField {
+                offset: offset + index * size,
+                size,
+                signed,
+                logical_max,
+            });
+            match usage {
+                Self::USAGE_X => {
+                    self.x = field;
+                    self.absolute = absolute;
+                }
+                Self::USAGE_Y => self.y = field,
+                Self::USAGE_WHEEL => self.wheel = field,
+                _ => (),
+            }
+        }
+    }
+
+    fn read(&self, report: &[u8]) -> Option<PointerEvent> {
+        let report = match self.report_id {
+            Some(id) => report.strip_prefix(&[id])?,
+            None => report,
+        };
+        let buttons = self.buttons.map_or(0, |(offset, count)| {
+            ReportField {
+                offset,
+                size: count,
+                signed: false,
+                logical_max: 0,
+            }
+            .read(report) as u8
+        });
+        let scale = |field: &ReportField| {
+            let value = field.read(report);
+            if self.absolute && field.logical_max > 0 {
+                (value as i64 * PointerEvent::ABS_MAX as i64 / field.logical_max as i64) as i32
+            } else {
+                value
+            }
+        };
+        Some(PointerEvent {
+            buttons: MouseButtons::from_bits_truncate(buttons),
+            x: self.x.as_ref().map_or(0, scale),
+            y: self.y.as_ref().map_or(0, scale),
+            wheel: self.wheel.map_or(0, |v| v.read(report)),
+            absolute: self.absolute,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum HidProtocol {
+    BootKeyboard,
+    BootMouse,
+    Report(PointerLayout),
+}
+
+pub struct HidDevice {
+    pipe: UsbPipe,
+    kind: HidKind,
+    protocol: HidProtocol,
+    /// The last report of a keyboard
+    keys: [u8; Self::BOOT_KEYBOARD_REPORT_LEN],
+    next_poll: u64,
+}
+
+impl HidDevice {
+    pub const CLASS: u8 = 0x03;
+
+    const SUBCLASS_BOOT: u8 = 0x01;
+    const PROTOCOL_KEYBOARD: u8 = 0x01;
+    const PROTOCOL_MOUSE: u8 = 0x02;
+
+    const DESCRIPTOR_HID: u8 = 0x21;
+    const DESCRIPTOR_REPORT: u8 = 0x22;
+
+    const SET_IDLE: u8 = 0x0A;
+    const SET_PROTOCOL: u8 = 0x0B;
+
+    const BOOT_KEYBOARD_REPORT_LEN: usize = 8;
+    const MAX_REPORT_LEN: usize = 64;
+    const MAX_REPORT_DESCRIPTOR_LEN: usize = 1024;
+    /// Key usage of ErrorRollOver
+    const USAGE_ROLL_OVER: u8 = 0x01;
+
+    /// Binds the interface, or returns `None` if it is not a keyboard or a pointing device.
+    pub fn new(
+        hc: &mut dyn UsbHostController,
+        device: &UsbDevice,
+        interface: &InterfaceDescriptor,
+        items: &[ConfigItem],
+    ) -> Result<Option<Self>, UsbError> {
+        let Some(endpoint) = items.iter().find_map(|v| match v {
+            ConfigItem::Endpoint(v) if v.is_in() && v.kind() == EndpointType::Interrupt => Some(*v),
+            _ => None,
+        }) else {
+            return Ok(None);
+        };
+        let class_request = |request: u8, value: u16| {
+            SetupPacket::new(
+                SetupPacket::TYPE_CLASS | SetupPacket::RECIPIENT_INTERFACE,
+                request,
+                value,
+                interface.number as u16,
+                0,
+            )
+        };
+
+        let (kind, protocol) = match (interface.subclass, interface.protocol) {
+            (Self::SUBCLASS_BOOT, Self::PROTOCOL_KEYBOARD) => {
+                (HidKind::Keyboard, HidProtocol::BootKeyboard)
+            }
+            (Self::SUBCLASS_BOOT, Self::PROTOCOL_MOUSE) => (HidKind::Mouse, HidProtocol::BootMouse),
+            _ => {
+                let desc_len = items
+                    .iter()
+                    .find_map(|v| match v {
+                        ConfigItem::Other(Self::DESCRIPTOR_HID, desc) if desc.len() >= 9 => {
+                            Some(u16::from_le_bytes([desc[7], desc[8]]) as usize)
+                        }
+                        _ => None,
+                    })
+                    .ok_or(UsbError::InvalidDescriptor)?;
+                let mut desc = vec![0u8; desc_len.min(Self::MAX_REPORT_DESCRIPTOR_LEN)];
+                let len = device.get_descriptor(
+                    hc,
+                    SetupPacket::RECIPIENT_INTERFACE,
+                    Self::DESCRIPTOR_REPORT,
+                    0,
+                    interface.number as u16,
+                    &mut desc,
+                )?;
+                let Some(layout) = PointerLayout::parse(&desc[..len]) else {
+                    return Ok(None);
+                };
+                let kind = if layout.absolute {
+                    HidKind::Tablet
+                } else {
+                    HidKind::Mouse
+                };
+                (kind, HidProtocol::Report(layout))
+            }
+        };
+
+        if !matches!(protocol, HidProtocol::Report(_)) {
+            device.control(hc, class_request(Self::SET_PROTOCOL, 0), &mut [])?;
+        }
+        // Reports only on changes; some devices do not support this.
+        let _ = device.control(hc, class_request(Self::SET_IDLE, 0), &mut []);
+
+        Ok(Some(Self {
+            pipe: UsbPipe::endpoint(device, &endpoint),
+            kind,
+            protocol,
+            keys: [0; Self::BOOT_KEYBOARD_REPORT_LEN],
+            next_poll: 0,
+        }))
+    }
+
+    #[inline]
+    pub const fn address(&self) -> u8 {
+        self.pipe.address
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> HidKind {
+        self.kind
+    }
+
+    /// Reads a report if the polling interval has passed, and posts its events to
+    /// [InputManager].
+    pub fn poll(&mut self, hc: &mut dyn UsbHostController, now: u64, ticks_per_ms: u64) {
+        if now < self.next_poll {
+            return;
+        }
+        self.next_poll = now + self.pipe.interval_ms() * ticks_per_ms;
+
+        let mut report = [0u8; Self::MAX_REPORT_LEN];
+        let max_len = (self.pipe.max_packet_size as usize).min(Self::MAX_REPORT_LEN);
+        let len = match hc.transfer_in(&mut self.pipe, &mut report[..max_len]) {
+            Ok(len) => len,
+            Err(UsbError::Nak) => return,
+            Err(err) => {
+                crate::debug!("usb: hid {}: {:?}", self.address(), err);
+                return;
+            }
+        };
+        let report = &report[..len];
+
+        match self.protocol {
+            HidProtocol::BootKeyboard => self.keyboard_report(report),
+            HidProtocol::BootMouse => {
+                if report.len() >= 3 {
+                    InputManager::post_pointer(PointerEvent {
+                        buttons: MouseButtons::from_bits_truncate(report[0]),
+                        x: report[1] as i8 as i32,
+                        y: report[2] as i8 as i32,
+                        wheel: report.get(3).map_or(0, |v| *v as i8 as i32),
+                        absolute: false,
+                    });
+                }
+            }
+            HidProtocol::Report(layout) => {
+                if let Some(event) = layout.read(report) {
+                    InputManager::post_pointer(event);
+                }
+            }
+        }
+    }
+
+    /// Compares the report with the last one, and posts the keys pressed and released.
+    fn keyboard_report(&mut self, report: &[u8]) {
+        if report.len() < Self::BOOT_KEYBOARD_REPORT_LEN || report[2] == Self::USAGE_ROLL_OVER {
+            return;
+        }
+        let prev = self.keys;
+        let modifiers = report[0];
+        let post = |usage: u8, pressed: bool| {
+            InputManager::post_key(usage as u32, Usage(usage), pressed);
+        };
+
+        for bit in 0..8 {
+            let mask = 1 << bit;
+            if ((prev[0] ^ modifiers) & mask) != 0 {
+                post(Usage::LEFT_CTRL.0 + bit, (modifiers & mask) != 0);
+            }
+        }
+        for &usage in &prev[2..] {
+            if usage > Self::USAGE_ROLL_OVER && !report[2..].contains(&usage) {
+                post(usage, false);
+            }
+        }
+        for &usage in &report[2..Self::BOOT_KEYBOARD_REPORT_LEN] {
+            if usage > Self::USAGE_ROLL_OVER && !prev[2..].contains(&usage) {
+                post(usage, true);
+            }
+        }
+
+        self.keys
+            .copy_from_slice(&report[..Self::BOOT_KEYBOARD_REPORT_LEN]);
+    }
+}
@@ -0,0 +1,247 @@
+//! USB hub class driver
+
+use super::{
+    ConfigItem, EndpointType, SetupPacket, UsbDevice, UsbError, UsbHostController, UsbPipe,
+    UsbSpeed,
+};
+use crate::arch::timer::GenericTimer;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+pub struct UsbHub {
+    control: UsbPipe,
+    status: UsbPipe,
+    num_ports: u8,
+    /// All ports are examined at the next poll.
+    scan_all: bool,
+    next_poll: u64,
+}
+
+impl UsbHub {
+    pub const CLASS: u8 = 0x09;
+
+    const DESCRIPTOR_TYPE: u8 = 0x29;
+
+    const PORT_CONNECTION: u16 = 0x0001;
+    const PORT_ENABLE: u16 = 0x0002;
+    const PORT_LOW_SPEED: u16 = 0x0200;
+    const PORT_HIGH_SPEED: u16 = 0x0400;
+
+    const C_PORT_CONNECTION: u16 = 0x0001;
+    const C_PORT_RESET: u16 = 0x0010;
+
+    const FEATURE_PORT_RESET: u16 = 4;
+    const FEATURE_PORT_POWER: u16 = 8;
+    /// The change features are from C_PORT_CONNECTION to C_PORT_RESET.
+    const FEATURE_C_PORT_CONNECTION: u16 = 16;
+    const FEATURE_C_PORT_RESET: u16 = 20;
+
+    const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+    const RESET_RECOVERY: Duration = Duration::from_millis(10);
+
+    pub fn new(
+        hc: &mut dyn UsbHostController,
+        device: &UsbDevice,
+        items: &[ConfigItem],
+    ) -> Result<Self, UsbError> {
+        let endpoint = items
+            .iter()
+            .find_map(|v| match v {
+                ConfigItem::Endpoint(v) if v.is_in() && v.kind() == EndpointType::Interrupt => {
+                    Some(*v)
+                }
+                _ => None,
+            })
+            .ok_or(UsbError::InvalidDescriptor)?;
+
+        let mut desc = [0u8; 8];
+        let len = device.get_descriptor(
+            hc,
+            SetupPacket::TYPE_CLASS,
+            Self::DESCRIPTOR_TYPE,
+            0,
+            0,
+            &mut desc,
+        )?;
+        if len < 7 || desc[1] != Self::DESCRIPTOR_TYPE {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        let hub = Self {
+            control: UsbPipe::control(device),
+            status: UsbPipe::endpoint(device, &endpoint),
+            num_ports: desc[2],
+            scan_all: true,
+            next_poll: 0,
+        };
+
+        for port in 1..=hub.num_ports {
+            hub.set_port_feature(hc, port, Self::FEATURE_PORT_POWER)?;
+        }
+        // bPwrOn2PwrGood is in units of 2 ms.
+        GenericTimer::wait(Duration::from_millis(desc[5] as u64 * 2 + 20));
+
+        Ok(hub)
+    }
+
+    #[inline]
+    pub const fn address(&self) -> u8 {
+        self.control.address
+    }
+
+    #[inline]
+    pub const fn num_ports(&self) -> u8 {
+        self.num_ports
+    }
+
+    fn port_request(
+        &self,
+        hc: &mut dyn UsbHostController,
+        request: u8,
+        port: u8,
+        feature: u16,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let dir = if request == SetupPacket::GET_STATUS {
+            SetupPacket::DIR_IN
+        } else {
+            0
+        };
+        let setup = SetupPacket::new(
+            dir | SetupPacket::TYPE_CLASS | SetupPacket::RECIPIENT_OTHER,
+            request,
+            feature,
+            port as u16,
+            data.len() as u16,
+        );
+        hc.control_transfer(&mut self.control.clone(), setup, data)
+    }
+
+    #[inline]
+    fn set_port_feature(
+        &self,
+        hc: &mut dyn UsbHostController,
+        port: u8,
+        feature: u16,
+    ) -> Result<(), UsbError> {
+        self.port_request(hc, SetupPacket::SET_FEATURE, port, feature, &mut [])
+            .map(|_| ())
+    }
+
+    #[inline]
+    fn clear_port_feature(
+        &self,
+        hc: &mut dyn UsbHostController,
+        port: u8,
+        feature: u16,
+    ) -> Result<(), UsbError> {
+        self.port_request(hc, SetupPacket::CLEAR_FEATURE, port, feature, &mut [])
+            .map(|_| ())
+    }
+
+    /// Returns the status and the changes of the port.
+    fn port_status(
+        &self,
+        hc: &mut dyn UsbHostController,
+        port: u8,
+    ) -> Result<(u16, u16), UsbError> {
+        let mut data = [0u8; 4];
+        self.port_request(hc, SetupPacket::GET_STATUS, port, 0, &mut data)?;
+        Ok((
+            u16::from_le_bytes([data[0], data[1]]),
+            u16::from_le_bytes([data[2], data[3]]),
+        ))
+    }
+
+    /// Resets the port and returns the speed of the device connected to it.
+    ///
+    /// The device answers at the default address until it is given one.
+    pub fn reset_port(
+        &self,
+        hc: &mut dyn UsbHostController,
+        port: u8,
+    ) -> Result<UsbSpeed, UsbError> {
+        self.set_port_feature(hc, port, Self::FEATURE_PORT_RESET)?;
+        let deadline = GenericTimer::counter()
+            + Self::RESET_TIMEOUT.as_millis() as u64 * GenericTimer::frequency() / 1000;
+        let status = loop {
+            GenericTimer::wait(Duration::from_millis(10));
+            let (status, change) = self.port_status(hc, port)?;
+            if (change & Self::C_PORT_RESET) != 0 {
+                self.clear_port_feature(hc, port, Self::FEATURE_C_PORT_RESET)?;
+                break status;
+            }
+            if GenericTimer::counter() > deadline {
+                return Err(UsbError::Timeout);
+            }
+        };
+        if (status & Self::PORT_ENABLE) == 0 {
+            return Err(UsbError::NoDevice);
+        }
+        GenericTimer::wait(Self::RESET_RECOVERY);
+
+        Ok(if (status & Self::PORT_LOW_SPEED) != 0 {
+            UsbSpeed::Low
+        } else if (status & Self::PORT_HIGH_SPEED) != 0 {
+            UsbSpeed::High
+        } else {
+            UsbSpeed::Full
+        })
+    }
+
+    /// Collects the ports whose connection changed, with whether a device is connected.
+    pub fn poll(
+        &mut self,
+        hc: &mut dyn UsbHostController,
+        now: u64,
+        ticks_per_ms: u64,
+        changes: &mut Vec<(u8, u8, bool)>,
+    ) {
+        if now < self.next_poll {
+            return;
+        }
+        self.next_poll = now + self.status.interval_ms() * ticks_per_ms;
+
+        let mut bitmap = [0u8; 32];
+        let scanning = self.scan_all;
+        let ports = if scanning {
+            self.scan_all = false;
+            bitmap.fill(0xFF);
+            &bitmap[..]
+        } else {
+            let len = (self.num_ports as usize + 8) / 8;
+            match hc.transfer_in(&mut self.status, &mut bitmap[..len]) {
+                Ok(len) => &bitmap[..len],
+                Err(UsbError::Nak) => return,
+                Err(err) => {
+                    crate::debug!("usb: hub {}: {:?}", self.address(), err);
+                    return;
+                }
+            }
+        };
+        // Bit 0 is the hub itself.
+        let changed = (1..=self.num_ports)
+            .filter(|port| {
+                let port = *port as usize;
+                (ports.get(port / 8).copied().unwrap_or(0) & (1 << (port % 8))) != 0
+            })
+            .collect::<Vec<_>>();
+
+        for port in changed {
+            let Ok((status, change)) = self.port_status(hc, port) else {
+                continue;
+            };
+            // Acknowledge all changes from C_PORT_CONNECTION to C_PORT_RESET.
+            for bit in 0..5 {
+                if (change & (1 << bit)) != 0 {
+                    let _ =
+                        self.clear_port_feature(hc, port, Self::FEATURE_C_PORT_CONNECTION + bit);
+                }
+            }
+            let connected = (status & Self::PORT_CONNECTION) != 0;
+            if (change & Self::C_PORT_CONNECTION) == 0 && !(scanning && connected) {
+                continue;
+            }
+            changes.push((self.address(), port, connected));
+        }
+    }
+}
@@ -0,0 +1,818 @@
+//! Universal Serial Bus
+//!
+//! The host controller driver implements [UsbHostController], and the devices on its root
+//! port are enumerated through hubs. There are no interrupts for the devices yet, so
+//! [Usb::poll] polls the interrupt endpoints of the hubs and HID devices, whose input
+//! goes to [InputManager](crate::io::input::InputManager). Mass storage devices are
+//! [BlockDevice](crate::io::block::BlockDevice)s.
+
+use crate::{
+    arch::timer::GenericTimer,
+    io::block::BlockDevice,
+    shell::{ShellCommand, ShellError},
+};
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use core::{cell::UnsafeCell, fmt, fmt::Write, time::Duration};
+
+pub mod dwc2;
+pub mod hid;
+pub mod hub;
+pub mod storage;
+
+use self::{hid::HidDevice, hub::UsbHub, storage::UsbStorage};
+
+static mut USB: UnsafeCell<Usb> = UnsafeCell::new(Usb::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// The host controller did not find a connected device.
+    NoDevice,
+    /// The device did not respond in time.
+    Timeout,
+    /// The endpoint returned STALL.
+    Stall,
+    /// The endpoint has no data yet, returned only for interrupt endpoints.
+    Nak,
+    /// CRC, bit stuffing or other transaction errors
+    TransactionError,
+    /// The device sent more data than requested.
+    Babble,
+    DataToggle,
+    /// The device returned a broken descriptor.
+    InvalidDescriptor,
+    /// The device failed a class specific command.
+    CommandFailed,
+    /// No more addresses or DMA memory
+    OutOfResources,
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbSpeed {
+    Low,
+    Full,
+    High,
+}
+
+impl fmt::Display for UsbSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Low => "1.5M",
+            Self::Full => "12M",
+            Self::High => "480M",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointType {
+    Control = 0,
+    Isochronous = 1,
+    Bulk = 2,
+    Interrupt = 3,
+}
+
+/// The setup packet of a control transfer
+#[derive(Debug, Clone, Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub const DIR_IN: u8 = 0x80;
+    pub const TYPE_CLASS: u8 = 0x20;
+    pub const RECIPIENT_INTERFACE: u8 = 0x01;
+    pub const RECIPIENT_ENDPOINT: u8 = 0x02;
+    pub const RECIPIENT_OTHER: u8 = 0x03;
+
+    pub const GET_STATUS: u8 = 0x00;
+    pub const CLEAR_FEATURE: u8 = 0x01;
+    pub const SET_FEATURE: u8 = 0x03;
+    pub const SET_ADDRESS: u8 = 0x05;
+    pub const GET_DESCRIPTOR: u8 = 0x06;
+    pub const SET_CONFIGURATION: u8 = 0x09;
+
+    /// The feature of CLEAR_FEATURE to clear a halted endpoint
+    pub const ENDPOINT_HALT: u16 = 0x00;
+
+    #[inline]
+    pub const fn new(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> Self {
+        Self {
+            request_type,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    #[inline]
+    pub const fn is_in(&self) -> bool {
+        (self.request_type & Self::DIR_IN) != 0
+    }
+
+    #[inline]
+    pub const fn to_bytes(&self) -> [u8; 8] {
+        let value = self.value.to_le_bytes();
+        let index = self.index.to_le_bytes();
+        let length = self.length.to_le_bytes();
+        [
+            self.request_type,
+            self.request,
+            value[0],
+            value[1],
+            index[0],
+            index[1],
+            length[0],
+            length[1],
+        ]
+    }
+}
+
+/// An endpoint of a device and the state of its data toggle
+#[derive(Debug, Clone, Copy)]
+pub struct UsbPipe {
+    pub address: u8,
+    pub endpoint: u8,
+    pub is_in: bool,
+    pub kind: EndpointType,
+    pub max_packet_size: u16,
+    pub speed: UsbSpeed,
+    /// Polling interval of an interrupt endpoint in milliseconds
+    pub interval: u8,
+    /// Address and port of the high speed hub whose transaction translator serves
+    /// this low or full speed device
+    pub tt: Option<(u8, u8)>,
+    /// The next data packet is DATA1.
+    pub toggle: bool,
+}
+
+impl UsbPipe {
+    /// Returns the default control pipe of the device.
+    #[inline]
+    pub const fn control(device: &UsbDevice) -> Self {
+        Self {
+            address: device.address,
+            endpoint: 0,
+            is_in: false,
+            kind: EndpointType::Control,
+            max_packet_size: device.max_packet_size0,
+            speed: device.speed,
+            interval: 0,
+            tt: device.tt,
+            toggle: false,
+        }
+    }
+
+    /// Returns the pipe of an endpoint of the device.
+    #[inline]
+    pub const fn endpoint(device: &UsbDevice, endpoint: &EndpointDescriptor) -> Self {
+        Self {
+            address: device.address,
+            endpoint: endpoint.number(),
+            is_in: endpoint.is_in(),
+            kind: endpoint.kind(),
+            max_packet_size: endpoint.max_packet_size(),
+            speed: device.speed,
+            interval: endpoint.interval,
+            tt: device.tt,
+            toggle: false,
+        }
+    }
+
+    /// Returns the polling interval in milliseconds.
+    pub const fn interval_ms(&self) -> u64 {
+        match (self.speed, self.interval) {
+            (_, 0) => 1,
+            // In units of 2^(bInterval-1) microframes
+            (UsbSpeed::High, v) => ((1u64 << (v.min(16) - 1)) / 8).max(1),
+            (_, v) => v as u64,
+        }
+    }
+}
+
+/// A USB host controller with a single root port
+pub trait UsbHostController {
+    fn name(&self) -> &'static str;
+
+    /// Resets the root port, and returns the speed of the device connected to it.
+    fn reset_root_port(&mut self) -> Result<UsbSpeed, UsbError>;
+
+    /// Performs a control transfer. `data` is read or written by the direction of `setup`.
+    fn control_transfer(
+        &mut self,
+        pipe: &mut UsbPipe,
+        setup: SetupPacket,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError>;
+
+    /// Performs a bulk or interrupt IN transfer, and returns the length received.
+    fn transfer_in(&mut self, pipe: &mut UsbPipe, data: &mut [u8]) -> Result<usize, UsbError>;
+
+    /// Performs a bulk or interrupt OUT transfer.
+    fn transfer_out(&mut self, pipe: &mut UsbPipe, data: &[u8]) -> Result<usize, UsbError>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer: u8,
+    pub product: u8,
+    pub serial_number: u8,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const TYPE: u8 = 0x01;
+    pub const LEN: usize = 18;
+
+    pub fn parse(data: &[u8]) -> Result<Self, UsbError> {
+        if data.len() < Self::LEN || data[1] != Self::TYPE {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        Ok(Self {
+            usb_version: u16_at(2),
+            class: data[4],
+            subclass: data[5],
+            protocol: data[6],
+            max_packet_size0: data[7],
+            vendor_id: u16_at(8),
+            product_id: u16_at(10),
+            device_version: u16_at(12),
+            manufacturer: data[14],
+            product: data[15],
+            serial_number: data[16],
+            num_configurations: data[17],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub num_endpoints: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+impl InterfaceDescriptor {
+    pub const TYPE: u8 = 0x04;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub const TYPE: u8 = 0x05;
+
+    #[inline]
+    pub const fn number(&self) -> u8 {
+        self.address & 0x0F
+    }
+
+    #[inline]
+    pub const fn is_in(&self) -> bool {
+        (self.address & 0x80) != 0
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> EndpointType {
+        match self.attributes & 3 {
+            0 => EndpointType::Control,
+            1 => EndpointType::Isochronous,
+            2 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        }
+    }
+
+    /// Returns the maximum packet size without the additional transactions of high speed.
+    #[inline]
+    pub const fn max_packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7FF
+    }
+}
+
+/// A descriptor in the configuration descriptor
+#[derive(Debug, Clone, Copy)]
+pub enum ConfigItem<'a> {
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    /// Class specific or unknown descriptor with its type and the whole descriptor
+    Other(u8, &'a [u8]),
+}
+
+/// The configuration descriptor with the descriptors that follow it
+pub struct Configuration {
+    data: Vec<u8>,
+}
+
+impl Configuration {
+    pub const TYPE: u8 = 0x02;
+    pub const LEN: usize = 9;
+
+    pub fn new(data: Vec<u8>) -> Result<Self, UsbError> {
+        if data.len() < Self::LEN || data[1] != Self::TYPE {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        Ok(Self { data })
+    }
+
+    #[inline]
+    pub fn value(&self) -> u8 {
+        self.data[5]
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = ConfigItem<'_>> {
+        let mut data = &self.data[Self::LEN.min(self.data[0] as usize)..];
+        core::iter::from_fn(move || {
+            let len = *data.first()? as usize;
+            if len < 2 || len > data.len() {
+                return None;
+            }
+            let (desc, rest) = data.split_at(len);
+            data = rest;
+            Some(match desc[1] {
+                InterfaceDescriptor::TYPE if len >= 9 => {
+                    ConfigItem::Interface(InterfaceDescriptor {
+                        number: desc[2],
+                        alternate_setting: desc[3],
+                        num_endpoints: desc[4],
+                        class: desc[5],
+                        subclass: desc[6],
+                        protocol: desc[7],
+                    })
+                }
+                EndpointDescriptor::TYPE if len >= 7 => ConfigItem::Endpoint(EndpointDescriptor {
+                    address: desc[2],
+                    attributes: desc[3],
+                    max_packet_size: u16::from_le_bytes([desc[4], desc[5]]),
+                    interval: desc[6],
+                }),
+                kind => ConfigItem::Other(kind, desc),
+            })
+        })
+    }
+
+    /// Returns the interfaces of the first alternate setting with their descriptors.
+    pub fn interfaces(&self) -> impl Iterator<Item = (InterfaceDescriptor, Vec<ConfigItem<'_>>)> {
+        let mut items = self.items().peekable();
+        core::iter::from_fn(move || loop {
+            let ConfigItem::Interface(interface) = items.next()? else {
+                continue;
+            };
+            let mut children = Vec::new();
+            while let Some(item) = items.next_if(|v| !matches!(v, ConfigItem::Interface(_))) {
+                children.push(item);
+            }
+            if interface.alternate_setting == 0 {
+                return Some((interface, children));
+            }
+        })
+    }
+}
+
+/// A device on the bus
+pub struct UsbDevice {
+    address: u8,
+    speed: UsbSpeed,
+    max_packet_size0: u16,
+    /// The hub and its port the device is connected to, or `None` for the root port
+    parent: Option<(u8, u8)>,
+    tt: Option<(u8, u8)>,
+    descriptor: DeviceDescriptor,
+    product: String,
+}
+
+impl UsbDevice {
+    /// Maximum length of the descriptors read
+    const MAX_DESCRIPTOR_LEN: usize = 1024;
+
+    #[inline]
+    pub const fn address(&self) -> u8 {
+        self.address
+    }
+
+    #[inline]
+    pub const fn speed(&self) -> UsbSpeed {
+        self.speed
+    }
+
+    #[inline]
+    pub const fn parent(&self) -> Option<(u8, u8)> {
+        self.parent
+    }
+
+    #[inline]
+    pub const fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+
+    #[inline]
+    pub fn product(&self) -> &str {
+        &self.product
+    }
+
+    /// Performs a control transfer on the default pipe.
+    pub fn control(
+        &self,
+        hc: &mut dyn UsbHostController,
+        setup: SetupPacket,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        hc.control_transfer(&mut UsbPipe::control(self), setup, data)
+    }
+
+    pub fn get_descriptor(
+        &self,
+        hc: &mut dyn UsbHostController,
+        request_type: u8,
+        kind: u8,
+        index: u8,
+        lang: u16,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let setup = SetupPacket::new(
+            SetupPacket::DIR_IN | request_type,
+            SetupPacket::GET_DESCRIPTOR,
+            ((kind as u16) << 8) | index as u16,
+            lang,
+            data.len() as u16,
+        );
+        self.control(hc, setup, data)
+    }
+
+    /// Reads a string descriptor in US English.
+    fn get_string(&self, hc: &mut dyn UsbHostController, index: u8) -> Option<String> {
+        const STRING: u8 = 0x03;
+        const LANG_EN_US: u16 = 0x0409;
+        if index == 0 {
+            return None;
+        }
+        let mut data = [0u8; 256];
+        let len = self
+            .get_descriptor(hc, 0, STRING, index, LANG_EN_US, &mut data)
+            .ok()?;
+        let len = len.min(data[0] as usize);
+        if len < 2 {
+            return None;
+        }
+        let chars = data[2..len]
+            .chunks_exact(2)
+            .map(|v| u16::from_le_bytes([v[0], v[1]]));
+        Some(
+            char::decode_utf16(chars)
+                .map(|v| v.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+
+    /// Reads the whole first configuration.
+    fn get_configuration(&self, hc: &mut dyn UsbHostController) -> Result<Configuration, UsbError> {
+        let mut header = [0u8; Configuration::LEN];
+        self.get_descriptor(hc, 0, Configuration::TYPE, 0, 0, &mut header)?;
+        let total_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if !(Configuration::LEN..=Self::MAX_DESCRIPTOR_LEN).contains(&total_len) {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        let mut data = vec![0u8; total_len];
+        let len = self.get_descriptor(hc, 0, Configuration::TYPE, 0, 0, &mut data)?;
+        data.truncate(len);
+        Configuration::new(data)
+    }
+}
+
+/// The host controller and the devices on its bus
+pub struct Usb {
+    hc: Option<Box<dyn UsbHostController>>,
+    devices: Vec<UsbDevice>,
+    hubs: Vec<UsbHub>,
+    hid: Vec<HidDevice>,
+    storage: Vec<UsbStorage>,
+    /// Addresses in use, where address 0 is the default address
+    addresses: [u64; 2],
+}
+
+impl Usb {
+    const MAX_ADDRESS: u8 = 127;
+
+    const fn new() -> Self {
+        Self {
+            hc: None,
+            devices: Vec::new(),
+            hubs: Vec::new(),
+            hid: Vec::new(),
+            storage: Vec::new(),
+            addresses: [1, 0],
+        }
+    }
+
+    #[inline]
+    unsafe fn shared_mut<'a>() -> &'a mut Self {
+        &mut *USB.get()
+    }
+
+    /// Starts the host controller and enumerates the devices connected to it.
+    pub unsafe fn init(hc: Box<dyn UsbHostController>) {
+        let shared = Self::shared_mut();
+        crate::info!("usb: {}", hc.name());
+        shared.hc = Some(hc);
+
+        let speed = match shared.hc.as_mut().unwrap().reset_root_port() {
+            Ok(v) => v,
+            Err(UsbError::NoDevice) => return,
+            Err(err) => {
+                crate::error!("usb: root port: {:?}", err);
+                return;
+            }
+        };
+        if let Err(err) = shared.attach(speed, None, None) {
+            crate::error!("usb: root port: {:?}", err);
+        }
+    }
+
+    /// Returns the devices on the bus.
+    #[inline]
+    pub fn devices<'a>() -> &'a [UsbDevice] {
+        unsafe { Self::shared_mut().devices.as_slice() }
+    }
+
+    #[inline]
+    pub fn hid_devices<'a>() -> &'a [HidDevice] {
+        unsafe { Self::shared_mut().hid.as_slice() }
+    }
+
+    /// Returns the mass storage devices.
+    #[inline]
+    pub fn storage_devices<'a>() -> &'a mut [UsbStorage] {
+        unsafe { Self::shared_mut().storage.as_mut_slice() }
+    }
+
+    /// Returns the host controller for the class drivers outside of [Usb::poll].
+    #[inline]
+    fn host_controller<'a>() -> Option<&'a mut dyn UsbHostController> {
+        match unsafe { Self::shared_mut().hc.as_mut() } {
+            Some(hc) => Some(hc.as_mut()),
+            None => None,
+        }
+    }
+
+    /// Polls the hubs for new devices and the HID devices for input.
+    ///
+    /// Only one device may answer at the default address, so each new device is reset
+    /// and given an address before the next port is reset.
+    pub fn poll() {
+        let shared = unsafe { Self::shared_mut() };
+        let Some(hc) = shared.hc.as_mut() else {
+            return;
+        };
+        let now = GenericTimer::counter();
+        let ticks_per_ms = GenericTimer::frequency() / 1000;
+
+        let hc = hc.as_mut();
+        for hid in shared.hid.iter_mut() {
+            hid.poll(hc, now, ticks_per_ms);
+        }
+
+        let mut changes = Vec::new();
+        for hub in shared.hubs.iter_mut() {
+            hub.poll(hc, now, ticks_per_ms, &mut changes);
+        }
+        for (hub, port, connected) in changes {
+            shared.detach(Some((hub, port)));
+            if !connected {
+                continue;
+            }
+            // The hub itself may have been removed by an earlier change.
+            let Some(hub_driver) = shared.hubs.iter().find(|v| v.address() == hub) else {
+                continue;
+            };
+            let hc = shared.hc.as_mut().unwrap().as_mut();
+            let result = hub_driver.reset_port(hc, port).and_then(|speed| {
+                let tt = shared.tt_for(hub, port, speed);
+                shared.attach(speed, Some((hub, port)), tt)
+            });
+            if let Err(err) = result {
+                crate::error!("usb: hub {} port {}: {:?}", hub, port, err);
+            }
+        }
+    }
+
+    fn alloc_address(&mut self) -> Option<u8> {
+        let address = (1..=Self::MAX_ADDRESS)
+            .find(|v| (self.addresses[*v as usize / 64] & (1 << (*v % 64))) == 0)?;
+        self.addresses[address as usize / 64] |= 1 << (address % 64);
+        Some(address)
+    }
+
+    #[inline]
+    fn free_address(&mut self, address: u8) {
+        self.addresses[address as usize / 64] &= !(1 << (address % 64));
+    }
+
+    /// Returns the transaction translator for a device connected to the port of the hub.
+    fn tt_for(&self, hub: u8, port: u8, speed: UsbSpeed) -> Option<(u8, u8)> {
+        if speed == UsbSpeed::High {
+            return None;
+        }
+        let hub_device = self.devices.iter().find(|v| v.address == hub)?;
+        match hub_device.speed {
+            UsbSpeed::High => Some((hub, port)),
+            _ => hub_device.tt,
+        }
+    }
+
+    /// Enumerates the device that has just been reset on a port.
+    fn attach(
+        &mut self,
+        speed: UsbSpeed,
+        parent: Option<(u8, u8)>,
+        tt: Option<(u8, u8)>,
+    ) -> Result<(), UsbError> {
+        let hc = self.hc.as_mut().ok_or(UsbError::NoDevice)?.as_mut();
+        let mut device = UsbDevice {
+            address: 0,
+            speed,
+            max_packet_size0: match speed {
+                UsbSpeed::High => 64,
+                _ => 8,
+            },
+            parent,
+            tt,
+            descriptor: DeviceDescriptor::default(),
+            product: String::new(),
+        };
+
+        // The first 8 bytes have the maximum packet size of the default pipe.
+        let mut data = [0u8; DeviceDescriptor::LEN];
+        device.get_descriptor(hc, 0, DeviceDescriptor::TYPE, 0, 0, &mut data[..8])?;
+        if !matches!(data[7], 8 | 16 | 32 | 64) {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        device.max_packet_size0 = data[7] as u16;
+
+        let address = self.alloc_address().ok_or(UsbError::OutOfResources)?;
+        let hc = self.hc.as_mut().unwrap().as_mut();
+        let setup = SetupPacket::new(0, SetupPacket::SET_ADDRESS, address as u16, 0, 0);
+        if let Err(err) = device.control(hc, setup, &mut []) {
+            self.free_address(address);
+            return Err(err);
+        }
+        device.address = address;
+        // SET_ADDRESS recovery interval
+        GenericTimer::wait(Duration::from_millis(2));
+
+        let result = self.configure(&mut device);
+        match result {
+            Ok(_) => {
+                crate::info!(
+                    "usb: {} {:04x}:{:04x} {} at address {}",
+                    speed,
+                    device.descriptor.vendor_id,
+                    device.descriptor.product_id,
+                    device.product,
+                    address
+                );
+                self.devices.push(device);
+                Ok(())
+            }
+            Err(err) => {
+                self.free_address(address);
+                Err(err)
+            }
+        }
+    }
+
+    /// Reads the descriptors, selects the first configuration and binds the class drivers.
+    fn configure(&mut self, device: &mut UsbDevice) -> Result<(), UsbError> {
+        let hc = self.hc.as_mut().ok_or(UsbError::NoDevice)?.as_mut();
+        let mut data = [0u8; DeviceDescriptor::LEN];
+        device.get_descriptor(hc, 0, DeviceDescriptor::TYPE, 0, 0, &mut data)?;
+        device.descriptor = DeviceDescriptor::parse(&data)?;
+        device.product = device
+            .get_string(hc, device.descriptor.product)
+            .unwrap_or_default();
+
+        let config = device.get_configuration(hc)?;
+        let setup = SetupPacket::new(
+            0,
+            SetupPacket::SET_CONFIGURATION,
+            config.value() as u16,
+            0,
+            0,
+        );
+        device.control(hc, setup, &mut [])?;
+
+        for (interface, items) in config.interfaces() {
+            let hc = self.hc.as_mut().unwrap().as_mut();
+            match interface.class {
+                UsbHub::CLASS => match UsbHub::new(hc, device, &items) {
+                    Ok(hub) => self.hubs.push(hub),
+                    Err(err) => crate::error!("usb: hub {}: {:?}", device.address, err),
+                },
+                HidDevice::CLASS => match HidDevice::new(hc, device, &interface, &items) {
+                    Ok(Some(hid)) => self.hid.push(hid),
+                    Ok(None) => (),
+                    Err(err) => crate::error!("usb: hid {}: {:?}", device.address, err),
+                },
+                UsbStorage::CLASS => match UsbStorage::new(hc, device, &interface, &items) {
+                    Ok(Some(storage)) => self.storage.push(storage),
+                    Ok(None) => (),
+                    Err(err) => crate::error!("usb: storage {}: {:?}", device.address, err),
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the device on the port and the devices behind it.
+    fn detach(&mut self, port: Option<(u8, u8)>) {
+        while let Some(index) = self.devices.iter().position(|v| v.parent == port) {
+            let device = self.devices.remove(index);
+            let address = device.address;
+            crate::info!("usb: device {} removed", address);
+            self.hid.retain(|v| v.address() != address);
+            self.hubs.retain(|v| v.address() != address);
+            self.storage.retain(|v| v.address() != address);
+            let children = self
+                .devices
+                .iter()
+                .filter_map(|v| v.parent.filter(|(hub, _)| *hub == address))
+                .collect::<Vec<_>>();
+            for child in children {
+                self.detach(Some(child));
+            }
+            self.free_address(address);
+        }
+    }
+}
+
+struct UsbCommand;
+
+impl ShellCommand for UsbCommand {
+    fn name(&self) -> &'static str {
+        "usb"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the USB devices"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        for device in Usb::devices() {
+            let desc = device.descriptor();
+            write!(
+                out,
+                "{:3} {:>4} {:04x}:{:04x} class {:02x}",
+                device.address(),
+                device.speed(),
+                desc.vendor_id,
+                desc.product_id,
+                desc.class,
+            )?;
+            if let Some((hub, port)) = device.parent() {
+                write!(out, " hub {} port {}", hub, port)?;
+            }
+            writeln!(out, " {}", device.product())?;
+        }
+        for hid in Usb::hid_devices() {
+            writeln!(out, "{:3} {:?}", hid.address(), hid.kind())?;
+        }
+        for storage in Usb::storage_devices().iter() {
+            writeln!(
+                out,
+                "{:3} Storage {} MB {}",
+                storage.address(),
+                storage.size() >> 20,
+                storage.name(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static USB_COMMAND: UsbCommand = UsbCommand;
+}
@@ -0,0 +1,471 @@
+//! USB mass storage class driver
+//!
+//! Only the Bulk-Only Transport with the SCSI transparent command set is supported,
+//! which covers USB flash drives and card readers. Logical unit 0 is used.
+
+use super::{
+    ConfigItem, EndpointType, InterfaceDescriptor, SetupPacket, Usb, UsbDevice, UsbError,
+    UsbHostController, UsbPipe,
+};
+use crate::{
+    arch::timer::GenericTimer,
+    io::block::{BlockDevice, BlockError},
+};
+use alloc::{format, string::String};
+use core::{str, time::Duration};
+
+/// The data phase of a command
+enum DataPhase<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+/// The sense data of a failed command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sense {
+    key: u8,
+    asc: u8,
+    ascq: u8,
+}
+
+impl Sense {
+    const NO_SENSE: u8 = 0x0;
+    const NOT_READY: u8 = 0x2;
+    const ILLEGAL_REQUEST: u8 = 0x5;
+    const UNIT_ATTENTION: u8 = 0x6;
+    const DATA_PROTECT: u8 = 0x7;
+
+    fn to_error(self) -> BlockError {
+        match self.key {
+            Self::NOT_READY | Self::UNIT_ATTENTION => BlockError::NotReady,
+            Self::ILLEGAL_REQUEST => BlockError::OutOfRange,
+            Self::DATA_PROTECT => BlockError::ReadOnly,
+            _ => BlockError::IoError,
+        }
+    }
+}
+
+pub struct UsbStorage {
+    control: UsbPipe,
+    bulk_in: UsbPipe,
+    bulk_out: UsbPipe,
+    interface: u8,
+    tag: u32,
+    block_size: usize,
+    num_blocks: u64,
+    read_only: bool,
+    /// The vendor and product given by INQUIRY
+    name: String,
+}
+
+impl UsbStorage {
+    pub const CLASS: u8 = 0x08;
+
+    const SUBCLASS_SCSI: u8 = 0x06;
+    const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+    const BULK_ONLY_RESET: u8 = 0xFF;
+    const GET_MAX_LUN: u8 = 0xFE;
+
+    const CBW_SIGNATURE: u32 = 0x4342_5355;
+    const CBW_LEN: usize = 31;
+    const CBW_FLAG_IN: u8 = 0x80;
+    const CSW_SIGNATURE: u32 = 0x5342_5355;
+    const CSW_LEN: usize = 13;
+    const CSW_PASSED: u8 = 0;
+    const CSW_FAILED: u8 = 1;
+
+    const TEST_UNIT_READY: u8 = 0x00;
+    const REQUEST_SENSE: u8 = 0x03;
+    const INQUIRY: u8 = 0x12;
+    const MODE_SENSE_6: u8 = 0x1A;
+    const READ_CAPACITY_10: u8 = 0x25;
+    const READ_10: u8 = 0x28;
+    const WRITE_10: u8 = 0x2A;
+    const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+    const SERVICE_ACTION_IN_16: u8 = 0x9E;
+    const SA_READ_CAPACITY_16: u8 = 0x10;
+
+    const INQUIRY_LEN: usize = 36;
+    const SENSE_LEN: usize = 18;
+    const DEVICE_TYPE_DISK: u8 = 0x00;
+
+    /// Maximum length of a READ or WRITE command
+    const MAX_TRANSFER: usize = 0x10000;
+
+    const READY_RETRIES: usize = 10;
+    const READY_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn new(
+        hc: &mut dyn UsbHostController,
+        device: &UsbDevice,
+        interface: &InterfaceDescriptor,
+        items: &[ConfigItem],
+    ) -> Result<Option<Self>, UsbError> {
+        if interface.subclass != Self::SUBCLASS_SCSI
+            || interface.protocol != Self::PROTOCOL_BULK_ONLY
+        {
+            return Ok(None);
+        }
+        let find_bulk = |is_in: bool| {
+            items.iter().find_map(|v| match v {
+                ConfigItem::Endpoint(v) if v.is_in() == is_in && v.kind() == EndpointType::Bulk => {
+                    Some(UsbPipe::endpoint(device, v))
+                }
+                _ => None,
+            })
+        };
+        let (Some(bulk_in), Some(bulk_out)) = (find_bulk(true), find_bulk(false)) else {
+            return Err(UsbError::InvalidDescriptor);
+        };
+
+        let mut storage = Self {
+            control: UsbPipe::control(device),
+            bulk_in,
+            bulk_out,
+            interface: interface.number,
+            tag: 0,
+            block_size: 0,
+            num_blocks: 0,
+            read_only: false,
+            name: String::new(),
+        };
+
+        // Some devices expect this before the first command, and may stall it if they
+        // have a single LUN.
+        let mut max_lun = [0u8; 1];
+        let _ = storage.class_request(hc, Self::GET_MAX_LUN, true, &mut max_lun);
+
+        let mut inquiry = [0u8; Self::INQUIRY_LEN];
+        let cdb = [Self::INQUIRY, 0, 0, 0, Self::INQUIRY_LEN as u8, 0];
+        storage.command(hc, &cdb, DataPhase::In(&mut inquiry))?;
+        if (inquiry[0] & 0x1F) != Self::DEVICE_TYPE_DISK {
+            return Ok(None);
+        }
+        let vendor = str::from_utf8(&inquiry[8..16]).unwrap_or_default().trim();
+        let product = str::from_utf8(&inquiry[16..32]).unwrap_or_default().trim();
+        storage.name = format!("{} {}", vendor, product).trim().into();
+
+        // A unit attention is reported once after the reset.
+        for _ in 0..Self::READY_RETRIES {
+            match storage.test_unit_ready(hc) {
+                Ok(_) => break,
+                Err(_) => GenericTimer::wait(Self::READY_INTERVAL),
+            }
+        }
+        // There may be no medium in a card reader.
+        match storage.read_capacity(hc) {
+            Ok((last_lba, block_size)) => {
+                // The size in bytes must fit in 64 bits.
+                let num_blocks = last_lba
+                    .checked_add(1)
+                    .filter(|v| v.checked_mul(block_size as u64).is_some())
+                    .ok_or(UsbError::InvalidDescriptor)?;
+                if (512..=0x10000).contains(&block_size) && block_size.is_power_of_two() {
+                    storage.num_blocks = num_blocks;
+                    storage.block_size = block_size as usize;
+                } else {
+                    crate::warn!(
+                        "usb: storage {}: unsupported block size {}",
+                        device.address(),
+                        block_size
+                    );
+                }
+            }
+            Err(err) => crate::warn!("usb: storage {}: {:?}", device.address(), err),
+        }
+
+        let mut mode = [0u8; 4];
+        let cdb = [Self::MODE_SENSE_6, 0, 0x3F, 0, mode.len() as u8, 0];
+        if storage.command(hc, &cdb, DataPhase::In(&mut mode)).is_ok() {
+            storage.read_only = (mode[2] & 0x80) != 0;
+        }
+
+        Ok(Some(storage))
+    }
+
+    #[inline]
+    pub const fn address(&self) -> u8 {
+        self.control.address
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn class_request(
+        &mut self,
+        hc: &mut dyn UsbHostController,
+        request: u8,
+        is_in: bool,
+        data: &mut [u8],
+    ) -> Result<usize, UsbError> {
+        let direction = if is_in { SetupPacket::DIR_IN } else { 0 };
+        let setup = SetupPacket::new(
+            direction | SetupPacket::TYPE_CLASS | SetupPacket::RECIPIENT_INTERFACE,
+            request,
+            0,
+            self.interface as u16,
+            data.len() as u16,
+        );
+        hc.control_transfer(&mut self.control, setup, data)
+    }
+
+    fn clear_halt(&mut self, hc: &mut dyn UsbHostController, is_in: bool) -> Result<(), UsbError> {
+        let pipe = if is_in {
+            &mut self.bulk_in
+        } else {
+            &mut self.bulk_out
+        };
+        let endpoint = pipe.endpoint | if is_in { 0x80 } else { 0 };
+        pipe.toggle = false;
+        let setup = SetupPacket::new(
+            SetupPacket::RECIPIENT_ENDPOINT,
+            SetupPacket::CLEAR_FEATURE,
+            SetupPacket::ENDPOINT_HALT,
+            endpoint as u16,
+            0,
+        );
+        hc.control_transfer(&mut self.control, setup, &mut [])
+            .map(|_| ())
+    }
+
+    /// Recovers the device after a phase error or an invalid status.
+    fn reset_recovery(&mut self, hc: &mut dyn UsbHostController) {
+        let _ = self.class_request(hc, Self::BULK_ONLY_RESET, false, &mut []);
+        let _ = self.clear_halt(hc, true);
+        let _ = self.clear_halt(hc, false);
+    }
+
+    /// Sends a command, transfers its data, and returns the length of the data
+    /// transferred.
+    fn command(
+        &mut self,
+        hc: &mut dyn UsbHostController,
+        cdb: &[u8],
+        data: DataPhase,
+    ) -> Result<usize, UsbError> {
+        self.tag = self.tag.wrapping_add(1);
+        let (data_len, flags) = match &data {
+            DataPhase::None => (0, 0),
+            DataPhase::In(v) => (v.len(), Self::CBW_FLAG_IN),
+            DataPhase::Out(v) => (v.len(), 0),
+        };
+        let mut cbw = [0u8; Self::CBW_LEN];
+        cbw[0..4].copy_from_slice(&Self::CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(data_len as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[14] = cdb.len() as u8;
+        cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+        if let Err(err) = hc.transfer_out(&mut self.bulk_out, &cbw) {
+            self.reset_recovery(hc);
+            return Err(err);
+        }
+
+        // A stalled data phase is followed by the status.
+        let result = match data {
+            DataPhase::None => Ok(0),
+            DataPhase::In(buf) => hc.transfer_in(&mut self.bulk_in, buf),
+            DataPhase::Out(buf) => hc.transfer_out(&mut self.bulk_out, buf),
+        };
+        let transferred = match result {
+            Ok(v) => v,
+            Err(UsbError::Stall) => {
+                self.clear_halt(hc, flags == Self::CBW_FLAG_IN)?;
+                0
+            }
+            Err(err) => {
+                self.reset_recovery(hc);
+                return Err(err);
+            }
+        };
+
+        let mut csw = [0u8; Self::CSW_LEN];
+        let len = match hc.transfer_in(&mut self.bulk_in, &mut csw) {
+            Err(UsbError::Stall) => {
+                self.clear_halt(hc, true)?;
+                hc.transfer_in(&mut self.bulk_in, &mut csw)
+            }
+            result => result,
+        };
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                csw[offset],
+                csw[offset + 1],
+                csw[offset + 2],
+                csw[offset + 3],
+            ])
+        };
+        match len {
+            Ok(Self::CSW_LEN) if u32_at(0) == Self::CSW_SIGNATURE && u32_at(4) == self.tag => {
+                match csw[12] {
+                    Self::CSW_PASSED => Ok(transferred),
+                    Self::CSW_FAILED => Err(UsbError::CommandFailed),
+                    _ => {
+                        self.reset_recovery(hc);
+                        Err(UsbError::CommandFailed)
+                    }
+                }
+            }
+            Ok(_) => {
+                self.reset_recovery(hc);
+                Err(UsbError::TransactionError)
+            }
+            Err(err) => {
+                self.reset_recovery(hc);
+                Err(err)
+            }
+        }
+    }
+
+    fn request_sense(&mut self, hc: &mut dyn UsbHostController) -> Result<Sense, UsbError> {
+        let mut data = [0u8; Self::SENSE_LEN];
+        let cdb = [Self::REQUEST_SENSE, 0, 0, 0, Self::SENSE_LEN as u8, 0];
+        self.command(hc, &cdb, DataPhase::In(&mut data))?;
+        Ok(Sense {
+            key: data[2] & 0x0F,
+            asc: data[12],
+            ascq: data[13],
+        })
+    }
+
+    /// Sends a command, and reads the sense data if it fails.
+    fn scsi_command(
+        &mut self,
+        hc: &mut dyn UsbHostController,
+        cdb: &[u8],
+        data: DataPhase,
+    ) -> Result<usize, BlockError> {
+        match self.command(hc, cdb, data) {
+            Ok(v) => Ok(v),
+            Err(UsbError::CommandFailed) => {
+                let sense = self.request_sense(hc).map_err(|_| BlockError::IoError)?;
+                if sense.key == Sense::NO_SENSE {
+                    return Err(BlockError::IoError);
+                }
+                crate::debug!(
+                    "usb: storage {}: sense {:x} {:02x}/{:02x}",
+                    self.address(),
+                    sense.key,
+                    sense.asc,
+                    sense.ascq
+                );
+                Err(sense.to_error())
+            }
+            Err(_) => Err(BlockError::IoError),
+        }
+    }
+
+    fn test_unit_ready(&mut self, hc: &mut dyn UsbHostController) -> Result<(), BlockError> {
+        let cdb = [Self::TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        self.scsi_command(hc, &cdb, DataPhase::None).map(|_| ())
+    }
+
+    /// Reads the address of the last block and the block size, with READ CAPACITY (16)
+    /// for devices larger than 2 TiB.
+    fn read_capacity(&mut self, hc: &mut dyn UsbHostController) -> Result<(u64, u32), BlockError> {
+        let mut data = [0u8; 8];
+        let cdb = [Self::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.scsi_command(hc, &cdb, DataPhase::In(&mut data))?;
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        if last_lba == u32::MAX {
+            let mut data = [0u8; 32];
+            let mut cdb = [0u8; 16];
+            cdb[0] = Self::SERVICE_ACTION_IN_16;
+            cdb[1] = Self::SA_READ_CAPACITY_16;
+            cdb[13] = data.len() as u8;
+            self.scsi_command(hc, &cdb, DataPhase::In(&mut data))?;
+            let mut last_lba = [0u8; 8];
+            last_lba.copy_from_slice(&data[..8]);
+            Ok((
+                u64::from_be_bytes(last_lba),
+                u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            ))
+        } else {
+            Ok((
+                last_lba as u64,
+                u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            ))
+        }
+    }
+
+    /// Returns the CDB of READ (10) or WRITE (10).
+    fn rw_command(opcode: u8, lba: u64, count: usize) -> Result<[u8; 10], BlockError> {
+        let lba = u32::try_from(lba).map_err(|_| BlockError::Unsupported)?;
+        let lba = lba.to_be_bytes();
+        let count = (count as u16).to_be_bytes();
+        Ok([
+            opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0,
+        ])
+    }
+}
+
+impl BlockDevice for UsbStorage {
+    #[inline]
+    fn block_size(&self) -> usize {
+        self.block_size.max(1)
+    }
+
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.num_blocks
+    }
+
+    #[inline]
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        if self.block_size == 0 {
+            return Err(BlockError::NotReady);
+        }
+        self.check_range(lba, buf.len())?;
+        let hc = Usb::host_controller().ok_or(BlockError::NotReady)?;
+        let mut lba = lba;
+        for chunk in buf.chunks_mut(Self::MAX_TRANSFER) {
+            let count = chunk.len() / self.block_size;
+            let cdb = Self::rw_command(Self::READ_10, lba, count)?;
+            let len = chunk.len();
+            if self.scsi_command(hc, &cdb, DataPhase::In(chunk))? < len {
+                return Err(BlockError::IoError);
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.block_size == 0 {
+            return Err(BlockError::NotReady);
+        }
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_range(lba, buf.len())?;
+        let hc = Usb::host_controller().ok_or(BlockError::NotReady)?;
+        let mut lba = lba;
+        for chunk in buf.chunks(Self::MAX_TRANSFER) {
+            let count = chunk.len() / self.block_size;
+            let cdb = Self::rw_command(Self::WRITE_10, lba, count)?;
+            if self.scsi_command(hc, &cdb, DataPhase::Out(chunk))? < chunk.len() {
+                return Err(BlockError::IoError);
+            }
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        let hc = Usb::host_controller().ok_or(BlockError::NotReady)?;
+        let cdb = [Self::SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        match self.scsi_command(hc, &cdb, DataPhase::None) {
+            // Flash drives without a cache may not support it.
+            Ok(_) | Err(BlockError::OutOfRange) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! VIRTIO block device
+
+use super::{
+    queue::{VirtqBuffer, Virtqueue},
+    VirtioError, VirtioMmio,
+};
+use crate::{
+    io::block::{BlockDevice, BlockError},
+    mem::{MemoryManager, PhysicalAddress},
+};
+use core::mem::size_of;
+
+pub struct VirtioBlk {
+    transport: VirtioMmio,
+    queue: Virtqueue,
+    capacity: u64,
+    read_only: bool,
+    /// Request header, status byte and bounce buffer
+    dma_pa: PhysicalAddress,
+    dma: *mut u8,
+}
+
+#[repr(C)]
+struct VirtioBlkReqHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+impl VirtioBlk {
+    pub const SECTOR_SIZE: usize = 512;
+
+    const F_RO: u64 = 1 << 5;
+    const F_FLUSH: u64 = 1 << 9;
+
+    const T_IN: u32 = 0;
+    const T_OUT: u32 = 1;
+    const T_FLUSH: u32 = 4;
+
+    const S_OK: u8 = 0;
+    const S_UNSUPP: u8 = 2;
+
+    const CONFIG_CAPACITY: usize = 0x00;
+
+    const HEADER_OFFSET: usize = 0;
+    const STATUS_OFFSET: usize = 0x10;
+    const BUFFER_OFFSET: usize = 0x1000;
+    const BUFFER_SIZE: usize = 0x4000;
+
+    pub unsafe fn new(transport: VirtioMmio) -> Result<Self, VirtioError> {
+        const DMA_SIZE: usize = VirtioBlk::BUFFER_OFFSET + VirtioBlk::BUFFER_SIZE;
+        let features = transport.negotiate(Self::F_RO | Self::F_FLUSH)?;
+        let Some((dma_pa, dma)) = MemoryManager::alloc_dma::<u8>(DMA_SIZE) else {
+            transport.fail();
+            return Err(VirtioError::OutOfMemory);
+        };
+        let queue = Virtqueue::new(&transport, 0, 16).map_err(|err| {
+            transport.fail();
+            MemoryManager::free_dma::<u8>(dma_pa, DMA_SIZE);
+            err
+        })?;
+        let capacity = transport.config_read64(Self::CONFIG_CAPACITY);
+        transport.driver_ok();
+
+        Ok(Self {
+            transport,
+            queue,
+            capacity,
+            read_only: (features & Self::F_RO) != 0,
+            dma_pa,
+            dma,
+        })
+    }
+
+    unsafe fn request(
+        &mut self,
+        req_type: u32,
+        sector: u64,
+        data_len: usize,
+    ) -> Result<(), VirtioError> {
+        let header = self.dma.add(Self::HEADER_OFFSET) as *mut VirtioBlkReqHeader;
+        header.write_volatile(VirtioBlkReqHeader {
+            req_type,
+            reserved: 0,
+            sector,
+        });
+        let status = self.dma.add(Self::STATUS_OFFSET);
+        status.write_volatile(0xFF);
+
+        let header_buf = VirtqBuffer::readable(
+            self.dma_pa + Self::HEADER_OFFSET,
+            size_of::<VirtioBlkReqHeader>(),
+        );
+        let status_buf = VirtqBuffer::writable(self.dma_pa + Self::STATUS_OFFSET, 1);
+        let data_pa = self.dma_pa + Self::BUFFER_OFFSET;
+        if data_len > 0 {
+            let data_buf = if req_type == Self::T_IN {
+                VirtqBuffer::writable(data_pa, data_len)
+            } else {
+                VirtqBuffer::readable(data_pa, data_len)
+            };
+            self.queue
+                .submit_and_wait(&self.transport, &[header_buf, data_buf, status_buf])?;
+        } else {
+            self.queue
+                .submit_and_wait(&self.transport, &[header_buf, status_buf])?;
+        }
+
+        match status.read_volatile() {
+            Self::S_OK => Ok(()),
+            Self::S_UNSUPP => Err(VirtioError::Unsupported),
+            _ => Err(VirtioError::IoError),
+        }
+    }
+}
+
+impl From<VirtioError> for BlockError {
+    fn from(err: VirtioError) -> Self {
+        match err {
+            VirtioError::Unsupported => Self::Unsupported,
+            _ => Self::IoError,
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    #[inline]
+    fn block_size(&self) -> usize {
+        Self::SECTOR_SIZE
+    }
+
+    #[inline]
+    fn num_blocks(&self) -> u64 {
+        self.capacity
+    }
+
+    #[inline]
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(lba, buf.len())?;
+        let mut sector = lba;
+        for chunk in buf.chunks_mut(Self::BUFFER_SIZE) {
+            unsafe {
+                self.request(Self::T_IN, sector, chunk.len())?;
+                let src = self.dma.add(Self::BUFFER_OFFSET);
+                chunk
+                    .as_mut_ptr()
+                    .copy_from_nonoverlapping(src, chunk.len());
+            }
+            sector += (chunk.len() / Self::SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check_range(lba, buf.len())?;
+        let mut sector = lba;
+        for chunk in buf.chunks(Self::BUFFER_SIZE) {
+            unsafe {
+                let dest = self.dma.add(Self::BUFFER_OFFSET);
+                dest.copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+                self.request(Self::T_OUT, sector, chunk.len())?;
+            }
+            sector += (chunk.len() / Self::SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        unsafe { self.request(Self::T_FLUSH, 0, 0) }.map_err(Into::into)
+    }
+}
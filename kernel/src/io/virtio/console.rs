@@ -0,0 +1,169 @@
+//! VIRTIO console device (port 0 only)
+
+use super::{
+    queue::{VirtqBuffer, Virtqueue},
+    VirtioError, VirtioMmio,
+};
+use crate::{
+    io::uart::Uart,
+    mem::{MemoryManager, PhysicalAddress},
+};
+
+pub struct VirtioConsole {
+    transport: VirtioMmio,
+    rx_queue: Virtqueue,
+    tx_queue: Virtqueue,
+    dma_pa: PhysicalAddress,
+    dma: *mut u8,
+    /// Received bytes not yet read: (descriptor, position, length)
+    rx_pending: Option<(u16, usize, usize)>,
+}
+
+impl VirtioConsole {
+    const RX_QUEUE: u16 = 0;
+    const TX_QUEUE: u16 = 1;
+
+    const RX_BUFFERS: usize = 8;
+    const RX_BUFFER_SIZE: usize = 0x100;
+    const TX_OFFSET: usize = Self::RX_BUFFERS * Self::RX_BUFFER_SIZE;
+    const TX_BUFFER_SIZE: usize = Self::DMA_SIZE - Self::TX_OFFSET;
+    const DMA_SIZE: usize = 0x1000;
+
+    pub unsafe fn new(transport: VirtioMmio) -> Result<Self, VirtioError> {
+        transport.negotiate(0)?;
+        let Some((dma_pa, dma)) = MemoryManager::alloc_dma::<u8>(Self::DMA_SIZE) else {
+            transport.fail();
+            return Err(VirtioError::OutOfMemory);
+        };
+        let (rx_queue, tx_queue) = match (
+            Virtqueue::new(&transport, Self::RX_QUEUE, Self::RX_BUFFERS as u16),
+            Virtqueue::new(&transport, Self::TX_QUEUE, 4),
+        ) {
+            (Ok(rx), Ok(tx)) => (rx, tx),
+            (rx, tx) => {
+                transport.fail();
+                let mut error = VirtioError::QueueUnavailable;
+                for queue in [rx, tx] {
+                    match queue {
+                        Ok(queue) => queue.free(&transport),
+                        Err(err) => error = err,
+                    }
+                }
+                MemoryManager::free_dma::<u8>(dma_pa, Self::DMA_SIZE);
+                return Err(error);
+            }
+        };
+
+        let mut console = Self {
+            transport,
+            rx_queue,
+            tx_queue,
+            dma_pa,
+            dma,
+            rx_pending: None,
+        };
+        let count = Self::RX_BUFFERS.min(console.rx_queue.size() as usize);
+        for index in 0..count {
+            if let Err(err) = console.post_rx_buffer(index) {
+                console.free();
+                return Err(err);
+            }
+        }
+        console.transport.driver_ok();
+        console.transport.notify(Self::RX_QUEUE);
+
+        Ok(console)
+    }
+
+    /// Releases the queues and the buffers of a device that failed to start.
+    unsafe fn free(self) {
+        self.transport.fail();
+        self.rx_queue.free(&self.transport);
+        self.tx_queue.free(&self.transport);
+        MemoryManager::free_dma::<u8>(self.dma_pa, Self::DMA_SIZE);
+    }
+
+    #[inline]
+    fn rx_buffer_pa(&self, index: usize) -> PhysicalAddress {
+        self.dma_pa + index * Self::RX_BUFFER_SIZE
+    }
+
+    fn post_rx_buffer(&mut self, index: usize) -> Result<(), VirtioError> {
+        let buf = VirtqBuffer::writable(self.rx_buffer_pa(index), Self::RX_BUFFER_SIZE);
+        self.rx_queue.add(&[buf]).map(|_| ())
+    }
+
+    /// Returns the index of the receive buffer that starts with descriptor `desc`.
+    ///
+    /// Every receive buffer is a single descriptor, and they are posted in order,
+    /// so descriptor `n` always refers to buffer `n`.
+    #[inline]
+    const fn rx_index(desc: u16) -> usize {
+        desc as usize
+    }
+
+    fn fetch_rx(&mut self) -> bool {
+        if self.rx_pending.is_some() {
+            return true;
+        }
+        while let Some((desc, len)) = self.rx_queue.pop_used() {
+            if len > 0 {
+                self.rx_pending = Some((desc, 0, len as usize));
+                return true;
+            }
+            let _ = self.post_rx_buffer(Self::rx_index(desc));
+            self.transport.notify(Self::RX_QUEUE);
+        }
+        false
+    }
+
+    /// Writes `bytes` to the console.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), VirtioError> {
+        for chunk in bytes.chunks(Self::TX_BUFFER_SIZE) {
+            unsafe {
+                let dest = self.dma.add(Self::TX_OFFSET);
+                dest.copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+            }
+            let buf = VirtqBuffer::readable(self.dma_pa + Self::TX_OFFSET, chunk.len());
+            self.tx_queue.submit_and_wait(&self.transport, &[buf])?;
+        }
+        Ok(())
+    }
+}
+
+impl Uart for VirtioConsole {
+    #[inline]
+    fn is_output_ready(&mut self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn is_input_ready(&mut self) -> bool {
+        self.fetch_rx()
+    }
+
+    fn write_byte(&mut self, ch: u8) {
+        let _ = self.write_bytes(&[ch]);
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        while !self.fetch_rx() {
+            core::hint::spin_loop();
+        }
+        let (desc, pos, len) = self.rx_pending.unwrap();
+        let index = Self::rx_index(desc);
+        let result = unsafe {
+            self.dma
+                .add(index * Self::RX_BUFFER_SIZE + pos)
+                .read_volatile()
+        };
+        if pos + 1 < len {
+            self.rx_pending = Some((desc, pos + 1, len));
+        } else {
+            self.rx_pending = None;
+            let _ = self.post_rx_buffer(index);
+            self.transport.notify(Self::RX_QUEUE);
+        }
+        result
+    }
+}
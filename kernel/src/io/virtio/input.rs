@@ -0,0 +1,285 @@
+//! VIRTIO input device (keyboard, mouse, tablet)
+
+use super::{
+    queue::{VirtqBuffer, Virtqueue},
+    VirtioError, VirtioMmio,
+};
+use crate::{
+    io::input::{InputManager, MouseButtons, PointerEvent, Usage},
+    mem::{MemoryManager, PhysicalAddress},
+};
+use core::{mem::size_of, str};
+
+/// An input event in the evdev format
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VirtioInputEvent {
+    pub event_type: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+impl VirtioInputEvent {
+    pub const EV_SYN: u16 = 0x00;
+    pub const EV_KEY: u16 = 0x01;
+    pub const EV_REL: u16 = 0x02;
+    pub const EV_ABS: u16 = 0x03;
+
+    pub const SYN_REPORT: u16 = 0x00;
+
+    pub const REL_X: u16 = 0x00;
+    pub const REL_Y: u16 = 0x01;
+    pub const REL_WHEEL: u16 = 0x08;
+
+    pub const ABS_X: u16 = 0x00;
+    pub const ABS_Y: u16 = 0x01;
+
+    pub const BTN_LEFT: u16 = 0x110;
+    pub const BTN_RIGHT: u16 = 0x111;
+    pub const BTN_MIDDLE: u16 = 0x112;
+    pub const BTN_TOUCH: u16 = 0x14A;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioInputKind {
+    Keyboard,
+    Mouse,
+    Tablet,
+    Unknown,
+}
+
+pub struct VirtioInput {
+    transport: VirtioMmio,
+    event_queue: Virtqueue,
+    kind: VirtioInputKind,
+    name: [u8; Self::MAX_NAME_LEN],
+    name_len: usize,
+    dma_pa: PhysicalAddress,
+    dma: *mut VirtioInputEvent,
+    /// The maximum values of the absolute X and Y axes
+    abs_max: [u32; 2],
+    /// The pointer event being assembled until the next SYN_REPORT
+    pointer: PointerEvent,
+    pointer_changed: bool,
+}
+
+impl VirtioInput {
+    const EVENT_QUEUE: u16 = 0;
+    const EVENT_BUFFERS: u16 = 64;
+    const MAX_NAME_LEN: usize = 64;
+
+    const CONFIG_SELECT: usize = 0x00;
+    const CONFIG_SUBSEL: usize = 0x01;
+    const CONFIG_SIZE: usize = 0x02;
+    const CONFIG_DATA: usize = 0x08;
+
+    const CFG_ID_NAME: u8 = 0x01;
+    const CFG_EV_BITS: u8 = 0x11;
+    const CFG_ABS_INFO: u8 = 0x12;
+
+    /// HID usages of the evdev key codes
+    const KEY_USAGES: [u8; 128] = [
+        0x00, 0x29, 0x1E, 0x1F, 0x20, 0x21, 0x22, 0x23, // 0x00
+        0x24, 0x25, 0x26, 0x27, 0x2D, 0x2E, 0x2A, 0x2B, // 0x08
+        0x14, 0x1A, 0x08, 0x15, 0x17, 0x1C, 0x18, 0x0C, // 0x10
+        0x12, 0x13, 0x2F, 0x30, 0x28, 0xE0, 0x04, 0x16, // 0x18
+        0x07, 0x09, 0x0A, 0x0B, 0x0D, 0x0E, 0x0F, 0x33, // 0x20
+        0x34, 0x35, 0xE1, 0x31, 0x1D, 0x1B, 0x06, 0x19, // 0x28
+        0x05, 0x11, 0x10, 0x36, 0x37, 0x38, 0xE5, 0x55, // 0x30
+        0xE2, 0x2C, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, // 0x38
+        0x3F, 0x40, 0x41, 0x42, 0x43, 0x53, 0x47, 0x5F, // 0x40
+        0x60, 0x61, 0x56, 0x5C, 0x5D, 0x5E, 0x57, 0x59, // 0x48
+        0x5A, 0x5B, 0x62, 0x63, 0x00, 0x94, 0x64, 0x44, // 0x50
+        0x45, 0x87, 0x92, 0x93, 0x8A, 0x88, 0x8B, 0x8C, // 0x58
+        0x58, 0xE4, 0x54, 0x46, 0xE6, 0x00, 0x4A, 0x52, // 0x60
+        0x4B, 0x50, 0x4F, 0x4D, 0x51, 0x4E, 0x49, 0x4C, // 0x68
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x67, 0x00, 0x48, // 0x70
+        0x00, 0x85, 0x90, 0x91, 0x89, 0xE3, 0xE7, 0x65, // 0x78
+    ];
+
+    pub unsafe fn new(transport: VirtioMmio) -> Result<Self, VirtioError> {
+        transport.negotiate(0)?;
+        let Some((dma_pa, dma)) =
+            MemoryManager::alloc_dma::<VirtioInputEvent>(Self::EVENT_BUFFERS as usize)
+        else {
+            transport.fail();
+            return Err(VirtioError::OutOfMemory);
+        };
+        let event_queue = Virtqueue::new(&transport, Self::EVENT_QUEUE, Self::EVENT_BUFFERS)
+            .map_err(|err| {
+                transport.fail();
+                MemoryManager::free_dma::<VirtioInputEvent>(dma_pa, Self::EVENT_BUFFERS as usize);
+                err
+            })?;
+
+        let mut name = [0; Self::MAX_NAME_LEN];
+        let name_len = Self::read_config(&transport, Self::CFG_ID_NAME, 0, &mut name);
+        let kind = if Self::has_config(
+            &transport,
+            Self::CFG_EV_BITS,
+            VirtioInputEvent::EV_ABS as u8,
+        ) {
+            VirtioInputKind::Tablet
+        } else if Self::has_config(
+            &transport,
+            Self::CFG_EV_BITS,
+            VirtioInputEvent::EV_REL as u8,
+        ) {
+            VirtioInputKind::Mouse
+        } else if Self::has_config(
+            &transport,
+            Self::CFG_EV_BITS,
+            VirtioInputEvent::EV_KEY as u8,
+        ) {
+            VirtioInputKind::Keyboard
+        } else {
+            VirtioInputKind::Unknown
+        };
+        let mut abs_max = [0; 2];
+        for (axis, max) in abs_max.iter_mut().enumerate() {
+            let mut info = [0; 8];
+            if Self::read_config(&transport, Self::CFG_ABS_INFO, axis as u8, &mut info) == 8 {
+                *max = u32::from_le_bytes([info[4], info[5], info[6], info[7]]);
+            }
+        }
+
+        let mut input = Self {
+            transport,
+            event_queue,
+            kind,
+            name,
+            name_len,
+            dma_pa,
+            dma,
+            abs_max,
+            pointer: PointerEvent {
+                buttons: MouseButtons::empty(),
+                x: 0,
+                y: 0,
+                wheel: 0,
+                absolute: kind == VirtioInputKind::Tablet,
+            },
+            pointer_changed: false,
+        };
+        for index in 0..input.event_queue.size() as usize {
+            if let Err(err) = input.post_event_buffer(index) {
+                input.transport.fail();
+                input.event_queue.free(&input.transport);
+                MemoryManager::free_dma::<VirtioInputEvent>(dma_pa, Self::EVENT_BUFFERS as usize);
+                return Err(err);
+            }
+        }
+        input.transport.driver_ok();
+        input.transport.notify(Self::EVENT_QUEUE);
+
+        Ok(input)
+    }
+
+    fn read_config(transport: &VirtioMmio, select: u8, subsel: u8, buf: &mut [u8]) -> usize {
+        transport.config_write8(Self::CONFIG_SELECT, select);
+        transport.config_write8(Self::CONFIG_SUBSEL, subsel);
+        let size = (transport.config_read8(Self::CONFIG_SIZE) as usize).min(buf.len());
+        for (index, byte) in buf[..size].iter_mut().enumerate() {
+            *byte = transport.config_read8(Self::CONFIG_DATA + index);
+        }
+        size
+    }
+
+    #[inline]
+    fn has_config(transport: &VirtioMmio, select: u8, subsel: u8) -> bool {
+        transport.config_write8(Self::CONFIG_SELECT, select);
+        transport.config_write8(Self::CONFIG_SUBSEL, subsel);
+        transport.config_read8(Self::CONFIG_SIZE) > 0
+    }
+
+    fn post_event_buffer(&mut self, index: usize) -> Result<(), VirtioError> {
+        let size = size_of::<VirtioInputEvent>();
+        let buf = VirtqBuffer::writable(self.dma_pa + index * size, size);
+        self.event_queue.add(&[buf]).map(|_| ())
+    }
+
+    #[inline]
+    pub const fn kind(&self) -> VirtioInputKind {
+        self.kind
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        str::from_utf8(&self.name[..self.name_len]).unwrap_or_default()
+    }
+
+    /// Returns the next input event if available.
+    pub fn poll(&mut self) -> Option<VirtioInputEvent> {
+        let (desc, _) = self.event_queue.pop_used()?;
+        // Every event buffer is a single descriptor, so descriptor `n` refers to buffer `n`.
+        let index = desc as usize;
+        let event = unsafe { self.dma.add(index).read_volatile() };
+        let _ = self.post_event_buffer(index);
+        self.transport.notify(Self::EVENT_QUEUE);
+        Some(event)
+    }
+
+    /// Delivers the pending events to [InputManager].
+    pub fn poll_input(&mut self) {
+        while let Some(event) = self.poll() {
+            self.process_event(event);
+        }
+    }
+
+    fn process_event(&mut self, event: VirtioInputEvent) {
+        match event.event_type {
+            VirtioInputEvent::EV_KEY => {
+                let button = match event.code {
+                    VirtioInputEvent::BTN_LEFT | VirtioInputEvent::BTN_TOUCH => MouseButtons::LEFT,
+                    VirtioInputEvent::BTN_RIGHT => MouseButtons::RIGHT,
+                    VirtioInputEvent::BTN_MIDDLE => MouseButtons::MIDDLE,
+                    code => {
+                        // Value 2 is the autorepeat of the device, which InputManager
+                        // does by itself.
+                        let usage = Self::KEY_USAGES.get(code as usize).copied();
+                        if let Some(usage) = usage.filter(|v| *v != 0) {
+                            if event.value < 2 {
+                                InputManager::post_key(code as u32, Usage(usage), event.value != 0);
+                            }
+                        }
+                        return;
+                    }
+                };
+                self.pointer.buttons.set(button, event.value != 0);
+                self.pointer_changed = true;
+            }
+            VirtioInputEvent::EV_REL => {
+                let value = event.value as i32;
+                match event.code {
+                    VirtioInputEvent::REL_X => self.pointer.x += value,
+                    VirtioInputEvent::REL_Y => self.pointer.y += value,
+                    VirtioInputEvent::REL_WHEEL => self.pointer.wheel += value,
+                    _ => return,
+                }
+                self.pointer_changed = true;
+            }
+            VirtioInputEvent::EV_ABS => {
+                let (value, max) = match event.code {
+                    VirtioInputEvent::ABS_X => (&mut self.pointer.x, self.abs_max[0]),
+                    VirtioInputEvent::ABS_Y => (&mut self.pointer.y, self.abs_max[1]),
+                    _ => return,
+                };
+                *value = (event.value as u64 * PointerEvent::ABS_MAX as u64 / max.max(1) as u64)
+                    .min(PointerEvent::ABS_MAX as u64) as i32;
+                self.pointer_changed = true;
+            }
+            VirtioInputEvent::EV_SYN if event.code == VirtioInputEvent::SYN_REPORT => {
+                if self.pointer_changed {
+                    InputManager::post_pointer(self.pointer);
+                    self.pointer_changed = false;
+                }
+                if !self.pointer.absolute {
+                    self.pointer.x = 0;
+                    self.pointer.y = 0;
+                }
+                self.pointer.wheel = 0;
+            }
+            _ => (),
+        }
+    }
+}
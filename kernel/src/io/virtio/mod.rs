@@ -0,0 +1,337 @@
+//! Virtual I/O Device (VIRTIO) over MMIO
+
+use crate::fw::dt::DeviceTree;
+use alloc::vec::Vec;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+
+pub mod blk;
+pub mod console;
+pub mod input;
+pub mod queue;
+pub mod rng;
+
+use self::{blk::VirtioBlk, console::VirtioConsole, input::VirtioInput, rng::VirtioRng};
+
+static mut VIRTIO: UnsafeCell<Virtio> = UnsafeCell::new(Virtio::new());
+
+/// Devices found on the VIRTIO MMIO transports
+pub struct Virtio {
+    blk: Vec<VirtioBlk>,
+    console: Vec<VirtioConsole>,
+    input: Vec<VirtioInput>,
+}
+
+impl Virtio {
+    const fn new() -> Self {
+        Self {
+            blk: Vec::new(),
+            console: Vec::new(),
+            input: Vec::new(),
+        }
+    }
+
+    #[inline]
+    unsafe fn shared_mut<'a>() -> &'a mut Self {
+        &mut *VIRTIO.get()
+    }
+
+    /// Probes all `virtio,mmio` nodes in the device tree and initializes the supported devices.
+    pub unsafe fn init(dt: &DeviceTree) {
+        let shared = Self::shared_mut();
+
+        for node in dt.find_compatible("virtio,mmio") {
+            let Some((base, _)) = node.reg().next() else {
+                continue;
+            };
+            let Some(transport) = VirtioMmio::probe(base.as_usize()) else {
+                continue;
+            };
+            let device_id = transport.device_id();
+            let result = match device_id {
+                VirtioDeviceId::BLOCK => VirtioBlk::new(transport).map(|v| shared.blk.push(v)),
+                VirtioDeviceId::CONSOLE => {
+                    VirtioConsole::new(transport).map(|v| shared.console.push(v))
+                }
+                VirtioDeviceId::ENTROPY => {
+                    VirtioRng::new(transport).map(|v| rng::VIRTIO_ENTROPY.attach(v))
+                }
+                VirtioDeviceId::INPUT => VirtioInput::new(transport).map(|v| shared.input.push(v)),
+                _ => continue,
+            };
+            match result {
+                Ok(_) => crate::info!("{} at {:08x}", device_id, base.as_usize()),
+                Err(err) => crate::error!("{} at {:08x}: {:?}", device_id, base.as_usize(), err),
+            }
+        }
+    }
+
+    #[inline]
+    pub fn block_devices<'a>() -> &'a mut [VirtioBlk] {
+        unsafe { Self::shared_mut().blk.as_mut_slice() }
+    }
+
+    #[inline]
+    pub fn consoles<'a>() -> &'a mut [VirtioConsole] {
+        unsafe { Self::shared_mut().console.as_mut_slice() }
+    }
+
+    #[inline]
+    pub fn input_devices<'a>() -> &'a mut [VirtioInput] {
+        unsafe { Self::shared_mut().input.as_mut_slice() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device rejected the negotiated features.
+    FeaturesRejected,
+    /// The queue does not exist or is already in use.
+    QueueUnavailable,
+    /// There are not enough free descriptors.
+    QueueFull,
+    /// Failed to allocate DMA memory.
+    OutOfMemory,
+    /// The device reported an error.
+    IoError,
+    /// The request is not supported by the device.
+    Unsupported,
+}
+
+/// Device type of a VIRTIO device
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioDeviceId(pub u32);
+
+impl VirtioDeviceId {
+    pub const NETWORK: Self = Self(1);
+    pub const BLOCK: Self = Self(2);
+    pub const CONSOLE: Self = Self(3);
+    pub const ENTROPY: Self = Self(4);
+    pub const GPU: Self = Self(16);
+    pub const INPUT: Self = Self(18);
+}
+
+impl fmt::Display for VirtioDeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::NETWORK => write!(f, "net"),
+            Self::BLOCK => write!(f, "blk"),
+            Self::CONSOLE => write!(f, "console"),
+            Self::ENTROPY => write!(f, "rng"),
+            Self::GPU => write!(f, "gpu"),
+            Self::INPUT => write!(f, "input"),
+            _ => write!(f, "device({})", self.0),
+        }
+    }
+}
+
+/// VIRTIO MMIO transport (legacy and modern)
+pub struct VirtioMmio {
+    base: usize,
+    version: u32,
+}
+
+impl VirtioMmio {
+    pub const MAGIC: u32 = 0x7472_6976;
+
+    pub const STATUS_ACKNOWLEDGE: u32 = 1;
+    pub const STATUS_DRIVER: u32 = 2;
+    pub const STATUS_DRIVER_OK: u32 = 4;
+    pub const STATUS_FEATURES_OK: u32 = 8;
+    pub const STATUS_FAILED: u32 = 128;
+
+    pub const F_VERSION_1: u64 = 1 << 32;
+
+    /// Page size of the legacy interface
+    pub const LEGACY_PAGE_SIZE: usize = 0x1000;
+
+    /// Returns the transport at `base` if a device is attached.
+    pub unsafe fn probe(base: usize) -> Option<Self> {
+        let transport = Self { base, version: 0 };
+        if transport.read32(VirtioMmioReg::MagicValue) != Self::MAGIC {
+            return None;
+        }
+        let version = transport.read32(VirtioMmioReg::Version);
+        if !matches!(version, 1 | 2) || transport.read32(VirtioMmioReg::DeviceId) == 0 {
+            return None;
+        }
+        Some(Self { base, version })
+    }
+
+    #[inline]
+    pub const fn base(&self) -> usize {
+        self.base
+    }
+
+    #[inline]
+    pub const fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    #[inline]
+    pub fn device_id(&self) -> VirtioDeviceId {
+        VirtioDeviceId(self.read32(VirtioMmioReg::DeviceId))
+    }
+
+    #[inline]
+    fn reg(&self, reg: VirtioMmioReg) -> &AtomicU32 {
+        unsafe { &*((self.base + reg as usize) as *const AtomicU32) }
+    }
+
+    #[inline]
+    fn read32(&self, reg: VirtioMmioReg) -> u32 {
+        self.reg(reg).load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn write32(&self, reg: VirtioMmioReg, val: u32) {
+        self.reg(reg).store(val, Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn status(&self) -> u32 {
+        self.read32(VirtioMmioReg::Status)
+    }
+
+    #[inline]
+    fn set_status(&self, status: u32) {
+        self.write32(VirtioMmioReg::Status, status);
+    }
+
+    /// Resets the device and negotiates `features`, returning the accepted features.
+    pub fn negotiate(&self, features: u64) -> Result<u64, VirtioError> {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+        self.set_status(Self::STATUS_ACKNOWLEDGE);
+        self.set_status(Self::STATUS_ACKNOWLEDGE | Self::STATUS_DRIVER);
+
+        let mut device_features = 0;
+        for sel in 0..2 {
+            self.write32(VirtioMmioReg::DeviceFeaturesSel, sel);
+            device_features |= (self.read32(VirtioMmioReg::DeviceFeatures) as u64) << (sel * 32);
+        }
+
+        let features = if self.is_legacy() {
+            features & device_features & 0xFFFF_FFFF
+        } else {
+            (features | Self::F_VERSION_1) & device_features
+        };
+        for sel in 0..2 {
+            self.write32(VirtioMmioReg::DriverFeaturesSel, sel);
+            self.write32(
+                VirtioMmioReg::DriverFeatures,
+                (features >> (sel * 32)) as u32,
+            );
+        }
+
+        if self.is_legacy() {
+            self.write32(VirtioMmioReg::GuestPageSize, Self::LEGACY_PAGE_SIZE as u32);
+        } else {
+            let status = Self::STATUS_ACKNOWLEDGE | Self::STATUS_DRIVER | Self::STATUS_FEATURES_OK;
+            self.set_status(status);
+            if (self.status() & Self::STATUS_FEATURES_OK) == 0 {
+                self.set_status(Self::STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+
+        Ok(features)
+    }
+
+    /// Tells the device that the driver is ready.
+    pub fn driver_ok(&self) {
+        self.set_status(self.status() | Self::STATUS_DRIVER_OK);
+    }
+
+    /// Marks the device as failed.
+    pub fn fail(&self) {
+        self.set_status(self.status() | Self::STATUS_FAILED);
+    }
+
+    /// Notifies the device that there are new buffers in `queue`.
+    #[inline]
+    pub fn notify(&self, queue: u16) {
+        fence(Ordering::SeqCst);
+        self.write32(VirtioMmioReg::QueueNotify, queue as u32);
+    }
+
+    /// Acknowledges all pending interrupts and returns them.
+    #[inline]
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read32(VirtioMmioReg::InterruptStatus);
+        self.write32(VirtioMmioReg::InterruptAck, status);
+        status
+    }
+
+    #[inline]
+    pub fn config_read8(&self, offset: usize) -> u8 {
+        unsafe { ((self.base + VirtioMmioReg::CONFIG + offset) as *const u8).read_volatile() }
+    }
+
+    #[inline]
+    pub fn config_write8(&self, offset: usize, val: u8) {
+        unsafe { ((self.base + VirtioMmioReg::CONFIG + offset) as *mut u8).write_volatile(val) }
+    }
+
+    #[inline]
+    pub fn config_read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + VirtioMmioReg::CONFIG + offset) as *const u32).read_volatile() }
+    }
+
+    pub fn config_read64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.read32(VirtioMmioReg::ConfigGeneration);
+            let lo = self.config_read32(offset) as u64;
+            let hi = self.config_read32(offset + 4) as u64;
+            if self.is_legacy() || generation == self.read32(VirtioMmioReg::ConfigGeneration) {
+                break lo | (hi << 32);
+            }
+        }
+    }
+}
+
+/// Registers of the VIRTIO MMIO transport
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+enum VirtioMmioReg {
+    MagicValue = 0x000,
+    Version = 0x004,
+    DeviceId = 0x008,
+    VendorId = 0x00C,
+    DeviceFeatures = 0x010,
+    DeviceFeaturesSel = 0x014,
+    DriverFeatures = 0x020,
+    DriverFeaturesSel = 0x024,
+    /// Legacy only
+    GuestPageSize = 0x028,
+    QueueSel = 0x030,
+    QueueNumMax = 0x034,
+    QueueNum = 0x038,
+    /// Legacy only
+    QueueAlign = 0x03C,
+    /// Legacy only
+    QueuePfn = 0x040,
+    QueueReady = 0x044,
+    QueueNotify = 0x050,
+    InterruptStatus = 0x060,
+    InterruptAck = 0x064,
+    Status = 0x070,
+    QueueDescLow = 0x080,
+    QueueDescHigh = 0x084,
+    QueueDriverLow = 0x090,
+    QueueDriverHigh = 0x094,
+    QueueDeviceLow = 0x0A0,
+    QueueDeviceHigh = 0x0A4,
+    ConfigGeneration = 0x0FC,
+}
+
+impl VirtioMmioReg {
+    /// Offset of the device specific configuration space
+    pub const CONFIG: usize = 0x100;
+}
@@ -0,0 +1,271 @@
+//! Split virtqueue
+
+use super::{VirtioError, VirtioMmio, VirtioMmioReg};
+use crate::mem::{MemoryManager, PhysicalAddress};
+use core::{
+    mem::size_of,
+    sync::atomic::{fence, Ordering},
+};
+
+/// A buffer in a descriptor chain
+#[derive(Debug, Clone, Copy)]
+pub struct VirtqBuffer {
+    pub pa: PhysicalAddress,
+    pub len: u32,
+    /// Whether the device writes to this buffer
+    pub writable: bool,
+}
+
+impl VirtqBuffer {
+    #[inline]
+    pub const fn readable(pa: PhysicalAddress, len: usize) -> Self {
+        Self {
+            pa,
+            len: len as u32,
+            writable: false,
+        }
+    }
+
+    #[inline]
+    pub const fn writable(pa: PhysicalAddress, len: usize) -> Self {
+        Self {
+            pa,
+            len: len as u32,
+            writable: true,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl VirtqDesc {
+    const F_NEXT: u16 = 1;
+    const F_WRITE: u16 = 2;
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// Split virtqueue in a single DMA area with the legacy layout,
+/// which is also valid for the modern interface.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    desc: *mut VirtqDesc,
+    /// flags: u16, idx: u16, ring: [u16; size]
+    avail: *mut u16,
+    /// flags: u16, idx: u16, ring: [VirtqUsedElem; size]
+    used: *mut u16,
+    dma_pa: PhysicalAddress,
+    dma_size: usize,
+    free_head: u16,
+    num_free: u16,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+impl Virtqueue {
+    pub const MAX_SIZE: u16 = 256;
+
+    /// Allocates the queue `index` of `transport` with at most `size` entries.
+    pub unsafe fn new(transport: &VirtioMmio, index: u16, size: u16) -> Result<Self, VirtioError> {
+        transport.write32(VirtioMmioReg::QueueSel, index as u32);
+        let ready = if transport.is_legacy() {
+            transport.read32(VirtioMmioReg::QueuePfn)
+        } else {
+            transport.read32(VirtioMmioReg::QueueReady)
+        };
+        let max_size = transport.read32(VirtioMmioReg::QueueNumMax);
+        if ready != 0 || max_size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let size = (size.min(Self::MAX_SIZE) as u32).min(max_size) as u16;
+
+        let page_mask = VirtioMmio::LEGACY_PAGE_SIZE - 1;
+        let avail_offset = size_of::<VirtqDesc>() * size as usize;
+        let used_offset = (avail_offset + 2 * (3 + size as usize) + page_mask) & !page_mask;
+        let used_size = 2 * 3 + size_of::<VirtqUsedElem>() * size as usize;
+        let total_size = (used_offset + used_size + page_mask) & !page_mask;
+        let (pa, ptr) =
+            MemoryManager::alloc_dma::<u8>(total_size).ok_or(VirtioError::OutOfMemory)?;
+
+        let desc = ptr as *mut VirtqDesc;
+        let avail = ptr.add(avail_offset) as *mut u16;
+        let used = ptr.add(used_offset) as *mut u16;
+        for i in 0..size {
+            desc.add(i as usize).write_volatile(VirtqDesc {
+                addr: 0,
+                len: 0,
+                flags: 0,
+                next: i.wrapping_add(1),
+            });
+        }
+
+        transport.write32(VirtioMmioReg::QueueNum, size as u32);
+        if transport.is_legacy() {
+            transport.write32(
+                VirtioMmioReg::QueueAlign,
+                VirtioMmio::LEGACY_PAGE_SIZE as u32,
+            );
+            transport.write32(
+                VirtioMmioReg::QueuePfn,
+                (pa.as_usize() / VirtioMmio::LEGACY_PAGE_SIZE) as u32,
+            );
+        } else {
+            let desc_pa = pa.as_u64();
+            let avail_pa = desc_pa + avail_offset as u64;
+            let used_pa = desc_pa + used_offset as u64;
+            transport.write32(VirtioMmioReg::QueueDescLow, desc_pa as u32);
+            transport.write32(VirtioMmioReg::QueueDescHigh, (desc_pa >> 32) as u32);
+            transport.write32(VirtioMmioReg::QueueDriverLow, avail_pa as u32);
+            transport.write32(VirtioMmioReg::QueueDriverHigh, (avail_pa >> 32) as u32);
+            transport.write32(VirtioMmioReg::QueueDeviceLow, used_pa as u32);
+            transport.write32(VirtioMmioReg::QueueDeviceHigh, (used_pa >> 32) as u32);
+            transport.write32(VirtioMmioReg::QueueReady, 1);
+        }
+
+        Ok(Self {
+            index,
+            size,
+            desc,
+            avail,
+            used,
+            dma_pa: pa,
+            dma_size: total_size,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used_idx: 0,
+        })
+    }
+
+    /// Detaches the queue from the device and frees it, when the device fails to start.
+    pub unsafe fn free(self, transport: &VirtioMmio) {
+        transport.write32(VirtioMmioReg::QueueSel, self.index as u32);
+        if transport.is_legacy() {
+            transport.write32(VirtioMmioReg::QueuePfn, 0);
+        } else {
+            transport.write32(VirtioMmioReg::QueueReady, 0);
+        }
+        MemoryManager::free_dma::<u8>(self.dma_pa, self.dma_size);
+    }
+
+    #[inline]
+    pub const fn index(&self) -> u16 {
+        self.index
+    }
+
+    #[inline]
+    pub const fn size(&self) -> u16 {
+        self.size
+    }
+
+    #[inline]
+    pub const fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Adds a descriptor chain of `bufs` to the available ring and returns the head descriptor.
+    ///
+    /// The device is not notified until [`VirtioMmio::notify`] is called.
+    pub fn add(&mut self, bufs: &[VirtqBuffer]) -> Result<u16, VirtioError> {
+        if bufs.is_empty() || bufs.len() > self.num_free as usize {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut last = head;
+        let mut index = head;
+        for (i, buf) in bufs.iter().enumerate() {
+            let desc = unsafe { &mut *self.desc.add(index as usize) };
+            let mut flags = if buf.writable { VirtqDesc::F_WRITE } else { 0 };
+            if i + 1 < bufs.len() {
+                flags |= VirtqDesc::F_NEXT;
+            }
+            desc.addr = buf.pa.as_u64();
+            desc.len = buf.len;
+            desc.flags = flags;
+            last = index;
+            index = desc.next;
+        }
+        self.free_head = unsafe { (*self.desc.add(last as usize)).next };
+        self.num_free -= bufs.len() as u16;
+
+        unsafe {
+            let slot = 2 + (self.avail_idx % self.size) as usize;
+            self.avail.add(slot).write_volatile(head);
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            self.avail.add(1).write_volatile(self.avail_idx);
+        }
+
+        Ok(head)
+    }
+
+    /// Returns whether the device has returned any descriptor chains.
+    #[inline]
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        unsafe { self.used.add(1).read_volatile() != self.last_used_idx }
+    }
+
+    /// Takes a descriptor chain returned by the device and frees it.
+    ///
+    /// Returns the head descriptor and the number of bytes written by the device.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        // The element must not be read before the index that covers it.
+        fence(Ordering::Acquire);
+        let elem = unsafe {
+            let ring = self.used.add(2) as *const VirtqUsedElem;
+            ring.add((self.last_used_idx % self.size) as usize)
+                .read_volatile()
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+
+        let head = elem.id as u16;
+        let mut index = head;
+        loop {
+            let desc = unsafe { &mut *self.desc.add(index as usize) };
+            self.num_free += 1;
+            if (desc.flags & VirtqDesc::F_NEXT) == 0 {
+                desc.next = self.free_head;
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+
+        Some((head, elem.len))
+    }
+
+    /// Submits `bufs` and waits for the device to complete them.
+    pub fn submit_and_wait(
+        &mut self,
+        transport: &VirtioMmio,
+        bufs: &[VirtqBuffer],
+    ) -> Result<u32, VirtioError> {
+        let head = self.add(bufs)?;
+        transport.notify(self.index);
+        loop {
+            match self.pop_used() {
+                Some((id, len)) if id == head => return Ok(len),
+                Some(_) => (),
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! VIRTIO entropy device
+
+use super::{
+    queue::{VirtqBuffer, Virtqueue},
+    VirtioError, VirtioMmio,
+};
+use crate::{
+    mem::{MemoryManager, PhysicalAddress},
+    random::{EntropySource, Random},
+    sync::spinlock::SpinMutex,
+};
+
+pub struct VirtioRng {
+    transport: VirtioMmio,
+    queue: Virtqueue,
+    dma_pa: PhysicalAddress,
+    dma: *mut u8,
+}
+
+// The buffer is only reached through the device, which one core uses at a time.
+unsafe impl Send for VirtioRng {}
+
+impl VirtioRng {
+    const BUFFER_SIZE: usize = 0x1000;
+
+    pub unsafe fn new(transport: VirtioMmio) -> Result<Self, VirtioError> {
+        transport.negotiate(0)?;
+        let Some((dma_pa, dma)) = MemoryManager::alloc_dma::<u8>(Self::BUFFER_SIZE) else {
+            transport.fail();
+            return Err(VirtioError::OutOfMemory);
+        };
+        let queue = Virtqueue::new(&transport, 0, 4).map_err(|err| {
+            transport.fail();
+            MemoryManager::free_dma::<u8>(dma_pa, Self::BUFFER_SIZE);
+            err
+        })?;
+        transport.driver_ok();
+
+        Ok(Self {
+            transport,
+            queue,
+            dma_pa,
+            dma,
+        })
+    }
+
+    /// Fills `buf` with random bytes from the host, and returns the length filled.
+    ///
+    /// The length is short if the device runs out of entropy.
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) -> Result<usize, VirtioError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let len = (buf.len() - filled).min(Self::BUFFER_SIZE);
+            let desc = VirtqBuffer::writable(self.dma_pa, len);
+            let written = self.queue.submit_and_wait(&self.transport, &[desc])? as usize;
+            let written = written.min(len);
+            if written == 0 {
+                break;
+            }
+            unsafe {
+                buf[filled..filled + written]
+                    .as_mut_ptr()
+                    .copy_from_nonoverlapping(self.dma, written);
+            }
+            filled += written;
+        }
+        Ok(filled)
+    }
+}
+
+pub static VIRTIO_ENTROPY: VirtioEntropy = VirtioEntropy::new();
+
+/// The first entropy device as a source of [Random]
+pub struct VirtioEntropy {
+    device: SpinMutex<Option<VirtioRng>>,
+}
+
+impl VirtioEntropy {
+    const fn new() -> Self {
+        Self {
+            device: SpinMutex::new(None),
+        }
+    }
+
+    /// Takes the device and registers it to [Random], unless a device is already used.
+    pub fn attach(&'static self, rng: VirtioRng) {
+        let mut device = self.device.lock();
+        if device.is_none() {
+            *device = Some(rng);
+            drop(device);
+            let _ = Random::add_source(self);
+        }
+    }
+
+    #[inline]
+    pub fn is_present(&self) -> bool {
+        self.device.lock().is_some()
+    }
+}
+
+impl EntropySource for VirtioEntropy {
+    fn name(&self) -> &'static str {
+        "virtio entropy"
+    }
+
+    fn read_entropy(&self, buf: &mut [u8]) -> usize {
+        match self.device.lock().as_mut().map(|rng| rng.fill_bytes(buf)) {
+            Some(Ok(len)) => len,
+            _ => 0,
+        }
+    }
+}
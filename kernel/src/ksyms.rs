@@ -0,0 +1,58 @@
+//! Kernel symbol table
+//!
+//! The `.ksyms` section is reserved by the kernel and filled after linking with the
+//! output of `nm -n -C` for the text symbols of the kernel, one `address type name`
+//! per line sorted by address and padded with zeros. A kernel built without this step
+//! has no symbols.
+
+use core::{slice, str};
+
+/// Size of the `.ksyms` section, which must match `KSYMS_SIZE` of the Makefile
+pub const KSYMS_SIZE: usize = 0x4_0000;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+/// Returns the symbol table, without the padding.
+fn table() -> &'static [u8] {
+    extern "C" {
+        static __ksyms_start: u8;
+        static __ksyms_end: u8;
+    }
+    // The contents are written after linking, so they are read through the linker symbols.
+    let table = unsafe {
+        let start = &__ksyms_start as *const u8;
+        let end = &__ksyms_end as *const u8;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    let len = table.iter().position(|v| *v == 0).unwrap_or(table.len());
+    &table[..len]
+}
+
+/// Returns the symbol containing `addr` and the offset from it.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let mut result = None;
+    for line in table().split(|v| *v == b'\n') {
+        let Some((symbol, name)) = parse(line) else {
+            continue;
+        };
+        if symbol > addr {
+            break;
+        }
+        result = Some((name, addr - symbol));
+    }
+    result
+}
+
+/// Parses a line such as `0000000000080000 T _start`.
+fn parse(line: &[u8]) -> Option<(usize, &str)> {
+    let line = str::from_utf8(line).ok()?;
+    let mut fields = line.splitn(3, ' ');
+    let addr = usize::from_str_radix(fields.next()?, 16).ok()?;
+    match fields.next()? {
+        "t" | "T" | "w" | "W" => (),
+        _ => return None,
+    }
+    Some((addr, fields.next()?))
+}
@@ -12,20 +12,62 @@
 pub mod arch;
 pub mod fw;
 pub mod io;
+pub mod ksyms;
+pub mod log;
 pub mod mem;
+pub mod param;
+pub mod proc;
+pub mod random;
+pub mod shell;
 pub mod sync;
 pub mod system;
 pub use meggl as drawing;
 use system::System;
 extern crate alloc;
 
-use core::fmt::Write;
+use arch::{backtrace::Backtrace, cpu::Cpu, exception, exception::TrapFrame, smp::Smp};
+use core::fmt::{self, Write};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Writes the panic report to both the UART and the screen.
+struct PanicOutput;
+
+impl Write for PanicOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let _ = System::stdout().write_str(s);
+        System::em_console().write_str(s)
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    let stdout = System::stdout();
-    let _ = writeln!(stdout, "!!! PANIC: {}", info);
-    loop {}
+    unsafe {
+        Cpu::disable_interrupt();
+    }
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        // Another core is reporting, or the report itself panicked.
+        let _ = writeln!(System::stdout(), "!!! PANIC: {}", info);
+        Smp::halt();
+    }
+    Smp::halt_others();
+
+    let frame = exception::fault_frame().unwrap_or_else(TrapFrame::capture);
+    let mut out = PanicOutput;
+    let _ = writeln!(out, "!!! PANIC on core {}: {}", Cpu::current().id(), info);
+    let _ = write!(out, "{}", frame);
+    let _ = writeln!(out, "backtrace:");
+    for (index, pc) in Backtrace::new(frame.elr as usize, frame.x[29] as usize).enumerate() {
+        let _ = match ksyms::lookup(pc) {
+            Some((name, offset)) => {
+                writeln!(out, "{:>4} {:016x} {}+{:#x}", index, pc, name, offset)
+            }
+            None => writeln!(out, "{:>4} {:016x}", index, pc),
+        };
+    }
+    Smp::halt();
 }
 
 #[lang = "eh_personality"]
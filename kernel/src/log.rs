@@ -0,0 +1,520 @@
+//! Kernel log
+//!
+//! Records are kept in a lock-free ring buffer from the very beginning of the boot and
+//! delivered in order to the registered [LogSink]s. A sink added later, such as the
+//! screen, receives the records still in the buffer first.
+//!
+//! ```ignore
+//! crate::info!("{} cores online", Smp::online_cpus());
+//! crate::log!(target: "smp", Level::Warn, "core #{} failed to start", cpuid);
+//! ```
+
+use crate::{
+    arch::{cpu::Cpu, timer::GenericTimer},
+    mem::fixedvec::FixedVec,
+    param::{Param, ParamError},
+    shell::{ShellCommand, ShellError},
+    sync::spinlock::SpinMutex,
+    system::System,
+};
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    mem::MaybeUninit,
+    str,
+    sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+crate::kernel_param! {
+    static LOG_LEVEL: LevelParam = LevelParam::new(
+        "log.level",
+        Level::Info,
+        "Most verbose level of the records kept in the log",
+    );
+}
+
+crate::kernel_param! {
+    static SCREEN_LEVEL: LevelParam = LevelParam::new(
+        "log.screen",
+        Level::Warn,
+        "Most verbose level of the records shown on the screen",
+    );
+}
+
+/// Logs a record with the level and the module as the target unless specified.
+#[macro_export]
+macro_rules! log {
+    (target: $target:expr, $level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if level <= $crate::log::Log::max_level() {
+            $crate::log::Log::write(level, $target, format_args!($($arg)+));
+        }
+    }};
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log!(target: module_path!(), $level, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Self; 5] = [
+        Self::Error,
+        Self::Warn,
+        Self::Info,
+        Self::Debug,
+        Self::Trace,
+    ];
+
+    #[inline]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+
+    /// Parses the name, or the number from 1 (`error`) to 5 (`trace`).
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|v| v.as_str().eq_ignore_ascii_case(s) || s.as_bytes() == [*v as u8 + b'0'])
+            .or_else(|| s.eq_ignore_ascii_case("warning").then_some(Self::Warn))
+    }
+
+    #[inline]
+    fn from_u8(val: u8) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|v| *v as u8 == val)
+            .unwrap_or(Self::Trace)
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// Log level parameter
+///
+/// Accepts the name of a level or its number, e.g. `log.level=debug` or `log.level=4`.
+pub struct LevelParam {
+    name: &'static str,
+    help: &'static str,
+    default: Level,
+    value: AtomicUsize,
+}
+
+impl LevelParam {
+    #[inline]
+    pub const fn new(name: &'static str, default: Level, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            default,
+            value: AtomicUsize::new(default as usize),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> Level {
+        Level::from_u8(self.value.load(Ordering::Relaxed) as u8)
+    }
+
+    fn parse_value(value: Option<&str>) -> Result<Level, ParamError> {
+        Level::parse(value.ok_or(ParamError::MissingValue)?).ok_or(ParamError::InvalidValue)
+    }
+}
+
+impl Param for LevelParam {
+    #[inline]
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn validate(&self, value: Option<&str>) -> Result<(), ParamError> {
+        Self::parse_value(value).map(|_| ())
+    }
+
+    fn set(&self, value: Option<&str>) -> Result<(), ParamError> {
+        let value = Self::parse_value(value)?;
+        self.value.store(value as usize, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.value.store(self.default as usize, Ordering::Relaxed);
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get())
+    }
+
+    fn fmt_default(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default)
+    }
+}
+
+/// Destination of log records
+pub trait LogSink: Sync {
+    /// Returns the most verbose level this sink accepts.
+    fn max_level(&self) -> Level {
+        Level::Trace
+    }
+
+    fn write_record(&self, record: &Record);
+}
+
+/// Writes records to the standard UART.
+pub struct UartSink;
+
+impl LogSink for UartSink {
+    fn write_record(&self, record: &Record) {
+        let _ = writeln!(System::stdout(), "{}", record);
+    }
+}
+
+/// Writes records to the emergency console on the screen.
+pub struct ScreenSink;
+
+impl LogSink for ScreenSink {
+    fn max_level(&self) -> Level {
+        SCREEN_LEVEL.get()
+    }
+
+    fn write_record(&self, record: &Record) {
+        let _ = writeln!(System::em_console(), "{}", record);
+    }
+}
+
+/// Maximum length of the text of a record
+pub const MAX_TEXT: usize = 192;
+
+/// Number of records kept in the ring buffer
+const NUM_SLOTS: usize = 256;
+
+/// Maximum number of sinks
+const MAX_SINKS: usize = 4;
+
+/// A record read back from the log
+#[derive(Clone, Copy)]
+pub struct Record {
+    seq: u64,
+    timestamp: u64,
+    cpu: u16,
+    level: Level,
+    len: u16,
+    target: &'static str,
+    text: [u8; MAX_TEXT],
+}
+
+impl Record {
+    const EMPTY: Self = Self {
+        seq: 0,
+        timestamp: 0,
+        cpu: 0,
+        level: Level::Trace,
+        len: 0,
+        target: "",
+        text: [0; MAX_TEXT],
+    };
+
+    /// Returns the sequence number, which starts at zero at boot.
+    #[inline]
+    pub const fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    #[inline]
+    pub const fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Returns the id of the core that logged the record.
+    #[inline]
+    pub const fn cpu(&self) -> usize {
+        self.cpu as usize
+    }
+
+    /// Returns the target without the crate name.
+    #[inline]
+    pub fn target(&self) -> &'static str {
+        self.target
+            .split_once("::")
+            .filter(|(krate, _)| *krate == env!("CARGO_CRATE_NAME"))
+            .map_or(self.target, |(_, path)| path)
+    }
+
+    /// Returns the counter of the system timer when the record was logged.
+    #[inline]
+    pub const fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    #[inline]
+    pub fn text(&self) -> &str {
+        let bytes = &self.text[..self.len as usize];
+        str::from_utf8(bytes).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let freq = GenericTimer::frequency().max(1);
+        write!(
+            f,
+            "[{:5}.{:06}] {} {:<5} {}: {}",
+            self.timestamp / freq,
+            (self.timestamp % freq) * 1_000_000 / freq,
+            self.cpu,
+            self.level,
+            self.target(),
+            self.text()
+        )
+    }
+}
+
+/// Truncates the formatted text at the end of the buffer of a record.
+struct TextWriter<'a> {
+    buf: &'a mut [u8; MAX_TEXT],
+    len: usize,
+}
+
+impl Write for TextWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(MAX_TEXT - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// A slot of the ring buffer guarded by a sequence lock
+///
+/// `state` is `seq * 2 + 1` while the record `seq` is written, and `seq * 2 + 2` once committed.
+struct Slot {
+    state: AtomicU64,
+    record: UnsafeCell<MaybeUninit<Record>>,
+}
+
+unsafe impl Sync for Slot {}
+
+impl Slot {
+    const EMPTY: Self = Self {
+        state: AtomicU64::new(0),
+        record: UnsafeCell::new(MaybeUninit::uninit()),
+    };
+}
+
+static SLOTS: [Slot; NUM_SLOTS] = [Slot::EMPTY; NUM_SLOTS];
+
+/// Sequence number of the next record
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Registered sinks and the sequence number of the next record to deliver to them
+static SINKS: SpinMutex<(FixedVec<Option<&'static dyn LogSink>, MAX_SINKS>, u64)> =
+    SpinMutex::new((FixedVec::new(None), 0));
+
+/// Whether a core is delivering records to the sinks
+static FLUSHING: AtomicBool = AtomicBool::new(false);
+
+pub struct Log;
+
+impl Log {
+    /// Returns the most verbose level that is logged.
+    #[inline]
+    pub fn max_level() -> Level {
+        LOG_LEVEL.get()
+    }
+
+    /// Appends a record to the ring buffer and delivers it to the sinks.
+    ///
+    /// Use the [log!] family of macros instead of calling this directly.
+    #[doc(hidden)]
+    pub fn write(level: Level, target: &'static str, args: fmt::Arguments) {
+        let mut record = Record {
+            timestamp: GenericTimer::counter(),
+            cpu: Cpu::current().id() as u16,
+            level,
+            target,
+            ..Record::EMPTY
+        };
+        let mut writer = TextWriter {
+            buf: &mut record.text,
+            len: 0,
+        };
+        let _ = writer.write_fmt(args);
+        record.len = writer.len as u16;
+
+        let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+        record.seq = seq;
+        let slot = &SLOTS[seq as usize % NUM_SLOTS];
+        slot.state.store(seq * 2 + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            slot.record.get().write(MaybeUninit::new(record));
+        }
+        slot.state.store(seq * 2 + 2, Ordering::Release);
+
+        Self::flush();
+    }
+
+    /// Returns a copy of the record `seq` if it is still in the ring buffer.
+    pub fn read(seq: u64) -> Option<Record> {
+        let slot = &SLOTS[seq as usize % NUM_SLOTS];
+        if slot.state.load(Ordering::Acquire) != seq * 2 + 2 {
+            return None;
+        }
+        let record = unsafe { slot.record.get().read_volatile() };
+        fence(Ordering::Acquire);
+        (slot.state.load(Ordering::Relaxed) == seq * 2 + 2).then(|| unsafe { record.assume_init() })
+    }
+
+    /// Returns the records in the ring buffer from the oldest.
+    pub fn records() -> impl Iterator<Item = Record> {
+        let end = NEXT_SEQ.load(Ordering::Acquire);
+        let start = end.saturating_sub(NUM_SLOTS as u64);
+        (start..end).filter_map(Self::read)
+    }
+
+    /// Writes the records in the ring buffer, like `dmesg`.
+    pub fn dump<W: Write + ?Sized>(w: &mut W, max_level: Level) -> fmt::Result {
+        for record in Self::records().filter(|v| v.level <= max_level) {
+            writeln!(w, "{}", record)?;
+        }
+        Ok(())
+    }
+
+    /// Registers a sink, which first receives the records still in the ring buffer.
+    pub fn add_sink(sink: &'static dyn LogSink) -> Result<(), ()> {
+        let next = {
+            let mut sinks = SINKS.lock();
+            let (list, next) = &mut *sinks;
+            list.push(Some(sink)).map_err(|_| ())?;
+            *next
+        };
+        for record in Self::records().take_while(|v| v.seq < next) {
+            if record.level <= sink.max_level() {
+                sink.write_record(&record);
+            }
+        }
+        Ok(())
+    }
+
+    /// Delivers the committed records to the sinks in order.
+    ///
+    /// One core at a time delivers the records, and writes them without holding [SINKS],
+    /// so a record logged meanwhile, even from a sink, is left to that core.
+    fn flush() {
+        // Pairs with the fence below, so either this core takes over or the delivering
+        // core sees the record committed.
+        fence(Ordering::SeqCst);
+        while !FLUSHING.swap(true, Ordering::Acquire) {
+            while let Some((record, sinks)) = Self::next_record() {
+                for sink in sinks.iter().flatten() {
+                    if record.level <= sink.max_level() {
+                        sink.write_record(&record);
+                    }
+                }
+            }
+            FLUSHING.store(false, Ordering::Release);
+            fence(Ordering::SeqCst);
+            let end = NEXT_SEQ.load(Ordering::Acquire);
+            let next = SINKS.lock().1.max(end.saturating_sub(NUM_SLOTS as u64));
+            if Self::read(next).is_none() {
+                break;
+            }
+        }
+    }
+
+    /// Takes the next record to deliver and a copy of the sinks.
+    fn next_record() -> Option<(Record, [Option<&'static dyn LogSink>; MAX_SINKS])> {
+        let mut sinks = SINKS.lock();
+        let (list, next) = &mut *sinks;
+        let end = NEXT_SEQ.load(Ordering::Acquire);
+        // Records overwritten before being delivered are lost.
+        *next = (*next).max(end.saturating_sub(NUM_SLOTS as u64));
+        // The core writing a record not committed yet delivers it once committed.
+        let record = Self::read(*next)?;
+        *next += 1;
+        let mut copy = [None; MAX_SINKS];
+        copy[..list.len()].copy_from_slice(list);
+        Some((record, copy))
+    }
+}
+
+struct DmesgCommand;
+
+impl ShellCommand for DmesgCommand {
+    fn name(&self) -> &'static str {
+        "dmesg"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[level]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the kernel log"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        let max_level = match args {
+            [] => Level::Trace,
+            [level] => Level::parse(level).ok_or(ShellError::InvalidArgument)?,
+            _ => return Err(ShellError::Usage),
+        };
+        Log::dump(out, max_level)?;
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static DMESG: DmesgCommand = DmesgCommand;
+}
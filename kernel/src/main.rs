@@ -2,17 +2,20 @@
 #![no_main]
 
 use alloc::vec::Vec;
+use bootprot::BootInfo;
 use core::fmt::Write;
 use rydia::mem::MemoryManager;
+use rydia::shell::Shell;
 use rydia::system::System;
 use rydia::{drawing::*, system};
 
 extern crate alloc;
 
 #[no_mangle]
-fn main(dtb: usize) -> ! {
+fn main(arg0: usize, arg1: usize) -> ! {
     unsafe {
-        System::init(dtb);
+        let info = BootInfo::from_entry(arg0, arg1).expect("invalid boot information");
+        System::init(&info);
     }
 
     let stdout = System::stdout();
@@ -68,13 +71,5 @@ fn main(dtb: usize) -> ! {
     )
     .unwrap();
 
-    loop {
-        if stdout.is_input_ready() {
-            let data = stdout.read_byte();
-            if data == '\r' as u8 {
-                stdout.write_byte('\n' as u8);
-            }
-            stdout.write_byte(data);
-        }
-    }
+    Shell::run(System::stdin())
 }
@@ -0,0 +1,187 @@
+//! Shell commands of the memory manager
+
+use super::{MemoryManager, PhysicalAddress};
+use crate::{
+    arch::{self, page::PageManager},
+    fw,
+    shell::{parse_number, ShellCommand, ShellError},
+    system::System,
+};
+use core::fmt::Write;
+
+/// Largest range that `dump` shows at once
+const MAX_DUMP: usize = 0x1000;
+
+/// Returns whether the range is RAM, VRAM or MMIO, and every page of it can be read,
+/// or written if `write`.
+///
+/// The guards of the kernel stacks are in RAM but not mapped.
+fn is_mapped(base: usize, len: usize, write: bool) -> bool {
+    let end = match base.checked_add(len) {
+        Some(v) => v,
+        None => return false,
+    };
+    if !fw::memory_ranges(System::boot_info())
+        .chain(arch::vram_memlist())
+        .chain(arch::device_memlist())
+        .any(|(start, size)| start.as_usize() <= base && end <= start.as_usize() + size)
+    {
+        return false;
+    }
+    let page_mask = PageManager::PAGE_SIZE_MIN - 1;
+    (base & !page_mask..end)
+        .step_by(PageManager::PAGE_SIZE_MIN)
+        .all(|pa| {
+            let va = PhysicalAddress::from_usize(pa).direct_mapped::<u8>() as usize;
+            PageManager::is_accessible(va, write)
+        })
+}
+
+struct MemCommand;
+
+impl ShellCommand for MemCommand {
+    fn name(&self) -> &'static str {
+        "mem"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the memory usage and the slab caches"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        let total = fw::memory_ranges(System::boot_info()).fold(0, |acc, v| acc + v.1);
+        writeln!(out, "total:    {:>10} KB", total >> 10)?;
+        writeln!(
+            out,
+            "reserved: {:>10} KB",
+            MemoryManager::reserved_memory_size() >> 10
+        )?;
+        writeln!(
+            out,
+            "free:     {:>10} KB",
+            MemoryManager::free_memory_size() >> 10
+        )?;
+        writeln!(out, "slab     used    total")?;
+        for (block_size, used, count) in MemoryManager::slab_statistics() {
+            writeln!(out, "{:>4} {:>8} {:>8}", block_size, used, count)?;
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static MEM: MemCommand = MemCommand;
+}
+
+struct DumpCommand;
+
+impl ShellCommand for DumpCommand {
+    fn name(&self) -> &'static str {
+        "dump"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<addr> [len]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Dump physical memory, which is read in 32-bit words"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        let (base, len) = match args {
+            [base] => (parse_number(base)? as usize, 0x100),
+            [base, len] => (parse_number(base)? as usize, parse_number(len)? as usize),
+            _ => return Err(ShellError::Usage),
+        };
+        let start = base & !15;
+        let end = base
+            .checked_add(len.clamp(1, MAX_DUMP))
+            .ok_or(ShellError::InvalidArgument)?
+            .next_multiple_of(16);
+        if !is_mapped(start, end - start, false) {
+            return Err(ShellError::InvalidArgument);
+        }
+
+        for line in (start..end).step_by(16) {
+            let mut bytes = [0u8; 16];
+            for (index, word) in bytes.chunks_exact_mut(4).enumerate() {
+                let ptr = PhysicalAddress::from_usize(line + index * 4).direct_mapped::<u32>();
+                word.copy_from_slice(&unsafe { ptr.read_volatile() }.to_le_bytes());
+            }
+            write!(out, "{:012x}:", line)?;
+            for byte in bytes {
+                write!(out, " {:02x}", byte)?;
+            }
+            write!(out, "  ")?;
+            for byte in bytes {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(out, "{}", c)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static DUMP: DumpCommand = DumpCommand;
+}
+
+struct PokeCommand;
+
+impl ShellCommand for PokeCommand {
+    fn name(&self) -> &'static str {
+        "poke"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<addr> <value> [1|2|4|8]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Write a value of the size in bytes, 4 by default, to physical memory"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        let (base, value, size) = match args {
+            [base, value] => (parse_number(base)? as usize, parse_number(value)?, 4),
+            [base, value, size] => (
+                parse_number(base)? as usize,
+                parse_number(value)?,
+                parse_number(size)? as usize,
+            ),
+            _ => return Err(ShellError::Usage),
+        };
+        if !matches!(size, 1 | 2 | 4 | 8)
+            || (base & (size - 1)) != 0
+            || (size < 8 && (value >> (size * 8)) != 0)
+            || !is_mapped(base, size, true)
+        {
+            return Err(ShellError::InvalidArgument);
+        }
+
+        let pa = PhysicalAddress::from_usize(base);
+        unsafe {
+            match size {
+                1 => pa.direct_mapped::<u8>().write_volatile(value as u8),
+                2 => pa.direct_mapped::<u16>().write_volatile(value as u16),
+                4 => pa.direct_mapped::<u32>().write_volatile(value as u32),
+                _ => pa.direct_mapped::<u64>().write_volatile(value),
+            }
+        }
+        writeln!(out, "{:012x}: {:0width$x}", base, value, width = size * 2)?;
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static POKE: PokeCommand = PokeCommand;
+}
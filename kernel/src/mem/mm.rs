@@ -1,12 +1,12 @@
-use super::{fixedvec::FixedVec, slab::*};
-use crate::{arch, fw::dt, sync::spinlock::SpinMutex, system::System};
-use alloc::boxed::Box;
+use super::{fixedvec::FixedVec, page::Page, slab::*};
+use crate::{arch, fw::dt, param::UintParam, sync::spinlock::SpinMutex};
+use alloc::{boxed::Box, vec::Vec};
 use bitflags::*;
+use bootprot::{BootInfo, BootMemoryType};
 use core::{
     alloc::Layout,
     cell::UnsafeCell,
     ffi::c_void,
-    fmt::Write,
     mem::{size_of, transmute},
     num::*,
     sync::atomic::*,
@@ -18,6 +18,17 @@ static mut MM: UnsafeCell<MemoryManager> = UnsafeCell::new(MemoryManager::new())
 
 static LAST_ALLOC_PTR: AtomicUsize = AtomicUsize::new(0);
 
+crate::kernel_param! {
+    pub static EARLY_ALLOC_SIZE: UintParam = UintParam::new(
+        "mem.early_size",
+        0x40_0000,
+        0x10_0000,
+        0x1000_0000,
+        "Size of the early allocation area after the kernel image",
+    )
+    .hex();
+}
+
 /// Memory Manager
 pub struct MemoryManager {
     reserved_memory_size: usize,
@@ -28,6 +39,11 @@ pub struct MemoryManager {
     mem_list: SpinMutex<FixedVec<MemFreePair, { Self::MAX_FREE_PAIRS }>>,
     slab: Option<Box<SlabAllocator>>,
 
+    /// Metadata of the pages from `pages_base`
+    pages: *mut Page,
+    pages_base: usize,
+    pages_len: usize,
+
     early_start: PhysicalAddress,
     early_end: PhysicalAddress,
 
@@ -38,12 +54,13 @@ pub struct MemoryManager {
 pub enum InitializationSource<'a> {
     /// Device Tree
     DeviceTree(&'a dt::DeviceTree),
-    /// TODO:
-    Uefi(usize),
+    /// Memory map from the UEFI loader
+    Uefi(&'a BootInfo),
 }
 
 impl MemoryManager {
     const MAX_FREE_PAIRS: usize = 1024;
+    const MAX_RESERVED: usize = 16;
     pub const PAGE_SIZE_MIN: usize = 0x1000;
 
     const fn new() -> Self {
@@ -56,6 +73,10 @@ impl MemoryManager {
             mem_list: SpinMutex::new(FixedVec::new(MemFreePair::empty())),
             slab: None,
 
+            pages: core::ptr::null_mut(),
+            pages_base: 0,
+            pages_len: 0,
+
             early_start: PhysicalAddress::NULL,
             early_end: PhysicalAddress::NULL,
 
@@ -77,34 +98,17 @@ impl MemoryManager {
             InitializationSource::DeviceTree(dt) => {
                 free_count = Self::_init_dt(dt).unwrap();
             }
-            InitializationSource::Uefi(_) => {
-                todo!()
-
-                // let mm: &[BootMemoryMapDescriptor] =
-                //     slice::from_raw_parts(info.mmap_base as usize as *const _, info.mmap_len as usize);
-
-                // let mut list = shared.mem_list.lock();
-                // for mem_desc in mm {
-                //     if mem_desc.mem_type == BootMemoryType::Available {
-                //         let size = mem_desc.page_count as usize * Self::PAGE_SIZE_MIN;
-                //         list.push(MemFreePair::new(mem_desc.base.into(), size))
-                //             .unwrap();
-                //         free_count += size;
-                //     }
-                // }
-                // shared.n_fragments.store(list.len(), Ordering::Release);
-                // drop(list);
-
-                // shared.reserved_memory_size = info.total_memory_size as usize - free_count;
-
-                // if cfg!(any(target_arch = "x86_64")) {
-                //     shared.real_bitmap = info.real_bitmap;
-                // }
+            InitializationSource::Uefi(info) => {
+                free_count = Self::_init_uefi(info).unwrap();
+                shared.reserved_memory_size =
+                    (info.total_memory_size as usize).saturating_sub(free_count);
             }
         }
 
         shared.free_pages.store(free_count, Ordering::SeqCst);
 
+        Self::_init_pages();
+
         // shared.slab = Some(Box::new(SlabAllocator::new()));
 
         // shared.fifo.write(EventQueue::new(100));
@@ -112,63 +116,155 @@ impl MemoryManager {
 
     #[inline(never)]
     unsafe fn _init_dt(dt: &dt::DeviceTree) -> Result<usize, ()> {
-        return Ok(0);
-        let stdout = System::stdout();
-
         let shared = Self::shared();
 
         let mut free_count = 0;
 
+        // Areas that must not be handed out: everything up to the end of the early allocation
+        // area (kernel image, boot stacks, page tables), the device tree blob itself
+        // and the entries of the memory reservation block.
+        let mut reserved = FixedVec::<(u64, u64), { Self::MAX_RESERVED }>::new((0, 0));
+        reserved.push((0, shared.early_end.as_u64())).unwrap();
+
         let dt_ptr = dt.header() as *const _ as usize;
-        writeln!(
-            stdout,
+        crate::debug!(
             "DeviceTree {:012x}-{:012x}",
             dt_ptr,
             dt_ptr + dt.header().total_size(),
-        )
-        .unwrap();
+        );
+        reserved
+            .push((dt_ptr as u64, (dt_ptr + dt.header().total_size()) as u64))
+            .unwrap();
 
         for item in dt.header().reserved_maps().into_iter() {
-            writeln!(
-                stdout,
-                "RESERVED {:012x}-{:012x}",
-                item.0,
-                item.0 + item.1 - 1
-            )
-            .unwrap();
+            crate::debug!("RESERVED {:012x}-{:012x}", item.0, item.0 + item.1 - 1);
+            reserved.push((item.0, item.0 + item.1)).map_err(|_| ())?;
         }
 
         let mut list = shared.mem_list.lock();
         for range in dt.memory_ranges().unwrap() {
             let (base, size) = range;
-            writeln!(
-                stdout,
+            crate::debug!(
                 "before: {:012x}-{:012x} ({})",
                 base.as_u64(),
                 (base + size - 1).as_u64(),
                 size >> 12
-            )
-            .unwrap();
+            );
             let (base, size) = arch::fix_memlist(base, size);
-            writeln!(
-                stdout,
-                "after_: {:012x}-{:012x} ({})",
-                base.as_u64(),
-                (base + size - 1).as_u64(),
-                size >> 12
-            )
-            .unwrap();
             if size > 0 {
-                list.push(MemFreePair::new(base, size)).unwrap();
+                free_count += Self::_add_free_range(
+                    &mut list,
+                    base.as_u64(),
+                    base.as_u64() + size as u64,
+                    reserved.as_slice(),
+                );
             }
-            free_count += size;
         }
+        Self::_report_free_list(list.as_slice());
         shared.n_fragments.store(list.len(), Ordering::Release);
         drop(list);
 
         Ok(free_count)
     }
 
+    #[inline(never)]
+    unsafe fn _init_uefi(info: &BootInfo) -> Result<usize, ()> {
+        let shared = Self::shared();
+
+        let mut free_count = 0;
+
+        // The kernel, the boot information and the device tree are in the loader's memory,
+        // but the framebuffer may be in the boot services memory.
+        let mut reserved = FixedVec::<(u64, u64), { Self::MAX_RESERVED }>::new((0, 0));
+        reserved.push((0, shared.early_end.as_u64())).unwrap();
+        if info.has_framebuffer() {
+            let vram_size = info.vram_stride as u64 * info.screen_height as u64 * 4;
+            reserved
+                .push((info.vram_base, info.vram_base + vram_size))
+                .unwrap();
+        }
+
+        let mut list = shared.mem_list.lock();
+        for desc in info.memory_map() {
+            if desc.mem_type == BootMemoryType::Available {
+                free_count +=
+                    Self::_add_free_range(&mut list, desc.base, desc.end(), reserved.as_slice());
+            }
+        }
+        Self::_report_free_list(list.as_slice());
+        shared.n_fragments.store(list.len(), Ordering::Release);
+        drop(list);
+
+        Ok(free_count)
+    }
+
+    /// Allocates the metadata of every page in the free list.
+    unsafe fn _init_pages() {
+        let shared = Self::shared_mut();
+        let list = shared.mem_list.lock();
+        let start = list.as_slice().iter().map(|v| v.base().as_usize()).min();
+        let end = list
+            .as_slice()
+            .iter()
+            .map(|v| (v.base() + v.size()).as_usize())
+            .max();
+        drop(list);
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return,
+        };
+
+        let len = (end - start) / Self::PAGE_SIZE_MIN;
+        if let Some(pa) = Self::alloc_pages(len * size_of::<Page>()) {
+            shared.pages = pa.get().direct_mapped();
+            shared.pages_base = start / Self::PAGE_SIZE_MIN;
+            shared.pages_len = len;
+        }
+    }
+
+    fn _report_free_list(list: &[MemFreePair]) {
+        for pair in list {
+            crate::debug!(
+                "after_: {:012x}-{:012x} ({})",
+                pair.base().as_u64(),
+                (pair.base() + pair.size() - 1).as_u64(),
+                pair.size() >> 12
+            );
+        }
+    }
+
+    /// Adds the pages in `start..end` except `reserved` to the free list.
+    fn _add_free_range(
+        list: &mut FixedVec<MemFreePair, { Self::MAX_FREE_PAIRS }>,
+        start: u64,
+        end: u64,
+        reserved: &[(u64, u64)],
+    ) -> usize {
+        let page_mask = Self::PAGE_SIZE_MIN as u64 - 1;
+        let start = (start + page_mask) & !page_mask;
+        let end = end & !page_mask;
+        if start >= end {
+            return 0;
+        }
+        match reserved.split_first() {
+            Some((&(r_start, r_end), rest)) => {
+                if r_end <= start || r_start >= end {
+                    Self::_add_free_range(list, start, end, rest)
+                } else {
+                    Self::_add_free_range(list, start, r_start.max(start), rest)
+                        + Self::_add_free_range(list, r_end.min(end), end, rest)
+                }
+            }
+            None => {
+                let size = (end - start) as usize;
+                match list.push(MemFreePair::new(PhysicalAddress::new(start), size)) {
+                    Ok(_) => size,
+                    Err(_) => 0,
+                }
+            }
+        }
+    }
+
     #[inline]
     unsafe fn shared_mut() -> &'static mut Self {
         MM.get_mut()
@@ -303,6 +399,15 @@ impl MemoryManager {
         })
     }
 
+    /// Frees the memory allocated by [MemoryManager::alloc_dma].
+    #[inline]
+    pub unsafe fn free_dma<T>(pa: PhysicalAddress, len: usize) {
+        Self::pg_dealloc(
+            pa,
+            Layout::from_size_align_unchecked(size_of::<T>() * len, Self::PAGE_SIZE_MIN),
+        );
+    }
+
     /// Allocate kernel memory
     #[must_use]
     pub unsafe fn zalloc(layout: Layout) -> Option<NonZeroUsize> {
@@ -327,6 +432,24 @@ impl MemoryManager {
             })
     }
 
+    /// Returns the metadata of the page at `pa`.
+    #[inline]
+    pub fn page(pa: PhysicalAddress) -> Option<&'static Page> {
+        let shared = Self::shared();
+        let index = (pa.as_usize() / Self::PAGE_SIZE_MIN).checked_sub(shared.pages_base)?;
+        (index < shared.pages_len).then(|| unsafe { &*shared.pages.add(index) })
+    }
+
+    /// Returns the block size, the number of used blocks and the number of all blocks of
+    /// each slab cache.
+    pub fn slab_statistics() -> Vec<(usize, usize, usize)> {
+        Self::shared()
+            .slab
+            .as_ref()
+            .map(|slab| slab.statistics())
+            .unwrap_or_default()
+    }
+
     #[inline]
     pub fn last_alloc_ptr() -> usize {
         LAST_ALLOC_PTR.load(core::sync::atomic::Ordering::Relaxed)
@@ -446,7 +569,7 @@ bitflags! {
         const NONE  = 0x0;
 
         const READ_WRITE = Self::READ.bits | Self::WRITE.bits;
-        const READ_EXEC = Self::READ.bits | Self::WRITE.bits;
+        const READ_EXEC = Self::READ.bits | Self::EXEC.bits;
     }
 }
 
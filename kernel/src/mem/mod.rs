@@ -1,7 +1,9 @@
+mod cmd;
 mod mm;
 pub use mm::*;
 
 pub mod alloc;
 pub mod fixedvec;
 pub mod mmio;
+pub mod page;
 pub mod slab;
@@ -0,0 +1,31 @@
+//! Metadata of physical pages
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Metadata of a 4KB physical page
+///
+/// The reference count is the number of user mappings of the page, which is zero
+/// for the pages used by the kernel.
+#[repr(C)]
+pub struct Page {
+    ref_count: AtomicU32,
+}
+
+impl Page {
+    #[inline]
+    pub fn ref_count(&self) -> usize {
+        self.ref_count.load(Ordering::Acquire) as usize
+    }
+
+    /// Adds a reference to the page.
+    #[inline]
+    pub fn retain(&self) {
+        self.ref_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Removes a reference and returns whether it was the last one.
+    #[inline]
+    pub fn release(&self) -> bool {
+        self.ref_count.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+}
@@ -57,7 +57,6 @@ impl SlabAllocator {
         SLABS.iter().fold(0, |v, i| v + i.free_memory_size())
     }
 
-    #[allow(dead_code)]
     pub(super) fn statistics(&self) -> Vec<(usize, usize, usize)> {
         let mut vec = Vec::with_capacity(SLABS.len());
         for slab in &SLABS {
@@ -0,0 +1,512 @@
+//! Kernel command-line parameters
+//!
+//! Parameters are declared by each module with [kernel_param!] and collected
+//! by the linker into the `.kparam` section. They are set from the
+//! `/chosen/bootargs` property of the device tree, e.g.
+//! `fb.width=1920 fb.height=1080 uart.baud=57600 emcon.bg=#000080`.
+
+use crate::{
+    drawing::*,
+    shell::{ShellCommand, ShellError},
+};
+use core::{
+    fmt::{self, Write},
+    slice, str,
+    sync::atomic::*,
+};
+
+static BOOTARGS_PTR: AtomicUsize = AtomicUsize::new(0);
+static BOOTARGS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+crate::kernel_param! {
+    static LIST_PARAMS: BoolParam = BoolParam::new(
+        "param.list",
+        false,
+        "Print all kernel parameters at boot",
+    );
+}
+
+/// Declares a kernel parameter and registers it to the parameter table.
+///
+/// ```ignore
+/// crate::kernel_param! {
+///     static FB_WIDTH: UintParam = UintParam::new("fb.width", 1280, 320, 4096, "Screen width");
+/// }
+/// ```
+#[macro_export]
+macro_rules! kernel_param {
+    ( $vis:vis static $name:ident : $ty:ty = $init:expr ; ) => {
+        $vis static $name: $ty = $init;
+
+        const _: () = {
+            #[used]
+            #[link_section = ".kparam"]
+            static ENTRY: $crate::param::ParamEntry = $crate::param::ParamEntry(&$name);
+        };
+    };
+}
+
+/// Common interface of kernel parameters
+pub trait Param: Sync {
+    /// Returns the name used on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Returns the description of this parameter.
+    fn help(&self) -> &'static str;
+
+    /// Checks the value without changing the parameter.
+    fn validate(&self, value: Option<&str>) -> Result<(), ParamError>;
+
+    /// Parses the value and sets it to the parameter.
+    fn set(&self, value: Option<&str>) -> Result<(), ParamError>;
+
+    /// Restores the default value.
+    fn reset(&self);
+
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+
+    fn fmt_default(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+impl fmt::Display for dyn Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = ", self.name())?;
+        self.fmt_value(f)?;
+        write!(f, " (default ")?;
+        self.fmt_default(f)?;
+        write!(f, "): {}", self.help())
+    }
+}
+
+/// An entry of the parameter table placed in the `.kparam` section
+#[repr(transparent)]
+pub struct ParamEntry(pub &'static dyn Param);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamError {
+    MissingValue,
+    InvalidValue,
+    OutOfRange,
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::MissingValue => write!(f, "missing value"),
+            ParamError::InvalidValue => write!(f, "invalid value"),
+            ParamError::OutOfRange => write!(f, "out of range"),
+        }
+    }
+}
+
+/// Boolean parameter
+///
+/// A parameter given without a value (`foo`) is treated as `foo=1`.
+pub struct BoolParam {
+    name: &'static str,
+    help: &'static str,
+    default: bool,
+    value: AtomicBool,
+}
+
+impl BoolParam {
+    #[inline]
+    pub const fn new(name: &'static str, default: bool, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            default,
+            value: AtomicBool::new(default),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> bool {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn parse_value(value: Option<&str>) -> Result<bool, ParamError> {
+        match value {
+            None => Ok(true),
+            Some("1" | "y" | "yes" | "on" | "true") => Ok(true),
+            Some("0" | "n" | "no" | "off" | "false") => Ok(false),
+            Some(_) => Err(ParamError::InvalidValue),
+        }
+    }
+}
+
+impl Param for BoolParam {
+    #[inline]
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn validate(&self, value: Option<&str>) -> Result<(), ParamError> {
+        Self::parse_value(value).map(|_| ())
+    }
+
+    fn set(&self, value: Option<&str>) -> Result<(), ParamError> {
+        let value = Self::parse_value(value)?;
+        self.value.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.value.store(self.default, Ordering::Relaxed);
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get() as usize)
+    }
+
+    fn fmt_default(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default as usize)
+    }
+}
+
+/// Unsigned integer parameter with an inclusive range
+///
+/// Values may be written in decimal or hexadecimal (`0x`), with an optional
+/// `K`, `M` or `G` suffix.
+pub struct UintParam {
+    name: &'static str,
+    help: &'static str,
+    default: usize,
+    min: usize,
+    max: usize,
+    hex: bool,
+    value: AtomicUsize,
+}
+
+impl UintParam {
+    #[inline]
+    pub const fn new(
+        name: &'static str,
+        default: usize,
+        min: usize,
+        max: usize,
+        help: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            help,
+            default,
+            min,
+            max,
+            hex: false,
+            value: AtomicUsize::new(default),
+        }
+    }
+
+    /// Displays the value in hexadecimal.
+    #[inline]
+    pub const fn hex(mut self) -> Self {
+        self.hex = true;
+        self
+    }
+
+    #[inline]
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub const fn min(&self) -> usize {
+        self.min
+    }
+
+    #[inline]
+    pub const fn max(&self) -> usize {
+        self.max
+    }
+
+    fn parse_value(&self, value: Option<&str>) -> Result<usize, ParamError> {
+        let value = value.ok_or(ParamError::MissingValue)?;
+        let (value, scale) = match value.as_bytes().last() {
+            Some(b'k' | b'K') => (&value[..value.len() - 1], 1 << 10),
+            Some(b'm' | b'M') => (&value[..value.len() - 1], 1 << 20),
+            Some(b'g' | b'G') => (&value[..value.len() - 1], 1 << 30),
+            _ => (value, 1),
+        };
+        let value = if let Some(hex) = value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            usize::from_str_radix(hex, 16)
+        } else {
+            usize::from_str_radix(value, 10)
+        }
+        .map_err(|_| ParamError::InvalidValue)?
+        .checked_mul(scale)
+        .ok_or(ParamError::OutOfRange)?;
+        (value >= self.min && value <= self.max)
+            .then_some(value)
+            .ok_or(ParamError::OutOfRange)
+    }
+
+    fn fmt_number(&self, f: &mut fmt::Formatter<'_>, value: usize) -> fmt::Result {
+        if self.hex {
+            write!(f, "{:#x}", value)
+        } else {
+            write!(f, "{}", value)
+        }
+    }
+}
+
+impl Param for UintParam {
+    #[inline]
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn validate(&self, value: Option<&str>) -> Result<(), ParamError> {
+        self.parse_value(value).map(|_| ())
+    }
+
+    fn set(&self, value: Option<&str>) -> Result<(), ParamError> {
+        let value = self.parse_value(value)?;
+        self.value.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.value.store(self.default, Ordering::Relaxed);
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_number(f, self.get())
+    }
+
+    fn fmt_default(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_number(f, self.default)?;
+        write!(f, ", ")?;
+        self.fmt_number(f, self.min)?;
+        write!(f, "..=")?;
+        self.fmt_number(f, self.max)
+    }
+}
+
+/// Color parameter
+///
+/// Accepts `#RRGGBB`, `0xRRGGBB` or an index of the system palette.
+pub struct ColorParam {
+    name: &'static str,
+    help: &'static str,
+    default: u32,
+    value: AtomicU32,
+}
+
+impl ColorParam {
+    #[inline]
+    pub const fn new(name: &'static str, default: Color, help: &'static str) -> Self {
+        let default = default.into_true_color().argb();
+        Self {
+            name,
+            help,
+            default,
+            value: AtomicU32::new(default),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> Color {
+        Color::from_argb(self.value.load(Ordering::Relaxed))
+    }
+
+    fn parse_value(value: Option<&str>) -> Result<u32, ParamError> {
+        let value = value.ok_or(ParamError::MissingValue)?;
+        if let Some(rgb) = value.strip_prefix('#').or_else(|| value.strip_prefix("0x")) {
+            if rgb.len() != 6 {
+                return Err(ParamError::InvalidValue);
+            }
+            u32::from_str_radix(rgb, 16)
+                .map(|rgb| TrueColor::from_rgb(rgb).argb())
+                .map_err(|_| ParamError::InvalidValue)
+        } else {
+            let index = u8::from_str_radix(value, 10).map_err(|_| ParamError::InvalidValue)?;
+            Ok(IndexedColor(index).as_argb())
+        }
+    }
+}
+
+impl Param for ColorParam {
+    #[inline]
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn validate(&self, value: Option<&str>) -> Result<(), ParamError> {
+        Self::parse_value(value).map(|_| ())
+    }
+
+    fn set(&self, value: Option<&str>) -> Result<(), ParamError> {
+        let value = Self::parse_value(value)?;
+        self.value.store(value, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.value.store(self.default, Ordering::Relaxed);
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{:06x}",
+            self.value.load(Ordering::Relaxed) & 0xFF_FF_FF
+        )
+    }
+
+    fn fmt_default(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:06x}", self.default & 0xFF_FF_FF)
+    }
+}
+
+/// Iterator over `key[=value]` pairs of a command line
+///
+/// Values may be enclosed in double quotes to include spaces.
+pub struct BootArgs<'a> {
+    rest: &'a str,
+}
+
+impl<'a> BootArgs<'a> {
+    #[inline]
+    pub const fn new(args: &'a str) -> Self {
+        Self { rest: args }
+    }
+}
+
+impl<'a> Iterator for BootArgs<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.rest.trim_start();
+        if s.is_empty() {
+            self.rest = s;
+            return None;
+        }
+        let mut in_quote = false;
+        let mut end = s.len();
+        for (index, c) in s.char_indices() {
+            match c {
+                '"' => in_quote = !in_quote,
+                c if c.is_ascii_whitespace() && !in_quote => {
+                    end = index;
+                    break;
+                }
+                _ => (),
+            }
+        }
+        let (token, rest) = s.split_at(end);
+        self.rest = rest;
+        Some(match token.split_once('=') {
+            Some((key, value)) => (key, Some(value.trim_matches('"'))),
+            None => (token, None),
+        })
+    }
+}
+
+/// Returns all registered parameters.
+pub fn params() -> impl Iterator<Item = &'static dyn Param> {
+    extern "C" {
+        static __kparam_start: u8;
+        static __kparam_end: u8;
+    }
+    unsafe {
+        let start = &__kparam_start as *const _ as *const ParamEntry;
+        let end = &__kparam_end as *const _ as *const ParamEntry;
+        let len = end.offset_from(start) as usize;
+        slice::from_raw_parts(start, len).iter().map(|v| v.0)
+    }
+}
+
+/// Finds the parameter with the specified name.
+pub fn find(name: &str) -> Option<&'static dyn Param> {
+    params().find(|v| v.name() == name)
+}
+
+/// Returns the command line given at boot.
+pub fn bootargs() -> &'static str {
+    let ptr = BOOTARGS_PTR.load(Ordering::Relaxed) as *const u8;
+    let len = BOOTARGS_LEN.load(Ordering::Relaxed);
+    if ptr.is_null() {
+        return "";
+    }
+    unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) }
+}
+
+/// Sets the parameters from the command line.
+///
+/// Unknown keys are ignored, and invalid values leave the parameter at its default.
+/// This runs before any console is available, so errors are reported later by [report].
+pub fn init(bootargs: &'static str) {
+    BOOTARGS_PTR.store(bootargs.as_ptr() as usize, Ordering::Relaxed);
+    BOOTARGS_LEN.store(bootargs.len(), Ordering::Relaxed);
+
+    for (key, value) in BootArgs::new(bootargs) {
+        if let Some(param) = find(key) {
+            let _ = param.set(value);
+        }
+    }
+}
+
+/// Reports rejected arguments, and lists all parameters if `param.list` is given.
+pub fn report<W: Write + ?Sized>(w: &mut W) {
+    for (key, value) in BootArgs::new(bootargs()) {
+        if let Some(param) = find(key) {
+            if let Err(err) = param.validate(value) {
+                let _ = writeln!(w, "param: {}: {}, using default", key, err);
+            }
+        }
+    }
+    if LIST_PARAMS.get() {
+        let _ = write_list(w);
+    }
+}
+
+/// Writes all parameters with their current values.
+pub fn write_list<W: Write + ?Sized>(w: &mut W) -> fmt::Result {
+    for param in params() {
+        writeln!(w, "{}", param)?;
+    }
+    Ok(())
+}
+
+struct ParamsCommand;
+
+impl ShellCommand for ParamsCommand {
+    fn name(&self) -> &'static str {
+        "params"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the kernel parameters"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        write_list(out)?;
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static PARAMS: ParamsCommand = ParamsCommand;
+}
@@ -0,0 +1,240 @@
+//! Shell commands of user processes
+
+use super::{ExitStatus, Process, ProcessError};
+use crate::{
+    arch::{smp::Smp, stack::KernelStack, uspace::AddressSpace},
+    fw,
+    mem::PhysicalAddress,
+    shell::{parse_number, ShellCommand, ShellError},
+    system::System,
+};
+use core::{arch::global_asm, fmt::Write, slice};
+
+/// Returns the built-in executables and their names.
+fn images() -> [(&'static str, &'static [u8]); 3] {
+    extern "C" {
+        static __user_hello_start: u8;
+        static __user_hello_end: u8;
+        static __user_spin_start: u8;
+        static __user_spin_end: u8;
+        static __user_fork_start: u8;
+        static __user_fork_end: u8;
+    }
+    unsafe fn image(start: &'static u8, end: &'static u8) -> &'static [u8] {
+        let start = start as *const u8;
+        let end = end as *const u8;
+        slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+    unsafe {
+        [
+            ("hello", image(&__user_hello_start, &__user_hello_end)),
+            ("spin", image(&__user_spin_start, &__user_spin_end)),
+            ("fork", image(&__user_fork_start, &__user_fork_end)),
+        ]
+    }
+}
+
+// Each image is a static ELF executable with a single segment, which maps the whole file
+// at the start of the user address space.
+//
+// hello: writes a message to the standard output and exits.
+// spin:  loops forever without system calls, until Ctrl-C stops it.
+// fork:  forks after storing a message on the stack, which the child overwrites in its
+//        copy of the page. Both write their message, `child` and then `parent`.
+global_asm!(
+    "
+    .set USER_IMAGE_BASE, {base}
+
+    .macro user_image_header name
+    .balign 8
+    .global __user_\\name\\()_start
+__user_\\name\\()_start:
+    .byte   0x7F, 0x45, 0x4C, 0x46, 2, 1, 1, 0
+    .quad   0
+    .hword  2, 183
+    .word   1
+    .quad   USER_IMAGE_BASE + (.Luser_\\name\\()_entry - __user_\\name\\()_start)
+    .quad   64, 0
+    .word   0
+    .hword  64, 56, 1, 64, 0, 0
+    .word   1, 5
+    .quad   0, USER_IMAGE_BASE, USER_IMAGE_BASE
+    .quad   __user_\\name\\()_end - __user_\\name\\()_start
+    .quad   __user_\\name\\()_end - __user_\\name\\()_start
+    .quad   {page_size}
+    .endm
+
+    .pushsection .rodata.user_images, \"a\"
+
+    user_image_header hello
+.Luser_hello_message:
+    .ascii  \"Hello from EL0\\n\"
+.Luser_hello_message_end:
+    .balign 4
+.Luser_hello_entry:
+    mov     x0, #1
+    adr     x1, .Luser_hello_message
+    mov     x2, #(.Luser_hello_message_end - .Luser_hello_message)
+    mov     x8, #1
+    svc     #0
+    mov     x0, #0
+    mov     x8, #0
+    svc     #0
+    .global __user_hello_end
+__user_hello_end:
+
+    user_image_header spin
+.Luser_spin_entry:
+    b       .Luser_spin_entry
+    .global __user_spin_end
+__user_spin_end:
+
+    user_image_header fork
+.Luser_fork_parent:
+    .ascii  \"parent\\n\\0\"
+.Luser_fork_child:
+    .ascii  \"child\\n\\0\\0\"
+.Luser_fork_entry:
+    ldr     x9, .Luser_fork_parent
+    str     x9, [sp, #-16]!
+    mov     x8, #12
+    svc     #0
+    mov     x2, #7
+    cbnz    x0, .Luser_fork_write
+    ldr     x9, .Luser_fork_child
+    str     x9, [sp]
+    mov     x2, #6
+.Luser_fork_write:
+    mov     x0, #1
+    mov     x1, sp
+    mov     x8, #1
+    svc     #0
+    mov     x0, #0
+    mov     x8, #0
+    svc     #0
+    .global __user_fork_end
+__user_fork_end:
+    .popsection
+    ",
+    base = const AddressSpace::USER_BASE,
+    page_size = const AddressSpace::PAGE_SIZE,
+);
+
+/// Returns whether the range is in RAM.
+fn is_ram(base: usize, len: usize) -> bool {
+    let end = match base.checked_add(len) {
+        Some(v) => v,
+        None => return false,
+    };
+    fw::memory_ranges(System::boot_info())
+        .any(|(start, size)| start.as_usize() <= base && end <= start.as_usize() + size)
+}
+
+struct RunCommand;
+
+impl ShellCommand for RunCommand {
+    fn name(&self) -> &'static str {
+        "run"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<hello|spin|fork> | <addr> <len>"
+    }
+
+    fn help(&self) -> &'static str {
+        "Run a built-in program or an ELF executable in physical memory as a process"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        let (name, image) = match args {
+            [name] => images()
+                .into_iter()
+                .find(|v| v.0 == *name)
+                .ok_or(ShellError::InvalidArgument)?,
+            [base, len] => {
+                let base = parse_number(base)? as usize;
+                let len = parse_number(len)? as usize;
+                if len == 0 || !is_ram(base, len) {
+                    return Err(ShellError::InvalidArgument);
+                }
+                let ptr = PhysicalAddress::from_usize(base).direct_mapped::<u8>() as *const u8;
+                ("a.out", unsafe { slice::from_raw_parts(ptr, len) })
+            }
+            _ => return Err(ShellError::Usage),
+        };
+
+        let mut process = match Process::from_elf(name, image) {
+            Ok(v) => v,
+            Err(ProcessError::InvalidExecutable) => return Err(ShellError::InvalidArgument),
+            Err(err) => {
+                writeln!(out, "cannot create the process: {:?}", err)?;
+                return Ok(());
+            }
+        };
+        let pid = process.pid();
+        match process.run() {
+            ExitStatus::Exited(code) => writeln!(out, "process {} exited with {}", pid, code)?,
+            ExitStatus::Killed(fault) => writeln!(out, "process {} killed by {}", pid, fault)?,
+            ExitStatus::Interrupted => writeln!(out, "process {} interrupted", pid)?,
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static RUN: RunCommand = RunCommand;
+}
+
+struct ThreadsCommand;
+
+impl ShellCommand for ThreadsCommand {
+    fn name(&self) -> &'static str {
+        "threads"
+    }
+
+    fn help(&self) -> &'static str {
+        "List the kernel threads and the running processes"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+
+        writeln!(out, "THREAD  STACK")?;
+        for (thread, stack) in KernelStack::owners() {
+            write!(
+                out,
+                "{:6}  {:#018x}-{:#018x}",
+                thread, stack.start, stack.end
+            )?;
+            if thread < Smp::num_cpus() {
+                write!(out, "  idle of core #{}", thread)?;
+            }
+            writeln!(out)?;
+        }
+
+        let running = Process::running();
+        if running.is_empty() {
+            return Ok(());
+        }
+        writeln!(out)?;
+        writeln!(out, "   PID  CORE  NAME              THREADS")?;
+        for process in running {
+            write!(
+                out,
+                "{:6}  {:4}  {:16}",
+                process.pid.0, process.cpu, process.name
+            )?;
+            for tid in &process.threads {
+                write!(out, "  {}", tid.0)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static THREADS: ThreadsCommand = ThreadsCommand;
+}
@@ -0,0 +1,136 @@
+//! Loader of static ELF64 executables
+
+use super::{Process, ProcessError};
+use crate::{arch::uspace::AddressSpace, mem::MProtect};
+use core::mem::size_of;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Elf64Header {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Elf64ProgramHeader {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+impl Elf64ProgramHeader {
+    #[inline]
+    fn prot(&self) -> MProtect {
+        MProtect::from_bits_truncate(self.p_flags as usize)
+    }
+
+    /// Returns the page aligned range of the segment.
+    #[inline]
+    fn pages(&self) -> (usize, usize) {
+        let page_mask = AddressSpace::PAGE_SIZE - 1;
+        let start = self.p_vaddr as usize & !page_mask;
+        let end = (self.p_vaddr + self.p_memsz) as usize;
+        (start, (end + page_mask) & !page_mask)
+    }
+}
+
+#[inline]
+unsafe fn read<T: Copy>(image: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(size_of::<T>())?;
+    (end <= image.len()).then(|| (image.as_ptr().add(offset) as *const T).read_unaligned())
+}
+
+fn program_headers(image: &[u8]) -> Result<(u64, usize, usize), ProcessError> {
+    let header: Elf64Header = unsafe { read(image, 0) }.ok_or(ProcessError::InvalidExecutable)?;
+    if &header.e_ident[..4] != b"\x7fELF"
+        || header.e_ident[4] != ELFCLASS64
+        || header.e_ident[5] != ELFDATA2LSB
+        || header.e_type != ET_EXEC
+        || header.e_machine != EM_AARCH64
+        || header.e_phentsize as usize != size_of::<Elf64ProgramHeader>()
+    {
+        return Err(ProcessError::InvalidExecutable);
+    }
+    Ok((
+        header.e_entry,
+        header.e_phoff as usize,
+        header.e_phnum as usize,
+    ))
+}
+
+fn segments(
+    image: &[u8],
+    phoff: usize,
+    phnum: usize,
+) -> impl Iterator<Item = Elf64ProgramHeader> + '_ {
+    (0..phnum)
+        .filter_map(move |index| unsafe {
+            read::<Elf64ProgramHeader>(image, phoff + index * size_of::<Elf64ProgramHeader>())
+        })
+        .filter(|ph| ph.p_type == PT_LOAD && ph.p_memsz > 0)
+}
+
+/// Loads `image` into `process` and returns its entry point.
+pub(super) fn load(process: &mut Process, image: &[u8]) -> Result<usize, ProcessError> {
+    let (entry, phoff, phnum) = program_headers(image)?;
+    if phnum == 0 || !AddressSpace::is_user_range(entry as usize, 4) {
+        return Err(ProcessError::InvalidExecutable);
+    }
+
+    for ph in segments(image, phoff, phnum) {
+        if ph.p_filesz > ph.p_memsz
+            || ph.p_offset.saturating_add(ph.p_filesz) > image.len() as u64
+            || ph.p_vaddr.checked_add(ph.p_memsz).is_none()
+            || !AddressSpace::is_user_range(ph.p_vaddr as usize, ph.p_memsz as usize)
+        {
+            return Err(ProcessError::InvalidExecutable);
+        }
+        // Pages shared by segments get the union of their protections
+        let (start, end) = ph.pages();
+        for page in (start..end).step_by(AddressSpace::PAGE_SIZE) {
+            if process.region(page).is_some() {
+                continue;
+            }
+            let prot = segments(image, phoff, phnum)
+                .filter(|other| {
+                    let (start, end) = other.pages();
+                    (start..end).contains(&page)
+                })
+                .fold(MProtect::NONE, |acc, other| acc | other.prot());
+            process.map_anonymous(page, AddressSpace::PAGE_SIZE, prot)?;
+        }
+        process.populate(start, end - start)?;
+        let offset = ph.p_offset as usize;
+        process.space.write(
+            ph.p_vaddr as usize,
+            &image[offset..offset + ph.p_filesz as usize],
+        )?;
+    }
+    process.space.sync_icache();
+
+    Ok(entry as usize)
+}
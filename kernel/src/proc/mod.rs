@@ -0,0 +1,741 @@
+//! User processes
+//!
+//! A process owns an isolated address space and its threads, which run on the calling core
+//! until the process exits or faults. A fault kills only the process. The timer takes the
+//! core back from EL0 on every tick, to switch threads at the end of their time slice and
+//! to stop the process on Ctrl-C.
+//!
+//! Anonymous mappings are populated on the first access, and a forked process shares
+//! the pages of its parent copy-on-write. The parent resumes after the child exits.
+
+mod cmd;
+pub mod elf;
+pub mod syscall;
+
+use crate::{
+    arch::{
+        cpu::Cpu,
+        exception::{self, ExceptionClass, TrapFrame, UserExit},
+        timer::GenericTimer,
+        uspace::{AddressSpace, AddressSpaceError},
+    },
+    io::tty::TtySignal,
+    mem::{MProtect, MemoryManager, MemoryMapRequest, PhysicalAddress},
+    param::UintParam,
+    sync::spinlock::SpinMutex,
+    system::System,
+};
+use alloc::{string::String, vec::Vec};
+use core::{
+    alloc::Layout,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+crate::kernel_param! {
+    pub static PROC_MEMORY_LIMIT: UintParam = UintParam::new(
+        "proc.mem_limit",
+        0x0400_0000,
+        0x0010_0000,
+        0x4000_0000,
+        "Default limit of the memory used by a user process",
+    )
+    .hex();
+}
+
+crate::kernel_param! {
+    pub static PROC_TIME_SLICE: UintParam = UintParam::new(
+        "proc.time_slice",
+        20,
+        1,
+        1000,
+        "Milliseconds a user thread runs before the next one",
+    );
+}
+
+/// Interval of the timer while a user thread runs
+const TICK: Duration = Duration::from_millis(10);
+
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+/// The processes in [Process::run] on any core
+static RUNNING: SpinMutex<Vec<ProcessInfo>> = SpinMutex::new(Vec::new());
+
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessId(pub usize);
+
+impl fmt::Display for ProcessId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    OutOfMemory,
+    /// The process would exceed its memory limit
+    MemoryLimit,
+    /// Too many processes exist
+    TooManyProcesses,
+    TooManyThreads,
+    InvalidArgument,
+    InvalidExecutable,
+    InvalidAddress,
+    AlreadyMapped,
+    NotMapped,
+}
+
+impl From<AddressSpaceError> for ProcessError {
+    #[inline]
+    fn from(err: AddressSpaceError) -> Self {
+        match err {
+            AddressSpaceError::OutOfMemory => Self::OutOfMemory,
+            AddressSpaceError::NoAsid => Self::TooManyProcesses,
+            AddressSpaceError::InvalidAddress => Self::InvalidAddress,
+            AddressSpaceError::AlreadyMapped => Self::AlreadyMapped,
+            AddressSpaceError::NotMapped => Self::NotMapped,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The process called `exit`
+    Exited(i32),
+    /// The process was killed by a fault
+    Killed(Fault),
+    /// Ctrl-C was pressed at the console
+    Interrupted,
+}
+
+/// A fault that killed a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub class: ExceptionClass,
+    pub esr: u64,
+    /// Faulting address, valid for aborts
+    pub far: u64,
+    /// Address of the faulting instruction
+    pub pc: u64,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} at {:016x} (ESR {:08x} FAR {:016x})",
+            self.class, self.pc, self.esr, self.far
+        )
+    }
+}
+
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(pub usize);
+
+/// A running process as listed by [Process::running]
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: ProcessId,
+    pub name: String,
+    /// Logical id of the core running the process
+    pub cpu: usize,
+    pub threads: Vec<ThreadId>,
+}
+
+struct Thread {
+    tid: ThreadId,
+    context: TrapFrame,
+    /// The thread is sleeping until this time if not zero
+    wake_at: Duration,
+    /// Stack allocated by the kernel, freed when the thread exits
+    stack: Option<(usize, usize)>,
+}
+
+/// Range of anonymous memory mapped by the process
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    base: usize,
+    len: usize,
+    prot: MProtect,
+}
+
+impl Region {
+    #[inline]
+    const fn end(&self) -> usize {
+        self.base + self.len
+    }
+
+    #[inline]
+    const fn contains(&self, va: usize) -> bool {
+        va >= self.base && va < self.end()
+    }
+}
+
+pub struct Process {
+    pid: ProcessId,
+    name: String,
+    space: AddressSpace,
+    threads: Vec<Thread>,
+    current: usize,
+    /// End of the time slice of the running thread, or zero to start a new one
+    slice_end: Duration,
+    next_tid: usize,
+    regions: Vec<Region>,
+    /// Size of the regions, which counts against the memory limit
+    committed: usize,
+    memory_limit: usize,
+    mmap_next: usize,
+}
+
+impl Process {
+    /// Top of the initial stack
+    pub const STACK_TOP: usize = AddressSpace::USER_END - AddressSpace::PAGE_SIZE;
+    pub const STACK_SIZE: usize = 0x1_0000;
+    pub const MAX_THREADS: usize = 64;
+
+    /// Range where the kernel places anonymous mappings
+    const MMAP_BASE: usize = AddressSpace::USER_BASE + 0x10_0000_0000;
+    const MMAP_END: usize = AddressSpace::USER_END - 0x10_0000_0000;
+
+    /// Creates an empty process.
+    pub fn new(name: &str) -> Result<Self, ProcessError> {
+        let space = AddressSpace::new()?;
+        Ok(Self {
+            pid: ProcessId(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+            name: String::from(name),
+            space,
+            threads: Vec::new(),
+            current: 0,
+            slice_end: Duration::ZERO,
+            next_tid: 1,
+            regions: Vec::new(),
+            committed: 0,
+            memory_limit: PROC_MEMORY_LIMIT.get(),
+            mmap_next: Self::MMAP_BASE,
+        })
+    }
+
+    /// Creates a child process that shares the memory of this process copy-on-write,
+    /// with a copy of the running thread.
+    pub fn fork(&mut self) -> Result<Self, ProcessError> {
+        let space = self.space.fork()?;
+        let thread = &self.threads[self.current];
+        let mut threads = Vec::new();
+        threads
+            .try_reserve(1)
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        threads.push(Thread {
+            tid: thread.tid,
+            context: thread.context,
+            wake_at: Duration::ZERO,
+            stack: thread.stack,
+        });
+        let mut regions = Vec::new();
+        regions
+            .try_reserve(self.regions.len())
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        regions.extend_from_slice(&self.regions);
+        Ok(Self {
+            pid: ProcessId(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+            name: self.name.clone(),
+            space,
+            threads,
+            current: 0,
+            slice_end: Duration::ZERO,
+            next_tid: self.next_tid,
+            regions,
+            committed: self.committed,
+            memory_limit: self.memory_limit,
+            mmap_next: self.mmap_next,
+        })
+    }
+
+    /// Creates a process from a static ELF executable.
+    pub fn from_elf(name: &str, image: &[u8]) -> Result<Self, ProcessError> {
+        let mut process = Self::new(name)?;
+        let entry = elf::load(&mut process, image)?;
+        process.map_anonymous(
+            Self::STACK_TOP - Self::STACK_SIZE,
+            Self::STACK_SIZE,
+            MProtect::READ_WRITE,
+        )?;
+        process.add_thread(TrapFrame::new_user(entry, Self::STACK_TOP), None)?;
+        Ok(process)
+    }
+
+    #[inline]
+    pub const fn pid(&self) -> ProcessId {
+        self.pid
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub const fn address_space(&self) -> &AddressSpace {
+        &self.space
+    }
+
+    /// Returns the memory committed to the process, including its translation tables.
+    #[inline]
+    pub const fn memory_usage(&self) -> usize {
+        self.committed + self.space.table_pages() * AddressSpace::PAGE_SIZE
+    }
+
+    #[inline]
+    pub const fn memory_limit(&self) -> usize {
+        self.memory_limit
+    }
+
+    #[inline]
+    pub fn set_memory_limit(&mut self, limit: usize) {
+        self.memory_limit = limit;
+    }
+
+    #[inline]
+    fn region(&self, va: usize) -> Option<Region> {
+        self.regions.iter().find(|v| v.contains(va)).copied()
+    }
+
+    /// Maps `va..va+len` to zeroed pages, which are allocated on the first access.
+    pub fn map_anonymous(
+        &mut self,
+        va: usize,
+        len: usize,
+        prot: MProtect,
+    ) -> Result<(), ProcessError> {
+        let page_mask = AddressSpace::PAGE_SIZE - 1;
+        if (va & page_mask) != 0 || len == 0 || !AddressSpace::is_user_range(va, len) {
+            return Err(ProcessError::InvalidAddress);
+        }
+        let len = (len + page_mask) & !page_mask;
+        if self
+            .regions
+            .iter()
+            .any(|v| v.base < va + len && va < v.end())
+        {
+            return Err(ProcessError::AlreadyMapped);
+        }
+        if self.memory_usage() + len > self.memory_limit {
+            return Err(ProcessError::MemoryLimit);
+        }
+        self.committed += len;
+        if let Some(region) = self
+            .regions
+            .iter_mut()
+            .find(|v| v.end() == va && v.prot == prot)
+        {
+            region.len += len;
+            return Ok(());
+        }
+        self.regions.try_reserve(1).map_err(|_| {
+            self.committed -= len;
+            ProcessError::OutOfMemory
+        })?;
+        self.regions.push(Region {
+            base: va,
+            len,
+            prot,
+        });
+        Ok(())
+    }
+
+    /// Unmaps `va..va+len` and releases the pages.
+    pub fn unmap(&mut self, va: usize, len: usize) -> Result<(), ProcessError> {
+        let page_mask = AddressSpace::PAGE_SIZE - 1;
+        if (va & page_mask) != 0 || !AddressSpace::is_user_range(va, len) {
+            return Err(ProcessError::InvalidAddress);
+        }
+        let end = (va + len + page_mask) & !page_mask;
+        self.regions
+            .try_reserve(1)
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        let mut index = 0;
+        while index < self.regions.len() {
+            let region = self.regions[index];
+            if region.end() <= va || region.base >= end {
+                index += 1;
+                continue;
+            }
+            self.regions.remove(index);
+            self.committed -= region.end().min(end) - region.base.max(va);
+            if region.base < va {
+                self.regions.insert(
+                    index,
+                    Region {
+                        len: va - region.base,
+                        ..region
+                    },
+                );
+                index += 1;
+            }
+            if region.end() > end {
+                self.regions.insert(
+                    index,
+                    Region {
+                        base: end,
+                        len: region.end() - end,
+                        prot: region.prot,
+                    },
+                );
+                index += 1;
+            }
+        }
+        for page in (va..end).step_by(AddressSpace::PAGE_SIZE) {
+            match self.space.unmap_page(page) {
+                Ok(_) | Err(AddressSpaceError::NotMapped) => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the page at `va` accessible for `access`, allocating it or copying it on write.
+    fn fault_in(&mut self, va: usize, access: MProtect) -> Result<(), ProcessError> {
+        let region = self.region(va).ok_or(ProcessError::InvalidAddress)?;
+        if !region.prot.contains(access) {
+            return Err(ProcessError::InvalidAddress);
+        }
+        let va = va & !(AddressSpace::PAGE_SIZE - 1);
+        if self.space.translate(va).is_none() {
+            let pa = unsafe { MemoryManager::alloc_pages(AddressSpace::PAGE_SIZE) }
+                .ok_or(ProcessError::OutOfMemory)?
+                .get();
+            self.space.map_page(va, pa, region.prot).map_err(|err| {
+                Self::free_page(pa);
+                err
+            })?;
+        } else if access.contains(MProtect::WRITE) {
+            self.space.copy_on_write(va)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn free_page(pa: PhysicalAddress) {
+        unsafe {
+            MemoryManager::pg_dealloc(
+                pa,
+                Layout::from_size_align_unchecked(AddressSpace::PAGE_SIZE, AddressSpace::PAGE_SIZE),
+            );
+        }
+    }
+
+    /// Allocates the pages of `va..va+len` in advance.
+    pub fn populate(&mut self, va: usize, len: usize) -> Result<(), ProcessError> {
+        let page_mask = AddressSpace::PAGE_SIZE - 1;
+        for page in ((va & !page_mask)..va.saturating_add(len)).step_by(AddressSpace::PAGE_SIZE) {
+            self.fault_in(page, MProtect::NONE)?;
+        }
+        Ok(())
+    }
+
+    /// Copies the user memory at `va` to `buf`, with the permission of the process.
+    pub fn read_memory(&mut self, va: usize, buf: &mut [u8]) -> Result<(), ProcessError> {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let va = va.checked_add(offset).ok_or(ProcessError::InvalidAddress)?;
+            let len = (AddressSpace::PAGE_SIZE - (va & (AddressSpace::PAGE_SIZE - 1)))
+                .min(buf.len() - offset);
+            self.fault_in(va, MProtect::READ)?;
+            self.space.read(va, &mut buf[offset..offset + len])?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to the user memory at `va`, with the permission of the process.
+    pub fn write_memory(&mut self, va: usize, data: &[u8]) -> Result<(), ProcessError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let va = va.checked_add(offset).ok_or(ProcessError::InvalidAddress)?;
+            let len = (AddressSpace::PAGE_SIZE - (va & (AddressSpace::PAGE_SIZE - 1)))
+                .min(data.len() - offset);
+            self.fault_in(va, MProtect::WRITE)?;
+            self.space.write(va, &data[offset..offset + len])?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Resolves a translation or permission fault of the running thread.
+    ///
+    /// Returns `false` if the access is not allowed.
+    fn handle_page_fault(&mut self) -> bool {
+        const FSC_TRANSLATION: u64 = 0b00_0100;
+        const FSC_PERMISSION: u64 = 0b00_1100;
+        const ISS_WNR: u64 = 1 << 6;
+
+        let context = &self.threads[self.current].context;
+        let access = match context.exception_class() {
+            ExceptionClass::INSTRUCTION_ABORT_LOWER => MProtect::EXEC,
+            ExceptionClass::DATA_ABORT_LOWER if (context.esr & ISS_WNR) != 0 => MProtect::WRITE,
+            ExceptionClass::DATA_ABORT_LOWER => MProtect::READ,
+            _ => return false,
+        };
+        match context.esr & 0x3C {
+            FSC_TRANSLATION | FSC_PERMISSION => (),
+            _ => return false,
+        }
+        let va = context.far as usize;
+        self.fault_in(va, access).is_ok()
+    }
+
+    /// Handles the request of [MemoryMapRequest::User] and returns the mapped address.
+    ///
+    /// A base address of zero lets the kernel choose the address.
+    pub fn mmap(&mut self, request: MemoryMapRequest) -> Result<usize, ProcessError> {
+        let (base, len, prot) = match request {
+            MemoryMapRequest::User(base, len, prot) => (base, len, prot),
+            _ => return Err(ProcessError::InvalidArgument),
+        };
+        if len == 0 {
+            return Err(ProcessError::InvalidArgument);
+        }
+        if base != 0 {
+            self.map_anonymous(base, len, prot)?;
+            return Ok(base);
+        }
+        let page_mask = AddressSpace::PAGE_SIZE - 1;
+        let len = len
+            .checked_add(page_mask)
+            .ok_or(ProcessError::InvalidArgument)?
+            & !page_mask;
+        let base = self.find_mmap_area(len)?;
+        self.map_anonymous(base, len, prot)?;
+        // leave a guard page between mappings
+        self.mmap_next = base + len + AddressSpace::PAGE_SIZE;
+        Ok(base)
+    }
+
+    /// Finds free space of `len` bytes followed by a guard page from `mmap_next`,
+    /// skipping the regions mapped there at fixed addresses.
+    fn find_mmap_area(&self, len: usize) -> Result<usize, ProcessError> {
+        let mut base = self.mmap_next;
+        loop {
+            if Self::MMAP_END.saturating_sub(base) < len.saturating_add(AddressSpace::PAGE_SIZE) {
+                return Err(ProcessError::OutOfMemory);
+            }
+            let end = base + len + AddressSpace::PAGE_SIZE;
+            match self
+                .regions
+                .iter()
+                .filter(|v| v.base < end && base < v.end())
+                .map(|v| v.end())
+                .max()
+            {
+                Some(next) => base = next + AddressSpace::PAGE_SIZE,
+                None => return Ok(base),
+            }
+        }
+    }
+
+    /// Creates a thread running `entry(arg)` on a new stack, and returns its identifier.
+    pub fn create_thread(
+        &mut self,
+        entry: usize,
+        arg: usize,
+        stack_size: usize,
+    ) -> Result<ThreadId, ProcessError> {
+        if !AddressSpace::is_user_range(entry, 4) {
+            return Err(ProcessError::InvalidAddress);
+        }
+        if self.threads.len() >= Self::MAX_THREADS {
+            return Err(ProcessError::TooManyThreads);
+        }
+        let stack_size = if stack_size == 0 {
+            Self::STACK_SIZE
+        } else {
+            stack_size
+        };
+        let stack = self.mmap(MemoryMapRequest::User(0, stack_size, MProtect::READ_WRITE))?;
+        let page_mask = AddressSpace::PAGE_SIZE - 1;
+        let stack_size = (stack_size + page_mask) & !page_mask;
+        let mut context = TrapFrame::new_user(entry, stack + stack_size);
+        context.x[0] = arg as u64;
+        self.add_thread(context, Some((stack, stack_size)))
+            .map_err(|err| {
+                let _ = self.unmap(stack, stack_size);
+                err
+            })
+    }
+
+    fn add_thread(
+        &mut self,
+        context: TrapFrame,
+        stack: Option<(usize, usize)>,
+    ) -> Result<ThreadId, ProcessError> {
+        let tid = ThreadId(self.next_tid);
+        self.next_tid += 1;
+        self.threads
+            .try_reserve(1)
+            .map_err(|_| ProcessError::OutOfMemory)?;
+        self.threads.push(Thread {
+            tid,
+            context,
+            wake_at: Duration::ZERO,
+            stack,
+        });
+        self.publish_threads();
+        Ok(tid)
+    }
+
+    /// Returns the identifier of the running thread.
+    #[inline]
+    pub fn current_thread(&self) -> ThreadId {
+        self.threads[self.current].tid
+    }
+
+    #[inline]
+    fn context_mut(&mut self) -> &mut TrapFrame {
+        &mut self.threads[self.current].context
+    }
+
+    /// Terminates the running thread.
+    fn exit_thread(&mut self) {
+        let thread = self.threads.remove(self.current);
+        if let Some((base, len)) = thread.stack {
+            let _ = self.unmap(base, len);
+        }
+        if self.current >= self.threads.len() {
+            self.current = 0;
+        }
+        self.slice_end = Duration::ZERO;
+        self.publish_threads();
+    }
+
+    /// Lets the next thread run, after the running one sleeps for `duration` if not zero.
+    fn yield_thread(&mut self, duration: Duration) {
+        if duration > Duration::ZERO {
+            self.threads[self.current].wake_at = GenericTimer::monotonic() + duration;
+        }
+        self.current = (self.current + 1) % self.threads.len();
+        self.slice_end = Duration::ZERO;
+    }
+
+    /// Selects the first runnable thread from the current one in round robin.
+    fn next_runnable(&mut self) -> Option<usize> {
+        let now = GenericTimer::monotonic();
+        let len = self.threads.len();
+        let index = (0..len)
+            .map(|offset| (self.current + offset) % len)
+            .find(|&index| self.threads[index].wake_at <= now)?;
+        self.threads[index].wake_at = Duration::ZERO;
+        Some(index)
+    }
+
+    /// Returns the processes running on any core.
+    pub fn running() -> Vec<ProcessInfo> {
+        RUNNING.lock().clone()
+    }
+
+    /// Updates the threads of the process in the list of [Process::running].
+    fn publish_threads(&self) {
+        let mut running = RUNNING.lock();
+        if let Some(info) = running.iter_mut().find(|v| v.pid == self.pid) {
+            info.threads.clear();
+            if info.threads.try_reserve(self.threads.len()).is_ok() {
+                info.threads.extend(self.threads.iter().map(|v| v.tid));
+            }
+        }
+    }
+
+    /// Runs `child` on the current core until it exits, and resumes this process.
+    fn run_child(&mut self, child: &mut Process) -> ExitStatus {
+        let status = child.run();
+        unsafe {
+            self.space.activate();
+            GenericTimer::set_irq_enabled(true);
+        }
+        status
+    }
+
+    /// Polls the console, and returns whether Ctrl-C was pressed.
+    fn is_interrupted() -> bool {
+        let stdin = System::stdin();
+        stdin.poll();
+        stdin.take_signal() == Some(TtySignal::Interrupt)
+    }
+
+    /// Runs the process on the current core until it exits or is killed.
+    pub fn run(&mut self) -> ExitStatus {
+        let time_slice = Duration::from_millis(PROC_TIME_SLICE.get() as u64);
+        {
+            let mut running = RUNNING.lock();
+            if running.try_reserve(1).is_ok() {
+                running.push(ProcessInfo {
+                    pid: self.pid,
+                    name: self.name.clone(),
+                    cpu: Cpu::current().id(),
+                    threads: Vec::new(),
+                });
+            }
+        }
+        self.publish_threads();
+        unsafe {
+            self.space.activate();
+            GenericTimer::set_irq_enabled(true);
+        }
+        let status = loop {
+            if self.threads.is_empty() {
+                break ExitStatus::Exited(0);
+            }
+            let index = match self.next_runnable() {
+                Some(index) => index,
+                None => {
+                    if Self::is_interrupted() {
+                        break ExitStatus::Interrupted;
+                    }
+                    let now = GenericTimer::monotonic();
+                    let wake_at = self.threads.iter().map(|v| v.wake_at).min().unwrap_or(now);
+                    GenericTimer::wait(wake_at.saturating_sub(now).min(TICK));
+                    continue;
+                }
+            };
+            if index != self.current || self.slice_end.is_zero() {
+                self.current = index;
+                self.slice_end = GenericTimer::monotonic() + time_slice;
+            }
+            match unsafe { exception::run_user(self.context_mut(), TICK.min(time_slice)) } {
+                UserExit::Syscall => {
+                    if let Some(status) = syscall::dispatch(self) {
+                        break status;
+                    }
+                    if System::stdin().take_signal() == Some(TtySignal::Interrupt) {
+                        break ExitStatus::Interrupted;
+                    }
+                }
+                UserExit::Tick => {
+                    if Self::is_interrupted() {
+                        break ExitStatus::Interrupted;
+                    }
+                    if GenericTimer::monotonic() >= self.slice_end {
+                        self.yield_thread(Duration::ZERO);
+                    }
+                }
+                UserExit::Fault => {
+                    if self.handle_page_fault() {
+                        continue;
+                    }
+                    let context = &self.threads[self.current].context;
+                    break ExitStatus::Killed(Fault {
+                        class: context.exception_class(),
+                        esr: context.esr,
+                        far: context.far,
+                        pc: context.elr,
+                    });
+                }
+            }
+        };
+        unsafe {
+            GenericTimer::set_irq_enabled(false);
+            AddressSpace::deactivate();
+        }
+        RUNNING.lock().retain(|v| v.pid != self.pid);
+        status
+    }
+}
@@ -0,0 +1,209 @@
+//! System calls from user processes
+//!
+//! The ABI is defined in [rydia_rt::abi].
+
+use super::{ExitStatus, Process, ProcessError, ProcessId};
+use crate::{
+    arch::timer::GenericTimer,
+    drawing::*,
+    io::tty::{Tty, TtyError},
+    mem::{MProtect, MemoryMapRequest},
+    system::System,
+};
+use alloc::vec::Vec;
+use core::time::Duration;
+pub use rydia_rt::abi::{Errno, Handle, Syscall};
+
+impl From<ProcessError> for Errno {
+    #[inline]
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::OutOfMemory | ProcessError::MemoryLimit => Self::NoMemory,
+            ProcessError::TooManyProcesses | ProcessError::TooManyThreads => Self::Again,
+            ProcessError::InvalidAddress | ProcessError::NotMapped => Self::Fault,
+            ProcessError::InvalidArgument
+            | ProcessError::InvalidExecutable
+            | ProcessError::AlreadyMapped => Self::InvalidArgument,
+        }
+    }
+}
+
+/// Size of the buffer used to copy data from and to user memory
+const BUFFER_SIZE: usize = 256;
+
+/// Handles the system call of the running thread, and returns the exit status if the
+/// process terminates.
+pub(super) fn dispatch(process: &mut Process) -> Option<ExitStatus> {
+    let context = process.context_mut();
+    let syscall = Syscall::from_usize(context.syscall_number());
+    let args = [
+        context.syscall_arg(0),
+        context.syscall_arg(1),
+        context.syscall_arg(2),
+        context.syscall_arg(3),
+        context.syscall_arg(4),
+        context.syscall_arg(5),
+    ];
+
+    let result = match syscall {
+        Some(Syscall::Exit) => return Some(ExitStatus::Exited(args[0] as i32)),
+        Some(Syscall::ThreadExit) => {
+            process.exit_thread();
+            return None;
+        }
+        Some(Syscall::Write) => write(process, Handle(args[0]), args[1], args[2]),
+        Some(Syscall::Read) => read(process, Handle(args[0]), args[1], args[2]),
+        Some(Syscall::Mmap) => process
+            .mmap(MemoryMapRequest::User(
+                args[0],
+                args[1],
+                MProtect::from_bits_truncate(args[2]),
+            ))
+            .map_err(Errno::from),
+        Some(Syscall::Munmap) => process
+            .unmap(args[0], args[1])
+            .map(|_| 0)
+            .map_err(Errno::from),
+        Some(Syscall::Sleep) | Some(Syscall::Yield) => Ok(0),
+        Some(Syscall::Time) => Ok(GenericTimer::monotonic().as_nanos() as usize),
+        Some(Syscall::ThreadCreate) => process
+            .create_thread(args[0], args[1], args[2])
+            .map(|v| v.0)
+            .map_err(Errno::from),
+        Some(Syscall::FbInfo) => fb_info(),
+        Some(Syscall::FbBlt) => fb_blt(
+            process,
+            args[0] as isize,
+            args[1] as isize,
+            args[2],
+            args[3],
+            args[4],
+            args[5],
+        ),
+        Some(Syscall::Fork) => match fork(process) {
+            // Ctrl-C stops the parent with the child.
+            Ok((_, ExitStatus::Interrupted)) => return Some(ExitStatus::Interrupted),
+            Ok((pid, _)) => Ok(pid.0),
+            Err(err) => Err(err),
+        },
+        None => Err(Errno::NoSys),
+    };
+
+    process.context_mut().x[0] = Errno::raw(result) as u64;
+    match syscall {
+        Some(Syscall::Sleep) => process.yield_thread(Duration::from_nanos(args[0] as u64)),
+        Some(Syscall::Yield) => process.yield_thread(Duration::ZERO),
+        _ => (),
+    }
+    None
+}
+
+/// Runs a copy of the process until it exits, and returns its identifier and exit status.
+fn fork(process: &mut Process) -> Result<(ProcessId, ExitStatus), Errno> {
+    let mut child = process.fork()?;
+    child.context_mut().set_syscall_result(0);
+    let status = process.run_child(&mut child);
+    Ok((child.pid(), status))
+}
+
+fn write(process: &mut Process, handle: Handle, buf: usize, len: usize) -> Result<usize, Errno> {
+    let stdout = match handle {
+        Handle::STDOUT => true,
+        Handle::STDERR => false,
+        _ => return Err(Errno::BadHandle),
+    };
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = &mut buffer[..(len - offset).min(BUFFER_SIZE)];
+        process
+            .read_memory(buf.wrapping_add(offset), chunk)
+            .map_err(|_| Errno::Fault)?;
+        // Ctrl-C while the process writes also stops it.
+        let console = Tty::console();
+        console.poll();
+        console.write(chunk);
+        if stdout {
+            if let Some(tty) = Tty::get(Tty::SCREEN) {
+                tty.write(chunk);
+            }
+        }
+        offset += chunk.len();
+    }
+    Ok(len)
+}
+
+fn read(process: &mut Process, handle: Handle, buf: usize, len: usize) -> Result<usize, Errno> {
+    if handle != Handle::STDIN {
+        return Err(Errno::BadHandle);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+    let mut buffer = [0u8; BUFFER_SIZE];
+    let count = System::stdin()
+        .read(&mut buffer[..len.min(BUFFER_SIZE)])
+        .map_err(|err| match err {
+            TtyError::Interrupted => Errno::Interrupted,
+            TtyError::NotSupported | TtyError::NotReady => Errno::NoDevice,
+        })?;
+    process
+        .write_memory(buf, &buffer[..count])
+        .map_err(|_| Errno::Fault)?;
+    Ok(count)
+}
+
+fn fb_info() -> Result<usize, Errno> {
+    let screen = System::main_screen().ok_or(Errno::NoDevice)?;
+    let size = screen.size();
+    Ok(((size.width as usize) << 32) | (size.height as usize))
+}
+
+fn fb_blt(
+    process: &mut Process,
+    x: isize,
+    y: isize,
+    width: usize,
+    height: usize,
+    buf: usize,
+    stride: usize,
+) -> Result<usize, Errno> {
+    let mut screen = System::main_screen().ok_or(Errno::NoDevice)?;
+    let bitmap = match screen {
+        Bitmap::Argb32(ref mut v) => v,
+        Bitmap::Indexed(_) => return Err(Errno::NoDevice),
+    };
+    let size = bitmap.size();
+    if width == 0 || height == 0 {
+        return Ok(0);
+    }
+    if stride < width {
+        return Err(Errno::InvalidArgument);
+    }
+    // columns beyond the width of the screen can never be visible
+    let width = width.min(size.width as usize);
+
+    let mut row = Vec::new();
+    row.try_reserve_exact(width).map_err(|_| Errno::NoMemory)?;
+    row.resize(width, 0u32);
+    for line in 0..height {
+        let dy = y.saturating_add(line as isize);
+        if dy < 0 {
+            continue;
+        } else if dy >= size.height {
+            break;
+        }
+        let offset = line
+            .checked_mul(stride)
+            .and_then(|v| v.checked_mul(4))
+            .ok_or(Errno::InvalidArgument)?;
+        let bytes =
+            unsafe { core::slice::from_raw_parts_mut(row.as_mut_ptr() as *mut u8, width * 4) };
+        process
+            .read_memory(buf.wrapping_add(offset), bytes)
+            .map_err(|_| Errno::Fault)?;
+        let src = ConstBitmap32::from_bytes(&row, Size::new(width as isize, 1));
+        bitmap.blt(&src, Point::new(x, dy), Rect::new(0, 0, width as isize, 1));
+    }
+    Ok(0)
+}
@@ -0,0 +1,247 @@
+//! Random numbers
+//!
+//! The kernel CSPRNG generates ChaCha20 keystream with a key into which the hardware
+//! [EntropySource]s and the jitter of the timer are mixed. The key is replaced after
+//! every request, so that the state does not reveal the earlier output.
+//! [Random::fill_bytes] waits until 256 bits of entropy have been collected from the
+//! hardware sources. The jitter is mixed in without being credited, as its entropy on a
+//! virtual machine is unknown, so the output needs at least one hardware source.
+
+use crate::{
+    arch::timer::GenericTimer,
+    mem::fixedvec::FixedVec,
+    shell::{ShellCommand, ShellError},
+    sync::spinlock::SpinMutex,
+};
+use core::{fmt::Write, hint::black_box};
+
+mod chacha20;
+
+/// Maximum number of entropy sources
+const MAX_SOURCES: usize = 4;
+
+static SOURCES: SpinMutex<FixedVec<Option<&'static dyn EntropySource>, MAX_SOURCES>> =
+    SpinMutex::new(FixedVec::new(None));
+
+static STATE: SpinMutex<RandomState> = SpinMutex::new(RandomState::new());
+
+/// A hardware random number generator
+pub trait EntropySource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Fills `buf` with random bytes as far as available, and returns the length filled.
+    fn read_entropy(&self, buf: &mut [u8]) -> usize;
+}
+
+struct RandomState {
+    key: [u32; 8],
+    /// Bits of entropy mixed into the key, up to [Random::SEED_BITS]
+    entropy: usize,
+    /// Bytes generated since the last reseed
+    output: usize,
+}
+
+impl RandomState {
+    const fn new() -> Self {
+        Self {
+            key: [0; 8],
+            entropy: 0,
+            output: 0,
+        }
+    }
+}
+
+pub struct Random;
+
+impl Random {
+    /// Entropy needed before any output
+    const SEED_BITS: usize = 256;
+    /// Bytes read from each source at a reseed
+    const SEED_BYTES: usize = 32;
+    /// Bytes generated until the sources are read again
+    const RESEED_BYTES: usize = 0x10_0000;
+    /// Maximum bytes generated under the lock with the same key
+    const CHUNK_BYTES: usize = 256;
+
+    const JITTER_SAMPLES: usize = 256;
+
+    /// ChaCha20 nonces to separate the output from the mixing of the key
+    const NONCE_OUTPUT: u64 = 0;
+    const NONCE_MIX: u64 = 1;
+
+    /// Registers an entropy source, and mixes its output into the key.
+    pub fn add_source(source: &'static dyn EntropySource) -> Result<(), ()> {
+        SOURCES.lock().push(Some(source)).map_err(|_| ())?;
+        crate::info!("random: {}", source.name());
+        Self::read_source(source);
+        Ok(())
+    }
+
+    /// Mixes `data` into the key, crediting it with `bits` of entropy.
+    pub fn add_entropy(data: &[u8], bits: usize) {
+        let seeded = {
+            let mut state = STATE.lock();
+            let was_seeded = state.entropy >= Self::SEED_BITS;
+            for chunk in data.chunks(32) {
+                let mut bytes = [0u8; 32];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                for (key, word) in state.key.iter_mut().zip(bytes.chunks_exact(4)) {
+                    *key ^= u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+                }
+                let block = chacha20::block(&state.key, 0, Self::NONCE_MIX);
+                state.key.copy_from_slice(&block[..8]);
+            }
+            state.entropy = (state.entropy + bits).min(Self::SEED_BITS);
+            !was_seeded && state.entropy >= Self::SEED_BITS
+        };
+        if seeded {
+            crate::info!("random: seeded");
+        }
+    }
+
+    /// Returns true once enough entropy has been collected for the output.
+    #[inline]
+    pub fn is_seeded() -> bool {
+        STATE.lock().entropy >= Self::SEED_BITS
+    }
+
+    /// Mixes new output of the entropy sources and the timer jitter into the key.
+    pub fn reseed() {
+        let mut sources = [None; MAX_SOURCES];
+        for (slot, source) in sources.iter_mut().zip(SOURCES.lock().iter()) {
+            *slot = *source;
+        }
+        for source in sources.into_iter().flatten() {
+            Self::read_source(source);
+        }
+        Self::add_jitter();
+        STATE.lock().output = 0;
+    }
+
+    /// Fills `buf` with random bytes, waiting until seeded.
+    ///
+    /// Never returns if no hardware [EntropySource] is registered.
+    pub fn fill_bytes(buf: &mut [u8]) {
+        while !Self::is_seeded() {
+            Self::reseed();
+        }
+        let _ = Self::try_fill_bytes(buf);
+    }
+
+    /// Fills `buf` with random bytes, or returns an error if not seeded yet, which is
+    /// always the case without a hardware [EntropySource].
+    pub fn try_fill_bytes(buf: &mut [u8]) -> Result<(), ()> {
+        let output = {
+            let state = STATE.lock();
+            if state.entropy < Self::SEED_BITS {
+                return Err(());
+            }
+            state.output
+        };
+        if output >= Self::RESEED_BYTES {
+            Self::reseed();
+        }
+
+        for chunk in buf.chunks_mut(Self::CHUNK_BYTES) {
+            let mut state = STATE.lock();
+            let mut counter = 0;
+            for bytes in chunk.chunks_mut(64) {
+                let block = chacha20::block(&state.key, counter, Self::NONCE_OUTPUT);
+                counter += 1;
+                for (bytes, word) in bytes.chunks_mut(4).zip(block) {
+                    bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+                }
+            }
+            let block = chacha20::block(&state.key, counter, Self::NONCE_OUTPUT);
+            state.key.copy_from_slice(&block[..8]);
+            state.output += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Returns a random `u64`, waiting until seeded like [Random::fill_bytes].
+    #[inline]
+    pub fn next_u64() -> u64 {
+        let mut bytes = [0u8; 8];
+        Self::fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn read_source(source: &dyn EntropySource) {
+        let mut buf = [0u8; Self::SEED_BYTES];
+        let len = source.read_entropy(&mut buf).min(buf.len());
+        Self::add_entropy(&buf[..len], len * 8);
+    }
+
+    /// Mixes the timing of a loop whose length depends on the previous timing, with no
+    /// entropy credited.
+    fn add_jitter() {
+        let mut samples = [0u8; Self::JITTER_SAMPLES];
+        let mut last = GenericTimer::counter();
+        let mut acc = last;
+        for (index, sample) in samples.iter_mut().enumerate() {
+            for _ in 0..(acc as usize & 0x3F) + index % 7 {
+                acc = black_box(acc.rotate_left(7) ^ last);
+            }
+            let now = GenericTimer::counter();
+            let delta = now.wrapping_sub(last);
+            *sample = (delta ^ (delta >> 8)) as u8;
+            acc ^= delta;
+            last = now;
+        }
+        Self::add_entropy(&samples, 0);
+    }
+}
+
+struct RandomCommand;
+
+impl ShellCommand for RandomCommand {
+    fn name(&self) -> &'static str {
+        "random"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[bytes]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the entropy sources or generate random bytes"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        match args {
+            [] => {
+                let entropy = STATE.lock().entropy;
+                writeln!(out, "entropy: {}/{} bits", entropy, Random::SEED_BITS)?;
+                for source in SOURCES.lock().iter().flatten() {
+                    writeln!(out, "source:  {}", source.name())?;
+                }
+                Ok(())
+            }
+            [len] => {
+                let len = len
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|v| *v <= 256)
+                    .ok_or(ShellError::InvalidArgument)?;
+                let mut buf = [0u8; 256];
+                // Waiting would never end without a hardware source.
+                Random::try_fill_bytes(&mut buf[..len]).map_err(|_| ShellError::NotSupported)?;
+                for (index, byte) in buf[..len].iter().enumerate() {
+                    let sep = if index % 16 == 15 || index == len - 1 {
+                        '\n'
+                    } else {
+                        ' '
+                    };
+                    write!(out, "{:02x}{}", byte, sep)?;
+                }
+                Ok(())
+            }
+            _ => Err(ShellError::Usage),
+        }
+    }
+}
+
+crate::shell_command! {
+    static RANDOM: RandomCommand = RandomCommand;
+}
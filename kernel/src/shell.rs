@@ -0,0 +1,221 @@
+//! Kernel debug shell
+//!
+//! A monitor on a [Tty]. Commands are declared by each module with [shell_command!] and
+//! collected by the linker into the `.kcmd` section, so that drivers can add their own.
+
+use crate::{
+    arch::cpu::Cpu,
+    io::tty::{Tty, TtyError},
+    system::System,
+};
+use alloc::vec::Vec;
+use core::{
+    fmt::{self, Write},
+    slice, str,
+};
+
+/// Declares a command and registers it to the command table of the shell.
+///
+/// ```ignore
+/// crate::shell_command! {
+///     static REBOOT: RebootCommand = RebootCommand;
+/// }
+/// ```
+#[macro_export]
+macro_rules! shell_command {
+    ( $vis:vis static $name:ident : $ty:ty = $init:expr ; ) => {
+        $vis static $name: $ty = $init;
+
+        const _: () = {
+            #[used]
+            #[link_section = ".kcmd"]
+            static ENTRY: $crate::shell::CommandEntry = $crate::shell::CommandEntry(&$name);
+        };
+    };
+}
+
+/// Common interface of shell commands
+pub trait ShellCommand: Sync {
+    /// Returns the name typed on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Returns the arguments, e.g. `<addr> [len]`.
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    /// Returns the description shown by `help`.
+    fn help(&self) -> &'static str;
+
+    /// Runs the command with the arguments after its name.
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError>;
+}
+
+/// An entry of the command table placed in the `.kcmd` section
+#[repr(transparent)]
+pub struct CommandEntry(pub &'static dyn ShellCommand);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    /// The arguments do not match the usage.
+    Usage,
+    InvalidArgument,
+    NotSupported,
+    /// The device did not respond or reported an error.
+    DeviceError,
+    WriteError,
+}
+
+impl From<fmt::Error> for ShellError {
+    #[inline]
+    fn from(_: fmt::Error) -> Self {
+        Self::WriteError
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage => write!(f, "invalid usage"),
+            Self::InvalidArgument => write!(f, "invalid argument"),
+            Self::NotSupported => write!(f, "not supported on this machine"),
+            Self::DeviceError => write!(f, "device error"),
+            Self::WriteError => write!(f, "write error"),
+        }
+    }
+}
+
+/// Returns all registered commands.
+pub fn commands() -> impl Iterator<Item = &'static dyn ShellCommand> {
+    extern "C" {
+        static __kcmd_start: u8;
+        static __kcmd_end: u8;
+    }
+    unsafe {
+        let start = &__kcmd_start as *const _ as *const CommandEntry;
+        let end = &__kcmd_end as *const _ as *const CommandEntry;
+        let len = end.offset_from(start) as usize;
+        slice::from_raw_parts(start, len).iter().map(|v| v.0)
+    }
+}
+
+/// Finds the command with the specified name.
+pub fn find(name: &str) -> Option<&'static dyn ShellCommand> {
+    commands().find(|v| v.name() == name)
+}
+
+/// Parses a number, which is hexadecimal with the `0x` prefix.
+pub fn parse_number(s: &str) -> Result<u64, ShellError> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| ShellError::InvalidArgument)
+}
+
+pub struct Shell;
+
+impl Shell {
+    /// Maximum number of words on a command line
+    const MAX_ARGS: usize = 16;
+
+    /// Runs the shell on `tty`.
+    pub fn run(tty: &'static Tty) -> ! {
+        let mut out = tty;
+        let mut line = [0u8; 256];
+        let _ = writeln!(out, "Type `help` for the list of commands.");
+        loop {
+            let _ = write!(out, "{}> ", System::short_name());
+            let len = loop {
+                System::em_console().blink();
+                match tty.try_read(&mut line) {
+                    Ok(0) => {
+                        // Ctrl-D on an empty line
+                        let _ = writeln!(out);
+                        break 0;
+                    }
+                    Ok(len) => break len,
+                    Err(TtyError::Interrupted) => {
+                        tty.take_signal();
+                        break 0;
+                    }
+                    Err(_) => Cpu::spin_loop_hint(),
+                }
+            };
+            match str::from_utf8(&line[..len]) {
+                Ok(line) => Self::execute(line, &mut out),
+                Err(_) => {
+                    let _ = writeln!(out, "invalid input");
+                }
+            }
+        }
+    }
+
+    /// Runs a command line.
+    pub fn execute(line: &str, out: &mut dyn Write) {
+        let mut args = [""; Self::MAX_ARGS];
+        let mut argc = 0;
+        for word in line.split_ascii_whitespace() {
+            if argc == Self::MAX_ARGS {
+                let _ = writeln!(out, "too many arguments");
+                return;
+            }
+            args[argc] = word;
+            argc += 1;
+        }
+        if argc == 0 {
+            return;
+        }
+
+        let Some(command) = find(args[0]) else {
+            let _ = writeln!(out, "{}: command not found", args[0]);
+            return;
+        };
+        let _ = match command.run(&args[1..argc], out) {
+            Ok(()) => Ok(()),
+            Err(ShellError::Usage) => {
+                writeln!(out, "usage: {} {}", command.name(), command.usage())
+            }
+            Err(err) => writeln!(out, "{}: {}", command.name(), err),
+        };
+    }
+}
+
+struct HelpCommand;
+
+impl ShellCommand for HelpCommand {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[command]"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the commands, or the usage of a command"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        match args {
+            [] => {
+                let mut commands = commands().collect::<Vec<_>>();
+                commands.sort_unstable_by_key(|v| v.name());
+                for command in commands {
+                    writeln!(out, "  {:<10} {}", command.name(), command.help())?;
+                }
+            }
+            [name] => {
+                let command = find(name).ok_or(ShellError::InvalidArgument)?;
+                writeln!(out, "usage: {} {}", command.name(), command.usage())?;
+                writeln!(out, "  {}", command.help())?;
+            }
+            _ => return Err(ShellError::Usage),
+        }
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static HELP: HelpCommand = HelpCommand;
+}
@@ -0,0 +1,47 @@
+//! Bounded multi-producer multi-consumer channel
+//!
+//! The channel is built in `rydia_sync`, where its interleavings are tested on the host.
+//! There is no scheduler in the kernel yet, so a blocking call parks the core until
+//! another core signals an event.
+
+use crate::arch::{cpu::Cpu, timer::GenericTimer};
+use rydia_sync::channel::Park;
+
+pub use rydia_sync::channel::{RecvError, SendError};
+
+/// The sending side of a [channel]
+pub type Sender<T> = rydia_sync::channel::Sender<T, CpuPark>;
+
+/// The receiving side of a [channel]
+pub type Receiver<T> = rydia_sync::channel::Receiver<T, CpuPark>;
+
+/// Creates a channel that holds at least `capacity` values.
+#[inline]
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    rydia_sync::channel::channel(capacity)
+}
+
+/// Parks the core until another core signals an event
+pub struct CpuPark;
+
+impl Park for CpuPark {
+    #[inline]
+    fn park() {
+        Cpu::wait_for_event();
+    }
+
+    #[inline]
+    fn unpark() {
+        Cpu::send_event();
+    }
+
+    #[inline]
+    fn counter() -> u64 {
+        GenericTimer::counter()
+    }
+
+    #[inline]
+    fn frequency() -> u64 {
+        GenericTimer::frequency()
+    }
+}
@@ -0,0 +1,574 @@
+//! Lock validator
+//!
+//! Enabled with `lock.debug`. Each lock belongs to the class of the place where it was
+//! created, and the validator records which classes were held while another one was
+//! acquired. Acquiring in the opposite order of a recorded path, locking a lock that the
+//! core already holds, and spinning longer than `lock.timeout` are reported to the
+//! kernel log with the call sites involved. Contention and hold times are counted per class
+//! and written by [LockDep::write_stats].
+
+use crate::{
+    arch::{cpu::Cpu, timer::GenericTimer},
+    log::Level,
+    param::{BoolParam, UintParam},
+    shell::{ShellCommand, ShellError},
+};
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    panic::Location,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+crate::kernel_param! {
+    static LOCK_DEBUG: BoolParam = BoolParam::new(
+        "lock.debug",
+        false,
+        "Validate the order of locks and collect lock statistics",
+    );
+}
+
+crate::kernel_param! {
+    static LOCK_TIMEOUT: UintParam = UintParam::new(
+        "lock.timeout",
+        1000,
+        1,
+        60_000,
+        "Milliseconds of spinning on a lock before it is reported",
+    );
+}
+
+/// Maximum number of lock classes
+const MAX_CLASSES: usize = 256;
+/// Maximum number of recorded orders between two classes
+const MAX_EDGES: usize = 1024;
+/// Maximum number of locks a core holds at once
+const MAX_HELD: usize = 16;
+
+const BITMAP_WORDS: usize = MAX_CLASSES / 64;
+
+static CLASSES: [LockClass; MAX_CLASSES] = [LockClass::INIT; MAX_CLASSES];
+static EDGES: [Edge; MAX_EDGES] = [Edge::INIT; MAX_EDGES];
+/// `DEPENDS[a]` has the bit of `b` if `b` was acquired while `a` was held
+static DEPENDS: [[AtomicU64; BITMAP_WORDS]; MAX_CLASSES] = [LockClass::NO_BITS; MAX_CLASSES];
+/// Pairs already reported, in the same layout as `DEPENDS`
+static REPORTED: [[AtomicU64; BITMAP_WORDS]; MAX_CLASSES] = [LockClass::NO_BITS; MAX_CLASSES];
+
+crate::percpu! {
+    static HELD: RefCell<HeldLocks> = RefCell::new(HeldLocks::new());
+}
+
+/// State of the validator embedded in each lock
+pub struct LockDepMap {
+    /// Where the lock was created, which identifies its class
+    site: &'static Location<'static>,
+    /// Index of the class plus one, or zero if not looked up yet
+    class: AtomicUsize,
+    /// Where the holder acquired the lock
+    holder_site: AtomicUsize,
+    /// Logical id of the core holding the lock plus one
+    holder_cpu: AtomicUsize,
+}
+
+impl LockDepMap {
+    /// Uses the caller as the class of the lock.
+    #[inline]
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            site: Location::caller(),
+            class: AtomicUsize::new(0),
+            holder_site: AtomicUsize::new(0),
+            holder_cpu: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns where the lock was created.
+    #[inline]
+    pub const fn site(&self) -> &'static Location<'static> {
+        self.site
+    }
+
+    fn class(&self) -> Option<usize> {
+        match self.class.load(Ordering::Relaxed) {
+            0 => {
+                let class = LockClass::lookup(self.site)?;
+                self.class.store(class + 1, Ordering::Relaxed);
+                Some(class)
+            }
+            class => Some(class - 1),
+        }
+    }
+
+    #[inline]
+    fn key(&self) -> usize {
+        self as *const _ as usize
+    }
+}
+
+impl Default for LockDepMap {
+    #[inline]
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics of the locks created at one place
+struct LockClass {
+    site: AtomicUsize,
+    acquisitions: AtomicU64,
+    contentions: AtomicU64,
+    wait_total: AtomicU64,
+    wait_max: AtomicU64,
+    hold_total: AtomicU64,
+    hold_max: AtomicU64,
+}
+
+impl LockClass {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        site: AtomicUsize::new(0),
+        acquisitions: AtomicU64::new(0),
+        contentions: AtomicU64::new(0),
+        wait_total: AtomicU64::new(0),
+        wait_max: AtomicU64::new(0),
+        hold_total: AtomicU64::new(0),
+        hold_max: AtomicU64::new(0),
+    };
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NO_BITS: [AtomicU64; BITMAP_WORDS] = [Self::ZERO; BITMAP_WORDS];
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+
+    /// Returns the index of the class of `site`, adding it if needed.
+    ///
+    /// Classes are added in order, so the first empty slot ends the search.
+    fn lookup(site: &'static Location<'static>) -> Option<usize> {
+        let key = site as *const _ as usize;
+        for (index, class) in CLASSES.iter().enumerate() {
+            match class
+                .site
+                .compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Some(index),
+                Err(current) if current == key => return Some(index),
+                Err(_) => (),
+            }
+        }
+        None
+    }
+
+    #[inline]
+    fn site(index: usize) -> Site {
+        Site(CLASSES[index].site.load(Ordering::Acquire))
+    }
+}
+
+/// A recorded order with the call sites that made it
+struct Edge {
+    /// `from * MAX_CLASSES + to` plus one, or zero if unused
+    key: AtomicUsize,
+    from_site: AtomicUsize,
+    to_site: AtomicUsize,
+}
+
+impl Edge {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        key: AtomicUsize::new(0),
+        from_site: AtomicUsize::new(0),
+        to_site: AtomicUsize::new(0),
+    };
+
+    fn add(from: usize, to: usize, from_site: usize, to_site: usize) {
+        let key = from * MAX_CLASSES + to + 1;
+        for edge in EDGES.iter() {
+            match edge
+                .key
+                .compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    edge.from_site.store(from_site, Ordering::Relaxed);
+                    edge.to_site.store(to_site, Ordering::Release);
+                    break;
+                }
+                Err(current) if current == key => break,
+                Err(_) => (),
+            }
+        }
+        DEPENDS[from][to / 64].fetch_or(1 << (to % 64), Ordering::AcqRel);
+    }
+
+    /// Returns the call sites of the order `from` then `to`.
+    fn sites(from: usize, to: usize) -> Option<(Site, Site)> {
+        let key = from * MAX_CLASSES + to + 1;
+        EDGES
+            .iter()
+            .find(|edge| edge.key.load(Ordering::Acquire) == key)
+            .map(|edge| {
+                (
+                    Site(edge.from_site.load(Ordering::Relaxed)),
+                    Site(edge.to_site.load(Ordering::Acquire)),
+                )
+            })
+    }
+
+    #[inline]
+    fn depends(from: usize, to: usize) -> bool {
+        (DEPENDS[from][to / 64].load(Ordering::Acquire) & (1 << (to % 64))) != 0
+    }
+
+    /// Marks the pair as reported, and returns whether it was not yet.
+    #[inline]
+    fn report_once(from: usize, to: usize) -> bool {
+        let bit = 1 << (to % 64);
+        (REPORTED[from][to / 64].fetch_or(bit, Ordering::AcqRel) & bit) == 0
+    }
+
+    /// Finds a path of recorded orders from `from` to `to`, and returns its length.
+    /// `path` receives the classes on the path, ending with `to`.
+    fn find_path(from: usize, to: usize, path: &mut [u16; MAX_CLASSES]) -> Option<usize> {
+        let mut parent = [u16::MAX; MAX_CLASSES];
+        let mut queue = [0u16; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from as u16;
+        parent[from] = from as u16;
+        while head < tail {
+            let current = queue[head] as usize;
+            head += 1;
+            for next in 0..MAX_CLASSES {
+                if parent[next] != u16::MAX || !Self::depends(current, next) {
+                    continue;
+                }
+                parent[next] = current as u16;
+                if next == to {
+                    let mut len = 0;
+                    let mut class = to;
+                    while class != from {
+                        path[len] = class as u16;
+                        len += 1;
+                        class = parent[class] as usize;
+                    }
+                    path[..len].reverse();
+                    return Some(len);
+                }
+                queue[tail] = next as u16;
+                tail += 1;
+            }
+        }
+        None
+    }
+}
+
+/// A call site stored as the address of its [Location]
+#[derive(Clone, Copy)]
+struct Site(usize);
+
+impl Site {
+    #[inline]
+    fn new(location: &'static Location<'static>) -> Self {
+        Self(location as *const _ as usize)
+    }
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match unsafe { (self.0 as *const Location<'static>).as_ref() } {
+            Some(location) => write!(f, "{}", location),
+            None => write!(f, "?"),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    map: usize,
+    class: usize,
+    site: Site,
+    since: u64,
+}
+
+struct HeldLocks {
+    len: usize,
+    locks: [HeldLock; MAX_HELD],
+}
+
+impl HeldLocks {
+    const fn new() -> Self {
+        Self {
+            len: 0,
+            locks: [HeldLock {
+                map: 0,
+                class: 0,
+                site: Site(0),
+                since: 0,
+            }; MAX_HELD],
+        }
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[HeldLock] {
+        &self.locks[..self.len]
+    }
+
+    fn push(&mut self, lock: HeldLock) {
+        if self.len < MAX_HELD {
+            self.locks[self.len] = lock;
+            self.len += 1;
+        }
+    }
+
+    /// Removes `map`, which need not be the last one acquired.
+    fn remove(&mut self, map: usize) -> Option<HeldLock> {
+        let index = self.as_slice().iter().rposition(|v| v.map == map)?;
+        let lock = self.locks[index];
+        self.locks.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        Some(lock)
+    }
+}
+
+/// A problem found while the held locks were examined, reported after that
+enum Violation {
+    Recursive { class: usize, held_site: Site },
+    Inversion { held: HeldLock, class: usize },
+}
+
+pub struct LockDep;
+
+impl LockDep {
+    #[inline]
+    pub fn is_enabled() -> bool {
+        LOCK_DEBUG.get()
+    }
+
+    /// Acquires a lock, validating the order against the locks held by the current core.
+    ///
+    /// `try_lock` is tried once to tell contention apart, and then `lock` takes the lock in
+    /// the way of the lock. `lock` calls the closure it gets while it waits, which reports a
+    /// spin longer than the timeout.
+    pub fn lock<T, L>(map: &LockDepMap, site: &'static Location<'static>, try_lock: T, lock: L)
+    where
+        T: FnOnce() -> bool,
+        L: FnOnce(&mut dyn FnMut()),
+    {
+        let Some(class) = map.class() else {
+            lock(&mut || ());
+            return;
+        };
+        let site = Site::new(site);
+        Self::validate(map, class, site);
+
+        let start = GenericTimer::counter();
+        let mut waited = 0;
+        if !try_lock() {
+            let timeout = GenericTimer::frequency() * LOCK_TIMEOUT.get() as u64 / 1000;
+            let mut reported = false;
+            lock(&mut || {
+                let waited = GenericTimer::counter() - start;
+                if !reported && waited > timeout {
+                    reported = true;
+                    Self::report_timeout(map, class, site, waited);
+                }
+            });
+            waited = GenericTimer::counter() - start;
+            let stats = &CLASSES[class];
+            stats.contentions.fetch_add(1, Ordering::Relaxed);
+            stats.wait_total.fetch_add(waited, Ordering::Relaxed);
+            stats.wait_max.fetch_max(waited, Ordering::Relaxed);
+        }
+        Self::acquired(map, class, site, start + waited);
+    }
+
+    /// Records a lock acquired without waiting, which cannot deadlock.
+    pub fn try_locked(map: &LockDepMap, site: &'static Location<'static>) {
+        if let Some(class) = map.class() {
+            Self::acquired(map, class, Site::new(site), GenericTimer::counter());
+        }
+    }
+
+    /// Records the release of a lock.
+    pub fn unlock(map: &LockDepMap) {
+        map.holder_cpu.store(0, Ordering::Relaxed);
+        let Some(held) = HELD.with(|held| held.borrow_mut().remove(map.key())) else {
+            return;
+        };
+        let hold = GenericTimer::counter().saturating_sub(held.since);
+        let stats = &CLASSES[held.class];
+        stats.hold_total.fetch_add(hold, Ordering::Relaxed);
+        stats.hold_max.fetch_max(hold, Ordering::Relaxed);
+    }
+
+    fn acquired(map: &LockDepMap, class: usize, site: Site, since: u64) {
+        map.holder_site.store(site.0, Ordering::Relaxed);
+        map.holder_cpu
+            .store(Cpu::current().id() + 1, Ordering::Relaxed);
+        CLASSES[class].acquisitions.fetch_add(1, Ordering::Relaxed);
+        HELD.with(|held| {
+            held.borrow_mut().push(HeldLock {
+                map: map.key(),
+                class,
+                site,
+                since,
+            })
+        });
+    }
+
+    /// Checks the held locks, and records their order before `class`.
+    fn validate(map: &LockDepMap, class: usize, site: Site) {
+        let violation = HELD.with(|held| {
+            let mut violation = None;
+            for lock in held.borrow().as_slice() {
+                if lock.map == map.key() {
+                    violation = Some(Violation::Recursive {
+                        class,
+                        held_site: lock.site,
+                    });
+                    continue;
+                }
+                if lock.class == class || Edge::depends(lock.class, class) {
+                    continue;
+                }
+                if Edge::find_path(class, lock.class, &mut [0; MAX_CLASSES]).is_none() {
+                    Edge::add(lock.class, class, lock.site.0, site.0);
+                } else if violation.is_none() && Edge::report_once(lock.class, class) {
+                    // The order that closes the cycle is not recorded.
+                    violation = Some(Violation::Inversion { held: *lock, class });
+                }
+            }
+            violation
+        });
+
+        match violation {
+            Some(Violation::Recursive { class, held_site }) => {
+                crate::error!(
+                    "core {} locks {} again at {}",
+                    Cpu::current().id(),
+                    LockClass::site(class),
+                    site
+                );
+                crate::error!("  already held since {}", held_site);
+                Self::log_backtrace(Level::Error);
+            }
+            Some(Violation::Inversion { held, class }) => {
+                crate::error!("possible deadlock on core {}", Cpu::current().id());
+                crate::error!("  locking {} at {}", LockClass::site(class), site);
+                crate::error!(
+                    "  while holding {} locked at {}",
+                    LockClass::site(held.class),
+                    held.site
+                );
+                crate::error!("  but the opposite order was seen:");
+                let mut path = [0; MAX_CLASSES];
+                let len = Edge::find_path(class, held.class, &mut path).unwrap_or(0);
+                let mut from = class;
+                for &to in &path[..len] {
+                    let to = to as usize;
+                    if let Some((from_site, to_site)) = Edge::sites(from, to) {
+                        crate::error!(
+                            "    {} locked at {}, then {} at {}",
+                            LockClass::site(from),
+                            from_site,
+                            LockClass::site(to),
+                            to_site
+                        );
+                    }
+                    from = to;
+                }
+                Self::log_backtrace(Level::Error);
+            }
+            None => (),
+        }
+    }
+
+    fn report_timeout(map: &LockDepMap, class: usize, site: Site, waited: u64) {
+        crate::warn!(
+            "core {} spinning for {} ms on {} at {}",
+            Cpu::current().id(),
+            waited * 1000 / GenericTimer::frequency().max(1),
+            LockClass::site(class),
+            site
+        );
+        match map.holder_cpu.load(Ordering::Relaxed) {
+            0 => crate::warn!("  held by an unknown owner"),
+            cpu => crate::warn!(
+                "  held by core {} since {}",
+                cpu - 1,
+                Site(map.holder_site.load(Ordering::Relaxed))
+            ),
+        }
+        Self::log_backtrace(Level::Warn);
+    }
+
+    fn log_backtrace(level: Level) {
+        for pc in crate::arch::backtrace::Backtrace::current().skip(1) {
+            match crate::ksyms::lookup(pc) {
+                Some((name, offset)) => {
+                    crate::log!(level, "    {:016x} {}+{:#x}", pc, name, offset)
+                }
+                None => crate::log!(level, "    {:016x}", pc),
+            }
+        }
+    }
+
+    /// Writes the statistics of each lock class, in microseconds.
+    pub fn write_stats<W: Write + ?Sized>(w: &mut W) -> fmt::Result {
+        if !Self::is_enabled() {
+            return writeln!(w, "lockdep: disabled, boot with lock.debug=on");
+        }
+        let freq = GenericTimer::frequency().max(1);
+        let us = |ticks: u64| ticks * 1_000_000 / freq;
+        writeln!(
+            w,
+            "  acquired contended wait_max wait_avg hold_max hold_avg class"
+        )?;
+        for (index, stats) in CLASSES.iter().enumerate() {
+            let site = LockClass::site(index);
+            if site.0 == 0 {
+                break;
+            }
+            let acquisitions = stats.acquisitions.load(Ordering::Relaxed);
+            let contentions = stats.contentions.load(Ordering::Relaxed);
+            let wait_total = stats.wait_total.load(Ordering::Relaxed);
+            let hold_total = stats.hold_total.load(Ordering::Relaxed);
+            writeln!(
+                w,
+                "{:>10} {:>9} {:>8} {:>8} {:>8} {:>8} {}",
+                acquisitions,
+                contentions,
+                us(stats.wait_max.load(Ordering::Relaxed)),
+                us(wait_total.checked_div(contentions).unwrap_or(0)),
+                us(stats.hold_max.load(Ordering::Relaxed)),
+                us(hold_total.checked_div(acquisitions).unwrap_or(0)),
+                site
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct LockStatCommand;
+
+impl ShellCommand for LockStatCommand {
+    fn name(&self) -> &'static str {
+        "lockstat"
+    }
+
+    fn help(&self) -> &'static str {
+        "Show the statistics of the lock validator"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), ShellError> {
+        if !args.is_empty() {
+            return Err(ShellError::Usage);
+        }
+        LockDep::write_stats(out)?;
+        Ok(())
+    }
+}
+
+crate::shell_command! {
+    static LOCKSTAT: LockStatCommand = LockStatCommand;
+}
@@ -1,6 +1,8 @@
 //! Classes to synchronize
 
+pub mod channel;
 pub mod fifo;
+pub mod lockdep;
 pub mod spinlock;
 
 pub mod atomic {
@@ -12,6 +14,8 @@ pub mod atomic {
     pub use atomicfloat::*;
 }
 
+pub use rydia_sync::deque;
+
 use core::fmt;
 
 pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
@@ -1,25 +1,37 @@
 //! Spinlock
 
 use crate::arch::cpu::*;
+use crate::sync::lockdep::{LockDep, LockDepMap};
 use core::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
+    panic::Location,
 };
 
-pub use crate::arch::spin::{SpinLoopWait, Spinlock};
+pub use crate::arch::spin::{
+    McsLock, RawRwSpinlock, RawSpinlock, SpinLoopWait, Spinlock, TasLock, TicketLock,
+};
+
+/// [SpinMutex] that is taken in the order of arrival
+pub type TicketMutex<T> = SpinMutex<T, TicketLock>;
+
+/// [SpinMutex] whose waiters spin on their own cache lines
+pub type McsMutex<T> = SpinMutex<T, McsLock>;
 
 /// Mutual exclusion primitives like std::sync::Mutex implemented in Spinlock
-pub struct SpinMutex<T: ?Sized> {
-    lock: Spinlock,
+pub struct SpinMutex<T: ?Sized, L: RawSpinlock = TasLock> {
+    lock: Spinlock<L>,
     data: UnsafeCell<T>,
 }
 
-unsafe impl<T: ?Sized + Send> Sync for SpinMutex<T> {}
+unsafe impl<T: ?Sized + Send, L: RawSpinlock> Sync for SpinMutex<T, L> {}
 
-unsafe impl<T: ?Sized + Send> Send for SpinMutex<T> {}
+unsafe impl<T: ?Sized + Send, L: RawSpinlock + Send> Send for SpinMutex<T, L> {}
 
-impl<T> SpinMutex<T> {
+impl<T, L: RawSpinlock> SpinMutex<T, L> {
+    /// Creates a mutex whose class for [crate::sync::lockdep] is the caller.
     #[inline]
+    #[track_caller]
     pub const fn new(data: T) -> Self {
         Self {
             lock: Spinlock::new(),
@@ -33,9 +45,10 @@ impl<T> SpinMutex<T> {
     }
 }
 
-impl<T: ?Sized> SpinMutex<T> {
+impl<T: ?Sized, L: RawSpinlock> SpinMutex<T, L> {
     #[inline]
-    pub fn try_lock(&self) -> Option<SpinMutexGuard<T>> {
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<T, L>> {
         let interrupt_guard = unsafe { Cpu::interrupt_guard() };
         self.lock
             .try_lock()
@@ -43,7 +56,8 @@ impl<T: ?Sized> SpinMutex<T> {
     }
 
     #[inline]
-    pub fn lock<'a>(&'a self) -> SpinMutexGuard<'a, T> {
+    #[track_caller]
+    pub fn lock<'a>(&'a self) -> SpinMutexGuard<'a, T, L> {
         let interrupt_guard = unsafe { Cpu::interrupt_guard() };
         self.lock.lock();
         SpinMutexGuard::new(self, interrupt_guard)
@@ -55,35 +69,37 @@ impl<T: ?Sized> SpinMutex<T> {
     }
 }
 
-impl<T> const From<T> for SpinMutex<T> {
+impl<T, L: RawSpinlock> const From<T> for SpinMutex<T, L> {
     #[inline]
+    #[track_caller]
     fn from(t: T) -> Self {
         Self::new(t)
     }
 }
 
-impl<T: ?Sized + Default> Default for SpinMutex<T> {
+impl<T: ?Sized + Default, L: RawSpinlock> Default for SpinMutex<T, L> {
     #[inline]
+    #[track_caller]
     fn default() -> Self {
         Self::new(Default::default())
     }
 }
 
 #[must_use = "if unused the Mutex will immediately unlock"]
-pub struct SpinMutexGuard<'a, T: ?Sized + 'a> {
-    mutex: &'a SpinMutex<T>,
+pub struct SpinMutexGuard<'a, T: ?Sized + 'a, L: RawSpinlock = TasLock> {
+    mutex: &'a SpinMutex<T, L>,
     #[allow(dead_code)]
     interrupt_guard: InterruptGuard,
 }
 
-impl<T: ?Sized> !Send for SpinMutexGuard<'_, T> {}
+impl<T: ?Sized, L: RawSpinlock> !Send for SpinMutexGuard<'_, T, L> {}
 
-impl<T: ?Sized> !Sync for SpinMutexGuard<'_, T> {}
+impl<T: ?Sized, L: RawSpinlock> !Sync for SpinMutexGuard<'_, T, L> {}
 // unsafe impl<T: ?Sized + Sync> Sync for SpinMutexGuard<'_, T> {}
 
-impl<'a, T: ?Sized> SpinMutexGuard<'a, T> {
+impl<'a, T: ?Sized, L: RawSpinlock> SpinMutexGuard<'a, T, L> {
     #[inline]
-    fn new(mutex: &'a SpinMutex<T>, interrupt_guard: InterruptGuard) -> SpinMutexGuard<'a, T> {
+    fn new(mutex: &'a SpinMutex<T, L>, interrupt_guard: InterruptGuard) -> Self {
         Self {
             mutex,
             interrupt_guard,
@@ -91,7 +107,7 @@ impl<'a, T: ?Sized> SpinMutexGuard<'a, T> {
     }
 }
 
-impl<T: ?Sized> Drop for SpinMutexGuard<'_, T> {
+impl<T: ?Sized, L: RawSpinlock> Drop for SpinMutexGuard<'_, T, L> {
     #[inline]
     fn drop(&mut self) {
         unsafe {
@@ -100,7 +116,7 @@ impl<T: ?Sized> Drop for SpinMutexGuard<'_, T> {
     }
 }
 
-impl<T: ?Sized> Deref for SpinMutexGuard<'_, T> {
+impl<T: ?Sized, L: RawSpinlock> Deref for SpinMutexGuard<'_, T, L> {
     type Target = T;
 
     #[inline]
@@ -109,9 +125,188 @@ impl<T: ?Sized> Deref for SpinMutexGuard<'_, T> {
     }
 }
 
-impl<T: ?Sized> DerefMut for SpinMutexGuard<'_, T> {
+impl<T: ?Sized, L: RawSpinlock> DerefMut for SpinMutexGuard<'_, T, L> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.mutex.data.get() }
     }
 }
+
+/// Reader-writer lock like std::sync::RwLock implemented in spinlocks
+///
+/// A waiting writer stops new readers, so a core must not take the read lock again
+/// while it holds it.
+pub struct RwSpinlock<T: ?Sized> {
+    lock: RawRwSpinlock,
+    dep: LockDepMap,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinlock<T> {}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinlock<T> {}
+
+impl<T> RwSpinlock<T> {
+    /// Creates a lock whose class for [crate::sync::lockdep] is the caller.
+    #[inline]
+    #[track_caller]
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock: RawRwSpinlock::new(),
+            dep: LockDepMap::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinlock<T> {
+    #[track_caller]
+    pub fn read<'a>(&'a self) -> RwSpinlockReadGuard<'a, T> {
+        let interrupt_guard = unsafe { Cpu::interrupt_guard() };
+        if LockDep::is_enabled() {
+            LockDep::lock(
+                &self.dep,
+                Location::caller(),
+                || self.lock.try_read_lock(),
+                |spin| self.lock.read_lock_with(spin),
+            );
+        } else {
+            self.lock.read_lock();
+        }
+        RwSpinlockReadGuard {
+            lock: self,
+            interrupt_guard,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwSpinlockReadGuard<'_, T>> {
+        let interrupt_guard = unsafe { Cpu::interrupt_guard() };
+        if !self.lock.try_read_lock() {
+            return None;
+        }
+        if LockDep::is_enabled() {
+            LockDep::try_locked(&self.dep, Location::caller());
+        }
+        Some(RwSpinlockReadGuard {
+            lock: self,
+            interrupt_guard,
+        })
+    }
+
+    #[track_caller]
+    pub fn write<'a>(&'a self) -> RwSpinlockWriteGuard<'a, T> {
+        let interrupt_guard = unsafe { Cpu::interrupt_guard() };
+        if LockDep::is_enabled() {
+            LockDep::lock(
+                &self.dep,
+                Location::caller(),
+                || self.lock.try_write_lock(),
+                |spin| self.lock.write_lock_with(spin),
+            );
+        } else {
+            self.lock.write_lock();
+        }
+        RwSpinlockWriteGuard {
+            lock: self,
+            interrupt_guard,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwSpinlockWriteGuard<'_, T>> {
+        let interrupt_guard = unsafe { Cpu::interrupt_guard() };
+        if !self.lock.try_write_lock() {
+            return None;
+        }
+        if LockDep::is_enabled() {
+            LockDep::try_locked(&self.dep, Location::caller());
+        }
+        Some(RwSpinlockWriteGuard {
+            lock: self,
+            interrupt_guard,
+        })
+    }
+}
+
+impl<T: Default> Default for RwSpinlock<T> {
+    #[inline]
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+#[must_use = "if unused the RwSpinlock will immediately unlock"]
+pub struct RwSpinlockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwSpinlock<T>,
+    #[allow(dead_code)]
+    interrupt_guard: InterruptGuard,
+}
+
+impl<T: ?Sized> !Send for RwSpinlockReadGuard<'_, T> {}
+
+impl<T: ?Sized> Drop for RwSpinlockReadGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        if LockDep::is_enabled() {
+            LockDep::unlock(&self.lock.dep);
+        }
+        unsafe {
+            self.lock.lock.read_unlock();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinlockReadGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+#[must_use = "if unused the RwSpinlock will immediately unlock"]
+pub struct RwSpinlockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwSpinlock<T>,
+    #[allow(dead_code)]
+    interrupt_guard: InterruptGuard,
+}
+
+impl<T: ?Sized> !Send for RwSpinlockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> !Sync for RwSpinlockWriteGuard<'_, T> {}
+
+impl<T: ?Sized> Drop for RwSpinlockWriteGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        if LockDep::is_enabled() {
+            LockDep::unlock(&self.lock.dep);
+        }
+        unsafe {
+            self.lock.lock.write_unlock();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinlockWriteGuard<'_, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSpinlockWriteGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
@@ -5,13 +5,16 @@ use crate::{
     drawing::*,
     fw,
     fw::dt,
-    io::{emcon::EmConsole, font::FontManager, uart::Uart},
-    mem,
+    io::{self, emcon::EmConsole, font::FontManager, input::InputManager, tty::Tty, uart::Uart},
+    log::{Log, ScreenSink, UartSink},
+    mem, param,
 };
+use bootprot::{BootInfo, BootSource};
 use core::{
     cell::UnsafeCell,
     fmt::{self},
     ptr::null,
+    str,
 };
 
 static mut SYSTEM: UnsafeCell<System> = UnsafeCell::new(System::new());
@@ -22,6 +25,7 @@ pub struct System {
     device_tree: Option<fw::dt::DeviceTree>,
     #[allow(dead_code)]
     model_name: (*const u8, usize),
+    boot_info: BootInfo,
 }
 
 impl System {
@@ -37,6 +41,7 @@ impl System {
             em_console: EmConsole::new(FontManager::preferred_console_font()),
             device_tree: None,
             model_name: (null(), 0),
+            boot_info: BootInfo::with_dtb(0),
         }
     }
 
@@ -51,24 +56,51 @@ impl System {
         unsafe { &*SYSTEM.get() }
     }
 
-    pub unsafe fn init(dtb: usize) {
+    pub unsafe fn init(info: &BootInfo) {
         let shared = Self::shared_mut();
-
-        arch::init_early(dtb);
-
-        if dtb != 0 {
-            if let Some(dt) = dt::DeviceTree::parse(dtb as *const u8).ok() {
-                mem::MemoryManager::init(mem::InitializationSource::DeviceTree(&dt));
-                shared.device_tree = Some(dt);
+        shared.boot_info = *info;
+        let info = &Self::shared().boot_info;
+
+        let dt = dt::DeviceTree::parse(info.dtb as usize as *const u8).ok();
+        let bootargs = info
+            .cmdline()
+            .and_then(|v| str::from_utf8(v).ok())
+            .or_else(|| dt.as_ref().and_then(|dt| dt.chosen_bootargs()));
+        param::init(bootargs.unwrap_or_default());
+        shared.em_console.load_params();
+
+        arch::init_early(info);
+        let _ = Log::add_sink(&UartSink);
+        let _ = InputManager::subscribe(Tty::console());
+
+        param::report(Self::stdout());
+
+        match (info.source, dt.as_ref()) {
+            (BootSource::Uefi, _) => {
+                mem::MemoryManager::init(mem::InitializationSource::Uefi(info));
+            }
+            (BootSource::DeviceTree, Some(dt)) => {
+                mem::MemoryManager::init(mem::InitializationSource::DeviceTree(dt));
             }
+            (BootSource::DeviceTree, None) => (),
         }
 
+        if let Some(dt) = dt {
+            arch::init_smp(&dt);
+            io::virtio::Virtio::init(&dt);
+            io::bcmrng::BcmRng::init(&dt);
+            shared.device_tree = Some(dt);
+        }
+        arch::init_devices();
+
         if let Some((ptr, w, h, stride)) = arch::std_screen() {
             shared.main_screen = Some(UnsafeCell::new(Bitmap32::from_static(
                 ptr as *mut TrueColor,
                 Size::new(w, h),
                 stride,
             )));
+            let _ = Log::add_sink(&ScreenSink);
+            let _ = InputManager::subscribe(&io::input::cursor::POINTER);
         }
     }
 
@@ -116,11 +148,23 @@ impl System {
         Self::shared().device_tree.as_ref()
     }
 
+    /// Returns the information passed by the boot loader.
+    #[inline]
+    pub fn boot_info() -> &'static BootInfo {
+        &Self::shared().boot_info
+    }
+
     #[inline]
     pub fn stdout<'a>() -> &'a mut dyn Uart {
         arch::std_uart()
     }
 
+    /// Returns the TTY of the standard UART, which reads the input with the line discipline.
+    #[inline]
+    pub fn stdin() -> &'static Tty {
+        Tty::console()
+    }
+
     #[inline]
     pub fn model_name<'a>() -> Option<&'a str> {
         Self::device_tree().and_then(|dt| dt.root_model())
@@ -0,0 +1,203 @@
+//! Boot protocol between the boot loaders and the kernel
+//!
+//! A loader jumps to the kernel entry point with a pointer to [BootInfo] in `x0`
+//! and [BootInfo::MAGIC] in `x1`. Boot loaders following the arm64 Linux boot
+//! protocol pass the device tree in `x0` and zero in `x1` instead.
+#![no_std]
+
+use core::{ptr, slice};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo {
+    pub source: BootSource,
+    /// Number of entries in the memory map
+    pub mmap_len: u32,
+    /// Memory map sorted by base address
+    pub mmap_base: u64,
+    pub total_memory_size: u64,
+    /// Flattened device tree
+    pub dtb: u64,
+    /// ACPI 2.0 RSDP
+    pub acpi_rsdp: u64,
+    /// Start of the memory reserved for the kernel image
+    pub kernel_base: u64,
+    /// End of the memory reserved for the kernel image and its early allocation area
+    pub kernel_end: u64,
+    /// Framebuffer in 32bit BGRX, or zero if not available
+    pub vram_base: u64,
+    pub vram_stride: u32,
+    pub screen_width: u32,
+    pub screen_height: u32,
+    /// Length of the command line
+    pub cmdline_len: u32,
+    /// Command line in ASCII
+    pub cmdline: u64,
+}
+
+impl BootInfo {
+    /// Value in `x1` indicating that `x0` points to a [BootInfo]
+    pub const MAGIC: u64 = 0x4F46_4E49_544F_4F42;
+
+    /// Size of the area after the kernel image reserved by loaders for early allocation
+    pub const EARLY_RESERVE_SIZE: u64 = 0x0200_0000;
+
+    #[inline]
+    pub const fn with_dtb(dtb: u64) -> Self {
+        Self {
+            source: BootSource::DeviceTree,
+            mmap_len: 0,
+            mmap_base: 0,
+            total_memory_size: 0,
+            dtb,
+            acpi_rsdp: 0,
+            kernel_base: 0,
+            kernel_end: 0,
+            vram_base: 0,
+            vram_stride: 0,
+            screen_width: 0,
+            screen_height: 0,
+            cmdline_len: 0,
+            cmdline: 0,
+        }
+    }
+
+    /// Gets the boot information from the arguments of the kernel entry point.
+    ///
+    /// Returns `None` if the boot information has a source or a memory type that this
+    /// kernel does not know.
+    ///
+    /// # Safety
+    ///
+    /// `x0` must point to a [BootInfo] and its memory map if `x1` is [BootInfo::MAGIC].
+    pub unsafe fn from_entry(x0: usize, x1: usize) -> Option<Self> {
+        if x1 as u64 != Self::MAGIC {
+            return Some(Self::with_dtb(x0 as u64));
+        }
+
+        // The tags are read as integers, as the enums are valid only after the check.
+        let ptr = x0 as *const Self;
+        let source = (ptr::addr_of!((*ptr).source) as *const u32).read();
+        BootSource::try_from(source).ok()?;
+        let mmap_base = ptr::addr_of!((*ptr).mmap_base).read() as usize;
+        let mmap_len = ptr::addr_of!((*ptr).mmap_len).read() as usize;
+        if mmap_base != 0 {
+            let mmap = mmap_base as *const BootMemoryMapDescriptor;
+            for index in 0..mmap_len {
+                let mem_type = (ptr::addr_of!((*mmap.add(index)).mem_type) as *const u32).read();
+                BootMemoryType::try_from(mem_type).ok()?;
+            }
+        }
+
+        Some(ptr.read())
+    }
+
+    #[inline]
+    pub fn memory_map(&self) -> &[BootMemoryMapDescriptor] {
+        if self.mmap_base == 0 {
+            return &[];
+        }
+        unsafe {
+            slice::from_raw_parts(self.mmap_base as usize as *const _, self.mmap_len as usize)
+        }
+    }
+
+    #[inline]
+    pub fn cmdline(&self) -> Option<&[u8]> {
+        (self.cmdline != 0 && self.cmdline_len > 0).then(|| unsafe {
+            slice::from_raw_parts(
+                self.cmdline as usize as *const u8,
+                self.cmdline_len as usize,
+            )
+        })
+    }
+
+    #[inline]
+    pub const fn has_framebuffer(&self) -> bool {
+        self.vram_base != 0
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootSource {
+    /// Booted by a loader following the Linux boot protocol, such as the Raspberry Pi firmware
+    DeviceTree,
+    /// Booted by the UEFI loader
+    Uefi,
+}
+
+impl TryFrom<u32> for BootSource {
+    type Error = ();
+
+    #[inline]
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::DeviceTree),
+            1 => Ok(Self::Uefi),
+            _ => Err(()),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootMemoryMapDescriptor {
+    pub base: u64,
+    pub page_count: u32,
+    pub mem_type: BootMemoryType,
+}
+
+impl BootMemoryMapDescriptor {
+    pub const PAGE_SIZE: u64 = 0x1000;
+
+    #[inline]
+    pub const fn end(&self) -> u64 {
+        self.base + self.page_count as u64 * Self::PAGE_SIZE
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMemoryType {
+    /// Free memory, including the memory used by the boot services
+    Available,
+    /// Memory allocated by the loader: the kernel, the boot information and the memory map
+    OsLoader,
+    /// Memory used by the UEFI runtime services
+    UefiRuntime,
+    AcpiReclaim,
+    AcpiNvs,
+    /// Memory that must not be used, but that is still RAM
+    Reserved,
+    /// Memory mapped I/O
+    Mmio,
+    Unavailable,
+}
+
+impl TryFrom<u32> for BootMemoryType {
+    type Error = ();
+
+    #[inline]
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Available),
+            1 => Ok(Self::OsLoader),
+            2 => Ok(Self::UefiRuntime),
+            3 => Ok(Self::AcpiReclaim),
+            4 => Ok(Self::AcpiNvs),
+            5 => Ok(Self::Reserved),
+            6 => Ok(Self::Mmio),
+            7 => Ok(Self::Unavailable),
+            _ => Err(()),
+        }
+    }
+}
+
+impl BootMemoryType {
+    /// Returns whether this memory is RAM, that is, it can be mapped as normal memory.
+    #[inline]
+    pub const fn is_ram(&self) -> bool {
+        !matches!(self, Self::Mmio | Self::Unavailable)
+    }
+}
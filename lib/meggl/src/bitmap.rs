@@ -740,6 +740,47 @@ impl Bitmap8<'_> {
     }
 }
 
+impl Bitmap8<'_> {
+    /// Draws `color` where the coverage of the mask is at least half, as there is no blending.
+    pub fn blend_alpha_mask(&mut self, mask: &[u8], size: Size, origin: Point, color: IndexedColor) {
+        blend_mask_rows(self.size(), mask, size, origin, |offset, cursor, count| {
+            let stride = self.stride();
+            let slice = self.slice_mut();
+            for (i, coverage) in mask[cursor..cursor + count].iter().enumerate() {
+                if *coverage >= 0x80 {
+                    slice[offset.x as usize + i + offset.y as usize * stride] = color;
+                }
+            }
+        });
+    }
+}
+
+/// Calls `f` with the destination, the index in the mask and the length of each visible row.
+fn blend_mask_rows<F>(bounds: Size, mask: &[u8], size: Size, origin: Point, mut f: F)
+where
+    F: FnMut(Point, usize, usize),
+{
+    let width = size.width();
+    let height = size.height();
+    if width <= 0 || height <= 0 || mask.len() < (width * height) as usize {
+        return;
+    }
+    let left = 0.max(-origin.x);
+    let top = 0.max(-origin.y);
+    let right = width.min(bounds.width() - origin.x);
+    let bottom = height.min(bounds.height() - origin.y);
+    if left >= right || top >= bottom {
+        return;
+    }
+    for y in top..bottom {
+        f(
+            Point::new(origin.x + left, origin.y + y),
+            (y * width + left) as usize,
+            (right - left) as usize,
+        );
+    }
+}
+
 impl BasicDrawing for Bitmap8<'_> {
     fn fill_rect(&mut self, rect: Rect, color: Self::ColorType) {
         let mut width = rect.width();
@@ -1164,6 +1205,25 @@ impl Bitmap32<'_> {
     }
 }
 
+impl Bitmap32<'_> {
+    /// Draws `color` through an 8-bit coverage mask, e.g. an anti-aliased glyph.
+    pub fn blend_alpha_mask(&mut self, mask: &[u8], size: Size, origin: Point, color: TrueColor) {
+        let alpha = color.opacity() as usize;
+        blend_mask_rows(self.size(), mask, size, origin, |offset, cursor, count| {
+            let stride = self.stride();
+            let slice = self.slice_mut();
+            for (i, coverage) in mask[cursor..cursor + count].iter().enumerate() {
+                let coverage = *coverage as usize * alpha / 255;
+                if coverage == 0 {
+                    continue;
+                }
+                let pixel = &mut slice[offset.x as usize + i + offset.y as usize * stride];
+                *pixel = pixel.blend(color.with_opacity(coverage as u8));
+            }
+        });
+    }
+}
+
 impl Bitmap32<'static> {
     /// SAFETY: Must guarantee the existence of the `ptr`.
     #[inline]
@@ -1753,6 +1813,17 @@ impl BasicDrawing for Bitmap<'_> {
     }
 }
 
+impl Bitmap<'_> {
+    /// Draws `color` through an 8-bit coverage mask, e.g. an anti-aliased glyph.
+    #[inline]
+    pub fn blend_alpha_mask(&mut self, mask: &[u8], size: Size, origin: Point, color: Color) {
+        match self {
+            Self::Indexed(ref mut v) => v.blend_alpha_mask(mask, size, origin, color.into()),
+            Self::Argb32(ref mut v) => v.blend_alpha_mask(mask, size, origin, color.into()),
+        }
+    }
+}
+
 impl Bitmap<'_> {
     #[inline]
     pub const fn color_mode(&self) -> usize {
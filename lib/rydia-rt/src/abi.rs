@@ -0,0 +1,133 @@
+//! System call ABI shared with the kernel
+//!
+//! `svc #0` with the system call number in `x8` and the arguments in `x0`-`x5`.
+//! The result is returned in `x0`; negative values are [Errno].
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    /// `exit(code)` terminates the process.
+    Exit = 0,
+    /// `write(handle, buf, len) -> len`
+    Write,
+    /// `read(handle, buf, len) -> len` blocks until some data is available.
+    Read,
+    /// `mmap(addr, len, prot) -> addr` maps zeroed pages; `addr` zero lets the kernel choose.
+    Mmap,
+    /// `munmap(addr, len)`
+    Munmap,
+    /// `sleep(nanos)`
+    Sleep,
+    /// `time() -> nanos` returns the monotonic time since boot.
+    Time,
+    /// `thread_create(entry, arg, stack_size) -> tid` starts `entry(arg)` on a new stack.
+    ThreadCreate,
+    /// `thread_exit()` terminates the current thread, or the process if it was the last one.
+    ThreadExit,
+    /// `yield()`
+    Yield,
+    /// `fb_info() -> (width << 32) | height`
+    FbInfo,
+    /// `fb_blt(x, y, width, height, buf, stride)` copies 32bit ARGB pixels to the screen.
+    FbBlt,
+    /// `fork() -> pid` copies the process with the calling thread, and returns zero in the
+    /// copy. The copy runs until it exits before the call returns in the original.
+    Fork,
+}
+
+impl Syscall {
+    #[inline]
+    pub const fn from_usize(value: usize) -> Option<Self> {
+        Some(match value {
+            0 => Self::Exit,
+            1 => Self::Write,
+            2 => Self::Read,
+            3 => Self::Mmap,
+            4 => Self::Munmap,
+            5 => Self::Sleep,
+            6 => Self::Time,
+            7 => Self::ThreadCreate,
+            8 => Self::ThreadExit,
+            9 => Self::Yield,
+            10 => Self::FbInfo,
+            11 => Self::FbBlt,
+            12 => Self::Fork,
+            _ => return None,
+        })
+    }
+}
+
+/// Error numbers returned as negative values
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// Ctrl-C was pressed at the terminal while waiting.
+    Interrupted = 4,
+    BadHandle = 9,
+    Again = 11,
+    NoMemory = 12,
+    Fault = 14,
+    NoDevice = 19,
+    InvalidArgument = 22,
+    NoSys = 38,
+}
+
+impl Errno {
+    #[inline]
+    pub const fn from_isize(value: isize) -> Option<Self> {
+        Some(match value {
+            4 => Self::Interrupted,
+            9 => Self::BadHandle,
+            11 => Self::Again,
+            12 => Self::NoMemory,
+            14 => Self::Fault,
+            19 => Self::NoDevice,
+            22 => Self::InvalidArgument,
+            38 => Self::NoSys,
+            _ => return None,
+        })
+    }
+
+    /// Converts the raw value of `x0` into the result of a system call.
+    #[inline]
+    pub const fn result(value: usize) -> Result<usize, Self> {
+        let value = value as isize;
+        if value >= 0 {
+            Ok(value as usize)
+        } else {
+            match Self::from_isize(-value) {
+                Some(err) => Err(err),
+                None => Err(Self::InvalidArgument),
+            }
+        }
+    }
+
+    /// Converts the result of a system call into the raw value of `x0`.
+    #[inline]
+    pub const fn raw(result: Result<usize, Self>) -> usize {
+        match result {
+            Ok(v) => v,
+            Err(err) => (-(err as isize)) as usize,
+        }
+    }
+}
+
+/// Handles available to every process
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle(pub usize);
+
+impl Handle {
+    pub const STDIN: Self = Self(0);
+    pub const STDOUT: Self = Self(1);
+    pub const STDERR: Self = Self(2);
+}
+
+/// Protection flags of [Syscall::Mmap]
+pub mod prot {
+    pub const NONE: usize = 0;
+    pub const EXEC: usize = 1;
+    pub const WRITE: usize = 2;
+    pub const READ: usize = 4;
+    pub const READ_WRITE: usize = READ | WRITE;
+}
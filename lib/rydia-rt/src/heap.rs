@@ -0,0 +1,117 @@
+//! Global allocator over `mmap`
+//!
+//! Small blocks are carved out of 64KB chunks into power of two size classes.
+//! Larger blocks are mapped and unmapped directly.
+
+use crate::{abi::prot, sys};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[global_allocator]
+static ALLOCATOR: Heap = Heap::new();
+
+pub struct Heap {
+    lock: AtomicBool,
+    free_lists: UnsafeCell<[usize; Heap::N_CLASSES]>,
+}
+
+unsafe impl Sync for Heap {}
+
+impl Heap {
+    const PAGE_SIZE: usize = 0x1000;
+    const CHUNK_SIZE: usize = 0x1_0000;
+    const MIN_SHIFT: usize = 4;
+    const MAX_SHIFT: usize = 11;
+    const N_CLASSES: usize = Self::MAX_SHIFT - Self::MIN_SHIFT + 1;
+
+    const fn new() -> Self {
+        Self {
+            lock: AtomicBool::new(false),
+            free_lists: UnsafeCell::new([0; Self::N_CLASSES]),
+        }
+    }
+
+    #[inline]
+    fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).max(1 << Self::MIN_SHIFT);
+        let shift = size.next_power_of_two().trailing_zeros() as usize;
+        (shift <= Self::MAX_SHIFT).then(|| shift - Self::MIN_SHIFT)
+    }
+
+    #[inline]
+    fn lock(&self) {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        self.lock.store(false, Ordering::Release);
+    }
+
+    /// Fills the free list of `class` with a new chunk.
+    unsafe fn refill(free_list: &mut usize, class: usize) -> bool {
+        let chunk = match sys::mmap(0, Self::CHUNK_SIZE, prot::READ_WRITE) {
+            Ok(v) => v,
+            Err(_) => return false,
+        };
+        let block_size = 1 << (class + Self::MIN_SHIFT);
+        for block in (chunk..chunk + Self::CHUNK_SIZE).step_by(block_size).rev() {
+            *(block as *mut usize) = *free_list;
+            *free_list = block;
+        }
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match Self::size_class(layout) {
+            Some(class) => {
+                self.lock();
+                let free_list = &mut (*self.free_lists.get())[class];
+                let result = if *free_list != 0 || Self::refill(free_list, class) {
+                    let block = *free_list;
+                    *free_list = *(block as *const usize);
+                    block as *mut u8
+                } else {
+                    null_mut()
+                };
+                self.unlock();
+                result
+            }
+            None => {
+                if layout.align() > Self::PAGE_SIZE {
+                    return null_mut();
+                }
+                sys::mmap(0, layout.size(), prot::READ_WRITE)
+                    .map(|v| v as *mut u8)
+                    .unwrap_or(null_mut())
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match Self::size_class(layout) {
+            Some(class) => {
+                self.lock();
+                let free_list = &mut (*self.free_lists.get())[class];
+                *(ptr as *mut usize) = *free_list;
+                *free_list = ptr as usize;
+                self.unlock();
+            }
+            None => {
+                let _ = sys::munmap(ptr as usize, layout.size());
+            }
+        }
+    }
+}
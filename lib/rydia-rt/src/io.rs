@@ -0,0 +1,109 @@
+//! Standard input and output
+
+use crate::{
+    abi::{Errno, Handle},
+    sys,
+};
+use core::fmt;
+
+pub struct Stdout;
+
+impl Stdout {
+    /// Writes all of `buf`.
+    pub fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Errno> {
+        while !buf.is_empty() {
+            let len = sys::write(Handle::STDOUT, buf)?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Write for Stdout {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+pub struct Stderr;
+
+impl fmt::Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut buf = s.as_bytes();
+        while !buf.is_empty() {
+            let len = sys::write(Handle::STDERR, buf).map_err(|_| fmt::Error)?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+}
+
+pub struct Stdin;
+
+impl Stdin {
+    /// Reads at least one byte, blocking until available.
+    #[inline]
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Errno> {
+        sys::read(Handle::STDIN, buf)
+    }
+}
+
+#[inline]
+pub fn stdout() -> Stdout {
+    Stdout
+}
+
+#[inline]
+pub fn stderr() -> Stderr {
+    Stderr
+}
+
+#[inline]
+pub fn stdin() -> Stdin {
+    Stdin
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Stdout, args);
+}
+
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Stderr, args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::io::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::io::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => {
+        $crate::io::_eprint(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        $crate::eprint!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::io::_eprint(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
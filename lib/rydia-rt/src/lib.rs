@@ -0,0 +1,80 @@
+//! Runtime for user programs running on rydia
+//!
+//! A program links with `link.ld` of this crate and names its entry point with [entry]:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use rydia_rt::println;
+//!
+//! rydia_rt::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     println!("hello, world");
+//!     0
+//! }
+//! ```
+#![no_std]
+
+pub mod abi;
+
+#[cfg(feature = "runtime")]
+extern crate alloc;
+
+#[cfg(feature = "runtime")]
+mod heap;
+#[cfg(feature = "runtime")]
+pub mod io;
+#[cfg(feature = "runtime")]
+pub mod sys;
+#[cfg(feature = "runtime")]
+pub mod thread;
+
+#[cfg(feature = "runtime")]
+pub use sys::{exit, time};
+
+/// Defines `fn() -> i32` as the entry point of the program.
+#[macro_export]
+macro_rules! entry {
+    ($path:path) => {
+        #[no_mangle]
+        fn __rydia_main() -> i32 {
+            let f: fn() -> i32 = $path;
+            f()
+        }
+    };
+}
+
+#[cfg(feature = "runtime")]
+mod rt {
+    use crate::{eprintln, sys};
+    use core::{arch::global_asm, panic::PanicInfo};
+
+    extern "Rust" {
+        fn __rydia_main() -> i32;
+    }
+
+    #[no_mangle]
+    extern "C" fn _rt_start() -> ! {
+        sys::exit(unsafe { __rydia_main() })
+    }
+
+    #[panic_handler]
+    fn panic(info: &PanicInfo) -> ! {
+        eprintln!("panicked: {}", info);
+        sys::exit(101)
+    }
+
+    // The kernel starts the program with a 16 byte aligned stack and zeroed registers.
+    global_asm!(
+        "
+        .section .text._start
+        .global _start
+    _start:
+        mov     x29, #0
+        mov     x30, #0
+        bl      _rt_start
+        "
+    );
+}
@@ -0,0 +1,162 @@
+//! Raw system calls
+
+use crate::abi::{Errno, Handle, Syscall};
+use core::{arch::asm, time::Duration};
+
+#[inline]
+unsafe fn syscall(number: Syscall, args: [usize; 6]) -> usize {
+    let result: usize;
+    asm!(
+        "svc #0",
+        inlateout("x0") args[0] => result,
+        in("x1") args[1],
+        in("x2") args[2],
+        in("x3") args[3],
+        in("x4") args[4],
+        in("x5") args[5],
+        in("x8") number as usize,
+        options(nostack),
+    );
+    result
+}
+
+#[inline]
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        syscall(Syscall::Exit, [code as usize, 0, 0, 0, 0, 0]);
+    }
+    unreachable!()
+}
+
+#[inline]
+pub fn write(handle: Handle, buf: &[u8]) -> Result<usize, Errno> {
+    Errno::result(unsafe {
+        syscall(
+            Syscall::Write,
+            [handle.0, buf.as_ptr() as usize, buf.len(), 0, 0, 0],
+        )
+    })
+}
+
+#[inline]
+pub fn read(handle: Handle, buf: &mut [u8]) -> Result<usize, Errno> {
+    Errno::result(unsafe {
+        syscall(
+            Syscall::Read,
+            [handle.0, buf.as_mut_ptr() as usize, buf.len(), 0, 0, 0],
+        )
+    })
+}
+
+/// Maps zeroed pages. `addr` zero lets the kernel choose the address.
+///
+/// # Safety
+///
+/// Mapping over memory in use is rejected, but `addr` must not be reused while referenced.
+#[inline]
+pub unsafe fn mmap(addr: usize, len: usize, prot: usize) -> Result<usize, Errno> {
+    Errno::result(syscall(Syscall::Mmap, [addr, len, prot, 0, 0, 0]))
+}
+
+/// # Safety
+///
+/// Nothing may refer to the memory after it is unmapped.
+#[inline]
+pub unsafe fn munmap(addr: usize, len: usize) -> Result<(), Errno> {
+    Errno::result(syscall(Syscall::Munmap, [addr, len, 0, 0, 0, 0])).map(|_| ())
+}
+
+#[inline]
+pub fn sleep(duration: Duration) {
+    let nanos = duration.as_nanos().min(usize::MAX as u128) as usize;
+    unsafe {
+        syscall(Syscall::Sleep, [nanos, 0, 0, 0, 0, 0]);
+    }
+}
+
+/// Returns the monotonic time since boot.
+#[inline]
+pub fn time() -> Duration {
+    Duration::from_nanos(unsafe { syscall(Syscall::Time, [0; 6]) } as u64)
+}
+
+/// Starts `entry(arg)` on a new thread with a stack allocated by the kernel.
+///
+/// # Safety
+///
+/// `arg` must be valid for the new thread, which can outlive the caller.
+#[inline]
+pub unsafe fn thread_create(
+    entry: extern "C" fn(usize) -> !,
+    arg: usize,
+    stack_size: usize,
+) -> Result<usize, Errno> {
+    Errno::result(syscall(
+        Syscall::ThreadCreate,
+        [entry as usize, arg, stack_size, 0, 0, 0],
+    ))
+}
+
+#[inline]
+pub fn thread_exit() -> ! {
+    unsafe {
+        syscall(Syscall::ThreadExit, [0; 6]);
+    }
+    unreachable!()
+}
+
+/// Creates a copy of the process, which shares its memory copy-on-write.
+///
+/// Returns zero in the child, and its identifier in the parent after the child exits.
+///
+/// # Safety
+///
+/// Only the calling thread is copied, so the child must not wait for the other threads
+/// or the locks they hold.
+#[inline]
+pub unsafe fn fork() -> Result<usize, Errno> {
+    Errno::result(syscall(Syscall::Fork, [0; 6]))
+}
+
+#[inline]
+pub fn yield_now() {
+    unsafe {
+        syscall(Syscall::Yield, [0; 6]);
+    }
+}
+
+/// Returns the size of the screen.
+#[inline]
+pub fn fb_info() -> Result<(usize, usize), Errno> {
+    Errno::result(unsafe { syscall(Syscall::FbInfo, [0; 6]) })
+        .map(|v| (v >> 32, v & 0xFFFF_FFFF))
+}
+
+/// Copies `width` x `height` pixels of `buf` to the screen at (`x`, `y`).
+#[inline]
+pub fn fb_blt(
+    x: isize,
+    y: isize,
+    width: usize,
+    height: usize,
+    buf: &[u32],
+    stride: usize,
+) -> Result<(), Errno> {
+    if height > 0 && (stride < width || buf.len() < stride * (height - 1) + width) {
+        return Err(Errno::InvalidArgument);
+    }
+    Errno::result(unsafe {
+        syscall(
+            Syscall::FbBlt,
+            [
+                x as usize,
+                y as usize,
+                width,
+                height,
+                buf.as_ptr() as usize,
+                stride,
+            ],
+        )
+    })
+    .map(|_| ())
+}
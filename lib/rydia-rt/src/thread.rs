@@ -0,0 +1,59 @@
+//! Threads
+//!
+//! Threads of a process run cooperatively; they switch only in `sleep`, `yield_now`
+//! and when a thread exits.
+
+use crate::{abi::Errno, sys};
+use alloc::boxed::Box;
+use core::time::Duration;
+
+pub const DEFAULT_STACK_SIZE: usize = 0x1_0000;
+
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(pub usize);
+
+/// Starts `f` on a new thread.
+pub fn spawn<F>(f: F) -> Result<ThreadId, Errno>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_stack(DEFAULT_STACK_SIZE, f)
+}
+
+pub fn spawn_with_stack<F>(stack_size: usize, f: F) -> Result<ThreadId, Errno>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let payload: Box<Box<dyn FnOnce()>> = Box::new(Box::new(f));
+    let arg = Box::into_raw(payload) as usize;
+    match unsafe { sys::thread_create(thread_start, arg, stack_size) } {
+        Ok(tid) => Ok(ThreadId(tid)),
+        Err(err) => {
+            drop(unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce()>) });
+            Err(err)
+        }
+    }
+}
+
+extern "C" fn thread_start(arg: usize) -> ! {
+    let f = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce()>) };
+    f();
+    sys::thread_exit()
+}
+
+#[inline]
+pub fn sleep(duration: Duration) {
+    sys::sleep(duration)
+}
+
+#[inline]
+pub fn yield_now() {
+    sys::yield_now()
+}
+
+/// Terminates the current thread.
+#[inline]
+pub fn exit() -> ! {
+    sys::thread_exit()
+}
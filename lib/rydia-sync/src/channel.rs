@@ -0,0 +1,417 @@
+//! Bounded multi-producer multi-consumer channel
+//!
+//! Values are passed through a ring of stamped slots like `ConcurrentFifo` of the
+//! kernel. The channel closes when either side calls `close` or drops its last handle,
+//! after which sending fails and receiving fails once the remaining values are taken.
+//!
+//! Closing sets a bit in the tail index, so a value is either published by the same
+//! compare-and-swap that checks the bit or not sent at all.
+//!
+//! How a blocking call waits is up to the [Park] of the channel.
+
+use crate::primitive::{
+    atomic::{fence, AtomicUsize, Ordering},
+    hint, Arc, UnsafeCell,
+};
+use alloc::boxed::Box;
+use core::{fmt, marker::PhantomData, mem::MaybeUninit, time::Duration};
+
+/// How a blocking call waits for the other side of a channel
+pub trait Park {
+    /// Sleeps until [Park::unpark] is called, or returns early.
+    fn park();
+
+    /// Wakes up all callers of [Park::park].
+    fn unpark();
+
+    /// Returns the current count of a monotonic clock.
+    fn counter() -> u64;
+
+    /// Returns the counts of [Park::counter] in a second.
+    fn frequency() -> u64;
+}
+
+/// Creates a channel that holds at least `capacity` values.
+pub fn channel<T, P: Park>(capacity: usize) -> (Sender<T, P>, Receiver<T, P>) {
+    let shared = Arc::new(Shared::new(capacity));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The channel is full, returned only by the non-blocking calls.
+    Full(T),
+    Closed(T),
+    Timeout(T),
+}
+
+impl<T> SendError<T> {
+    /// Returns the value that was not sent.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Full(v) | Self::Closed(v) | Self::Timeout(v) => v,
+        }
+    }
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => "Full(..)".fmt(f),
+            Self::Closed(_) => "Closed(..)".fmt(f),
+            Self::Timeout(_) => "Timeout(..)".fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The channel is empty, returned only by the non-blocking calls.
+    Empty,
+    /// The channel is closed and empty.
+    Closed,
+    Timeout,
+}
+
+struct Slot<T> {
+    /// The index that can write this slot next, or the index plus one that can read it
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// The ring shared by the handles
+///
+/// An index holds the position in the ring in the bits below `mark_bit`, and the lap
+/// in the bits from `one_lap`. `tail` has `mark_bit` once the channel is closed.
+struct Shared<T, P> {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    buffer: Box<[Slot<T>]>,
+    capacity: usize,
+    mark_bit: usize,
+    one_lap: usize,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    _phantom: PhantomData<fn() -> P>,
+}
+
+unsafe impl<T: Send, P> Send for Shared<T, P> {}
+
+unsafe impl<T: Send, P> Sync for Shared<T, P> {}
+
+impl<T, P: Park> Shared<T, P> {
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let mark_bit = (capacity + 1).next_power_of_two();
+        let buffer = (0..capacity)
+            .map(|index| Slot {
+                stamp: AtomicUsize::new(index),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer,
+            capacity,
+            mark_bit,
+            one_lap: mark_bit * 2,
+            senders: AtomicUsize::new(1),
+            receivers: AtomicUsize::new(1),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the index after `index`.
+    #[inline]
+    fn next_index(&self, index: usize) -> usize {
+        if (index & (self.mark_bit - 1)) + 1 < self.capacity {
+            index + 1
+        } else {
+            (index & !(self.one_lap - 1)).wrapping_add(self.one_lap)
+        }
+    }
+
+    #[inline]
+    fn is_closed(&self) -> bool {
+        (self.tail.load(Ordering::SeqCst) & self.mark_bit) != 0
+    }
+
+    #[inline]
+    fn close(&self) {
+        self.tail.fetch_or(self.mark_bit, Ordering::SeqCst);
+        P::unpark();
+    }
+
+    fn try_send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            if (tail & self.mark_bit) != 0 {
+                return Err(SendError::Closed(value));
+            }
+            let slot = &self.buffer[tail & (self.mark_bit - 1)];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            if stamp == tail {
+                // Fails if the channel has been closed meanwhile.
+                match self.tail.compare_exchange_weak(
+                    tail,
+                    self.next_index(tail),
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        slot.value
+                            .with_mut(|p| unsafe { p.write(MaybeUninit::new(value)) });
+                        slot.stamp.store(tail + 1, Ordering::Release);
+                        P::unpark();
+                        return Ok(());
+                    }
+                    Err(current) => tail = current,
+                }
+            } else if stamp.wrapping_add(self.one_lap) == tail + 1 {
+                // The slot still has the value of the previous lap.
+                fence(Ordering::SeqCst);
+                let head = self.head.load(Ordering::Relaxed);
+                if head.wrapping_add(self.one_lap) == tail {
+                    return Err(SendError::Full(value));
+                }
+                // A receiver has taken the value but not released the slot yet.
+                hint::spin_loop();
+                tail = self.tail.load(Ordering::Relaxed);
+            } else {
+                // Another sender has taken the slot but not written it yet.
+                hint::spin_loop();
+                tail = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn try_recv(&self) -> Result<T, RecvError> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.buffer[head & (self.mark_bit - 1)];
+            let stamp = slot.stamp.load(Ordering::Acquire);
+            if stamp == head + 1 {
+                match self.head.compare_exchange_weak(
+                    head,
+                    self.next_index(head),
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = slot.value.with(|p| unsafe { p.read().assume_init() });
+                        slot.stamp
+                            .store(head.wrapping_add(self.one_lap), Ordering::Release);
+                        P::unpark();
+                        return Ok(value);
+                    }
+                    Err(current) => head = current,
+                }
+            } else if stamp == head {
+                // The slot has not been written in this lap.
+                fence(Ordering::SeqCst);
+                let tail = self.tail.load(Ordering::Relaxed);
+                if (tail & !self.mark_bit) == head {
+                    // Values sent before closing are still delivered.
+                    return if (tail & self.mark_bit) != 0 {
+                        Err(RecvError::Closed)
+                    } else {
+                        Err(RecvError::Empty)
+                    };
+                }
+                hint::spin_loop();
+                head = self.head.load(Ordering::Relaxed);
+            } else {
+                // Another receiver has taken the slot but not read it yet.
+                hint::spin_loop();
+                head = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn send(&self, mut value: T, timeout: Option<Duration>) -> Result<(), SendError<T>> {
+        let deadline = Deadline::<P>::new(timeout);
+        loop {
+            match self.try_send(value) {
+                Err(SendError::Full(v)) => value = v,
+                result => return result,
+            }
+            if !deadline.park() {
+                return Err(SendError::Timeout(value));
+            }
+        }
+    }
+
+    fn recv(&self, timeout: Option<Duration>) -> Result<T, RecvError> {
+        let deadline = Deadline::<P>::new(timeout);
+        loop {
+            match self.try_recv() {
+                Err(RecvError::Empty) => (),
+                result => return result,
+            }
+            if !deadline.park() {
+                return Err(RecvError::Timeout);
+            }
+        }
+    }
+}
+
+impl<T, P> Drop for Shared<T, P> {
+    fn drop(&mut self) {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Relaxed) & !self.mark_bit;
+        let mut index = head;
+        while index != tail {
+            let position = index & (self.mark_bit - 1);
+            self.buffer[position]
+                .value
+                .with_mut(|p| unsafe { (*p).assume_init_drop() });
+            index = if position + 1 < self.capacity {
+                index + 1
+            } else {
+                (index & !(self.one_lap - 1)).wrapping_add(self.one_lap)
+            };
+        }
+    }
+}
+
+/// The sending side of a [channel]
+pub struct Sender<T, P: Park> {
+    shared: Arc<Shared<T, P>>,
+}
+
+impl<T, P: Park> Sender<T, P> {
+    /// Sends `value`, waiting while the channel is full.
+    #[inline]
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared.send(value, None)
+    }
+
+    #[inline]
+    pub fn try_send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared.try_send(value)
+    }
+
+    #[inline]
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), SendError<T>> {
+        self.shared.send(value, Some(timeout))
+    }
+
+    /// Closes the channel for all senders and receivers.
+    #[inline]
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+impl<T, P: Park> Clone for Sender<T, P> {
+    #[inline]
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T, P: Park> Drop for Sender<T, P> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.close();
+        }
+    }
+}
+
+/// The receiving side of a [channel]
+pub struct Receiver<T, P: Park> {
+    shared: Arc<Shared<T, P>>,
+}
+
+impl<T, P: Park> Receiver<T, P> {
+    /// Receives a value, waiting while the channel is empty.
+    #[inline]
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.shared.recv(None)
+    }
+
+    #[inline]
+    pub fn try_recv(&self) -> Result<T, RecvError> {
+        self.shared.try_recv()
+    }
+
+    #[inline]
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvError> {
+        self.shared.recv(Some(timeout))
+    }
+
+    /// Closes the channel for all senders and receivers.
+    #[inline]
+    pub fn close(&self) {
+        self.shared.close();
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.shared.is_closed()
+    }
+}
+
+impl<T, P: Park> Clone for Receiver<T, P> {
+    #[inline]
+    fn clone(&self) -> Self {
+        self.shared.receivers.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T, P: Park> Drop for Receiver<T, P> {
+    fn drop(&mut self) {
+        if self.shared.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.close();
+        }
+    }
+}
+
+/// The end of a wait, if any
+struct Deadline<P>(Option<u64>, PhantomData<fn() -> P>);
+
+impl<P: Park> Deadline<P> {
+    #[inline]
+    fn new(timeout: Option<Duration>) -> Self {
+        Self(
+            timeout.map(|timeout| {
+                let ticks = timeout.as_nanos() * P::frequency() as u128 / 1_000_000_000;
+                P::counter().saturating_add(u64::try_from(ticks).unwrap_or(u64::MAX))
+            }),
+            PhantomData,
+        )
+    }
+
+    /// Waits for a change of the channel, and returns false once the deadline has passed.
+    #[inline]
+    fn park(&self) -> bool {
+        match self.0 {
+            Some(deadline) => {
+                hint::spin_loop();
+                P::counter() < deadline
+            }
+            None => {
+                P::park();
+                true
+            }
+        }
+    }
+}
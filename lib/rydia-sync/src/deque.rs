@@ -0,0 +1,201 @@
+//! Work-stealing deque
+//!
+//! A bounded Chase-Lev deque: the owning [Worker] pushes and pops at the bottom, and
+//! any number of [Stealer]s take from the top. A scheduler gives each core a worker
+//! and lets idle cores steal from the others.
+
+use crate::primitive::{
+    atomic::{fence, AtomicIsize, Ordering},
+    Arc, UnsafeCell,
+};
+use alloc::boxed::Box;
+use core::{marker::PhantomData, mem::MaybeUninit, ptr};
+
+/// Creates a deque that holds at least `capacity` values.
+pub fn deque<T>(capacity: usize) -> (Worker<T>, Stealer<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let buffer = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let inner = Arc::new(Inner {
+        top: AtomicIsize::new(0),
+        bottom: AtomicIsize::new(0),
+        mask: capacity - 1,
+        buffer,
+    });
+    (
+        Worker {
+            inner: inner.clone(),
+            _phantom: PhantomData,
+        },
+        Stealer { inner },
+    )
+}
+
+/// The result of [Stealer::steal]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Steal<T> {
+    Empty,
+    Success(T),
+    /// Another core took the value first.
+    Retry,
+}
+
+struct Inner<T> {
+    top: AtomicIsize,
+    bottom: AtomicIsize,
+    mask: usize,
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Inner<T> {
+    #[inline]
+    fn slot(&self, index: isize) -> &UnsafeCell<MaybeUninit<T>> {
+        &self.buffer[index as usize & self.mask]
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        let bottom = self.bottom.load(Ordering::Acquire);
+        let top = self.top.load(Ordering::Acquire);
+        (bottom - top).max(0) as usize
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let top = self.top.load(Ordering::Relaxed);
+        let bottom = self.bottom.load(Ordering::Relaxed);
+        for index in top..bottom {
+            self.slot(index)
+                .with_mut(|p| unsafe { (*p).assume_init_drop() });
+        }
+    }
+}
+
+/// The owning side of a [deque], used by a single core
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+    _phantom: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: Send> Send for Worker<T> {}
+
+impl<T> Worker<T> {
+    /// Pushes `value` at the bottom, or returns it if the deque is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let inner = &self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        let top = inner.top.load(Ordering::Acquire);
+        if bottom - top > inner.mask as isize {
+            return Err(value);
+        }
+        inner
+            .slot(bottom)
+            .with_mut(|p| unsafe { p.write(MaybeUninit::new(value)) });
+        inner.bottom.store(bottom + 1, Ordering::Release);
+        Ok(())
+    }
+
+    /// Pops the value pushed last.
+    pub fn pop(&self) -> Option<T> {
+        let inner = &self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed) - 1;
+        inner.bottom.store(bottom, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let top = inner.top.load(Ordering::Relaxed);
+
+        if top > bottom {
+            inner.bottom.store(bottom + 1, Ordering::Relaxed);
+            return None;
+        }
+        if top < bottom {
+            return Some(
+                inner
+                    .slot(bottom)
+                    .with(|p| unsafe { (*p).assume_init_read() }),
+            );
+        }
+        // The last value, which stealers may be taking too
+        let result = inner
+            .top
+            .compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed)
+            .ok()
+            .map(|_| {
+                inner
+                    .slot(bottom)
+                    .with(|p| unsafe { (*p).assume_init_read() })
+            });
+        inner.bottom.store(bottom + 1, Ordering::Relaxed);
+        result
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns another stealer of this deque.
+    #[inline]
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// The stealing side of a [deque], which any core may use
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Stealer<T> {
+    /// Takes the value pushed first.
+    pub fn steal(&self) -> Steal<T> {
+        let inner = &self.inner;
+        let top = inner.top.load(Ordering::Acquire);
+        fence(Ordering::SeqCst);
+        let bottom = inner.bottom.load(Ordering::Acquire);
+        if top >= bottom {
+            return Steal::Empty;
+        }
+        // The slot can be overwritten once another stealer takes it, so the copy is
+        // kept only if this stealer wins.
+        let value = inner.slot(top).with(|p| unsafe { ptr::read_volatile(p) });
+        match inner
+            .top
+            .compare_exchange(top, top + 1, Ordering::SeqCst, Ordering::Relaxed)
+        {
+            Ok(_) => Steal::Success(unsafe { value.assume_init() }),
+            Err(_) => Steal::Retry,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for Stealer<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
@@ -0,0 +1,15 @@
+//! Lock-free channels and queues shared by the kernel and the host tests
+//!
+//! The crate builds without the kernel so that its interleavings can be checked on
+//! the host with loom:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release
+//! ```
+#![cfg_attr(not(loom), no_std)]
+
+extern crate alloc;
+
+pub mod channel;
+pub mod deque;
+mod primitive;
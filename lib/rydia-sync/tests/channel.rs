@@ -0,0 +1,140 @@
+//! Interleavings of the channel under loom
+#![cfg(loom)]
+
+use loom::thread;
+use rydia_sync::channel::{channel, Park, RecvError, SendError};
+
+struct LoomPark;
+
+impl Park for LoomPark {
+    fn park() {
+        thread::yield_now();
+    }
+
+    fn unpark() {}
+
+    fn counter() -> u64 {
+        0
+    }
+
+    fn frequency() -> u64 {
+        1
+    }
+}
+
+#[test]
+fn send_recv_in_order() {
+    loom::model(|| {
+        let (tx, rx) = channel::<usize, LoomPark>(1);
+        let sender = thread::spawn(move || {
+            tx.send(1).unwrap();
+            tx.send(2).unwrap();
+        });
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.recv(), Ok(2));
+        sender.join().unwrap();
+        assert_eq!(rx.recv(), Err(RecvError::Closed));
+    });
+}
+
+#[test]
+fn send_races_close() {
+    loom::model(|| {
+        let (tx, rx) = channel::<usize, LoomPark>(2);
+        let closer = {
+            let tx = tx.clone();
+            thread::spawn(move || tx.close())
+        };
+        let sent = match tx.try_send(1) {
+            Ok(()) => true,
+            Err(SendError::Closed(1)) => false,
+            Err(err) => panic!("unexpected {:?}", err),
+        };
+        closer.join().unwrap();
+        // A value is either rejected or delivered, even after closing.
+        if sent {
+            assert_eq!(rx.try_recv(), Ok(1));
+        }
+        assert_eq!(rx.try_recv(), Err(RecvError::Closed));
+        assert!(matches!(tx.try_send(2), Err(SendError::Closed(2))));
+    });
+}
+
+#[test]
+fn recv_races_last_sender_drop() {
+    loom::model(|| {
+        let (tx, rx) = channel::<usize, LoomPark>(1);
+        let sender = thread::spawn(move || {
+            tx.send(1).unwrap();
+            drop(tx);
+        });
+        assert_eq!(rx.recv(), Ok(1));
+        assert_eq!(rx.recv(), Err(RecvError::Closed));
+        sender.join().unwrap();
+    });
+}
+
+#[test]
+fn senders_race_each_other() {
+    // The receiver spins on a slot taken by a sender, which explodes without a bound.
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(3);
+    builder.check(|| {
+        let (tx, rx) = channel::<usize, LoomPark>(2);
+        let senders: Vec<_> = (1..=2)
+            .map(|value| {
+                let tx = tx.clone();
+                thread::spawn(move || tx.try_send(value).unwrap())
+            })
+            .collect();
+        drop(tx);
+        let first = rx.try_recv().unwrap_or(0);
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        let second = rx.try_recv().unwrap();
+        let third = rx.try_recv().unwrap_or(0);
+        assert_eq!(first + second + third, 3);
+        assert_eq!(rx.try_recv(), Err(RecvError::Closed));
+    });
+}
+
+#[test]
+fn receivers_race_each_other() {
+    loom::model(|| {
+        let (tx, rx) = channel::<usize, LoomPark>(2);
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        let receivers: Vec<_> = (0..2)
+            .map(|_| {
+                let rx = rx.clone();
+                thread::spawn(move || rx.try_recv().unwrap())
+            })
+            .collect();
+        // The third value races the receivers for a free slot.
+        let sent = tx.try_send(3).is_ok();
+        let received: usize = receivers.into_iter().map(|v| v.join().unwrap()).sum();
+        assert_eq!(received, 3);
+        let rest = if sent { Ok(3) } else { Err(RecvError::Empty) };
+        assert_eq!(rx.try_recv(), rest);
+    });
+}
+
+#[test]
+fn pending_values_dropped_with_channel() {
+    loom::model(|| {
+        let value = loom::sync::Arc::new(());
+        let (tx, rx) = channel::<loom::sync::Arc<()>, LoomPark>(2);
+        let sender = {
+            let value = value.clone();
+            thread::spawn(move || {
+                let _ = tx.try_send(value.clone());
+                let _ = tx.try_send(value);
+            })
+        };
+        let _ = rx.try_recv();
+        sender.join().unwrap();
+        drop(rx);
+        assert_eq!(loom::sync::Arc::strong_count(&value), 1);
+    });
+}
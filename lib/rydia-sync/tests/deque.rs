@@ -0,0 +1,87 @@
+//! Interleavings of the work-stealing deque under loom
+#![cfg(loom)]
+
+use loom::thread;
+use rydia_sync::deque::{deque, Steal, Stealer};
+
+/// Steals until the deque is empty, and returns the sum of the values taken.
+fn steal_all(stealer: &Stealer<usize>) -> usize {
+    let mut sum = 0;
+    loop {
+        match stealer.steal() {
+            Steal::Success(value) => sum += value,
+            Steal::Retry => thread::yield_now(),
+            Steal::Empty => return sum,
+        }
+    }
+}
+
+#[test]
+fn pop_races_steal_of_last_value() {
+    loom::model(|| {
+        let (worker, stealer) = deque::<usize>(2);
+        worker.push(1).unwrap();
+        let thief = thread::spawn(move || steal_all(&stealer));
+        let popped = worker.pop().unwrap_or(0);
+        let stolen = thief.join().unwrap();
+        assert_eq!(popped + stolen, 1);
+        assert!(worker.pop().is_none());
+    });
+}
+
+#[test]
+fn pop_and_steal_take_each_value_once() {
+    loom::model(|| {
+        let (worker, stealer) = deque::<usize>(4);
+        worker.push(1).unwrap();
+        worker.push(2).unwrap();
+        let thief = thread::spawn(move || steal_all(&stealer));
+        let mut popped = 0;
+        while let Some(value) = worker.pop() {
+            popped += value;
+        }
+        let stolen = thief.join().unwrap();
+        assert_eq!(popped + stolen, 3);
+    });
+}
+
+#[test]
+fn stealers_race_each_other() {
+    loom::model(|| {
+        let (worker, stealer) = deque::<usize>(4);
+        worker.push(1).unwrap();
+        worker.push(2).unwrap();
+        let thieves: Vec<_> = (0..2)
+            .map(|_| {
+                let stealer = stealer.clone();
+                thread::spawn(move || steal_all(&stealer))
+            })
+            .collect();
+        let stolen: usize = thieves.into_iter().map(|v| v.join().unwrap()).sum();
+        assert_eq!(stolen + worker.pop().unwrap_or(0), 3);
+    });
+}
+
+#[test]
+fn push_races_steal() {
+    loom::model(|| {
+        let (worker, stealer) = deque::<usize>(2);
+        let thief = thread::spawn(move || {
+            let mut sum = 0;
+            for _ in 0..2 {
+                if let Steal::Success(value) = stealer.steal() {
+                    sum += value;
+                }
+            }
+            sum
+        });
+        worker.push(1).unwrap();
+        worker.push(2).unwrap();
+        let stolen = thief.join().unwrap();
+        let mut popped = 0;
+        while let Some(value) = worker.pop() {
+            popped += value;
+        }
+        assert_eq!(popped + stolen, 3);
+    });
+}